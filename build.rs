@@ -1,4 +1,44 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/file_service.proto")?;
+    // Read payloads are generated as `Bytes`, so a record read from disk is
+    // sliced into the response without copying
+    tonic_build::configure()
+        .bytes([".fileservice.ReadResponse.data"])
+        .compile(&["proto/file_service.proto"], &["proto"])?;
+    tonic_build::compile_protos("proto/admin_service.proto")?;
+    if std::env::var_os("CARGO_FEATURE_SPDK").is_some() {
+        link_spdk();
+    }
     Ok(())
+}
+
+// Link SPDK's NVMe driver and environment, as pkg-config describes them for
+// an SPDK install whose `lib/pkgconfig` is on PKG_CONFIG_PATH, else from the
+// install under SPDK_DIR (by default /usr/local)
+fn link_spdk() {
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    println!("cargo:rerun-if-env-changed=SPDK_DIR");
+    let libs = std::process::Command::new("pkg-config")
+        .args(["--libs", "spdk_nvme", "spdk_env_dpdk", "spdk_syslibs"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned());
+    let Some(libs) = libs else {
+        let dir = std::env::var("SPDK_DIR").unwrap_or_else(|_| "/usr/local".to_string());
+        println!("cargo:warning=pkg-config does not know SPDK, linking it from {}/lib", dir);
+        println!("cargo:rustc-link-search=native={}/lib", dir);
+        for lib in ["spdk_nvme", "spdk_env_dpdk", "spdk_util", "spdk_log", "rte_eal", "rte_mempool", "rte_ring", "rte_bus_pci", "rte_pci"] {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+        return;
+    };
+    for flag in libs.split_whitespace() {
+        if let Some(dir) = flag.strip_prefix("-L") {
+            println!("cargo:rustc-link-search=native={}", dir);
+        } else if let Some(lib) = flag.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib={}", lib);
+        } else {
+            println!("cargo:rustc-link-arg={}", flag);
+        }
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,432 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::adminservice::admin_service_server::AdminService;
+use crate::adminservice::{CompactRequest, CompactResponse};
+use crate::adminservice::{MaintenanceModeRequest, MaintenanceModeResponse};
+use crate::adminservice::{RebuildIndexRequest, RebuildIndexResponse};
+use crate::adminservice::{CreateSnapshotRequest, CreateSnapshotResponse};
+use crate::adminservice::{RestoreSnapshotRequest, RestoreSnapshotResponse};
+use crate::adminservice::{StatsRequest, StatsResponse};
+use crate::adminservice::{TruncateRequest, TruncateResponse};
+use crate::adminservice::{ScrubStatusRequest, ScrubStatusResponse, CorruptRecord};
+use crate::adminservice::{MigrateRequest, MigrateResponse};
+use crate::adminservice::{BackupRequest, BackupResponse};
+use crate::backup::Backup;
+use crate::file_io::sector_size;
+use crate::file_manager::{self, FileManager, FileRegistry};
+use crate::migrate;
+use crate::scrubber::ScrubStatus;
+use crate::snapshot;
+
+// Build the interceptor guarding AdminService. Callers must send
+// `authorization: Bearer <token>`; without a configured token every call is
+// refused.
+#[allow(clippy::result_large_err)]
+pub(crate) fn authorize(token: Option<String>) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request: Request<()>| {
+        let Some(expected) = token.as_deref() else {
+            return Err(Status::permission_denied("Admin API is disabled, no admin token configured"));
+        };
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if provided == expected => Ok(request),
+            _ => Err(Status::unauthenticated("Missing or invalid admin token")),
+        }
+    }
+}
+
+// Privileged operations on the data files served by FileService
+pub struct AdminServiceImpl {
+    files: Arc<FileRegistry>,
+    // Shared with FileService, which rejects mutations while it is set
+    maintenance: Arc<AtomicBool>,
+    // Updated by the background scrubber, if it runs
+    scrub_status: Arc<Mutex<ScrubStatus>>,
+    // Object storage that snapshots are backed up to, if configured
+    backup: Option<Arc<Backup>>,
+}
+
+impl AdminServiceImpl {
+    pub(crate) fn new(files: Arc<FileRegistry>, maintenance: Arc<AtomicBool>, scrub_status: Arc<Mutex<ScrubStatus>>, backup: Option<Arc<Backup>>) -> Self {
+        Self { files, maintenance, scrub_status, backup }
+    }
+
+    async fn file_manager(&self, file_id: &str) -> Result<Arc<Mutex<FileManager>>, Status> {
+        file_manager::resolve_file_id(file_id).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.files.get(file_id).await.map_err(|e| {
+            Status::internal(format!("Failed to open file {}: {}", file_id, e))
+        })
+    }
+
+    async fn handle_truncate(&self, req: TruncateRequest) -> Result<TruncateResponse, Status> {
+        let manager = self.file_manager(&req.file_id).await?;
+        let offset = req.offset;
+
+        warn!("Received truncate request to offset {}", offset);
+
+        if !offset.is_multiple_of(sector_size()) {
+            return Err(Status::invalid_argument(format!(
+                "Offset {} is not aligned to {} bytes", offset, sector_size()
+            )));
+        }
+
+        let mut file_manager = manager.lock().unwrap();
+        match file_manager.truncate(offset) {
+            Ok(removed) => Ok(TruncateResponse {
+                success: true,
+                error_message: String::new(),
+                removed_records: removed.len() as u64,
+                file_size: offset,
+            }),
+            Err(e) => {
+                error!("Truncate to offset {} failed: {}", offset, e);
+                Ok(TruncateResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    removed_records: 0,
+                    file_size: file_manager.space.end(),
+                })
+            }
+        }
+    }
+
+    async fn handle_compact(&self, req: CompactRequest) -> Result<CompactResponse, Status> {
+        let manager = self.file_manager(&req.file_id).await?;
+
+        warn!("Received compact request for file {:?}", req.file_id);
+
+        match file_manager::compact(&manager).await {
+            Ok(stats) => Ok(CompactResponse {
+                success: true,
+                error_message: String::new(),
+                records_moved: stats.records_moved,
+                reclaimed_bytes: stats.reclaimed_bytes,
+                file_size: stats.file_size,
+            }),
+            Err(e) => {
+                error!("Compaction of file {:?} failed: {}", req.file_id, e);
+                Ok(CompactResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    records_moved: 0,
+                    reclaimed_bytes: 0,
+                    file_size: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_stats(&self, req: StatsRequest) -> Result<StatsResponse, Status> {
+        let manager = self.file_manager(&req.file_id).await?;
+        let file_manager = manager.lock().unwrap();
+
+        Ok(StatsResponse {
+            data_file: file_manager.file_path.clone(),
+            file_size: file_manager.space.end(),
+            record_count: file_manager.record_count() as u64,
+            namespace_count: file_manager.namespace_count() as u64,
+            reclaimable_bytes: file_manager.reclaimable_bytes(),
+            in_flight_writes: file_manager.space.in_flight().len() as u64,
+            maintenance_mode: self.maintenance.load(Ordering::SeqCst),
+        })
+    }
+
+    async fn handle_maintenance_mode(&self, req: MaintenanceModeRequest) -> Result<MaintenanceModeResponse, Status> {
+        let previous = self.maintenance.swap(req.enabled, Ordering::SeqCst);
+        if previous != req.enabled {
+            warn!("Maintenance mode {}", if req.enabled { "enabled" } else { "disabled" });
+        }
+
+        Ok(MaintenanceModeResponse { previous, enabled: req.enabled })
+    }
+
+    async fn handle_create_snapshot(&self, req: CreateSnapshotRequest) -> Result<CreateSnapshotResponse, Status> {
+        let file_id = file_manager::resolve_file_id(&req.file_id).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let manager = self.file_manager(file_id).await?;
+        let snapshot_id = uuid::Uuid::new_v4().to_string();
+
+        let parent_id = Some(req.parent_snapshot_id.as_str()).filter(|parent_id| !parent_id.is_empty());
+
+        match parent_id {
+            Some(parent_id) => info!("Creating snapshot {} of file {:?} on top of {}", snapshot_id, file_id, parent_id),
+            None => info!("Creating snapshot {} of file {:?}", snapshot_id, file_id),
+        }
+
+        // Writes are rejected while the data file is copied; maintenance mode
+        // is left on if an operator had already enabled it
+        let entered = !self.maintenance.swap(true, Ordering::SeqCst);
+        let created = snapshot::create(&manager, file_id, &snapshot_id, self.files.snapshot_dir(&snapshot_id), parent_id).await;
+        if entered {
+            self.maintenance.store(false, Ordering::SeqCst);
+        }
+
+        match created {
+            Ok(manifest) => Ok(CreateSnapshotResponse {
+                success: true,
+                error_message: String::new(),
+                snapshot_id,
+                record_count: manifest.record_count,
+                data_size: manifest.data_size,
+                copied_bytes: manifest.copied_bytes(),
+            }),
+            Err(e) => {
+                error!("Snapshot of file {:?} failed: {}", file_id, e);
+                Ok(CreateSnapshotResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    snapshot_id: String::new(),
+                    record_count: 0,
+                    data_size: 0,
+                    copied_bytes: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_restore_snapshot(&self, req: RestoreSnapshotRequest) -> Result<RestoreSnapshotResponse, Status> {
+        warn!("Received restore request for snapshot {}", req.snapshot_id);
+
+        let entered = !self.maintenance.swap(true, Ordering::SeqCst);
+        let restored = snapshot::restore(&self.files, &req.snapshot_id).await;
+        if entered {
+            self.maintenance.store(false, Ordering::SeqCst);
+        }
+
+        match restored {
+            Ok(manifest) => Ok(RestoreSnapshotResponse {
+                success: true,
+                error_message: String::new(),
+                file_id: manifest.file_id,
+                record_count: manifest.record_count,
+                data_size: manifest.data_size,
+            }),
+            Err(e) => {
+                error!("Restore from snapshot {} failed: {}", req.snapshot_id, e);
+                Ok(RestoreSnapshotResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    file_id: String::new(),
+                    record_count: 0,
+                    data_size: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_rebuild_index(&self, req: RebuildIndexRequest) -> Result<RebuildIndexResponse, Status> {
+        let manager = self.file_manager(&req.file_id).await?;
+
+        info!("Received index rebuild request for file {:?}", req.file_id);
+
+        let mut file_manager = manager.lock().unwrap();
+        match file_manager.rebuild_free_extents(SystemTime::now()) {
+            Ok(expired) => Ok(RebuildIndexResponse {
+                success: true,
+                error_message: String::new(),
+                expired_records: expired as u64,
+                free_extents: file_manager.space.free_extent_count() as u64,
+                reclaimable_bytes: file_manager.reclaimable_bytes(),
+            }),
+            Err(e) => {
+                error!("Index rebuild of file {:?} failed: {}", req.file_id, e);
+                Ok(RebuildIndexResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    expired_records: 0,
+                    free_extents: 0,
+                    reclaimable_bytes: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_migrate(&self, req: MigrateRequest) -> Result<MigrateResponse, Status> {
+        let manager = self.file_manager(&req.file_id).await?;
+
+        warn!("Received request to migrate file {:?} to {}", req.file_id, req.target_path);
+
+        // Writes are rejected while records are copied; reads go on against
+        // the old file
+        let entered = !self.maintenance.swap(true, Ordering::SeqCst);
+        let migrated = migrate::migrate(&manager, Path::new(&req.target_path)).await;
+        if entered {
+            self.maintenance.store(false, Ordering::SeqCst);
+        }
+
+        match migrated {
+            Ok(stats) => Ok(MigrateResponse {
+                success: true,
+                error_message: String::new(),
+                records_moved: stats.records_moved,
+                data_size: stats.data_size,
+            }),
+            Err(e) => {
+                error!("Migration of file {:?} to {} failed: {}", req.file_id, req.target_path, e);
+                Ok(MigrateResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    records_moved: 0,
+                    data_size: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_backup(&self, req: BackupRequest) -> Result<BackupResponse, Status> {
+        let Some(backup) = &self.backup else {
+            return Err(Status::failed_precondition("Backups are not configured, start the server with --backup-bucket"));
+        };
+
+        let backed_up = if req.snapshot_id.is_empty() {
+            file_manager::resolve_file_id(&req.file_id).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            info!("Backing up file {:?}", req.file_id);
+            let parent_id = Some(req.parent_snapshot_id.as_str()).filter(|parent_id| !parent_id.is_empty());
+            backup.back_up(&self.files, &self.maintenance, &req.file_id, parent_id).await
+        } else {
+            info!("Backing up snapshot {}", req.snapshot_id);
+            backup.upload(&self.files, &req.snapshot_id).await
+        };
+
+        match backed_up {
+            Ok(stats) => Ok(BackupResponse {
+                success: true,
+                error_message: String::new(),
+                snapshot_id: stats.snapshot_id,
+                object_prefix: stats.object_prefix,
+                uploaded_bytes: stats.uploaded_bytes,
+            }),
+            Err(e) => {
+                error!("Backup of file {:?} failed: {}", req.file_id, e);
+                Ok(BackupResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    snapshot_id: req.snapshot_id,
+                    object_prefix: String::new(),
+                    uploaded_bytes: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_scrub_status(&self, _req: ScrubStatusRequest) -> Result<ScrubStatusResponse, Status> {
+        let status = self.scrub_status.lock().unwrap();
+        let corrupt_records = status
+            .corrupt
+            .iter()
+            .map(|corrupt| CorruptRecord {
+                data_file: corrupt.data_file.clone(),
+                namespace: corrupt.key.namespace.clone(),
+                request_id: corrupt.key.request_id.clone(),
+                generation: corrupt.generation,
+                offset: corrupt.offset,
+                error: corrupt.error.clone(),
+                detected_at_ms: crate::unix_millis(corrupt.detected_at),
+            })
+            .collect();
+
+        Ok(ScrubStatusResponse {
+            enabled: status.enabled,
+            running: status.pass_started_at.is_some(),
+            current_file: status.current_file.clone().unwrap_or_default(),
+            passes_completed: status.passes_completed,
+            records_checked: status.records_checked,
+            bytes_checked: status.bytes_checked,
+            pass_started_at_ms: status.pass_started_at.map_or(0, crate::unix_millis),
+            last_pass_completed_at_ms: status.last_pass_completed_at.map_or(0, crate::unix_millis),
+            corrupt_records,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminServiceImpl {
+    async fn truncate(
+        &self,
+        request: Request<TruncateRequest>,
+    ) -> Result<Response<TruncateResponse>, Status> {
+        let response = self.handle_truncate(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let response = self.handle_compact(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let response = self.handle_stats(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn set_maintenance_mode(
+        &self,
+        request: Request<MaintenanceModeRequest>,
+    ) -> Result<Response<MaintenanceModeResponse>, Status> {
+        let response = self.handle_maintenance_mode(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn rebuild_index(
+        &self,
+        request: Request<RebuildIndexRequest>,
+    ) -> Result<Response<RebuildIndexResponse>, Status> {
+        let response = self.handle_rebuild_index(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CreateSnapshotResponse>, Status> {
+        let response = self.handle_create_snapshot(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn restore_snapshot(
+        &self,
+        request: Request<RestoreSnapshotRequest>,
+    ) -> Result<Response<RestoreSnapshotResponse>, Status> {
+        let response = self.handle_restore_snapshot(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_scrub_status(
+        &self,
+        request: Request<ScrubStatusRequest>,
+    ) -> Result<Response<ScrubStatusResponse>, Status> {
+        let response = self.handle_scrub_status(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn migrate(
+        &self,
+        request: Request<MigrateRequest>,
+    ) -> Result<Response<MigrateResponse>, Status> {
+        let response = self.handle_migrate(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<BackupResponse>, Status> {
+        let response = self.handle_backup(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::file_io::IoPriority;
+
+// Priority class a client asks for a request to run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestClass {
+    // Latency-sensitive requests, the default
+    Interactive,
+    // Throughput-bound requests such as bulk loads, which wait behind
+    // interactive ones and have an in-flight budget of their own
+    Bulk,
+}
+
+impl RequestClass {
+    // Parse the value of a request's priority header
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "interactive" => Some(RequestClass::Interactive),
+            "bulk" => Some(RequestClass::Bulk),
+            _ => None,
+        }
+    }
+
+    // Priority of the data file I/O the class's requests make
+    pub(crate) fn io_priority(self) -> IoPriority {
+        match self {
+            RequestClass::Interactive => IoPriority::Normal,
+            RequestClass::Bulk => IoPriority::Bulk,
+        }
+    }
+}
+
+// A request turned away because the server already has as many storage
+// operations in flight as it admits
+#[derive(Debug)]
+pub(crate) struct Overloaded {
+    pub(crate) limit: usize,
+    pub(crate) retry_after: Duration,
+}
+
+impl std::fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server has {} storage operations in flight, retry after {:?}", self.limit, self.retry_after)
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+// Bound on the storage operations in flight at once. Requests over the bound
+// fail at once rather than queueing, so under overload latency stays that of
+// the admitted operations and clients back off instead of piling on. A
+// rejected request is told to retry after about as long as an operation has
+// recently taken, by when one has likely finished.
+pub(crate) struct Admission {
+    permits: Arc<Semaphore>,
+    limit: usize,
+    // Moving average of how long admitted operations take, in microseconds
+    latency_micros: AtomicU64,
+}
+
+impl Admission {
+    pub(crate) fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self { permits: Arc::new(Semaphore::new(limit)), limit, latency_micros: AtomicU64::new(0) })
+    }
+
+    // Admit an operation, which stays in flight until the returned guard is
+    // dropped, or refuse it if the bound is reached
+    pub(crate) fn admit(self: &Arc<Self>) -> Result<Admitted, Overloaded> {
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Admitted { admission: self.clone(), _permit: permit, start: Instant::now() }),
+            Err(_) => Err(Overloaded { limit: self.limit, retry_after: self.retry_after() }),
+        }
+    }
+
+    // At least a millisecond, the resolution clients can wait to
+    fn retry_after(&self) -> Duration {
+        Duration::from_micros(self.latency_micros.load(Ordering::Relaxed)).max(Duration::from_millis(1))
+    }
+
+    // Fold an operation's duration into the average, weighted 1/8
+    fn record(&self, took: Duration) {
+        let took = took.as_micros() as u64;
+        let _ = self.latency_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(if average == 0 { took } else { average - average / 8 + took / 8 })
+        });
+    }
+}
+
+// An admitted operation
+pub(crate) struct Admitted {
+    admission: Arc<Admission>,
+    _permit: OwnedSemaphorePermit,
+    start: Instant,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.admission.record(self.start.elapsed());
+    }
+}
@@ -0,0 +1,202 @@
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+use crate::file_io::sector_size;
+
+// Alignment of every buffer at least; covers 512-byte and 4Kn devices alike
+const MIN_ALIGN: usize = 4096;
+
+// Buffers larger than this are allocated and freed directly rather than pooled
+const MAX_POOLED: usize = 4 * 1024 * 1024;
+
+// Free buffers kept per size class; more are freed when returned
+const BUFFERS_PER_CLASS: usize = 16;
+
+// Largest buffer one read or write of a data file is given; larger transfers
+// are split into pieces of this size, see `file_io::write_in_pieces`
+static MAX_BUFFER: AtomicU64 = AtomicU64::new(MAX_POOLED as u64);
+
+pub(crate) fn max_buffer_size() -> u64 {
+    MAX_BUFFER.load(Ordering::Relaxed)
+}
+
+// Set the largest buffer, a multiple of the sector size
+pub(crate) fn set_max_buffer_size(size: u64) {
+    MAX_BUFFER.store(size, Ordering::Relaxed);
+}
+
+// Size of a huge page, and the smallest buffer backed by them
+const HUGE_PAGE: usize = 2 * 1024 * 1024;
+
+// Whether buffers of a huge page or more are backed by huge pages
+static HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+
+// Set once a huge page mapping has failed, so the fallback is logged once
+static HUGE_PAGES_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+// Back buffers of 2 MiB or more with 2 MiB huge pages, which cover a large
+// transfer with a few TLB entries instead of hundreds. Pages come from the
+// kernel's reserved pool (vm.nr_hugepages); while it is empty buffers use
+// normal pages.
+pub(crate) fn enable_huge_pages() {
+    HUGE_PAGES.store(true, Ordering::Relaxed);
+}
+
+// Map `capacity` bytes, a multiple of the huge page size, of huge pages
+#[cfg(target_os = "linux")]
+fn map_huge(capacity: usize) -> Option<NonNull<u8>> {
+    // SAFETY: an anonymous private mapping at an address of the kernel's
+    // choosing has no effect on existing memory
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            capacity,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        if !HUGE_PAGES_EXHAUSTED.swap(true, Ordering::Relaxed) {
+            warn!("Could not map {} bytes of huge pages, using normal pages: {}", capacity, std::io::Error::last_os_error());
+        }
+        return None;
+    }
+    NonNull::new(ptr.cast())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_huge(_capacity: usize) -> Option<NonNull<u8>> {
+    if !HUGE_PAGES_EXHAUSTED.swap(true, Ordering::Relaxed) {
+        warn!("Huge pages are not supported on this platform, using normal pages");
+    }
+    None
+}
+
+// Memory of a free buffer, owned by the pool, and whether it is huge pages
+struct FreeBuffer(NonNull<u8>, bool);
+
+// SAFETY: a free buffer is owned by the pool alone and never aliased
+unsafe impl Send for FreeBuffer {}
+
+// Free buffers by alignment and capacity
+type Pool = HashMap<(usize, usize), Vec<FreeBuffer>>;
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+// Heap buffer whose address and capacity are multiples of the sector size, as
+// O_DIRECT requires of the memory it transfers, not just of offsets and
+// lengths. Buffers of up to 4 MiB are rounded up to a power of two and come
+// from a pool, to which they return when dropped, so the hot read and write
+// paths do not allocate.
+pub(crate) struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+    // Mapped huge pages rather than heap memory
+    huge: bool,
+}
+
+// SAFETY: the buffer owns its memory exclusively, like a Vec<u8>
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    // Zeroed buffer of `len` bytes
+    pub(crate) fn zeroed(len: usize) -> Self {
+        let buffer = Self::take(len);
+        // SAFETY: the buffer's memory spans its capacity, which is at least `len`
+        unsafe { std::ptr::write_bytes(buffer.ptr.as_ptr(), 0, len) };
+        buffer
+    }
+
+    // Copy of `data`, zero-padded to `len` bytes
+    pub(crate) fn copy_from(data: &[u8], len: usize) -> Self {
+        let len = len.max(data.len());
+        let buffer = Self::take(len);
+        // SAFETY: the buffer's memory spans at least `len` bytes and does not
+        // overlap `data`
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buffer.ptr.as_ptr(), data.len());
+            std::ptr::write_bytes(buffer.ptr.as_ptr().add(data.len()), 0, len - data.len());
+        }
+        buffer
+    }
+
+    // Uninitialized buffer of `len` bytes from the pool, or newly allocated
+    fn take(len: usize) -> Self {
+        let align = MIN_ALIGN.max(sector_size() as usize);
+        let mut capacity = if len <= MAX_POOLED { len.next_power_of_two().max(align) } else { len.div_ceil(align) * align };
+        let huge = capacity >= HUGE_PAGE && HUGE_PAGES.load(Ordering::Relaxed);
+        if huge {
+            capacity = capacity.div_ceil(HUGE_PAGE) * HUGE_PAGE;
+        }
+        let layout = Layout::from_size_align(capacity, align).expect("buffer layout");
+        let pooled = POOL.lock().unwrap().as_mut().and_then(|pool| pool.get_mut(&(align, capacity))?.pop());
+        let (ptr, huge) = match pooled {
+            Some(FreeBuffer(ptr, huge)) => (ptr, huge),
+            None => match huge.then(|| map_huge(capacity)).flatten() {
+                // Huge pages are aligned to their size, beyond any sector size
+                Some(ptr) => (ptr, true),
+                // SAFETY: the layout has a nonzero size
+                None => (NonNull::new(unsafe { alloc::alloc(layout) }).unwrap_or_else(|| alloc::handle_alloc_error(layout)), false),
+            },
+        };
+        Self { ptr, len, layout, huge }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.layout.size() <= MAX_POOLED {
+            let mut pool = POOL.lock().unwrap();
+            let free = pool.get_or_insert_with(HashMap::new).entry((self.layout.align(), self.layout.size())).or_default();
+            if free.len() < BUFFERS_PER_CLASS {
+                free.push(FreeBuffer(self.ptr, self.huge));
+                return;
+            }
+        }
+        if self.huge {
+            // SAFETY: the memory is a mapping of this size made by `map_huge`
+            // and is not pooled
+            #[cfg(target_os = "linux")]
+            unsafe {
+                libc::munmap(self.ptr.as_ptr().cast(), self.layout.size());
+            }
+            return;
+        }
+        // SAFETY: the memory was allocated with this layout and is not pooled
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the first `len` bytes are initialized and owned by the buffer
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// Lets a buffer back a `Bytes`, which returns it to the pool once the last
+// view of it is dropped
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the first `len` bytes are initialized and owned by the buffer
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
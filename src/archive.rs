@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::compression::{Compressor, Payload};
+use crate::encryption::RecordCipher;
+use crate::file_io::FileIO;
+use crate::file_manager::{self, FileRegistry, RecordKey, RequestMetadata};
+use crate::record_format;
+
+// Portable archive of live records, independent of the data file layout. It
+// is written and read front to back:
+//
+//   magic "ODGRPCAR" | version: u32
+//   per record: header length: u32 | JSON header | payload
+//   end: 0: u32 | record count: u64
+//
+// Integers are little-endian. Payloads are stored as clients wrote them,
+// decrypted and decompressed; the header carries their CRC-32.
+const MAGIC: &[u8; 8] = b"ODGRPCAR";
+const VERSION: u32 = 1;
+
+// Longest record header accepted when reading an archive
+const MAX_HEADER_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedRecord {
+    file_id: String,
+    namespace: String,
+    request_id: String,
+    size: u64,
+    crc32: u32,
+    written_at_ms: u64,
+    expires_at_ms: Option<u64>,
+    // Informational; imported records start over at generation 1
+    generation: u64,
+    metadata: HashMap<String, String>,
+}
+
+// Outcome of an export or import
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ArchiveStats {
+    pub(crate) records: u64,
+    // Payload bytes
+    pub(crate) bytes: u64,
+    // Records not imported because their TTL had passed
+    pub(crate) expired: u64,
+}
+
+// Read a record's payload as its client wrote it
+async fn read_payload(file: &(dyn FileIO + Send + Sync), cipher: Option<&RecordCipher>, key: &RecordKey, metadata: &RequestMetadata) -> Result<Bytes> {
+    // An encrypted record is read with its header, which holds its nonce and tag
+    let stored = record_format::read_stored(file, metadata, metadata.encrypted).await?;
+    record_format::open_payload(cipher, &key.request_id, metadata, stored)
+}
+
+// Write every live record of the given data files to `out`, each file's
+// records in file order. Every payload is verified against its checksum on
+// the way; a record that fails stops the export.
+pub(crate) async fn export(files: &FileRegistry, file_ids: &[String], cipher: Option<&RecordCipher>, out: &mut dyn Write) -> Result<ArchiveStats> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+
+    let mut stats = ArchiveStats::default();
+    for file_id in file_ids {
+        let manager = files.get(file_id).await?;
+        let (mut records, file) = {
+            let file_manager = manager.lock().unwrap();
+            (file_manager.entries(SystemTime::now()), file_manager.file.clone())
+        };
+        records.sort_by_key(|(_, metadata)| metadata.offset);
+
+        for (key, metadata) in records {
+            let payload = read_payload(file.as_ref(), cipher, &key, &metadata)
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot export {:?} of file {:?}: {}", key.request_id, file_id, e))?;
+            let header = serde_json::to_vec(&ArchivedRecord {
+                file_id: file_id.clone(),
+                namespace: key.namespace,
+                request_id: key.request_id,
+                size: payload.len() as u64,
+                crc32: record_format::checksum(&payload),
+                written_at_ms: crate::unix_millis(metadata.written_at),
+                expires_at_ms: metadata.expires_at.map(crate::unix_millis),
+                generation: metadata.generation,
+                metadata: metadata.user_metadata,
+            })?;
+            out.write_all(&(header.len() as u32).to_le_bytes())?;
+            out.write_all(&header)?;
+            out.write_all(&payload)?;
+            stats.records += 1;
+            stats.bytes += payload.len() as u64;
+        }
+        info!("Exported file {:?}", file_id);
+    }
+
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&stats.records.to_le_bytes())?;
+    out.flush()?;
+    Ok(stats)
+}
+
+// Write a record into its data file as a client write would, encrypting and
+// compressing it as configured, and index it
+async fn store(files: &FileRegistry, record: ArchivedRecord, payload: Vec<u8>, cipher: Option<&RecordCipher>, compressor: Option<&Compressor>) -> Result<()> {
+    let manager = files.get(&record.file_id).await?;
+    let key = RecordKey {
+        namespace: record.namespace,
+        request_id: record.request_id,
+    };
+    let (bytes, framing) = record_format::frame(&key, Payload::encode(payload, compressor), cipher);
+    let length = bytes.len() as u64;
+
+    let (extent, file) = {
+        let file_manager = manager.lock().unwrap();
+        let file = file_manager.file.clone();
+        (file_manager.space.reserve(length), file)
+    };
+    let written = file.write_at(bytes, extent.offset).await;
+
+    {
+        let mut file_manager = manager.lock().unwrap();
+        file_manager.space.finish(extent.offset);
+        if let Err(e) = written {
+            file_manager.release_extent(extent);
+            return Err(e);
+        }
+        let metadata = RequestMetadata {
+            offset: extent.offset + framing.header_len,
+            size: framing.size,
+            header_len: framing.header_len,
+            written_at: UNIX_EPOCH + Duration::from_millis(record.written_at_ms),
+            checksum: Some(framing.checksum),
+            encrypted: framing.encrypted,
+            compression: framing.compression,
+            generation: 0,
+            expires_at: record.expires_at_ms.map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            user_metadata: record.metadata,
+            chunks: Vec::new(),
+        };
+        file_manager.commit_write(&key, None, metadata)?;
+    }
+    file_manager::sync_log(&manager).await
+}
+
+// Write every record of an archive into the data files it names, replacing
+// records with the same request ID. Payloads are checked against their
+// CRC-32 before anything is written; records whose TTL has passed are
+// skipped. Fails on a truncated or corrupt archive, keeping the records
+// imported before the failure.
+pub(crate) async fn import(files: &FileRegistry, cipher: Option<&RecordCipher>, compressor: Option<&Compressor>, input: &mut dyn Read) -> Result<ArchiveStats> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        anyhow::bail!("Not a record archive");
+    }
+    let mut word = [0u8; 4];
+    input.read_exact(&mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version != VERSION {
+        anyhow::bail!("Unsupported archive version {}", version);
+    }
+
+    let now = SystemTime::now();
+    let mut stats = ArchiveStats::default();
+    let mut read = 0;
+    loop {
+        input.read_exact(&mut word).map_err(|e| anyhow::anyhow!("Archive is truncated after {} records: {}", read, e))?;
+        let header_len = u32::from_le_bytes(word);
+        if header_len == 0 {
+            break;
+        }
+        if header_len > MAX_HEADER_LEN {
+            anyhow::bail!("Record {} of the archive has a {}-byte header, limit is {}", read + 1, header_len, MAX_HEADER_LEN);
+        }
+        let mut header = vec![0u8; header_len as usize];
+        input.read_exact(&mut header)?;
+        let record: ArchivedRecord = serde_json::from_slice(&header)?;
+        let mut payload = vec![0u8; record.size as usize];
+        input.read_exact(&mut payload)
+            .map_err(|e| anyhow::anyhow!("Archive is truncated in record {:?}: {}", record.request_id, e))?;
+        read += 1;
+
+        if record_format::checksum(&payload) != record.crc32 {
+            anyhow::bail!("Record {:?} in the archive is corrupt: CRC-32 {:08x}, expected {:08x}", record.request_id, record_format::checksum(&payload), record.crc32);
+        }
+        if record.expires_at_ms.is_some_and(|ms| UNIX_EPOCH + Duration::from_millis(ms) <= now) {
+            stats.expired += 1;
+            continue;
+        }
+        stats.records += 1;
+        stats.bytes += payload.len() as u64;
+        let request_id = record.request_id.clone();
+        store(files, record, payload, cipher, compressor)
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot import {:?}: {}", request_id, e))?;
+    }
+
+    let mut count = [0u8; 8];
+    input.read_exact(&mut count)?;
+    if u64::from_le_bytes(count) != read {
+        anyhow::bail!("Archive holds {} records, its trailer says {}", read, u64::from_le_bytes(count));
+    }
+    Ok(stats)
+}
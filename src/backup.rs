@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use anyhow::Result;
+use s3::creds::Credentials;
+use s3::serde_types::Part;
+use s3::{Bucket, Region};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{error, info, warn};
+
+use crate::file_manager::{self, FileRegistry};
+use crate::schedule::Schedule;
+use crate::snapshot::{self, DATA_FILE, INDEX_FILE, MANIFEST_FILE};
+
+// Bytes of a snapshot's data file sent per multipart upload part; S3 needs at
+// least 5 MiB per part. Smaller data files are uploaded in one request.
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+
+// Upload progress kept in the snapshot directory, so an interrupted backup
+// resumes where it stopped
+const STATE_FILE: &str = "backup.json";
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+// S3-compatible bucket that snapshots are uploaded to
+#[derive(Debug, Clone)]
+pub(crate) struct BackupConfig {
+    pub(crate) endpoint: String,
+    pub(crate) region: String,
+    pub(crate) bucket: String,
+    // Prepended to object keys
+    pub(crate) prefix: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BackupState {
+    // Multipart upload of the data file, once started
+    upload_id: Option<String>,
+    // Parts of the data file uploaded so far
+    parts: Vec<UploadedPart>,
+    data_uploaded: bool,
+    // Set once every file of the snapshot is uploaded
+    completed_at_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadedPart {
+    part_number: u32,
+    etag: String,
+}
+
+// Outcome of a backup
+#[derive(Debug, Clone)]
+pub(crate) struct BackupStats {
+    pub(crate) snapshot_id: String,
+    // Key prefix the snapshot's files were uploaded under
+    pub(crate) object_prefix: String,
+    // Bytes sent by this attempt; parts uploaded by an earlier one are not counted
+    pub(crate) uploaded_bytes: u64,
+}
+
+fn read_state(dir: &Path) -> Result<Option<BackupState>> {
+    match std::fs::read(dir.join(STATE_FILE)) {
+        Ok(state) => Ok(Some(serde_json::from_slice(&state)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_state(dir: &Path, state: &BackupState) -> Result<()> {
+    // Written aside and renamed, so a crash never leaves a torn state file
+    let staged = dir.join(format!("{}.tmp", STATE_FILE));
+    std::fs::write(&staged, serde_json::to_vec_pretty(state)?)?;
+    std::fs::rename(staged, dir.join(STATE_FILE))?;
+    Ok(())
+}
+
+pub(crate) struct Backup {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl Backup {
+    pub(crate) fn new(config: &BackupConfig) -> Result<Self> {
+        let credentials = Credentials::new(Some(&config.access_key), Some(&config.secret_key), None, None, None)?;
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        // Path-style addressing works with every S3-compatible server
+        let bucket = Bucket::new(&config.bucket, region, credentials)?.with_path_style();
+        Ok(Self {
+            bucket,
+            prefix: config.prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    fn object_prefix(&self, file_id: &str, snapshot_id: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("{}/{}", file_id, snapshot_id)
+        } else {
+            format!("{}/{}/{}", self.prefix, file_id, snapshot_id)
+        }
+    }
+
+    // Snapshot a data file, holding writes off as CreateSnapshot does, and
+    // upload the snapshot. With a parent, the snapshot is incremental; the
+    // parent must have been backed up already, so the uploaded chain is
+    // complete.
+    pub(crate) async fn back_up(&self, files: &FileRegistry, maintenance: &AtomicBool, file_id: &str, parent_id: Option<&str>) -> Result<BackupStats> {
+        let file_id = file_manager::resolve_file_id(file_id)?;
+        if let Some(parent_id) = parent_id {
+            uuid::Uuid::parse_str(parent_id).map_err(|_| anyhow::anyhow!("Invalid snapshot ID {:?}", parent_id))?;
+            let uploaded = read_state(&files.snapshot_dir(parent_id))?.is_some_and(|state| state.completed_at_ms.is_some());
+            if !uploaded {
+                anyhow::bail!("Snapshot {} has not been backed up, so it cannot be the parent of a backup", parent_id);
+            }
+        }
+        let manager = files.get(file_id).await?;
+        let snapshot_id = uuid::Uuid::new_v4().to_string();
+
+        let entered = !maintenance.swap(true, Ordering::SeqCst);
+        let created = snapshot::create(&manager, file_id, &snapshot_id, files.snapshot_dir(&snapshot_id), parent_id).await;
+        if entered {
+            maintenance.store(false, Ordering::SeqCst);
+        }
+        created?;
+
+        self.upload(files, &snapshot_id).await
+    }
+
+    // Upload an existing snapshot to `<prefix>/<file_id>/<snapshot_id>/`,
+    // picking up where an earlier attempt stopped. The manifest goes last, so
+    // an object prefix without one is an incomplete backup.
+    pub(crate) async fn upload(&self, files: &FileRegistry, snapshot_id: &str) -> Result<BackupStats> {
+        // Snapshot IDs are generated UUIDs; anything else could escape the snapshot directory
+        uuid::Uuid::parse_str(snapshot_id).map_err(|_| anyhow::anyhow!("Invalid snapshot ID {:?}", snapshot_id))?;
+        let dir = files.snapshot_dir(snapshot_id);
+
+        let start = Instant::now();
+        let (manifest, _) = {
+            let dir = dir.clone();
+            tokio::task::spawn_blocking(move || snapshot::load(&dir)).await??
+        };
+        let object_prefix = self.object_prefix(&manifest.file_id, snapshot_id);
+        let mut state = read_state(&dir)?.unwrap_or_default();
+        if state.completed_at_ms.is_some() {
+            info!("Snapshot {} is already backed up to {}", snapshot_id, object_prefix);
+            return Ok(BackupStats { snapshot_id: snapshot_id.to_string(), object_prefix, uploaded_bytes: 0 });
+        }
+
+        let mut uploaded_bytes = 0;
+        if !state.data_uploaded {
+            uploaded_bytes += self.upload_data(&dir, &object_prefix, &mut state).await?;
+        }
+        for name in [INDEX_FILE, MANIFEST_FILE] {
+            let contents = tokio::fs::read(dir.join(name)).await?;
+            self.bucket.put_object(format!("{}/{}", object_prefix, name), &contents).await?;
+            uploaded_bytes += contents.len() as u64;
+        }
+        state.completed_at_ms = Some(crate::unix_millis(SystemTime::now()));
+        write_state(&dir, &state)?;
+
+        info!("Backed up snapshot {} of {} to {}: {} bytes in {:?}", snapshot_id, manifest.file_id, object_prefix, uploaded_bytes, start.elapsed());
+        Ok(BackupStats { snapshot_id: snapshot_id.to_string(), object_prefix, uploaded_bytes })
+    }
+
+    // Upload a snapshot's data file, in parts if it is large. Each finished
+    // part is recorded in the state file before the next is sent.
+    async fn upload_data(&self, dir: &Path, object_prefix: &str, state: &mut BackupState) -> Result<u64> {
+        let key = format!("{}/{}", object_prefix, DATA_FILE);
+        let path = dir.join(DATA_FILE);
+        let size = tokio::fs::metadata(&path).await?.len();
+
+        if size <= PART_SIZE && state.upload_id.is_none() {
+            let contents = tokio::fs::read(&path).await?;
+            self.bucket.put_object(&key, &contents).await?;
+        } else {
+            let upload_id = match &state.upload_id {
+                Some(upload_id) => upload_id.clone(),
+                None => {
+                    let upload_id = self.bucket.initiate_multipart_upload(&key, CONTENT_TYPE).await?.upload_id;
+                    state.upload_id = Some(upload_id.clone());
+                    write_state(dir, state)?;
+                    upload_id
+                }
+            };
+
+            let mut file = tokio::fs::File::open(&path).await?;
+            let mut sent = 0;
+            for index in 0..size.div_ceil(PART_SIZE) {
+                let part_number = index as u32 + 1;
+                if state.parts.iter().any(|part| part.part_number == part_number) {
+                    continue;
+                }
+                let offset = index * PART_SIZE;
+                let mut chunk = vec![0u8; PART_SIZE.min(size - offset) as usize];
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.read_exact(&mut chunk).await?;
+                sent += chunk.len() as u64;
+
+                let part = self.bucket.put_multipart_chunk(chunk, &key, part_number, &upload_id, CONTENT_TYPE).await?;
+                state.parts.push(UploadedPart { part_number, etag: part.etag });
+                write_state(dir, state)?;
+            }
+
+            let mut parts: Vec<Part> = state
+                .parts
+                .iter()
+                .map(|part| Part { part_number: part.part_number, etag: part.etag.clone() })
+                .collect();
+            parts.sort_by_key(|part| part.part_number);
+            self.bucket.complete_multipart_upload(&key, &upload_id, parts).await?;
+            state.data_uploaded = true;
+            write_state(dir, state)?;
+            return Ok(sent);
+        }
+
+        state.data_uploaded = true;
+        write_state(dir, state)?;
+        Ok(size)
+    }
+}
+
+// Snapshots whose upload was started but not finished
+fn pending_uploads(files: &FileRegistry) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(files.snapshots_dir()) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| matches!(read_state(&entry.path()), Ok(Some(state)) if state.completed_at_ms.is_none()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+// Back up every open data file each time the schedule fires. Uploads that an
+// earlier run left unfinished are resumed first. After a full backup of a
+// file, its next `incrementals` backups are incremental, each on top of the
+// one before; a failed backup or a restart starts over with a full one.
+pub(crate) async fn run_backup_scheduler(files: Arc<FileRegistry>, maintenance: Arc<AtomicBool>, backup: Arc<Backup>, schedule: Schedule, incrementals: u32) {
+    // Last backup of each file and how many increments it is on a full backup
+    let mut chains: HashMap<String, (String, u32)> = HashMap::new();
+    while let Some(next) = schedule.next_after(SystemTime::now()) {
+        tokio::time::sleep(next.duration_since(SystemTime::now()).unwrap_or_default()).await;
+
+        for snapshot_id in pending_uploads(&files) {
+            info!("Resuming backup of snapshot {}", snapshot_id);
+            if let Err(e) = backup.upload(&files, &snapshot_id).await {
+                warn!("Resuming backup of snapshot {} failed: {}", snapshot_id, e);
+            }
+        }
+        for file_id in files.file_ids().await {
+            let (parent_id, depth) = match chains.remove(&file_id) {
+                Some((parent_id, depth)) if depth < incrementals => (Some(parent_id), depth + 1),
+                _ => (None, 0),
+            };
+            match backup.back_up(&files, &maintenance, &file_id, parent_id.as_deref()).await {
+                Ok(stats) => {
+                    chains.insert(file_id, (stats.snapshot_id, depth));
+                }
+                Err(e) => error!("Scheduled backup of file {:?} failed: {}", file_id, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::file_io::{self, align_up, Advice, Durability, FileIO};
+
+// How far past the end of a write that crosses the recorded end of the data
+// the new recorded end is put, so it is rewritten once per this many bytes
+// appended rather than on every append
+const END_STEP: u64 = 16 * 1024 * 1024;
+
+// Sidecar file recording how far into the device the data may extend
+pub(crate) fn end_path(data_path: &str) -> String {
+    format!("{}.end", data_path)
+}
+
+#[cfg(target_os = "linux")]
+nix::ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+// Size in bytes of the block device `file`, with the BLKGETSIZE64 ioctl
+#[cfg(target_os = "linux")]
+fn device_size(file: &File) -> Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let mut size: u64 = 0;
+    // SAFETY: BLKGETSIZE64 writes one u64 to the pointer it is given
+    unsafe { blkgetsize64(file.as_raw_fd(), &mut size) }?;
+    Ok(size)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn device_size(_file: &File) -> Result<u64> {
+    anyhow::bail!("Raw block devices are only supported on Linux")
+}
+
+// End of the data on a device. A device has no file size to tell where the
+// data stops, so an upper bound on it is kept in a sidecar file, moved ahead
+// before any write can land past it. After a crash recovery starts from the
+// recorded bound and trims whatever past the last record it finds there,
+// as it does for a data file with a torn tail. A clean shutdown records the
+// exact end.
+struct DeviceEnd {
+    path: String,
+    // End of the data written, and the bound recorded in the sidecar
+    end: u64,
+    recorded: u64,
+}
+
+impl DeviceEnd {
+    fn record(&mut self, recorded: u64) -> Result<()> {
+        let temp_path = format!("{}.tmp", self.path);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(recorded.to_string().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+        self.recorded = recorded;
+        Ok(())
+    }
+
+    // Make sure the recorded bound covers data up to `end`
+    fn extend(&mut self, end: u64, device_size: u64) -> Result<()> {
+        if end > self.recorded {
+            self.record((end + END_STEP).min(device_size))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DeviceEnd {
+    fn drop(&mut self) {
+        if self.recorded != self.end {
+            if let Err(e) = self.record(self.end) {
+                warn!("Could not record the end of the data in {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+// Data file on a raw block device, managed as one flat extent space from
+// byte 0 to the end of the device. Reads and writes go straight to the
+// device through the platform backend; the device cannot grow, so writes
+// past its end fail, and its blocks are all allocated already.
+pub(crate) struct BlockDeviceFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    size: u64,
+    state: Mutex<DeviceEnd>,
+    // What the device is, for `storage_kind`
+    kind: &'static str,
+}
+
+// Open the block device `device` as the data file at `data_path`, whose
+// sidecar files go where the data file would be. The device must exist;
+// unlike a data file it is never created.
+pub(crate) async fn open(device: &Path, data_path: &str, durability: Durability) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    use std::os::unix::fs::FileTypeExt;
+    if !std::fs::metadata(device)?.file_type().is_block_device() {
+        anyhow::bail!("{} is not a block device", device.display());
+    }
+    let size = device_size(&File::open(device)?)?;
+    let inner = file_io::create_file_io(&device.to_string_lossy(), durability).await?;
+    flat(inner, size, data_path, &format!("block device {}", device.display()), "a raw block device")
+}
+
+// Use `inner`, I/O on a device of `size` bytes described by `device`, as the
+// data file at `data_path`, keeping the end of the data in a sidecar file
+pub(crate) fn flat(inner: Arc<dyn FileIO + Send + Sync>, size: u64, data_path: &str, device: &str, kind: &'static str) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    let path = end_path(data_path);
+    let recorded = match std::fs::read_to_string(&path) {
+        Ok(value) => value.trim().parse::<u64>().map_err(|_| anyhow::anyhow!("Unexpected data end {:?} in {}", value.trim(), path))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if recorded > size {
+        anyhow::bail!("{} records data up to byte {}, but {} holds only {} bytes", path, recorded, device, size);
+    }
+    info!("Using {} ({} bytes, data up to byte {}) for {}", device, size, recorded, data_path);
+    Ok(Arc::new(BlockDeviceFileIO { inner, size, state: Mutex::new(DeviceEnd { path, end: recorded, recorded }), kind }))
+}
+
+impl BlockDeviceFileIO {
+    // Check a write of `length` bytes at `offset` fits on the device and
+    // record the end of the data past it, returning the end of the write
+    fn reserve(&self, offset: u64, length: u64) -> Result<u64> {
+        let end = offset + align_up(length);
+        if end > self.size {
+            anyhow::bail!("Write of {} bytes at offset {} runs past the end of the {}-byte device", length, offset, self.size);
+        }
+        self.state.lock().unwrap().extend(end, self.size)?;
+        Ok(end)
+    }
+
+    fn written(&self, end: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.end = state.end.max(end);
+    }
+}
+
+#[async_trait]
+impl FileIO for BlockDeviceFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let end = self.reserve(offset, data.len() as u64)?;
+        self.inner.write_at(data, offset).await?;
+        self.written(end);
+        Ok(())
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        self.inner.read_at(size, offset).await
+    }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        let length = buffers.iter().map(|buffer| buffer.len() as u64).sum();
+        let end = self.reserve(offset, length)?;
+        self.inner.write_vectored_at(buffers, offset).await?;
+        self.written(end);
+        Ok(())
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        self.inner.read_vectored_at(sizes, offset).await
+    }
+
+    async fn len(&self) -> Result<u64> {
+        Ok(self.state.lock().unwrap().end)
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    // Move the end of the data; the device itself keeps its size
+    fn set_len(&self, size: u64) -> Result<()> {
+        if size > self.size {
+            anyhow::bail!("Cannot extend data to {} bytes on a {}-byte device", size, self.size);
+        }
+        let mut state = self.state.lock().unwrap();
+        state.record(size)?;
+        state.end = size;
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn preallocate(&self, _length: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        self.inner.try_read_at(size, offset)
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        Some(self.kind)
+    }
+
+    // Once the last write is done, record exactly where the data ends
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await?;
+        let mut state = self.state.lock().unwrap();
+        if state.recorded != state.end {
+            let end = state.end;
+            state.record(end)?;
+        }
+        Ok(())
+    }
+}
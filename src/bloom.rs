@@ -0,0 +1,89 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+
+use crate::file_manager::RecordKey;
+
+// Filter bits per key it is sized for; with `HASHES` probes this gives about
+// a 1% false positive rate at capacity
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+// Keys a filter is sized for at least, so small files are not rebuilt often
+const MIN_CAPACITY: usize = 1024;
+
+struct Bits {
+    words: Vec<u64>,
+    // Keys the filter is sized for, and keys inserted since it was built
+    capacity: usize,
+    inserted: usize,
+}
+
+impl Bits {
+    fn with_capacity(keys: usize) -> Self {
+        let capacity = (keys * 2).max(MIN_CAPACITY);
+        Self { words: vec![0; (capacity * BITS_PER_KEY).div_ceil(64)], capacity, inserted: 0 }
+    }
+
+    // Bit positions a key maps to, by double hashing
+    fn positions(&self, namespace: &str, request_id: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        namespace.hash(&mut hasher);
+        request_id.hash(&mut hasher);
+        let first = hasher.finish();
+        0xff_u8.hash(&mut hasher);
+        let second = hasher.finish() | 1;
+        let bits = (self.words.len() * 64) as u64;
+        (0..HASHES).map(move |probe| (first.wrapping_add(probe.wrapping_mul(second)) % bits) as usize)
+    }
+
+    fn insert(&mut self, namespace: &str, request_id: &str) {
+        for position in self.positions(namespace, request_id) {
+            self.words[position / 64] |= 1 << (position % 64);
+        }
+        self.inserted += 1;
+    }
+}
+
+// Bloom filter over the keys of a data file's records. A miss means the key
+// is certainly not indexed, so lookups of keys that were never written are
+// answered without taking the index lock. Deleted keys stay in the filter
+// until it is rebuilt, which the file manager does at every full checkpoint
+// and whenever more keys were inserted than the filter was sized for.
+pub(crate) struct BloomFilter {
+    bits: RwLock<Bits>,
+}
+
+impl BloomFilter {
+    pub(crate) fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    // Filter sized for twice `keys` keys
+    pub(crate) fn with_capacity(keys: usize) -> Self {
+        Self { bits: RwLock::new(Bits::with_capacity(keys)) }
+    }
+
+    // Replace the filter with one holding exactly the `count` keys given as
+    // namespace and request ID, sized for twice as many
+    pub(crate) fn rebuild<'a>(&self, count: usize, keys: impl Iterator<Item = (&'a str, &'a str)>) {
+        let mut bits = Bits::with_capacity(count);
+        for (namespace, request_id) in keys {
+            bits.insert(namespace, request_id);
+        }
+        *self.bits.write().unwrap() = bits;
+    }
+
+    // Add a key, returning false once the filter holds more keys than it was
+    // sized for and should be rebuilt
+    pub(crate) fn insert(&self, key: &RecordKey) -> bool {
+        let mut bits = self.bits.write().unwrap();
+        bits.insert(&key.namespace, &key.request_id);
+        bits.inserted <= bits.capacity
+    }
+
+    pub(crate) fn may_contain(&self, key: &RecordKey) -> bool {
+        let bits = self.bits.read().unwrap();
+        bits.positions(&key.namespace, &key.request_id).all(|position| bits.words[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
@@ -0,0 +1,59 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+// Fractions of the data directory's filesystem in use at which writes are
+// rejected (`high`) and accepted again (`low`). The gap keeps the server from
+// flapping around a single threshold.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Watermarks {
+    pub(crate) high: f64,
+    pub(crate) low: f64,
+}
+
+// Fraction of a filesystem in use, counting space reserved for root as used
+// since the server cannot write to it
+pub(crate) fn used_fraction(path: &Path) -> Result<f64> {
+    let stats = nix::sys::statvfs::statvfs(path)?;
+    let total = stats.blocks() as f64;
+    if total == 0.0 {
+        return Ok(0.0);
+    }
+    Ok(1.0 - stats.blocks_available() as f64 / total)
+}
+
+// Sample the filesystem holding the data directory every `interval`, setting
+// `full` once usage reaches the high watermark and clearing it once usage
+// drops below the low one. A failed sample keeps the previous state.
+pub(crate) async fn run_capacity_monitor(data_dir: PathBuf, watermarks: Watermarks, full: Arc<AtomicBool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let used = match used_fraction(&data_dir) {
+            Ok(used) => used,
+            Err(e) => {
+                warn!("Failed to check free space under {}: {}", data_dir.display(), e);
+                continue;
+            }
+        };
+
+        let was_full = full.load(Ordering::SeqCst);
+        if !was_full && used >= watermarks.high {
+            full.store(true, Ordering::SeqCst);
+            warn!(
+                "Filesystem of {} is {:.1}% full, above the {:.1}% high watermark; rejecting writes",
+                data_dir.display(), used * 100.0, watermarks.high * 100.0
+            );
+        } else if was_full && used < watermarks.low {
+            full.store(false, Ordering::SeqCst);
+            info!(
+                "Filesystem of {} is {:.1}% full, below the {:.1}% low watermark; accepting writes again",
+                data_dir.display(), used * 100.0, watermarks.low * 100.0
+            );
+        }
+    }
+}
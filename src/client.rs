@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+
 use tonic::transport::Channel;
 
+use crate::adminservice::admin_service_client::AdminServiceClient;
+use crate::adminservice::MigrateRequest;
 use crate::fileservice::file_service_client::FileServiceClient;
-use crate::fileservice::{WriteRequest, ReadRequest};
+use crate::fileservice::{WriteRequest, ReadRequest, DeleteRequest, PipelineRequest};
+use crate::fileservice::{pipeline_request, pipeline_response, DuplicatePolicy};
 
 pub async fn test_client() -> Result<(), anyhow::Error> {
     let channel = Channel::from_shared("http://[::1]:50051".to_string())?
@@ -23,6 +28,16 @@ pub async fn test_client() -> Result<(), anyhow::Error> {
         let request = tonic::Request::new(WriteRequest {
             request_id: request_id.to_string(),
             data,
+            expected_generation: None,
+            file_id: String::new(),
+            namespace: String::new(),
+            ttl_ms: None,
+            on_duplicate: DuplicatePolicy::Unspecified as i32,
+            fencing_token: None,
+            metadata: HashMap::new(),
+            lock_id: String::new(),
+            logical_size: None,
+            ranges: Vec::new(),
         });
         
         match client.write_data(request).await {
@@ -45,6 +60,12 @@ pub async fn test_client() -> Result<(), anyhow::Error> {
     for request_id in read_requests {
         let request = tonic::Request::new(ReadRequest {
             request_id: request_id.to_string(),
+            offset: None,
+            length: None,
+            file_id: String::new(),
+            namespace: String::new(),
+            session_token: String::new(),
+            generation: None,
         });
         
         match client.read_data(request).await {
@@ -65,5 +86,98 @@ pub async fn test_client() -> Result<(), anyhow::Error> {
         }
     }
     
+    // Test delete operations
+    println!("\nTesting delete operations...");
+    
+    let request = tonic::Request::new(DeleteRequest {
+        request_id: "test-3".to_string(),
+        file_id: String::new(),
+        namespace: String::new(),
+        lock_id: String::new(),
+    });
+    
+    match client.delete_data(request).await {
+        Ok(response) => {
+            let response = response.into_inner();
+            println!("Delete successful for {}: freed {} bytes", 
+                response.request_id, response.freed_bytes);
+        }
+        Err(e) => {
+            println!("Delete failed for test-3: {}", e);
+        }
+    }
+    
+    // Test pipelined operations
+    println!("\nTesting pipeline operations...");
+    
+    let ops = vec![
+        PipelineRequest {
+            tag: 1,
+            op: Some(pipeline_request::Op::Write(WriteRequest {
+                request_id: "pipeline-1".to_string(),
+                data: "Pipelined message".as_bytes().to_vec(),
+                expected_generation: None,
+            file_id: String::new(),
+            namespace: String::new(),
+            ttl_ms: None,
+            on_duplicate: DuplicatePolicy::Unspecified as i32,
+            fencing_token: None,
+            metadata: HashMap::new(),
+            lock_id: String::new(),
+            logical_size: None,
+            ranges: Vec::new(),
+            })),
+        },
+        PipelineRequest {
+            tag: 2,
+            op: Some(pipeline_request::Op::Read(ReadRequest {
+                request_id: "test-1".to_string(),
+                offset: Some(7),
+                length: Some(5),
+                file_id: String::new(),
+                namespace: String::new(),
+                session_token: String::new(),
+                generation: None,
+            })),
+        },
+    ];
+    
+    let mut responses = client.pipeline(tokio_stream::iter(ops)).await?.into_inner();
+    while let Some(response) = responses.message().await? {
+        match response.op {
+            Some(pipeline_response::Op::Write(write)) => {
+                println!("Pipeline write (tag {}) for {}: success = {}, offset = {}", 
+                    response.tag, write.request_id, write.success, write.offset);
+            }
+            Some(pipeline_response::Op::Read(read)) => {
+                println!("Pipeline read (tag {}) for {}: '{}'", 
+                    response.tag, read.request_id, String::from_utf8_lossy(&read.data));
+            }
+            None => {}
+        }
+    }
+    
+    Ok(())
+} 
+
+// Ask a running server to move a data file to `target_path` through the admin
+// API, and wait for the migration to finish
+pub async fn migrate(file_id: &str, target_path: &str, admin_token: &str) -> Result<(), anyhow::Error> {
+    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
+        .connect()
+        .await?;
+
+    let mut client = AdminServiceClient::new(channel);
+    let mut request = tonic::Request::new(MigrateRequest {
+        file_id: file_id.to_string(),
+        target_path: target_path.to_string(),
+    });
+    request.metadata_mut().insert("authorization", format!("Bearer {}", admin_token).parse()?);
+
+    let response = client.migrate(request).await?.into_inner();
+    if !response.success {
+        anyhow::bail!("Migration failed: {}", response.error_message);
+    }
+    println!("Migrated to {}: {} records, {} bytes", target_path, response.records_moved, response.data_size);
     Ok(())
-} 
\ No newline at end of file
+}
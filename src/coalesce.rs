@@ -0,0 +1,177 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::file_io::{align_up, io_priority, Advice, FileIO, IoPriority};
+
+// How long the first write of a batch waits for others to join it
+const WINDOW: Duration = Duration::from_micros(100);
+
+// Bytes after which a batch goes out without waiting out the window; writes
+// this large go out on their own
+const MAX_BATCH: u64 = 256 * 1024;
+
+// A write waiting in a batch
+struct Pending {
+    data: Vec<u8>,
+    offset: u64,
+    done: tokio::sync::oneshot::Sender<Result<()>>,
+}
+
+#[derive(Default)]
+struct Batch {
+    writes: Vec<Pending>,
+    bytes: u64,
+}
+
+// Decorator gathering the small writes made to a data file within a short
+// window into batches. A batch's writes to consecutive extents, such as
+// appends made together, go out as one vectored write; each write completes
+// when the write it went out in does. The first write of a batch starts a
+// task that waits out the window, so a cancelled request does not strand the
+// others. tokio's timer only counts milliseconds, so the task yields to the
+// runtime until the window is over rather than sleeping through it.
+pub(crate) struct CoalescingFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl CoalescingFileIO {
+    pub(crate) fn new(inner: Arc<dyn FileIO + Send + Sync>) -> Self {
+        Self { inner, batch: Arc::new(Mutex::new(Batch::default())) }
+    }
+
+    // Wait for the window to pass or the batch to fill, then write it out
+    async fn flush(inner: Arc<dyn FileIO + Send + Sync>, batch: Arc<Mutex<Batch>>) {
+        let deadline = Instant::now() + WINDOW;
+        while Instant::now() < deadline && batch.lock().unwrap().bytes < MAX_BATCH {
+            tokio::task::yield_now().await;
+        }
+        let mut writes = std::mem::take(&mut *batch.lock().unwrap()).writes;
+        writes.sort_by_key(|write| write.offset);
+        let mut runs: Vec<Vec<Pending>> = Vec::new();
+        for write in writes {
+            match runs.last_mut() {
+                Some(run) if run.last().is_some_and(|last| last.offset + align_up(last.data.len() as u64) == write.offset) => run.push(write),
+                _ => runs.push(vec![write]),
+            }
+        }
+        futures::future::join_all(runs.into_iter().map(|run| Self::write_run(&*inner, run))).await;
+    }
+
+    // Write consecutive writes out together and complete each
+    async fn write_run(inner: &(dyn FileIO + Send + Sync), mut run: Vec<Pending>) {
+        if run.len() == 1 {
+            let write = run.pop().unwrap();
+            let _ = write.done.send(inner.write_at(write.data, write.offset).await);
+            return;
+        }
+        let offset = run[0].offset;
+        let last = run.len() - 1;
+        let mut buffers = Vec::with_capacity(run.len());
+        let mut done = Vec::with_capacity(run.len());
+        for (index, write) in run.into_iter().enumerate() {
+            let mut data = write.data;
+            // Every buffer but the last is padded to its extent's sectors
+            if index < last {
+                data.resize(align_up(data.len() as u64) as usize, 0);
+            }
+            buffers.push(data);
+            done.push(write.done);
+        }
+        let count = buffers.len();
+        let written = inner.write_vectored_at(buffers, offset).await;
+        for done in done {
+            let _ = done.send(match &written {
+                Ok(()) => Ok(()),
+                Err(e) => Err(anyhow::anyhow!("Coalesced write of {} records at offset {} failed: {:#}", count, offset, e)),
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl FileIO for CoalescingFileIO {
+    // Writes at another priority, which the batch's task would make at
+    // Normal, and large ones go out as they are
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        if data.len() as u64 >= MAX_BATCH || io_priority() != IoPriority::Normal {
+            return self.inner.write_at(data, offset).await;
+        }
+        let (done, written) = tokio::sync::oneshot::channel();
+        {
+            let mut batch = self.batch.lock().unwrap();
+            if batch.writes.is_empty() {
+                tokio::spawn(Self::flush(self.inner.clone(), self.batch.clone()));
+            }
+            batch.bytes += data.len() as u64;
+            batch.writes.push(Pending { data, offset, done });
+        }
+        written.await.map_err(|_| anyhow::anyhow!("Coalesced write at offset {} was dropped", offset))?
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        self.inner.read_at(size, offset).await
+    }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        self.inner.write_vectored_at(buffers, offset).await
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        self.inner.read_vectored_at(sizes, offset).await
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.inner.set_len(size)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        self.inner.segment_size()
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        self.inner.storage_kind()
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        self.inner.preallocate(length)
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        self.inner.remove_segment(index)
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        self.inner.try_read_at(size, offset)
+    }
+
+    // Writes still batched have callers waiting on them, so need no flush
+    // here
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::file_manager::FileRegistry;
+
+// Commit marker of a data file, saved after every sync: the last durability
+// epoch whose writes are on stable storage, and the epochs that were open
+// when the server stopped, whose writes may or may not have survived
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CommitMarker {
+    durable_epoch: u64,
+    #[serde(default)]
+    lost_epochs: Vec<u64>,
+}
+
+// Sidecar file holding the commit marker of a data file
+pub(crate) fn commit_path(data_path: &str) -> String {
+    format!("{}.commit", data_path)
+}
+
+fn load(path: &str) -> Result<CommitMarker> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| anyhow::anyhow!("Commit marker {} is corrupt: {}", path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CommitMarker::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(path: &str, marker: &CommitMarker) -> Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec(marker)?)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Where an epoch stands, as WaitForDurable reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EpochState {
+    // Not closed by a commit marker yet
+    Pending,
+    Durable,
+    // Open when the server stopped
+    Lost,
+    // Not issued yet
+    Unknown,
+}
+
+// Durability epochs of one data file. Writes are acknowledged as applied in
+// the open epoch; a commit marker closes it, syncs the data file and then
+// records the epoch as durable, so every write acknowledged in an epoch up to
+// the durable one survives power loss. Epochs only advance while commit
+// markers are written, which `--durability interval:<ms>` does; otherwise they
+// stay in memory.
+pub(crate) struct Epochs {
+    // Sidecar file the commit marker is saved to, if markers are written
+    path: Option<String>,
+    open: u64,
+    // Set once a write is applied in the open epoch
+    dirty: bool,
+    lost: Vec<u64>,
+    // Latest durable epoch; WaitForDurable waits on it
+    pub(crate) durable: watch::Sender<u64>,
+}
+
+impl Epochs {
+    // Epochs of a data file without commit markers
+    pub(crate) fn volatile() -> Self {
+        Self { path: None, open: 1, dirty: false, lost: Vec::new(), durable: watch::channel(0).0 }
+    }
+
+    // Load the commit marker of a data file. The epoch that was open when the
+    // server stopped is recorded as lost and never reissued; the marker is
+    // saved before any write is applied, so a crash right after startup
+    // cannot reissue the next one either.
+    pub(crate) fn open(data_path: &str) -> Result<Self> {
+        let path = commit_path(data_path);
+        let mut marker = load(&path)?;
+        let interrupted = marker.durable_epoch + 1;
+        marker.lost_epochs.push(interrupted);
+        marker.durable_epoch = interrupted;
+        save(&path, &marker)?;
+        Ok(Self {
+            path: Some(path),
+            open: interrupted + 1,
+            dirty: false,
+            lost: marker.lost_epochs,
+            durable: watch::channel(interrupted).0,
+        })
+    }
+
+    // Epoch writes applied now are acknowledged in
+    pub(crate) fn current(&self) -> u64 {
+        self.open
+    }
+
+    pub(crate) fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub(crate) fn state(&self, epoch: u64) -> EpochState {
+        if self.lost.contains(&epoch) {
+            EpochState::Lost
+        } else if epoch <= *self.durable.borrow() {
+            EpochState::Durable
+        } else if epoch <= self.open {
+            EpochState::Pending
+        } else {
+            EpochState::Unknown
+        }
+    }
+
+    // Close the open epoch if a write was applied in it, returning it; later
+    // writes are acknowledged in the next one
+    fn close(&mut self) -> Option<u64> {
+        if !self.dirty || self.path.is_none() {
+            return None;
+        }
+        self.dirty = false;
+        self.open += 1;
+        Some(self.open - 1)
+    }
+
+    // Record an epoch closed by `close` as durable, once every write issued
+    // before it was closed has been synced
+    fn commit(&mut self, epoch: u64) -> Result<()> {
+        if let Some(path) = &self.path {
+            save(path, &CommitMarker { durable_epoch: epoch, lost_epochs: self.lost.clone() })?;
+        }
+        self.durable.send_if_modified(|durable| {
+            let advanced = epoch > *durable;
+            *durable = (*durable).max(epoch);
+            advanced
+        });
+        Ok(())
+    }
+}
+
+// Every `interval`, write a commit marker for each open data file with
+// writes applied since its last one: close the open epoch, sync the data
+// file, then record the epoch as durable. If the sync fails the closed
+// epoch's writes ride along with the next marker, which covers them too.
+pub(crate) async fn run_commit_markers(files: Arc<FileRegistry>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for manager in files.managers().await {
+            let closed = {
+                let mut file_manager = manager.lock().unwrap();
+                file_manager.epochs.close().map(|epoch| (epoch, file_manager.file.clone(), file_manager.file_path.clone()))
+            };
+            let Some((epoch, file, file_path)) = closed else {
+                continue;
+            };
+            let synced = file.sync_data().await;
+
+            let mut file_manager = manager.lock().unwrap();
+            match synced.and_then(|()| file_manager.epochs.commit(epoch)) {
+                Ok(()) => info!("Epoch {} of {} is durable", epoch, file_path),
+                Err(e) => {
+                    error!("Commit marker for epoch {} of {} failed: {}", epoch, file_path, e);
+                    file_manager.epochs.mark_dirty();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Bytes the compression fields add to a record header: codec (u32) and the
+// uncompressed length (u64)
+pub(crate) const COMPRESSION_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Codec {
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    // Identifier stored in record headers
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// How a compressed payload is stored, kept in its index entry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Compression {
+    pub(crate) codec: Codec,
+    // Length of the compressed bytes in the data file
+    pub(crate) stored_size: u64,
+}
+
+// Compression applied to new records
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Compressor {
+    codec: Codec,
+    // Zstd compression level; unused for LZ4
+    level: i32,
+}
+
+impl Compressor {
+    // Parse `lz4`, `zstd` or `zstd:<level>`
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            None if value == "lz4" => Ok(Self { codec: Codec::Lz4, level: 0 }),
+            None if value == "zstd" => Ok(Self { codec: Codec::Zstd, level: zstd::DEFAULT_COMPRESSION_LEVEL }),
+            Some(("zstd", level)) => match level.parse::<i32>() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => Ok(Self { codec: Codec::Zstd, level }),
+                _ => anyhow::bail!("Invalid zstd compression level {:?}", level),
+            },
+            _ => anyhow::bail!("Compression must be lz4, zstd or zstd:<level>, got {:?}", value),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.codec {
+            Codec::Lz4 => Ok(lz4_flex::compress(data)),
+            Codec::Zstd => Ok(zstd::bulk::compress(data, self.level)?),
+        }
+    }
+}
+
+// A record payload as it is stored: compressed when that saves space
+pub(crate) struct Payload {
+    // Bytes written after the record header, before any encryption
+    pub(crate) data: Vec<u8>,
+    // Length of the payload as written by the client
+    pub(crate) size: u64,
+    pub(crate) codec: Option<Codec>,
+}
+
+impl Payload {
+    // Compress a payload if a compressor is given, keeping it as is unless
+    // that makes the record smaller, header fields included
+    pub(crate) fn encode(data: Vec<u8>, compressor: Option<&Compressor>) -> Self {
+        let size = data.len() as u64;
+        if let Some(compressor) = compressor {
+            if let Ok(compressed) = compressor.compress(&data) {
+                if compressed.len() + COMPRESSION_LEN < data.len() {
+                    return Self { data: compressed, size, codec: Some(compressor.codec) };
+                }
+            }
+        }
+        Self { data, size, codec: None }
+    }
+}
+
+// Restore the `size` original bytes of a compressed payload
+pub(crate) fn decompress(codec: Codec, data: &[u8], size: u64) -> Result<Vec<u8>> {
+    let payload = match codec {
+        Codec::Lz4 => lz4_flex::decompress(data, size as usize)?,
+        Codec::Zstd => zstd::bulk::decompress(data, size as usize)?,
+    };
+    if payload.len() as u64 != size {
+        anyhow::bail!("Decompressed {} bytes, expected {}", payload.len(), size);
+    }
+    Ok(payload)
+}
@@ -0,0 +1,396 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::info;
+
+use crate::compression::Payload;
+use crate::file_io::{align_down, sector_size, Durability, FileIO, BLOCK_SIZE};
+use crate::file_manager::{FileManager, RecordKey, RequestMetadata};
+use crate::index_store;
+use crate::metadata_store::MetadataStoreKind;
+use crate::record_format;
+use crate::versions::VersionPolicy;
+use crate::wal;
+
+// Name of the data file the simulated device stands in for; its sidecar
+// index and log are real files in the test directory
+const DATA_FILE: &str = "crash-test.bin";
+
+const NAMESPACE: &str = "crash-test";
+
+// Largest payload written, so records span a few blocks
+const MAX_RECORD_SIZE: u64 = 3 * BLOCK_SIZE;
+
+// SplitMix64; runs are reproduced from their seed
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+struct DeviceState {
+    medium: Vec<u8>,
+    // Writes left until the device loses power, if a crash is armed
+    writes_until_crash: Option<u64>,
+    crashed: bool,
+    rng: Rng,
+}
+
+impl DeviceState {
+    fn check(&self) -> Result<()> {
+        if self.crashed {
+            anyhow::bail!("Simulated device has lost power");
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, data: &[u8], offset: u64) {
+        let end = offset as usize + data.len();
+        if self.medium.len() < end {
+            self.medium.resize(end, 0);
+        }
+        self.medium[offset as usize..end].copy_from_slice(data);
+    }
+
+    // Apply a random subset of the aligned blocks a write covers, as a device
+    // losing power in the middle of it may
+    fn tear(&mut self, data: &[u8], offset: u64) {
+        let end = offset + data.len() as u64;
+        let mut start = offset;
+        while start < end {
+            let block_end = (align_down(start) + sector_size()).min(end);
+            if self.rng.below(2) == 0 {
+                self.apply(&data[(start - offset) as usize..(block_end - offset) as usize], start);
+            }
+            start = block_end;
+        }
+    }
+}
+
+// In-memory block device that loses power on an armed write. The write that
+// crashes is torn: only some of its blocks reach the medium. Every operation
+// after it fails until the device is power cycled. Completed writes are on
+// the medium, as with O_DSYNC.
+#[derive(Clone)]
+struct SimulatedDevice {
+    state: Arc<Mutex<DeviceState>>,
+}
+
+impl SimulatedDevice {
+    fn new(seed: u64) -> Self {
+        let state = DeviceState { medium: Vec::new(), writes_until_crash: None, crashed: false, rng: Rng(seed) };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    // Lose power on the `writes`th write from now
+    fn arm(&self, writes: u64) {
+        self.state.lock().unwrap().writes_until_crash = Some(writes);
+    }
+
+    fn power_cycle(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.crashed = false;
+        state.writes_until_crash = None;
+    }
+}
+
+#[async_trait]
+impl FileIO for SimulatedDevice {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        if let Some(writes) = state.writes_until_crash.as_mut() {
+            *writes -= 1;
+            if *writes == 0 {
+                state.tear(&data, offset);
+                state.crashed = true;
+                anyhow::bail!("Simulated device lost power writing {} bytes at offset {}", data.len(), offset);
+            }
+        }
+        state.apply(&data, offset);
+        Ok(())
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        let state = self.state.lock().unwrap();
+        state.check()?;
+        // Past the end of the medium reads as zeros
+        let mut data = vec![0; size as usize];
+        let start = (offset as usize).min(state.medium.len());
+        let end = (offset as usize + size as usize).min(state.medium.len());
+        data[..end - start].copy_from_slice(&state.medium[start..end]);
+        Ok(data.into())
+    }
+
+    async fn len(&self) -> Result<u64> {
+        let state = self.state.lock().unwrap();
+        state.check()?;
+        Ok(state.medium.len() as u64)
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.state.lock().unwrap().check()
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        state.medium.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "simulated"
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        let start = (offset as usize).min(state.medium.len());
+        let end = (offset as usize + length as usize).min(state.medium.len());
+        state.medium[start..end].fill(0);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CrashTestConfig {
+    // Power losses to recover from
+    pub(crate) crashes: u64,
+    // Most operations run between two of them
+    pub(crate) max_ops: u64,
+    pub(crate) seed: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CrashTestReport {
+    pub(crate) crashes: u64,
+    // Operations acknowledged before a crash
+    pub(crate) acknowledged: u64,
+    // Crashes that tore a record write, or the log entry committing one
+    pub(crate) torn_writes: u64,
+    pub(crate) torn_logs: u64,
+    // Acknowledged writes lost or corrupted, and unacknowledged ones that
+    // surfaced; any of these is a bug
+    pub(crate) problems: Vec<String>,
+}
+
+enum Op {
+    Write { request_id: String, data: Vec<u8> },
+    Delete { request_id: String },
+}
+
+impl Op {
+    fn request_id(&self) -> &str {
+        match self {
+            Op::Write { request_id, .. } | Op::Delete { request_id } => request_id,
+        }
+    }
+
+    // The record's data once the operation has applied
+    fn outcome(&self) -> Option<&Vec<u8>> {
+        match self {
+            Op::Write { data, .. } => Some(data),
+            Op::Delete { .. } => None,
+        }
+    }
+
+    fn apply_to(&self, model: &mut BTreeMap<String, Vec<u8>>) {
+        match self {
+            Op::Write { request_id, data } => model.insert(request_id.clone(), data.clone()),
+            Op::Delete { request_id } => model.remove(request_id),
+        };
+    }
+}
+
+fn key(request_id: &str) -> RecordKey {
+    RecordKey { namespace: NAMESPACE.to_string(), request_id: request_id.to_string() }
+}
+
+// Half the operations write a new record; the rest overwrite or delete an
+// existing one
+fn next_op(rng: &mut Rng, model: &BTreeMap<String, Vec<u8>>, next_id: &mut u64) -> Op {
+    let choice = if model.is_empty() { 0 } else { rng.below(4) };
+    let request_id = if choice < 2 {
+        *next_id += 1;
+        format!("record-{}", next_id)
+    } else {
+        model.keys().nth(rng.below(model.len() as u64) as usize).unwrap().clone()
+    };
+    if choice == 3 {
+        return Op::Delete { request_id };
+    }
+    let data = (0..rng.below(MAX_RECORD_SIZE + 1)).map(|_| rng.next() as u8).collect();
+    Op::Write { request_id, data }
+}
+
+// Apply an operation the way the server does: a write is framed, written
+// into a reserved extent and then committed to the index and log
+async fn apply(manager: &mut FileManager, op: &Op) -> Result<()> {
+    let key = key(op.request_id());
+    let Op::Write { data, .. } = op else {
+        manager.delete(&key, SystemTime::now())?;
+        return manager.log_commit().wait().await;
+    };
+    let (record, framing) = record_format::frame(&key, Payload::encode(data.clone(), None), None);
+    let extent = manager.space.reserve(record.len() as u64);
+    let written = manager.file.write_at(record, extent.offset).await;
+    manager.space.finish(extent.offset);
+    written?;
+    let metadata = RequestMetadata {
+        offset: extent.offset + framing.header_len,
+        size: framing.size,
+        header_len: framing.header_len,
+        written_at: SystemTime::now(),
+        checksum: Some(framing.checksum),
+        encrypted: false,
+        compression: None,
+        generation: 0,
+        expires_at: None,
+        user_metadata: Default::default(),
+        chunks: Vec::new(),
+    };
+    manager.commit_write(&key, None, metadata)?;
+    manager.log_commit().wait().await
+}
+
+// Compare what recovery brought back with the acknowledged state. The
+// operation in flight at the crash may have applied or not; the model takes
+// whichever outcome was recovered.
+async fn verify(manager: &mut FileManager, model: &mut BTreeMap<String, Vec<u8>>, in_doubt: Option<Op>, report: &mut CrashTestReport) {
+    let mut recovered = BTreeMap::new();
+    let mut unreadable = HashSet::new();
+    for (key, metadata) in manager.entries(SystemTime::now()) {
+        let read = record_format::read_stored(manager.file.as_ref(), &metadata, false).await;
+        match read.and_then(|stored| record_format::open_payload(None, &key.request_id, &metadata, stored)) {
+            Ok(data) => {
+                recovered.insert(key.request_id, Vec::from(data));
+            }
+            Err(e) => {
+                report.problems.push(format!("{:?} cannot be read after recovery: {}", key.request_id, e));
+                unreadable.insert(key.request_id);
+            }
+        }
+    }
+    if let Some(op) = in_doubt {
+        if recovered.get(op.request_id()) == op.outcome() {
+            op.apply_to(model);
+        }
+    }
+
+    for (request_id, data) in model.iter() {
+        match recovered.get(request_id) {
+            Some(found) if found == data => {}
+            Some(found) => report.problems.push(format!("{:?} came back with different data: {} bytes, {} acknowledged", request_id, found.len(), data.len())),
+            None if unreadable.contains(request_id) => {}
+            None => report.problems.push(format!("acknowledged write of {:?} was lost", request_id)),
+        }
+    }
+    for request_id in recovered.keys().filter(|request_id| !model.contains_key(*request_id)) {
+        report.problems.push(format!("{:?} was recovered but is deleted or was never acknowledged", request_id));
+    }
+}
+
+fn log_len(path: &str) -> u64 {
+    std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+// Run the storage engine on a simulated device that loses power at random
+// points, recovering after each crash and checking every acknowledged write
+// came back intact. A crash either tears a record write on the device or,
+// with the device intact, cuts the log entry committing the last operation
+// short. The sidecar index and log live in `dir`, which should be used for
+// nothing else.
+pub(crate) async fn run(dir: &Path, config: CrashTestConfig) -> Result<CrashTestReport> {
+    let data_path = dir.join(DATA_FILE).to_string_lossy().into_owned();
+    let wal_path = wal::wal_path(&data_path);
+    for path in [index_store::index_path(&data_path), wal_path.clone()] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    let mut rng = Rng(config.seed);
+    let device = SimulatedDevice::new(rng.next());
+    let mut model = BTreeMap::new();
+    let mut in_doubt = None;
+    let mut next_id = 0;
+    let mut report = CrashTestReport::default();
+    loop {
+        let mut manager = FileManager::with_file(&data_path, Arc::new(device.clone()), Durability::ODsync, None, VersionPolicy::default(), None, MetadataStoreKind::Memory).await?;
+        verify(&mut manager, &mut model, in_doubt.take(), &mut report).await;
+        if !report.problems.is_empty() || report.crashes == config.crashes {
+            break;
+        }
+
+        let ops = 1 + rng.below(config.max_ops);
+        let tear_log = rng.below(4) == 0;
+        if !tear_log {
+            device.arm(1 + rng.below(ops));
+        }
+        let mut torn_log = None;
+        for index in 0..ops {
+            let op = next_op(&mut rng, &model, &mut next_id);
+            let len_before = log_len(&wal_path);
+            match apply(&mut manager, &op).await {
+                // A checkpoint may have emptied the log, leaving nothing to cut
+                Ok(()) if tear_log && index + 1 == ops && log_len(&wal_path) > len_before => {
+                    torn_log = Some(len_before + rng.below(log_len(&wal_path) - len_before));
+                    in_doubt = Some(op);
+                }
+                Ok(()) => {
+                    op.apply_to(&mut model);
+                    report.acknowledged += 1;
+                }
+                Err(_) => {
+                    in_doubt = Some(op);
+                    report.torn_writes += 1;
+                    break;
+                }
+            }
+        }
+        drop(manager);
+
+        if let Some(len) = torn_log {
+            let log = std::fs::OpenOptions::new().write(true).open(&wal_path)?;
+            log.set_len(len)?;
+            log.sync_all()?;
+            report.torn_logs += 1;
+        }
+        device.power_cycle();
+        report.crashes += 1;
+        info!("Crash {} of {}: {} records acknowledged", report.crashes, config.crashes, model.len());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recovers_every_acknowledged_write() {
+        let dir = std::env::temp_dir().join(format!("crash-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let report = run(&dir, CrashTestConfig { crashes: 10, max_ops: 20, seed: 7 }).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.crashes, 10);
+    }
+}
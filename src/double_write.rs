@@ -0,0 +1,143 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::file_io::FileIO;
+use crate::wal::{self, FRAME_HEADER};
+
+// Bytes the buffer grows to before it is emptied, once the data file has
+// been synced and no overwrite is between staging and writing in place
+const RESET_BYTES: u64 = 64 * 1024 * 1024;
+
+// Bytes before each staged image: the offset in the data file it goes to
+const TARGET_LEN: usize = 8;
+
+// Sidecar file holding the double-write buffer of a data file
+pub(crate) fn double_write_path(data_path: &str) -> String {
+    format!("{}.dwb", data_path)
+}
+
+// A record image staged for writing over live data at `offset`
+pub(crate) struct StagedExtent {
+    pub(crate) offset: u64,
+    pub(crate) image: Vec<u8>,
+}
+
+// Images staged in the buffer at `path`, oldest first. A torn or corrupt
+// entry, left by a crash while staging, ends the list; its overwrite had not
+// started, so the data it was meant for is intact.
+pub(crate) fn load(path: &str) -> Result<Vec<StagedExtent>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut staged = Vec::new();
+    let mut cursor = 0;
+    while let Some(payload) = wal::decode_frame(&contents, cursor) {
+        if payload.len() < TARGET_LEN {
+            break;
+        }
+        let offset = u64::from_le_bytes(payload[..TARGET_LEN].try_into().unwrap());
+        staged.push(StagedExtent { offset, image: payload[TARGET_LEN..].to_vec() });
+        cursor += FRAME_HEADER + payload.len();
+    }
+    if cursor < contents.len() {
+        warn!("Ignoring {} bytes of torn or corrupt entries at the end of {}", contents.len() - cursor, path);
+    }
+    Ok(staged)
+}
+
+// Empty the buffer at `path` once every image in it is known to be on stable
+// storage in the data file
+pub(crate) fn clear(path: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Double-write buffer of a data file. An overwrite in place first appends
+// the record image and its target offset here and syncs it, and only then
+// writes over the live record; a crash that tears the in-place write leaves
+// an intact copy to restore from on startup. Entries pile up until the
+// buffer reaches `RESET_BYTES` and is emptied, after syncing the data file
+// so every image in it is also on stable storage where it belongs.
+pub(crate) struct DoubleWriteBuffer {
+    path: String,
+    file: File,
+    len: u64,
+    // Images staged so far, and of those the ones not yet written in place
+    staged: u64,
+    pending: usize,
+}
+
+impl DoubleWriteBuffer {
+    // Open an empty buffer; recovery has already restored from and cleared
+    // whatever the previous run left
+    pub(crate) fn open(data_path: &str) -> Result<Self> {
+        let path = double_write_path(data_path);
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        file.set_len(0)?;
+        Ok(Self { path, file, len: 0, staged: 0, pending: 0 })
+    }
+
+    // Append an image bound for `offset` and sync it to stable storage
+    pub(crate) fn stage(&mut self, offset: u64, image: &[u8]) -> Result<()> {
+        let mut payload = Vec::with_capacity(TARGET_LEN + image.len());
+        payload.extend_from_slice(&offset.to_le_bytes());
+        payload.extend_from_slice(image);
+        let frame = wal::encode_frame(&payload);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()?;
+        self.len += frame.len() as u64;
+        self.staged += 1;
+        self.pending += 1;
+        Ok(())
+    }
+
+    // An image staged earlier has been written in place, or its write failed
+    pub(crate) fn finish(&mut self) {
+        self.pending -= 1;
+    }
+
+    // Whether the buffer is due to be emptied, returning how many images had
+    // been staged; the caller syncs the data file and then passes it to `reset`
+    pub(crate) fn reset_point(&self) -> Option<u64> {
+        (self.len >= RESET_BYTES && self.pending == 0).then_some(self.staged)
+    }
+
+    // Empty the buffer, unless an image was staged after `reset_point`, whose
+    // in-place write the data file sync may not have covered
+    pub(crate) fn reset(&mut self, staged: u64) -> Result<()> {
+        if self.staged != staged || self.pending > 0 {
+            return Ok(());
+        }
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        info!("Emptied the double-write buffer {} after {} bytes", self.path, self.len);
+        self.len = 0;
+        Ok(())
+    }
+}
+
+// Stage `image`, bound for `offset` in the data file `file`, in `buffer`,
+// handing the image back for the in-place write. A buffer due to be emptied
+// is emptied first, once the data file has been synced. The caller calls
+// `finish` once the in-place write is done.
+pub(crate) async fn stage(buffer: &Arc<Mutex<DoubleWriteBuffer>>, file: &(dyn FileIO + Send + Sync), offset: u64, image: Vec<u8>) -> Result<Vec<u8>> {
+    let reset_point = buffer.lock().unwrap().reset_point();
+    if let Some(staged) = reset_point {
+        file.sync_data().await?;
+        buffer.lock().unwrap().reset(staged)?;
+    }
+    let buffer = buffer.clone();
+    tokio::task::spawn_blocking(move || {
+        buffer.lock().unwrap().stage(offset, &image)?;
+        Ok(image)
+    })
+    .await?
+}
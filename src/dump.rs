@@ -0,0 +1,144 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::encryption::RecordCipher;
+use crate::file_io::{Durability, FileIO};
+use crate::file_manager::{self, RecordKey, RequestMetadata};
+use crate::index_store;
+use crate::record_format;
+use crate::wal;
+
+// Bytes per line of a hex preview
+const HEX_LINE: usize = 16;
+
+// How payloads are previewed under each record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PreviewFormat {
+    Hex,
+    Utf8,
+}
+
+impl PreviewFormat {
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        match value {
+            "hex" => Ok(Self::Hex),
+            "utf8" => Ok(Self::Utf8),
+            other => anyhow::bail!("--preview must be hex or utf8, got {:?}", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Preview {
+    pub(crate) format: PreviewFormat,
+    // Leading payload bytes shown
+    pub(crate) bytes: usize,
+}
+
+// Totals of a dump
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DumpStats {
+    pub(crate) records: u64,
+    pub(crate) file_size: u64,
+    // Bytes of aligned blocks the records cover
+    pub(crate) aligned_bytes: u64,
+}
+
+// The first `length` bytes of a record's payload as its client wrote it. The
+// stored payload is read whole if it has to be decrypted or decompressed, or
+// is split across extents.
+async fn read_preview(file: &(dyn FileIO + Send + Sync), cipher: Option<&RecordCipher>, key: &RecordKey, metadata: &RequestMetadata, length: usize) -> Result<Bytes> {
+    if !metadata.encrypted && metadata.compression.is_none() && metadata.chunks.is_empty() {
+        let length = metadata.stored_size().min(length as u64);
+        if length == 0 {
+            return Ok(Bytes::new());
+        }
+        return record_format::read_span(file, metadata.offset, length).await;
+    }
+    let stored = record_format::read_stored(file, metadata, metadata.encrypted).await?;
+    let mut payload = record_format::open_payload(cipher, &key.request_id, metadata, stored)?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+fn write_preview(out: &mut dyn Write, format: PreviewFormat, payload: &[u8]) -> Result<()> {
+    match format {
+        PreviewFormat::Hex => {
+            for (line, chunk) in payload.chunks(HEX_LINE).enumerate() {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                let text: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+                writeln!(out, "    {:08x}  {:<width$}  |{}|", line * HEX_LINE, hex.join(" "), text, width = HEX_LINE * 3 - 1)?;
+            }
+        }
+        PreviewFormat::Utf8 => writeln!(out, "    {:?}", String::from_utf8_lossy(payload))?,
+    }
+    Ok(())
+}
+
+// Print every record indexed for a data file, in file order: where its
+// header and payload sit, the aligned blocks it covers, its logical and
+// stored size and its checksum. Live records, prior versions and trashed
+// records are all listed. The index and log are read as the server would
+// recover them, and nothing is changed.
+pub(crate) async fn dump(data_path: &Path, cipher: Option<&RecordCipher>, preview: Option<Preview>, out: &mut dyn Write) -> Result<DumpStats> {
+    let data_path = data_path.to_string_lossy().into_owned();
+    let file = file_manager::open_data_file(&data_path, Durability::ODsync, None).await?;
+    let (checkpoint, contents) = index_store::load(&index_store::index_path(&data_path))?;
+    let (replay, _) = wal::read(&wal::wal_path(&data_path), checkpoint)?;
+    let contents = wal::replay_onto(contents, replay.ops);
+
+    let mut records: Vec<(&str, RecordKey, RequestMetadata)> = contents.entries.into_iter().map(|(key, metadata)| ("live", key, metadata)).collect();
+    records.extend(contents.versions.into_iter().map(|(key, metadata)| ("version", key, metadata)));
+    records.extend(contents.trash.into_iter().map(|(key, trashed)| ("trashed", key, trashed.metadata)));
+    records.sort_by_key(|(_, _, metadata)| metadata.offset);
+
+    let mut stats = DumpStats { file_size: file.len().await?, ..DumpStats::default() };
+    writeln!(out, "{:>14} {:>6} {:>12} {:>12} {:>12} {:>8} {:>5} {:<7} NAMESPACE/REQUEST ID", "OFFSET", "HEADER", "ALIGNED", "SIZE", "STORED", "CRC32", "GEN", "KIND")?;
+    for (kind, key, metadata) in records {
+        // A corrupt entry may point inside its own header; fsck reports those
+        let aligned = if metadata.offset >= metadata.header_len { metadata.aligned_size() } else { 0 };
+        let checksum = metadata.checksum.map_or_else(|| "-".to_string(), |checksum| format!("{:08x}", checksum));
+        let mut flags = String::new();
+        if metadata.encrypted {
+            flags.push_str(" encrypted");
+        }
+        if let Some(compression) = metadata.compression {
+            flags.push_str(&format!(" {:?}", compression.codec).to_lowercase());
+        }
+        if !metadata.chunks.is_empty() {
+            flags.push_str(&format!(" chunks={}", metadata.chunks.len()));
+        }
+        writeln!(
+            out,
+            "{:>14} {:>6} {:>12} {:>12} {:>12} {:>8} {:>5} {:<7} {}/{}{}",
+            metadata.offset,
+            metadata.header_len,
+            aligned,
+            metadata.size,
+            metadata.stored_size(),
+            checksum,
+            metadata.generation,
+            kind,
+            key.namespace,
+            key.request_id,
+            flags,
+        )?;
+        stats.records += 1;
+        stats.aligned_bytes += aligned;
+
+        if let Some(preview) = preview {
+            if metadata.encrypted && cipher.is_none() {
+                writeln!(out, "    (encrypted; pass the encryption key to preview)")?;
+                continue;
+            }
+            match read_preview(file.as_ref(), cipher, &key, &metadata, preview.bytes).await {
+                Ok(payload) => write_preview(out, preview.format, &payload)?,
+                Err(e) => writeln!(out, "    (cannot read payload: {})", e)?,
+            }
+        }
+    }
+    Ok(stats)
+}
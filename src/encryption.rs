@@ -0,0 +1,116 @@
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+use anyhow::Result;
+use tracing::info;
+
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+// Nonce and authentication tag of an encrypted payload, kept in its record
+// header
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Seal {
+    pub(crate) nonce: [u8; NONCE_LEN],
+    pub(crate) tag: [u8; TAG_LEN],
+}
+
+// AES-256-GCM encryption of record payloads at rest. Each payload is sealed
+// under a fresh random nonce, with the record key it was written under as
+// associated data, so a payload cannot be passed off as another record's.
+pub(crate) struct RecordCipher {
+    cipher: Aes256Gcm,
+}
+
+impl RecordCipher {
+    pub(crate) fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    // Encrypt a payload in place
+    pub(crate) fn seal(&self, aad: &[u8], payload: &mut [u8]) -> Seal {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        // Only payloads over 64 GiB are refused, far beyond any record
+        let tag = self.cipher.encrypt_in_place_detached(&nonce, aad, payload).expect("payload too large for AES-GCM");
+        Seal {
+            nonce: nonce.into(),
+            tag: tag.into(),
+        }
+    }
+
+    // Decrypt a payload in place, failing if it or its seal was altered or was
+    // sealed under another key
+    pub(crate) fn open(&self, aad: &[u8], seal: &Seal, payload: &mut [u8]) -> Result<(), aes_gcm::Error> {
+        self.cipher.decrypt_in_place_detached(Nonce::from_slice(&seal.nonce), aad, payload, Tag::from_slice(&seal.tag))
+    }
+}
+
+// An encrypted record could not be decrypted: the key is wrong or the record
+// was tampered with
+#[derive(Debug)]
+pub(crate) struct DecryptionFailed {
+    pub(crate) request_id: String,
+}
+
+impl std::fmt::Display for DecryptionFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request ID {} could not be decrypted: wrong key or tampered data", self.request_id)
+    }
+}
+
+impl std::error::Error for DecryptionFailed {}
+
+// An encrypted record was read by a server started without a key
+#[derive(Debug)]
+pub(crate) struct EncryptionKeyMissing {
+    pub(crate) request_id: String,
+}
+
+impl std::fmt::Display for EncryptionKeyMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request ID {} is encrypted and no encryption key is configured", self.request_id)
+    }
+}
+
+impl std::error::Error for EncryptionKeyMissing {}
+
+// Decode a key given as 64 hex digits
+fn parse_hex_key(text: &str) -> Result<[u8; KEY_LEN]> {
+    let text = text.trim();
+    if text.len() != KEY_LEN * 2 || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        anyhow::bail!("Encryption key must be {} hex digits", KEY_LEN * 2);
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16)?;
+    }
+    Ok(key)
+}
+
+// Load the data key, in order of preference, from a KMS hook (a shell
+// command printing the key in hex), a key file holding the key raw or in hex,
+// or a hex key given directly. Returns None if no source is configured.
+pub(crate) fn load_key(key_command: Option<&str>, key_file: Option<&str>, key_hex: Option<&str>) -> Result<Option<RecordCipher>> {
+    let key = if let Some(command) = key_command {
+        let output = std::process::Command::new("sh").arg("-c").arg(command).output()?;
+        if !output.status.success() {
+            anyhow::bail!("Encryption key command failed: {}", output.status);
+        }
+        info!("Loaded encryption key from key command");
+        parse_hex_key(&String::from_utf8_lossy(&output.stdout))?
+    } else if let Some(path) = key_file {
+        let bytes = std::fs::read(path)?;
+        info!("Loaded encryption key from {}", path);
+        match <[u8; KEY_LEN]>::try_from(bytes.as_slice()) {
+            Ok(key) => key,
+            Err(_) => parse_hex_key(&String::from_utf8_lossy(&bytes))?,
+        }
+    } else if let Some(text) = key_hex {
+        parse_hex_key(text)?
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(RecordCipher::new(&key)))
+}
@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::warn;
+
+use crate::crash_test::Rng;
+use crate::file_io::{align_down, align_up, sector_size, Advice, FileIO};
+
+// Failures to inject into every data file's I/O, parsed from `--inject-faults`
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FaultConfig {
+    // Fail every Nth read or write with EIO
+    pub(crate) eio_every: Option<u64>,
+    // Stop every Nth write partway, after a prefix of its sectors
+    pub(crate) short_write_every: Option<u64>,
+    // Delay every Nth read or write by `latency`
+    pub(crate) slow_every: Option<u64>,
+    pub(crate) latency: Duration,
+    // Lose power on the Nth write: only some of its sectors land, and every
+    // operation after it fails until the server restarts
+    pub(crate) crash_at: Option<u64>,
+    pub(crate) seed: u64,
+}
+
+impl FaultConfig {
+    // Parse a comma-separated list such as `eio=100,slow=20:250,crash=5000`:
+    // `eio=<n>`, `short-write=<n>`, `slow=<n>:<ms>`, `crash=<n>` and
+    // `seed=<n>`, where `<n>` counts operations on each data file
+    pub(crate) fn parse(spec: &str) -> Result<Self> {
+        let mut config = FaultConfig::default();
+        for fault in spec.split(',').filter(|fault| !fault.is_empty()) {
+            let (name, value) = fault.split_once('=').ok_or_else(|| anyhow::anyhow!("Fault {:?} is not of the form name=value", fault))?;
+            let count = |value: &str| match value.parse::<u64>() {
+                Ok(count) if count > 0 => Ok(count),
+                _ => Err(anyhow::anyhow!("Fault {:?} needs a positive operation count, got {:?}", name, value)),
+            };
+            match name {
+                "eio" => config.eio_every = Some(count(value)?),
+                "short-write" => config.short_write_every = Some(count(value)?),
+                "slow" => {
+                    let (every, millis) = value.split_once(':').ok_or_else(|| anyhow::anyhow!("Fault \"slow\" takes <n>:<ms>, got {:?}", value))?;
+                    config.slow_every = Some(count(every)?);
+                    config.latency = Duration::from_millis(millis.parse().map_err(|_| anyhow::anyhow!("Invalid latency {:?} in fault \"slow\"", millis))?);
+                }
+                "crash" => config.crash_at = Some(count(value)?),
+                "seed" => config.seed = value.parse().map_err(|_| anyhow::anyhow!("Invalid fault seed {:?}", value))?,
+                _ => anyhow::bail!("Unknown fault {:?}; expected eio, short-write, slow, crash or seed", name),
+            }
+        }
+        Ok(config)
+    }
+}
+
+// Operation counts and crash state of one data file
+struct FaultState {
+    ops: AtomicU64,
+    writes: AtomicU64,
+    crashed: AtomicBool,
+    rng: Mutex<Rng>,
+}
+
+// Decorator over any backend that injects the failures in a `FaultConfig`,
+// for testing how the service layer copes with a misbehaving device
+pub(crate) struct FaultyFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    config: FaultConfig,
+    state: FaultState,
+}
+
+impl FaultyFileIO {
+    pub(crate) fn new(inner: Arc<dyn FileIO + Send + Sync>, config: FaultConfig) -> Self {
+        let state = FaultState { ops: AtomicU64::new(0), writes: AtomicU64::new(0), crashed: AtomicBool::new(false), rng: Mutex::new(Rng(config.seed)) };
+        Self { inner, config, state }
+    }
+
+    // Count a read or write, applying the faults that are due on every kind
+    // of operation
+    async fn before_op(&self, op: &str, offset: u64) -> Result<()> {
+        if self.state.crashed.load(Ordering::SeqCst) {
+            anyhow::bail!("Injected fault: device lost power");
+        }
+        let count = self.state.ops.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.config.slow_every.is_some_and(|every| count.is_multiple_of(every)) {
+            tokio::time::sleep(self.config.latency).await;
+        }
+        if self.config.eio_every.is_some_and(|every| count.is_multiple_of(every)) {
+            warn!("Injecting EIO into {} at offset {}", op, offset);
+            return Err(std::io::Error::from_raw_os_error(libc::EIO).into());
+        }
+        Ok(())
+    }
+
+    // Write the sectors of `data` the filter picks, as a device that stops
+    // or loses power partway through a write leaves them
+    async fn write_sectors(&self, data: &[u8], offset: u64, mut keep: impl FnMut(usize) -> bool) -> Result<()> {
+        let end = offset + data.len() as u64;
+        let mut start = offset;
+        let mut index = 0;
+        while start < end {
+            let sector_end = (align_down(start) + sector_size()).min(end);
+            if keep(index) {
+                self.inner.write_at(data[(start - offset) as usize..(sector_end - offset) as usize].to_vec(), start).await?;
+            }
+            start = sector_end;
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileIO for FaultyFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        self.before_op("write", offset).await?;
+        let count = self.state.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        let sectors = align_up(data.len() as u64) / sector_size();
+        if self.config.crash_at == Some(count) {
+            self.state.crashed.store(true, Ordering::SeqCst);
+            let mut rng = Rng(self.state.rng.lock().unwrap().next());
+            self.write_sectors(&data, offset, |_| rng.below(2) == 0).await?;
+            warn!("Injected power loss tearing a {}-byte write at offset {}", data.len(), offset);
+            anyhow::bail!("Injected fault: device lost power writing {} bytes at offset {}", data.len(), offset);
+        }
+        if self.config.short_write_every.is_some_and(|every| count.is_multiple_of(every)) {
+            let written = self.state.rng.lock().unwrap().below(sectors.max(1)) as usize;
+            self.write_sectors(&data, offset, |index| index < written).await?;
+            warn!("Injecting a short write at offset {}: {} of {} sectors", offset, written, sectors);
+            anyhow::bail!("Injected fault: short write of {} of {} sectors at offset {}", written, sectors, offset);
+        }
+        self.inner.write_at(data, offset).await
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        self.before_op("read", offset).await?;
+        self.inner.read_at(size, offset).await
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        if self.state.crashed.load(Ordering::SeqCst) {
+            anyhow::bail!("Injected fault: device lost power");
+        }
+        self.inner.sync_data().await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.inner.set_len(size)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        self.inner.segment_size()
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        self.inner.preallocate(length)
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        self.inner.remove_segment(index)
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    // Non-blocking reads are not passed through, so every read is counted
+    // and can fail as configured
+    fn try_read_at(&self, _size: u64, _offset: u64) -> Result<Option<Bytes>> {
+        Ok(None)
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        self.inner.storage_kind()
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
@@ -1,42 +1,902 @@
 use async_trait::async_trait;
 use anyhow::Result;
-use std::time::Instant;
-use tracing::{info, warn};
+use bytes::Bytes;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 
+use crate::aligned_buf::{max_buffer_size, AlignedBuf};
+use crate::coalesce::CoalescingFileIO;
+use crate::faulty_file_io::{FaultConfig, FaultyFileIO};
+use crate::io_timeout::TimeoutFileIO;
+#[cfg(target_os = "linux")]
+use crate::io_workers::{self, WorkerFile};
+use crate::mem_file_io::MemFileIO;
+use crate::read_cache::{self, CachingFileIO};
+#[cfg(target_os = "linux")]
+use crate::numa;
+use crate::volume::{self, VolumeFileIO};
+#[cfg(target_os = "linux")]
+use crate::uring;
+
+// Smallest sector size O_DIRECT alignment is assumed to need, used until the
+// data directory's device has been probed and when it cannot be. Records
+// written before sector size detection are aligned to it.
+pub const BLOCK_SIZE: u64 = 512;
+
+// Whether data files are opened with O_DIRECT; cleared when the data
+// directory's filesystem rejects it
+static DIRECT_IO: AtomicBool = AtomicBool::new(true);
+
+// O_DIRECT where the platform has it; macOS has no such open flag
+#[cfg(not(target_os = "macos"))]
+const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(target_os = "macos")]
+const O_DIRECT: i32 = 0;
+
+// Whether data files bypass the page cache
+pub fn direct_io() -> bool {
+    DIRECT_IO.load(Ordering::Relaxed)
+}
+
+// Logical sector size of the device holding the data directory
+static SECTOR_SIZE: AtomicU64 = AtomicU64::new(BLOCK_SIZE);
+
+// Alignment of O_DIRECT offsets, lengths and record starts
+pub fn sector_size() -> u64 {
+    SECTOR_SIZE.load(Ordering::Relaxed)
+}
+
+// Round a byte count up to the next sector boundary
+pub fn align_up(size: u64) -> u64 {
+    size.div_ceil(sector_size()) * sector_size()
+}
+
+// Round a byte offset down to the containing sector boundary
+pub fn align_down(offset: u64) -> u64 {
+    (offset / sector_size()) * sector_size()
+}
+
+// Offset alignment the filesystem requires of O_DIRECT I/O on `file`, as
+// statx reports it with STATX_DIOALIGN (Linux 6.1 and later). `None` if the
+// kernel or filesystem does not say.
+#[cfg(target_os = "linux")]
+fn direct_io_alignment(file: &std::fs::File) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: statx is plain old data, for which all zeros is valid
+    let mut stat: libc::statx = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is a NUL-terminated empty string naming the descriptor
+    // itself, and `stat` is a valid statx buffer
+    let result = unsafe { libc::statx(file.as_raw_fd(), c"".as_ptr(), libc::AT_EMPTY_PATH, libc::STATX_DIOALIGN, &mut stat) };
+    (result == 0 && stat.stx_mask & libc::STATX_DIOALIGN != 0 && stat.stx_dio_offset_align > 0).then_some(u64::from(stat.stx_dio_offset_align))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn direct_io_alignment(_file: &std::fs::File) -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+nix::ioctl_read_bad!(blksszget, 0x1268, libc::c_int);
+
+// Logical sector size of a block device, with the BLKSSZGET ioctl
+#[cfg(target_os = "linux")]
+fn block_device_sector_size(file: &std::fs::File) -> Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let mut size: libc::c_int = 0;
+    // SAFETY: BLKSSZGET writes one int to the pointer it is given
+    unsafe { blksszget(file.as_raw_fd(), &mut size) }?;
+    Ok(size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_device_sector_size(_file: &std::fs::File) -> Result<u64> {
+    anyhow::bail!("Cannot query the sector size of a block device on this platform")
+}
+
+// Attribute `name` of the request queue of the block device at or holding
+// `path`, from sysfs. A partition has no queue of its own, so its parent
+// disk's is used. Returns the sysfs directory of the device too.
+fn sysfs_queue_attribute(path: &Path, name: &str) -> Result<(String, String)> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let metadata = std::fs::metadata(path)?;
+    let dev = if metadata.file_type().is_block_device() { metadata.rdev() } else { metadata.dev() };
+    let device = format!("/sys/dev/block/{}:{}", nix::sys::stat::major(dev), nix::sys::stat::minor(dev));
+    let value = std::fs::read_to_string(format!("{}/queue/{}", device, name))
+        .or_else(|_| std::fs::read_to_string(format!("{}/../queue/{}", device, name)))?;
+    Ok((value.trim().to_string(), device))
+}
+
+// Logical sector size of the block device holding `path`, from sysfs
+fn sysfs_sector_size(path: &Path) -> Result<u64> {
+    let (value, device) = sysfs_queue_attribute(path, "logical_block_size")?;
+    value.parse::<u64>().map_err(|_| anyhow::anyhow!("Unexpected logical block size {:?} for {}", value, device))
+}
+
+// Check that the block device at or holding `path` has poll queues. IOPOLL
+// reads on any other device fail with EOPNOTSUPP, which a probe read of an
+// empty file, never reaching the device, does not show.
+#[cfg(target_os = "linux")]
+fn check_device_polls(path: &Path) -> Result<()> {
+    let (value, device) = sysfs_queue_attribute(path, "io_poll")?;
+    if value != "1" {
+        anyhow::bail!("{} has no poll queues", device);
+    }
+    Ok(())
+}
+
+// Alignment O_DIRECT I/O needs on the file or block device at `path`, or in
+// the directory at `path`: what statx reports for it (for a directory, for a
+// probe file created in it), else BLKSSZGET for a block device, else the
+// logical block size sysfs reports for the device holding it
+pub fn logical_sector_size(path: &Path) -> Result<u64> {
+    use std::os::unix::fs::FileTypeExt;
+    let metadata = std::fs::metadata(path)?;
+    let reported = if metadata.is_dir() {
+        let probe = path.join(".sector-probe");
+        let file = std::fs::File::create(&probe)?;
+        let aligned = direct_io_alignment(&file);
+        drop(file);
+        std::fs::remove_file(&probe)?;
+        aligned
+    } else {
+        direct_io_alignment(&std::fs::File::open(path)?)
+    };
+    let size = match reported {
+        Some(size) => size,
+        None if metadata.file_type().is_block_device() => block_device_sector_size(&std::fs::File::open(path)?)?,
+        None => sysfs_sector_size(path)?,
+    };
+    if size < BLOCK_SIZE || !size.is_power_of_two() {
+        anyhow::bail!("Unexpected direct I/O alignment of {} bytes for {}", size, path.display());
+    }
+    Ok(size)
+}
+
+// Refuse to do O_DIRECT I/O on a file needing coarser alignment than the
+// sector size records are aligned to, e.g. a data file migrated or symlinked
+// onto another device; writes there would fail with EINVAL
+fn check_alignment(file: &std::fs::File, file_path: &str) -> Result<()> {
+    let configured = volume::for_path(Path::new(file_path)).and_then(|volume| volume.alignment);
+    match configured.or_else(|| direct_io_alignment(file)) {
+        Some(required) if required > sector_size() => anyhow::bail!(
+            "{} requires {}-byte aligned direct I/O, but records are aligned to {} bytes; restart with the data directory on that device",
+            file_path, required, sector_size()
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Check that the filesystem holding `data_dir` takes O_DIRECT, by opening a
+// probe file there with it and writing a sector. Some, such as tmpfs and
+// certain network filesystems, refuse it; data files are then opened for
+// buffered I/O instead, which GetServerInfo reports. Returns whether O_DIRECT
+// is in use.
+pub fn probe_direct_io(data_dir: &Path) -> bool {
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    let probe = data_dir.join(".direct-io-probe");
+    let result = if O_DIRECT == 0 {
+        Err(anyhow::anyhow!("this platform has no O_DIRECT"))
+    } else {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .custom_flags(O_DIRECT)
+            .open(&probe)
+            .and_then(|file| file.write_all_at(&AlignedBuf::zeroed(sector_size() as usize), 0))
+            .map_err(anyhow::Error::from)
+    };
+    let _ = std::fs::remove_file(&probe);
+    if let Err(e) = result {
+        DIRECT_IO.store(false, Ordering::Relaxed);
+        error!(
+            "O_DIRECT is not supported in {} ({}); FALLING BACK TO BUFFERED I/O. Writes go through the page cache and only reach stable storage when synced; use --durability odsync or interval:<ms> to bound what a power loss can lose",
+            data_dir.display(), e
+        );
+    }
+    direct_io()
+}
+
+// Align everything that follows to the logical sector size of the device
+// holding `data_dir`, so headers and payloads start on sector boundaries and
+// each record is read with a single O_DIRECT operation on 4Kn drives too.
+// Devices that cannot be probed, such as tmpfs or overlay mounts, keep
+// `BLOCK_SIZE`. Returns the sector size in use.
+pub fn detect_sector_size(data_dir: &Path) -> u64 {
+    let detected = match volume::for_path(data_dir).and_then(|volume| volume.alignment) {
+        Some(alignment) => Ok(alignment),
+        None => logical_sector_size(data_dir),
+    };
+    match detected {
+        Ok(size) => use_sector_size(size, &data_dir.display().to_string()),
+        Err(e) => {
+            warn!("Cannot detect the sector size of {}, assuming {} bytes: {}", data_dir.display(), BLOCK_SIZE, e);
+            suit_volumes()
+        }
+    }
+}
+
+// Align records to the `size`-byte sectors of `device`, such as a device
+// whose sectors are known without probing a path, or to the coarser
+// alignment of a configured volume. Returns the sector size in use.
+pub fn use_sector_size(size: u64, device: &str) -> u64 {
+    SECTOR_SIZE.store(size, Ordering::Relaxed);
+    info!("Aligning records in {} to {}-byte sectors", device, size);
+    suit_volumes()
+}
+
+fn suit_volumes() -> u64 {
+    // Data files may be moved to any configured volume, so records suit the
+    // coarsest alignment among them
+    if let Some(coarsest) = volume::coarsest_alignment().filter(|&coarsest| coarsest > sector_size()) {
+        SECTOR_SIZE.store(coarsest, Ordering::Relaxed);
+        info!("Aligning records to {}-byte sectors to suit every configured volume", coarsest);
+    }
+    sector_size()
+}
+
+// Sync the directory holding `path`, so a file renamed or created there
+// survives a crash
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+// How hard data files push writes to stable storage. O_DIRECT bypasses the
+// page cache but not the device's write cache, and does not make file size
+// changes durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // O_DIRECT only; the device flushes on its own schedule
+    NoSync,
+    // Open with O_DSYNC so every write returns once it is on stable storage
+    ODsync,
+    // fdatasync in the background at this interval while writes are outstanding
+    Interval(Duration),
+}
+
+impl Durability {
+    // Flags for opening a data file: O_DIRECT unless the data directory
+    // rejected it, plus O_DSYNC if requested
+    fn open_flags(self) -> i32 {
+        let flags = if direct_io() { O_DIRECT } else { 0 };
+        match self {
+            Durability::ODsync => flags | libc::O_DSYNC,
+            Durability::NoSync | Durability::Interval(_) => flags,
+        }
+    }
+}
+
+// Handle shared by a data file's I/O objects and its background flusher
+struct PeriodicSync {
+    file: std::fs::File,
+    // Set by writes, cleared by a successful fdatasync
+    dirty: AtomicBool,
+}
+
+impl PeriodicSync {
+    // Open a separate handle on the data file and fdatasync it every
+    // `interval` while it has unflushed writes. The flusher stops once every
+    // I/O object using the file has been dropped.
+    fn spawn(file_path: &str, interval: Duration) -> Result<Arc<Self>> {
+        let sync = Arc::new(Self {
+            file: std::fs::File::open(file_path)?,
+            dirty: AtomicBool::new(false),
+        });
+        let weak = Arc::downgrade(&sync);
+        let file_path = file_path.to_string();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(sync) = weak.upgrade() else {
+                    break;
+                };
+                if !sync.dirty.swap(false, Ordering::AcqRel) {
+                    continue;
+                }
+                let flushed = tokio::task::spawn_blocking(move || {
+                    let flushed = sync.file.sync_data();
+                    if flushed.is_err() {
+                        sync.dirty.store(true, Ordering::Release);
+                    }
+                    flushed
+                })
+                .await;
+                match flushed {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("Periodic fdatasync of {} failed: {}", file_path, e),
+                    Err(e) => error!("Periodic fdatasync of {} panicked: {}", file_path, e),
+                }
+            }
+        });
+        Ok(sync)
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+}
+
+// Start the background flusher for a data file if the policy asks for one
+fn periodic_sync(file_path: &str, durability: Durability) -> Result<Option<Arc<PeriodicSync>>> {
+    match durability {
+        Durability::Interval(interval) => Ok(Some(PeriodicSync::spawn(file_path, interval)?)),
+        Durability::NoSync | Durability::ODsync => Ok(None),
+    }
+}
+
+// Expected use of a range of a data file, passed to the kernel as a page
+// cache hint. With O_DIRECT reads and writes bypass the page cache, so hints
+// matter once the server has fallen back to buffered I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+    // The range will not be read again soon; its cached pages can be dropped
+    DontNeed,
+}
+
+#[cfg(target_os = "linux")]
+impl From<Advice> for nix::fcntl::PosixFadviseAdvice {
+    fn from(advice: Advice) -> Self {
+        match advice {
+            Advice::Normal => Self::POSIX_FADV_NORMAL,
+            Advice::Sequential => Self::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => Self::POSIX_FADV_RANDOM,
+            Advice::WillNeed => Self::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => Self::POSIX_FADV_DONTNEED,
+        }
+    }
+}
+
+// Read `size` bytes at `offset` with RWF_NOWAIT, which fails instead of
+// waiting for the device. Returns None unless every byte was already in the
+// page cache, or if the kernel does not support the flag.
+#[cfg(target_os = "linux")]
+fn read_nowait(file: &std::fs::File, size: u64, offset: u64) -> Result<Option<Bytes>> {
+    use std::os::unix::io::AsRawFd;
+    let mut buffer = AlignedBuf::zeroed(align_up(size) as usize);
+    let iov = libc::iovec { iov_base: buffer.as_mut_ptr().cast(), iov_len: buffer.len() };
+    // SAFETY: the iovec spans the buffer, which outlives the call
+    let read = unsafe { libc::preadv2(file.as_raw_fd(), &iov, 1, offset as libc::off_t, libc::RWF_NOWAIT) };
+    if read < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EAGAIN | libc::EOPNOTSUPP | libc::ENOSYS) => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+    // Partly cached, or running past the end of the file, which the regular
+    // read path pads with zeros
+    if (read as u64) < size {
+        return Ok(None);
+    }
+    Ok(Some(Bytes::from_owner(buffer).slice(..size as usize)))
+}
+
+// Positional I/O on a data file. Every method takes `&self`, as positional
+// reads and writes keep no cursor; implementations share their state
+// internally, so a data file is shared as an `Arc<dyn FileIO>` and any number
+// of reads and writes can run on it at once without a lock around it.
 #[async_trait]
 pub trait FileIO {
-    async fn write_at(&mut self, data: Vec<u8>, offset: u64) -> Result<()>;
-    async fn read_at(&mut self, size: u64, offset: u64) -> Result<Vec<u8>>;
-    fn try_clone(&self) -> Result<Box<dyn FileIO + Send + Sync>>;
-    async fn metadata(&self) -> Result<std::fs::Metadata>;
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()>;
+    // The bytes read are a view of the buffer they were read into where the
+    // backend can manage it, so they reach the response without a copy
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes>;
+    // Write `buffers` back to back from `offset` in a single submission.
+    // Every buffer but the last must be a whole number of sectors long, so
+    // each starts on a sector boundary without being copied into one.
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        check_vectored(buffers.iter().map(|buffer| buffer.len() as u64))?;
+        let mut position = offset;
+        for buffer in buffers {
+            let length = buffer.len() as u64;
+            self.write_at(buffer, position).await?;
+            position += length;
+        }
+        Ok(())
+    }
+    // Read consecutive ranges of the given sizes from `offset` in a single
+    // submission, into a buffer each; sizes are constrained as above
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        check_vectored(sizes.iter().copied())?;
+        let mut position = offset;
+        let mut buffers = Vec::with_capacity(sizes.len());
+        for size in sizes {
+            buffers.push(Vec::from(self.read_at(size, position).await?));
+            position += size;
+        }
+        Ok(buffers)
+    }
+    // Current size of the underlying file
+    async fn len(&self) -> Result<u64>;
+    // fdatasync the underlying file
+    async fn sync_data(&self) -> Result<()>;
+    // Resize the underlying file; used to truncate the data file
+    fn set_len(&self, size: u64) -> Result<()>;
+    // Short name of the I/O backend, reported by GetServerInfo
+    fn backend_name(&self) -> &'static str;
+    // Size of each segment file, for data files split into segments
+    fn segment_size(&self) -> Option<u64> {
+        None
+    }
+    // What holds the data when it is not a regular file at the data file's
+    // path, such as a raw block device. Snapshots, compaction and migration
+    // read or replace that file directly and refuse such data files.
+    fn storage_kind(&self) -> Option<&'static str> {
+        None
+    }
+    // Reserve disk blocks for the first `length` bytes without changing the
+    // file size, so appends into them need no block allocation
+    fn preallocate(&self, length: u64) -> Result<()> {
+        anyhow::bail!("Preallocation of {} bytes is not supported by the {} backend", length, self.backend_name())
+    }
+    // Delete a segment file. Returns false if it did not exist.
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        anyhow::bail!("Cannot remove segment {} of a single data file", index)
+    }
+    // Free the disk blocks of an aligned range without changing the file
+    // size; the range reads as zeros afterwards
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        anyhow::bail!("Punching a {}-byte hole at offset {} is not supported by the {} backend", length, offset, self.backend_name())
+    }
+    // Hint how a range will be used, with posix_fadvise. Hints are advisory:
+    // backends without a page cache ignore them.
+    fn advise(&self, _offset: u64, _length: u64, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+    // Read without blocking, if the bytes can be had at once, such as from
+    // the page cache; None means the caller should `read_at` instead. Backends
+    // that cannot tell always return None.
+    fn try_read_at(&self, _size: u64, _offset: u64) -> Result<Option<Bytes>> {
+        Ok(None)
+    }
+    // Wait for every read and write in flight to finish, refuse any more and
+    // flush the file. Called at shutdown, so the process never exits with a
+    // write submitted but not done.
+    async fn close(&self) -> Result<()> {
+        self.sync_data().await
+    }
+}
+
+// Operations in flight on a data file. Each holds a permit until the kernel
+// is done with it, even if whoever started it has stopped waiting, so closing
+// the file can wait them all out.
+pub(crate) struct InFlight(Arc<tokio::sync::Semaphore>);
+
+impl InFlight {
+    // More operations than can ever be in flight at once
+    const PERMITS: u32 = u32::MAX >> 3;
+
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(tokio::sync::Semaphore::new(Self::PERMITS as usize)))
+    }
+
+    // Admit an operation, unless the file is closing or closed
+    pub(crate) fn enter(&self) -> Result<tokio::sync::OwnedSemaphorePermit> {
+        self.0.clone().try_acquire_owned().map_err(|_| anyhow::anyhow!("Data file is closed"))
+    }
+
+    // Wait for the operations in flight and admit no more
+    pub(crate) async fn drain(&self) {
+        if let Ok(permits) = self.0.acquire_many(Self::PERMITS).await {
+            permits.forget();
+        }
+        self.0.close();
+    }
+}
+
+// Check the lengths of the buffers of a vectored operation: all but the last
+// must be whole sectors
+pub(crate) fn check_vectored(lengths: impl Iterator<Item = u64>) -> Result<()> {
+    let lengths: Vec<u64> = lengths.collect();
+    if let Some(index) = lengths.iter().take(lengths.len().saturating_sub(1)).position(|&length| length % sector_size() != 0) {
+        anyhow::bail!("Buffer {} of a vectored operation is {} bytes, not a multiple of the {}-byte sector size", index, lengths[index], sector_size());
+    }
+    Ok(())
+}
+
+// Offsets and lengths of the pieces of at most `max` bytes a `length`-byte
+// transfer at `offset` is split into; one piece if it fits
+pub(crate) fn io_pieces(offset: u64, length: u64, max: u64) -> impl Iterator<Item = (u64, u64)> {
+    (0..length.div_ceil(max).max(1)).map(move |index| {
+        let start = index * max;
+        (offset + start, max.min(length - start))
+    })
+}
+
+// Write `data` at `offset` as concurrent writes of at most `max` bytes each,
+// so no single buffer has to hold the whole payload. The pieces share the
+// payload rather than copying it.
+pub(crate) async fn write_in_pieces<F, Fut>(data: Vec<u8>, offset: u64, max: u64, write: F) -> Result<()>
+where
+    F: Fn(Bytes, u64) -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let data = Bytes::from(data);
+    let writes = io_pieces(offset, data.len() as u64, max).map(|(start, length)| {
+        let skip = (start - offset) as usize;
+        write(data.slice(skip..skip + length as usize), start)
+    });
+    futures::future::try_join_all(writes).await?;
+    Ok(())
+}
+
+// Read `size` bytes at `offset` as concurrent reads of at most `max` bytes
+// each, reassembled in order; a read that fits is returned as is
+pub(crate) async fn read_in_pieces<F, Fut>(size: u64, offset: u64, max: u64, read: F) -> Result<Bytes>
+where
+    F: Fn(u64, u64) -> Fut,
+    Fut: std::future::Future<Output = Result<Bytes>>,
+{
+    if size <= max {
+        return read(size, offset).await;
+    }
+    let pieces = futures::future::try_join_all(io_pieces(offset, size, max).map(|(start, length)| read(length, start))).await?;
+    let mut data = Vec::with_capacity(size as usize);
+    for piece in pieces {
+        data.extend_from_slice(&piece);
+    }
+    Ok(data.into())
+}
+
+// Whether data files are held in memory rather than on disk
+static MEMORY_BACKEND: AtomicBool = AtomicBool::new(false);
+
+// Hold data files opened from now on in memory, see `MemFileIO`
+pub fn use_memory_backend() {
+    MEMORY_BACKEND.store(true, Ordering::Relaxed);
+}
+
+// Failures injected into the I/O of every data file, if any
+static FAULTS: std::sync::OnceLock<FaultConfig> = std::sync::OnceLock::new();
+
+// Inject `config`'s failures into data files opened from now on, see
+// `FaultyFileIO`
+pub fn inject_faults(config: FaultConfig) {
+    let _ = FAULTS.set(config);
+}
+
+// Whether small writes to a data file are gathered into batches
+static COALESCE: AtomicBool = AtomicBool::new(false);
+
+// Gather the small writes to data files opened from now on into batches, see
+// `CoalescingFileIO`
+pub fn enable_write_coalescing() {
+    COALESCE.store(true, Ordering::Relaxed);
+}
+
+// Longest a read, write or sync of a data file may take, if limited
+static IO_TIMEOUT: std::sync::OnceLock<Duration> = std::sync::OnceLock::new();
+
+// Fail reads, writes and syncs of data files opened from now on that take
+// longer than `timeout`, see `TimeoutFileIO`
+pub fn set_io_timeout(timeout: Duration) {
+    let _ = IO_TIMEOUT.set(timeout);
+}
+
+// Whether data files are driven by IOPOLL rings
+static IOPOLL: AtomicBool = AtomicBool::new(false);
+
+// Poll for the completions of reads and writes rather than waiting for the
+// device to interrupt, for data files opened from now on. Files whose device
+// or filesystem cannot be polled keep using interrupts. Only the io_uring
+// backend polls.
+pub fn enable_iopoll() {
+    IOPOLL.store(true, Ordering::Relaxed);
+}
+
+// Priority of a data file read or write with the kernel's I/O scheduler
+// and on the I/O workers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoPriority {
+    // Client requests
+    #[default]
+    Normal,
+    // Client requests marked bulk, which wait behind Normal ones for a place
+    // on the I/O workers' rings; the kernel serves them as Normal
+    Bulk,
+    // Background work such as scrubbing and compaction, which the scheduler
+    // serves only while the device has nothing else to do
+    Idle,
+}
+
+tokio::task_local! {
+    // Priority of the data file I/O a task issues, where it is not Normal
+    static IO_PRIORITY: IoPriority;
+}
+
+// Run `work` with every data file read and write it issues at `priority`.
+// Decorators that move an operation to another task carry the priority over.
+pub(crate) async fn with_priority<F: std::future::Future>(priority: IoPriority, work: F) -> F::Output {
+    IO_PRIORITY.scope(priority, work).await
+}
+
+// Priority of data file I/O issued by the current task
+pub(crate) fn io_priority() -> IoPriority {
+    IO_PRIORITY.try_with(|priority| *priority).unwrap_or_default()
 }
 
+// ioprio(2) value of the idle class, which libc does not define
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: u16 = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+
+// ioprio field of io_uring reads and writes at `priority`. Only Idle sets
+// one; the rest take the priority of the ring's thread.
+#[cfg(target_os = "linux")]
+pub(crate) fn submission_priority(priority: IoPriority) -> u16 {
+    match priority {
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoPriority::Normal | IoPriority::Bulk => 0,
+    }
+}
+
+// Whether every write to a data file is read back and compared
+static VERIFY_WRITES: AtomicBool = AtomicBool::new(false);
+
+// Read every write back from the device once it completes and fail it if
+// what comes back differs, on every backend but the in-memory one
+pub fn enable_write_verification() {
+    VERIFY_WRITES.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn verify_writes() -> bool {
+    VERIFY_WRITES.load(Ordering::Relaxed)
+}
+
+// Compare what was read back from `offset` with the sector-padded image of
+// `data` written there
+pub(crate) fn check_written(data: &[u8], read_back: &[u8], offset: u64) -> std::io::Result<()> {
+    let (payload, padding) = read_back.split_at(data.len());
+    if payload == data && padding.iter().all(|&byte| byte == 0) {
+        return Ok(());
+    }
+    let first = payload.iter().zip(data).position(|(read, written)| read != written).unwrap_or(data.len());
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Write of {} bytes at offset {} read back differently from byte {}", data.len(), offset, first),
+    ))
+}
+
+// Read back `data` just written at `offset`, on the blocking thread that
+// wrote it
+#[cfg(not(target_os = "linux"))]
+fn verify_written_blocking(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    let mut buffer = AlignedBuf::zeroed(align_up(data.len() as u64) as usize);
+    file.read_exact_at(&mut buffer, offset)?;
+    check_written(data, &buffer, offset)
+}
+
+// How long the kernel thread that polls an IOPOLL ring spins once the ring
+// goes idle before it sleeps until the next submission
+#[cfg(target_os = "linux")]
+const SQPOLL_IDLE_MS: u32 = 100;
+
+// Operations an I/O worker takes off its queue at a time, and the size of its
+// submission queue, so a whole batch fits before the kernel is entered; a
+// volume's configured queue depth replaces it for polled rings
+#[cfg(target_os = "linux")]
+pub(crate) const SUBMIT_BATCH: usize = 256;
+
+// Operation run on a data file's ring
+#[cfg(target_os = "linux")]
+pub(crate) enum UringOp {
+    Write { data: Bytes, offset: u64, done: tokio::sync::oneshot::Sender<std::io::Result<()>> },
+    Read { size: u64, offset: u64, done: tokio::sync::oneshot::Sender<std::io::Result<Bytes>> },
+    WriteVectored { buffers: Vec<Vec<u8>>, offset: u64, done: tokio::sync::oneshot::Sender<std::io::Result<()>> },
+    ReadVectored { sizes: Vec<u64>, offset: u64, done: tokio::sync::oneshot::Sender<std::io::Result<Vec<Vec<u8>>>> },
+    Sync { done: tokio::sync::oneshot::Sender<std::io::Result<()>> },
+}
+
+#[cfg(target_os = "linux")]
+impl UringOp {
+    // Fail the operation without running it
+    pub(crate) fn fail(self, e: std::io::Error) {
+        match self {
+            UringOp::Write { done, .. } | UringOp::WriteVectored { done, .. } | UringOp::Sync { done } => {
+                let _ = done.send(Err(e));
+            }
+            UringOp::Read { done, .. } => {
+                let _ = done.send(Err(e));
+            }
+            UringOp::ReadVectored { done, .. } => {
+                let _ = done.send(Err(e));
+            }
+        }
+    }
+}
+
+// Handle on the data file of a LinuxFileIO. Reads, writes and syncs are
+// submitted to the I/O workers, see `IoWorkers`, and run concurrently there.
+// Size and allocation changes go through the same file descriptor,
+// duplicated, from the caller's thread. Small reads and writes use buffers
+// registered with the worker's ring, and the worker registers its descriptor
+// of the file with the ring too, see `uring::Ring`, so submissions name it by
+// slot rather than have the kernel look the descriptor up each time.
+//
+// With IOPOLL enabled the file gets a ring of its own instead, on a thread of
+// its own, which polls for completions instead of taking interrupts. A polled
+// ring fails operations on files that cannot be polled, so it cannot be
+// shared with other files. Nothing reaps polled completions unless asked to,
+// so such a ring also has a kernel thread polling its submission queue, which
+// reaps them as it goes. An IOPOLL ring only runs reads and writes; syncs are
+// made from a blocking thread instead. The thread exits once the handle is
+// dropped.
+#[cfg(target_os = "linux")]
+struct UringHandle {
+    file: std::fs::File,
+    ring: Ring,
+    in_flight: InFlight,
+}
+
+// Operation sent to a polled ring, with the ioprio of its reads and writes
+#[cfg(target_os = "linux")]
+type PolledOp = (UringOp, u16, tokio::sync::OwnedSemaphorePermit);
+
+// Where a data file's reads, writes and syncs run
+#[cfg(target_os = "linux")]
+enum Ring {
+    // The I/O workers every other data file shares
+    Workers(WorkerFile),
+    // A polled ring of the file's own
+    Polled(tokio::sync::mpsc::UnboundedSender<PolledOp>),
+}
+
+#[cfg(target_os = "linux")]
+impl UringHandle {
+    fn spawn(file: std::fs::File, file_path: &str, depth: usize) -> Result<Self> {
+        if IOPOLL.load(Ordering::Relaxed) {
+            match check_device_polls(Path::new(file_path)).and_then(|()| Self::spawn_polled(file.try_clone()?, file_path, depth)) {
+                Ok(ops) => return Ok(Self { file, ring: Ring::Polled(ops), in_flight: InFlight::new() }),
+                Err(e) => warn!("Cannot poll for completions on {}, using interrupts: {}", file_path, e),
+            }
+        }
+        let ring = Ring::Workers(io_workers::workers()?.open(&file, file_path)?);
+        Ok(Self { file, ring, in_flight: InFlight::new() })
+    }
+
+    // Whether the file has a polled ring
+    fn polled(&self) -> bool {
+        matches!(self.ring, Ring::Polled(_))
+    }
+
+    // Start the thread of a polled ring, returning once the ring is up. The
+    // ring is checked with a read first, which fails if the file's device or
+    // filesystem cannot be polled.
+    fn spawn_polled(file: std::fs::File, file_path: &str, depth: usize) -> Result<tokio::sync::mpsc::UnboundedSender<PolledOp>> {
+        let (ops, mut queue) = tokio::sync::mpsc::unbounded_channel::<PolledOp>();
+        let (ready, started) = std::sync::mpsc::sync_channel::<std::io::Result<()>>(1);
+        let thread_path = file_path.to_string();
+        let node = numa::node_for(Path::new(file_path));
+        std::thread::Builder::new().name(format!("uring-{}", file_path)).spawn(move || {
+            // Submit from, and allocate buffers on, the device's NUMA node
+            if let Some(node) = node {
+                match numa::bind_current_thread(node) {
+                    Ok(()) => info!("io_uring thread for {} runs on NUMA node {}", thread_path, node),
+                    Err(e) => warn!("Could not place the io_uring thread for {} on NUMA node {}: {}", thread_path, node, e),
+                }
+            }
+            let mut builder = io_uring::IoUring::builder();
+            builder.setup_iopoll().setup_sqpoll(SQPOLL_IDLE_MS);
+            // The kernel's polling thread too
+            if let Some(cpu) = node.and_then(|node| numa::node_cpus(node).ok()?.first().copied()) {
+                builder.setup_sqpoll_cpu(cpu as u32);
+            }
+            let mut ring = match uring::Ring::new(&builder, depth as u32, thread_path.clone(), None) {
+                Ok(ring) => ring,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            ring.add_file(0, file);
+            if let Err(e) = Self::probe(&mut ring) {
+                let _ = ready.send(Err(e));
+                return;
+            }
+            let _ = ready.send(Ok(()));
+            if let Err(e) = Self::drive(&mut ring, &mut queue, depth) {
+                error!("io_uring thread for {} stopped: {}", thread_path, e);
+                ring.abandon(&e);
+            }
+        })?;
+        started.recv().map_err(|_| anyhow::anyhow!("io_uring thread for {} exited while starting", file_path))??;
+        Ok(ops)
+    }
+
+    // Run the operations sent to a polled ring until its handle is dropped,
+    // batched as on the I/O workers. Nothing wakes a polled ring, so the
+    // thread waits on its queue whenever nothing is in flight.
+    fn drive(ring: &mut uring::Ring, queue: &mut tokio::sync::mpsc::UnboundedReceiver<PolledOp>, depth: usize) -> std::io::Result<()> {
+        loop {
+            let mut taken = 0;
+            if ring.in_flight() == 0 {
+                let Some((op, ioprio, permit)) = queue.blocking_recv() else {
+                    return Ok(());
+                };
+                ring.start(0, op, ioprio, Some(permit))?;
+                taken += 1;
+            }
+            while taken < depth {
+                let Ok((op, ioprio, permit)) = queue.try_recv() else {
+                    break;
+                };
+                ring.start(0, op, ioprio, Some(permit))?;
+                taken += 1;
+            }
+            ring.turn(taken == 0)?;
+        }
+    }
+
+    // Read the first sector of the file on a new polled ring
+    fn probe(ring: &mut uring::Ring) -> std::io::Result<()> {
+        let (done, mut probed) = tokio::sync::oneshot::channel();
+        ring.start(0, UringOp::Read { size: sector_size(), offset: 0, done }, 0, None)?;
+        while ring.in_flight() > 0 {
+            ring.turn(true)?;
+        }
+        probed.try_recv().map_err(|_| std::io::Error::other("probe read did not finish"))?.map(|_| ())
+    }
+
+    // Submit an operation to the file's ring and wait for its result
+    async fn submit<T>(&self, op: impl FnOnce(tokio::sync::oneshot::Sender<std::io::Result<T>>) -> UringOp) -> Result<T> {
+        let permit = self.in_flight.enter()?;
+        let (done, result) = tokio::sync::oneshot::channel();
+        match &self.ring {
+            Ring::Workers(workers) => workers.send(op(done), permit)?,
+            Ring::Polled(ops) => ops.send((op(done), submission_priority(io_priority()), permit)).map_err(|_| anyhow::anyhow!("io_uring thread has stopped"))?,
+        }
+        Ok(result.await.map_err(|_| anyhow::anyhow!("io_uring thread dropped an operation"))??)
+    }
+}
+
+// O_DIRECT data file driven by io_uring. Every operation takes `&self`, so
+// any number of reads and writes can be in flight on it at once.
 #[cfg(target_os = "linux")]
 pub struct LinuxFileIO {
-    file: tokio_uring::fs::File,
+    handle: UringHandle,
+    sync: Option<Arc<PeriodicSync>>,
 }
 
 #[cfg(target_os = "linux")]
 impl LinuxFileIO {
-    pub async fn new(file_path: &str) -> Result<Self> {
-        let file = tokio_uring::fs::OpenOptions::new()
+    pub async fn new(file_path: &str, durability: Durability) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = std::fs::OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
-            .custom_flags(0x4000) // O_DIRECT flag
-            .open(file_path)
-            .await?;
-        
-        Ok(Self { file })
+            .custom_flags(durability.open_flags())
+            .open(file_path)?;
+        check_alignment(&file, file_path)?;
+        let depth = volume::for_path(Path::new(file_path)).and_then(|volume| volume.queue_depth).unwrap_or(SUBMIT_BATCH);
+        let handle = UringHandle::spawn(file, file_path, depth)?;
+        let sync = periodic_sync(file_path, durability)?;
+
+        Ok(Self { handle, sync })
     }
 }
 
 #[cfg(target_os = "linux")]
 #[async_trait]
 impl FileIO for LinuxFileIO {
-    async fn write_at(&mut self, data: Vec<u8>, offset: u64) -> Result<()> {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
         let start = Instant::now();
-        self.file.write_at(data, offset).await?;
+        // Payloads over the largest buffer go out in pieces, in flight at once
+        write_in_pieces(data, offset, max_buffer_size(), |piece, offset| self.handle.submit(move |done| UringOp::Write { data: piece, offset, done })).await?;
+        if let Some(sync) = &self.sync {
+            sync.mark_dirty();
+        }
         let duration = start.elapsed();
         
         info!("Linux uring write completed in {:?}", duration);
@@ -47,13 +907,9 @@ impl FileIO for LinuxFileIO {
         Ok(())
     }
     
-    async fn read_at(&mut self, size: u64, offset: u64) -> Result<Vec<u8>> {
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
         let start = Instant::now();
-        let aligned_size = ((size + 511) / 512) * 512; // Align to 512 bytes
-        let mut buffer = vec![0u8; aligned_size as usize];
-        
-        self.file.read_at(buffer, offset).await?;
-        let data = buffer[..size as usize].to_vec();
+        let data = read_in_pieces(size, offset, max_buffer_size(), |size, offset| self.handle.submit(move |done| UringOp::Read { size, offset, done })).await?;
         
         let duration = start.elapsed();
         info!("Linux uring read completed in {:?}", duration);
@@ -63,65 +919,159 @@ impl FileIO for LinuxFileIO {
         
         Ok(data)
     }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        check_vectored(buffers.iter().map(|buffer| buffer.len() as u64))?;
+        self.handle.submit(|done| UringOp::WriteVectored { buffers, offset, done }).await?;
+        if let Some(sync) = &self.sync {
+            sync.mark_dirty();
+        }
+        Ok(())
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        check_vectored(sizes.iter().copied())?;
+        self.handle.submit(|done| UringOp::ReadVectored { sizes, offset, done }).await
+    }
     
-    fn try_clone(&self) -> Result<Box<dyn FileIO + Send + Sync>> {
-        // For Linux, we need to handle this differently since try_clone is async
-        // This is a limitation of the trait approach
-        Err(anyhow::anyhow!("try_clone not implemented for Linux uring"))
+    async fn len(&self) -> Result<u64> {
+        Ok(self.handle.file.metadata()?.len())
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        if self.handle.polled() {
+            let file = self.handle.file.try_clone()?;
+            tokio::task::spawn_blocking(move || file.sync_data()).await??;
+            return Ok(());
+        }
+        self.handle.submit(|done| UringOp::Sync { done }).await
     }
     
-    async fn metadata(&self) -> Result<std::fs::Metadata> {
-        Ok(self.file.metadata().await?)
+    fn set_len(&self, size: u64) -> Result<()> {
+        Ok(self.handle.file.set_len(size)?)
+    }
+    
+    fn backend_name(&self) -> &'static str {
+        "io_uring"
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        nix::fcntl::fallocate(
+            self.handle.file.as_raw_fd(),
+            nix::fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            0,
+            length as libc::off_t,
+        )?;
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        nix::fcntl::fallocate(
+            self.handle.file.as_raw_fd(),
+            nix::fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE | nix::fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            length as libc::off_t,
+        )?;
+        Ok(())
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        nix::fcntl::posix_fadvise(self.handle.file.as_raw_fd(), offset as libc::off_t, length as libc::off_t, advice.into())?;
+        Ok(())
+    }
+
+    // An O_DIRECT read always waits for the device, so only buffered reads
+    // are tried from the caller's thread, skipping the hop to the ring
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        if direct_io() {
+            return Ok(None);
+        }
+        read_nowait(&self.handle.file, size, offset)
+    }
+
+    // Submissions still on the ring finish before the file is flushed
+    async fn close(&self) -> Result<()> {
+        self.handle.in_flight.drain().await;
+        let file = self.handle.file.try_clone()?;
+        tokio::task::spawn_blocking(move || file.sync_data()).await??;
+        Ok(())
     }
 }
 
 #[cfg(not(target_os = "linux"))]
 pub struct FallbackFileIO {
     file: std::fs::File,
+    sync: Option<Arc<PeriodicSync>>,
+    // Blocking reads and writes, which run to completion once started
+    in_flight: InFlight,
 }
 
 #[cfg(not(target_os = "linux"))]
 impl FallbackFileIO {
-    pub async fn new(file_path: &str) -> Result<Self> {
+    pub async fn new(file_path: &str, durability: Durability) -> Result<Self> {
         use std::os::unix::fs::OpenOptionsExt;
         let file = std::fs::OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
-            .custom_flags(0x4000) // O_DIRECT flag
+            .custom_flags(durability.open_flags())
             .open(file_path)?;
+        check_alignment(&file, file_path)?;
+        let sync = periodic_sync(file_path, durability)?;
         
-        Ok(Self { file })
+        Ok(Self { file, sync, in_flight: InFlight::new() })
     }
-    
-    fn align_data_for_odirect(&self, mut data: Vec<u8>) -> Vec<u8> {
-        let block_size = 512;
-        let current_size = data.len();
-        let aligned_size = ((current_size + block_size - 1) / block_size) * block_size;
+
+    // Write one piece of a payload from a blocking thread
+    async fn write_piece(&self, data: Bytes, offset: u64) -> Result<()> {
+        let aligned_data = AlignedBuf::copy_from(&data, align_up(data.len() as u64) as usize);
+        let file_clone = self.file.try_clone()?;
+        let in_flight = self.in_flight.enter()?;
         
-        if current_size < aligned_size {
-            data.resize(aligned_size, 0);
-        }
+        // Positioned writes, as clones of the file share its cursor
+        tokio::task::spawn_blocking(move || {
+            use std::os::unix::fs::FileExt;
+            let _in_flight = in_flight;
+            file_clone.write_all_at(&aligned_data, offset)?;
+            if verify_writes() {
+                verify_written_blocking(&file_clone, &data, offset)?;
+            }
+            Ok::<(), std::io::Error>(())
+        }).await??;
+        Ok(())
+    }
+
+    // Read one piece of a payload from a blocking thread
+    async fn read_piece(&self, size: u64, offset: u64) -> Result<Bytes> {
+        let aligned_size = align_up(size);
+        let file_clone = self.file.try_clone()?;
+        let in_flight = self.in_flight.enter()?;
         
-        data
+        let data = tokio::task::spawn_blocking(move || {
+            use std::os::unix::fs::FileExt;
+            let _in_flight = in_flight;
+            let mut buffer = AlignedBuf::zeroed(aligned_size as usize);
+            file_clone.read_exact_at(&mut buffer, offset)?;
+            
+            Ok::<Bytes, std::io::Error>(Bytes::from_owner(buffer).slice(..size as usize))
+        }).await??;
+        Ok(data)
     }
 }
 
 #[cfg(not(target_os = "linux"))]
 #[async_trait]
 impl FileIO for FallbackFileIO {
-    async fn write_at(&mut self, data: Vec<u8>, offset: u64) -> Result<()> {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
         let start = Instant::now();
-        let aligned_data = self.align_data_for_odirect(data);
-        let file_clone = self.file.try_clone()?;
-        
-        tokio::task::spawn_blocking(move || {
-            use std::io::{Seek, SeekFrom, Write};
-            let mut file = file_clone;
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&aligned_data)?;
-            Ok::<(), std::io::Error>(())
-        }).await??;
+        // Payloads over the largest buffer go out in pieces, in flight at once
+        write_in_pieces(data, offset, max_buffer_size(), |piece, offset| self.write_piece(piece, offset)).await?;
+        if let Some(sync) = &self.sync {
+            sync.mark_dirty();
+        }
         
         let duration = start.elapsed();
         info!("Fallback write completed in {:?}", duration);
@@ -132,21 +1082,9 @@ impl FileIO for FallbackFileIO {
         Ok(())
     }
     
-    async fn read_at(&mut self, size: u64, offset: u64) -> Result<Vec<u8>> {
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
         let start = Instant::now();
-        let aligned_size = ((size + 511) / 512) * 512;
-        let file_clone = self.file.try_clone()?;
-        
-        let data = tokio::task::spawn_blocking(move || {
-            use std::io::{Seek, SeekFrom, Read};
-            let mut file = file_clone;
-            file.seek(SeekFrom::Start(offset))?;
-            
-            let mut buffer = vec![0u8; aligned_size as usize];
-            file.read_exact(&mut buffer)?;
-            
-            Ok::<Vec<u8>, std::io::Error>(buffer[..size as usize].to_vec())
-        }).await??;
+        let data = read_in_pieces(size, offset, max_buffer_size(), |size, offset| self.read_piece(size, offset)).await?;
         
         let duration = start.elapsed();
         info!("Fallback read completed in {:?}", duration);
@@ -156,25 +1094,119 @@ impl FileIO for FallbackFileIO {
         
         Ok(data)
     }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        check_vectored(buffers.iter().map(|buffer| buffer.len() as u64))?;
+        let file_clone = self.file.try_clone()?;
+        let in_flight = self.in_flight.enter()?;
+        tokio::task::spawn_blocking(move || {
+            use std::os::unix::io::AsRawFd;
+            let _in_flight = in_flight;
+            let padded: Vec<AlignedBuf> = buffers.iter().map(|data| AlignedBuf::copy_from(data, align_up(data.len() as u64) as usize)).collect();
+            let slices: Vec<std::io::IoSlice> = padded.iter().map(|buffer| std::io::IoSlice::new(buffer)).collect();
+            let total: usize = padded.iter().map(|buffer| buffer.len()).sum();
+            let written = nix::sys::uio::pwritev(file_clone.as_raw_fd(), &slices, offset as libc::off_t)?;
+            if written < total {
+                anyhow::bail!("Vectored write stopped after {} of {} bytes", written, total);
+            }
+            if verify_writes() {
+                let mut position = offset;
+                for data in &buffers {
+                    verify_written_blocking(&file_clone, data, position)?;
+                    position += align_up(data.len() as u64);
+                }
+            }
+            Ok(())
+        }).await??;
+        if let Some(sync) = &self.sync {
+            sync.mark_dirty();
+        }
+        Ok(())
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        check_vectored(sizes.iter().copied())?;
+        let file_clone = self.file.try_clone()?;
+        let in_flight = self.in_flight.enter()?;
+        tokio::task::spawn_blocking(move || {
+            use std::os::unix::io::AsRawFd;
+            let _in_flight = in_flight;
+            let mut buffers: Vec<AlignedBuf> = sizes.iter().map(|&size| AlignedBuf::zeroed(align_up(size) as usize)).collect();
+            let mut slices: Vec<std::io::IoSliceMut> = buffers.iter_mut().map(|buffer| std::io::IoSliceMut::new(buffer)).collect();
+            // A short read past the end of the file leaves zeros
+            nix::sys::uio::preadv(file_clone.as_raw_fd(), &mut slices, offset as libc::off_t)?;
+            Ok(buffers.iter().zip(&sizes).map(|(buffer, &size)| buffer[..size as usize].to_vec()).collect())
+        }).await?
+    }
+    
+    async fn len(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        let file = self.file.try_clone()?;
+        tokio::task::spawn_blocking(move || file.sync_data()).await??;
+        Ok(())
+    }
     
-    fn try_clone(&self) -> Result<Box<dyn FileIO + Send + Sync>> {
-        let cloned_file = self.file.try_clone()?;
-        Ok(Box::new(FallbackFileIO { file: cloned_file }))
+    fn set_len(&self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size)?)
     }
     
-    async fn metadata(&self) -> Result<std::fs::Metadata> {
-        Ok(self.file.metadata()?)
+    fn backend_name(&self) -> &'static str {
+        "fallback"
+    }
+
+    // Blocking writes cannot be abandoned, so they finish before the flush
+    async fn close(&self) -> Result<()> {
+        self.in_flight.drain().await;
+        let file = self.file.try_clone()?;
+        tokio::task::spawn_blocking(move || file.sync_data()).await??;
+        Ok(())
+    }
+}
+
+pub async fn create_file_io(file_path: &str, durability: Durability) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    Ok(add_layers(open_backend(file_path, durability).await?, file_path))
+}
+
+// Wrap a backend's I/O on `file_path` in the layers the command line asked
+// for
+pub(crate) fn add_layers(mut file: Arc<dyn FileIO + Send + Sync>, file_path: &str) -> Arc<dyn FileIO + Send + Sync> {
+    if let Some(volume) = volume::for_path(Path::new(file_path)) {
+        file = VolumeFileIO::wrap(file, volume);
+    }
+    // Batches are split to the volume's maximum I/O size, and faults hit the
+    // writes of each request
+    if COALESCE.load(Ordering::Relaxed) {
+        file = Arc::new(CoalescingFileIO::new(file));
+    }
+    if let Some(config) = FAULTS.get() {
+        file = Arc::new(FaultyFileIO::new(file, *config));
     }
+    // Outside faults, so injected latency counts against the timeout
+    if let Some(timeout) = IO_TIMEOUT.get() {
+        file = Arc::new(TimeoutFileIO::new(file, file_path, *timeout));
+    }
+    // Outermost, so a hit skips every other layer. Memory is not cached.
+    if let Some(cache) = read_cache::cache().filter(|_| !MEMORY_BACKEND.load(Ordering::Relaxed)) {
+        file = Arc::new(CachingFileIO::new(file, cache));
+    }
+    file
 }
 
-pub async fn create_file_io(file_path: &str) -> Result<Box<dyn FileIO + Send + Sync>> {
+async fn open_backend(file_path: &str, durability: Durability) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    if MEMORY_BACKEND.load(Ordering::Relaxed) {
+        return Ok(Arc::new(MemFileIO::open(file_path)));
+    }
+
     #[cfg(target_os = "linux")]
     {
-        Ok(Box::new(LinuxFileIO::new(file_path).await?))
+        Ok(Arc::new(LinuxFileIO::new(file_path, durability).await?))
     }
     
     #[cfg(not(target_os = "linux"))]
     {
-        Ok(Box::new(FallbackFileIO::new(file_path).await?))
+        Ok(Arc::new(FallbackFileIO::new(file_path, durability).await?))
     }
 } 
\ No newline at end of file
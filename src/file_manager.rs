@@ -0,0 +1,2314 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Bound;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
+use tracing::{error, info, warn};
+
+use crate::block_device;
+#[cfg(feature = "spdk")]
+use crate::spdk;
+use crate::bloom::BloomFilter;
+use crate::commit::Epochs;
+use crate::compression::Compression;
+use crate::double_write::{self, DoubleWriteBuffer, StagedExtent};
+use crate::file_io::{with_priority, Advice, FileIO, Durability, IoPriority, create_file_io, align_up, align_down, sync_parent_dir, sector_size};
+use crate::index_store::{self, IndexContents, IndexFile, PersistedRecord, PersistedTrash};
+use crate::metadata_store::{self, MetadataStoreKind, ShardedStore};
+use crate::record_format;
+use crate::segment::{self, SegmentedFileIO};
+use crate::space::Space;
+use crate::versions::VersionPolicy;
+use crate::wal::{self, LogFailed, Wal, WalOp};
+
+// File used when a request does not name one
+pub const DEFAULT_FILE_ID: &str = "data";
+
+// Change events buffered per data file before slow watchers start lagging
+const CHANGE_EVENT_CAPACITY: usize = 1024;
+
+// Write-ahead log entries after which the request map is checkpointed to the
+// sidecar index and the log emptied
+const WAL_CHECKPOINT_ENTRIES: usize = 4096;
+
+// Files with fewer reclaimable bytes than this are never compacted automatically
+const AUTO_COMPACT_MIN_RECLAIMABLE: u64 = 1024 * 1024;
+
+// Most recent changes kept per data file so change feeds can resume from a sequence
+const CHANGE_LOG_CAPACITY: usize = 64 * 1024;
+
+// Bytes read at a time when moving a torn tail to its quarantine file
+const TAIL_COPY_CHUNK: u64 = 1024 * 1024;
+
+// Bytes of copied record clusters gathered into each vectored write
+const COPY_BATCH: usize = 4 * 1024 * 1024;
+
+// Request metadata for tracking offsets
+#[derive(Debug, Clone)]
+pub(crate) struct RequestMetadata {
+    // Offset of the payload; the record header sits just before it
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    // Length of the record header preceding the payload
+    pub(crate) header_len: u64,
+    pub(crate) written_at: SystemTime,
+    pub(crate) checksum: Option<u32>,
+    // Whether the payload is stored encrypted; its nonce and tag are in the header
+    pub(crate) encrypted: bool,
+    // How the payload is compressed, if it is; `size` is then its uncompressed length
+    pub(crate) compression: Option<Compression>,
+    // Incremented on every write of the request ID, starting at 1
+    pub(crate) generation: u64,
+    // Records past this time are treated as deleted and swept in the background
+    pub(crate) expires_at: Option<SystemTime>,
+    // Application-defined key/value pairs stored with the record
+    pub(crate) user_metadata: HashMap<String, String>,
+    // Where the payload of a large object split across extents is, in order;
+    // empty if the payload directly follows the header
+    pub(crate) chunks: Vec<Chunk>,
+}
+
+// Part of a large object's stored payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+impl RequestMetadata {
+    // Aligned blocks of the data file covering a record stored in one piece
+    // and its header. Records packed by BatchWrite may share their first and
+    // last block with a neighbour.
+    pub(crate) fn extent(&self) -> Extent {
+        let start = align_down(self.offset - self.header_len);
+        Extent {
+            offset: start,
+            length: align_up(self.offset + self.stored_size()) - start,
+        }
+    }
+
+    // Aligned blocks of the data file covering this record: its header and
+    // every chunk of its payload, in file order, with blocks shared between
+    // them merged
+    pub(crate) fn extents(&self) -> Vec<Extent> {
+        if self.chunks.is_empty() {
+            return vec![self.extent()];
+        }
+        let mut covered: Vec<Extent> = std::iter::once((self.offset - self.header_len, self.offset))
+            .chain(self.chunks.iter().map(|chunk| (chunk.offset, chunk.offset + chunk.length)))
+            .filter(|(start, end)| end > start)
+            .map(|(start, end)| Extent { offset: align_down(start), length: align_up(end) - align_down(start) })
+            .collect();
+        covered.sort_by_key(|extent| extent.offset);
+        let mut merged: Vec<Extent> = Vec::with_capacity(covered.len());
+        for extent in covered {
+            match merged.last_mut() {
+                Some(last) if extent.offset <= last.end() => last.length = extent.end().max(last.end()) - last.offset,
+                _ => merged.push(extent),
+            }
+        }
+        merged
+    }
+
+    pub(crate) fn overlaps(&self, extent: &Extent) -> bool {
+        self.extents().iter().any(|own| own.overlaps(extent))
+    }
+
+    // Bytes of aligned blocks the record takes
+    pub(crate) fn aligned_size(&self) -> u64 {
+        self.extents().iter().map(|extent| extent.length).sum()
+    }
+
+    // End of the record's last byte in the data file
+    pub(crate) fn data_end(&self) -> u64 {
+        match self.chunks.iter().map(|chunk| chunk.offset + chunk.length).max() {
+            Some(end) => end.max(self.offset),
+            None => self.offset + self.stored_size(),
+        }
+    }
+
+    // Point the record at a copy with its payload at `offset`, right after
+    // its header. A large object keeps its chunks, the first following the
+    // header and each later one starting on the next block boundary.
+    pub(crate) fn move_to(&mut self, offset: u64) {
+        let mut cursor = offset;
+        for (index, chunk) in self.chunks.iter_mut().enumerate() {
+            if index > 0 {
+                cursor = align_up(cursor);
+            }
+            chunk.offset = cursor;
+            cursor += chunk.length;
+        }
+        self.offset = offset;
+    }
+
+    // Bytes the payload takes in the data file
+    pub(crate) fn stored_size(&self) -> u64 {
+        self.compression.map_or(self.size, |compression| compression.stored_size)
+    }
+
+    pub(crate) fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// Request map keyed by namespace and request ID, held in shards of whichever
+// metadata store the server was started with
+pub(crate) type RequestMap = ShardedStore;
+
+// Prior versions of overwritten records, oldest first. Each keeps its blocks
+// until it is pruned or its record is deleted.
+pub(crate) type Versions = HashMap<RecordKey, Vec<RequestMetadata>>;
+
+// A deleted record kept for undelete until the trash retention passes
+#[derive(Debug, Clone)]
+pub(crate) struct TrashedRecord {
+    pub(crate) metadata: RequestMetadata,
+    pub(crate) deleted_at: SystemTime,
+}
+
+// Deleted records in the trash; each keeps its blocks until it is purged
+pub(crate) type Trash = HashMap<RecordKey, TrashedRecord>;
+
+// Fully qualified record name; request IDs are unique within a namespace
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct RecordKey {
+    pub(crate) namespace: String,
+    pub(crate) request_id: String,
+}
+
+// Kind of change applied to a record in the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Written,
+    Overwritten,
+    Deleted,
+}
+
+// Published to watchers whenever the index changes
+#[derive(Debug, Clone)]
+pub(crate) struct RecordEvent {
+    // Position of the change in the file's index history, starting at 1
+    pub(crate) sequence: u64,
+    pub(crate) kind: ChangeKind,
+    pub(crate) key: RecordKey,
+    pub(crate) metadata: RequestMetadata,
+}
+
+// A conditional write found the record at a different generation than the
+// caller expected
+#[derive(Debug)]
+pub(crate) struct GenerationMismatch {
+    pub(crate) request_id: String,
+    pub(crate) expected: u64,
+    pub(crate) actual: u64,
+}
+
+impl std::fmt::Display for GenerationMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request ID {} is at generation {}, expected {}", self.request_id, self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for GenerationMismatch {}
+
+// A write carried a fencing token other than the namespace's current one,
+// meaning a newer writer has taken over
+#[derive(Debug)]
+pub(crate) struct StaleFencingToken {
+    pub(crate) namespace: String,
+    pub(crate) token: u64,
+    pub(crate) current: u64,
+}
+
+impl std::fmt::Display for StaleFencingToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fencing token {} for namespace {:?} is stale, current token is {}", self.token, self.namespace, self.current)
+    }
+}
+
+impl std::error::Error for StaleFencingToken {}
+
+// Advisory lock on a single record, held until released or expired
+#[derive(Debug, Clone)]
+pub(crate) struct RecordLock {
+    pub(crate) lock_id: String,
+    pub(crate) expires_at: SystemTime,
+}
+
+// A write or lock request targeted a record locked under another lock ID
+#[derive(Debug)]
+pub(crate) struct RecordLocked {
+    pub(crate) request_id: String,
+    pub(crate) expires_at: SystemTime,
+}
+
+impl std::fmt::Display for RecordLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let remaining = self.expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+        write!(f, "Request ID {} is locked by another client for {}ms", self.request_id, remaining.as_millis())
+    }
+}
+
+impl std::error::Error for RecordLocked {}
+
+// A write would take a namespace past its storage quota
+#[derive(Debug)]
+pub(crate) struct QuotaExceeded {
+    pub(crate) namespace: String,
+    pub(crate) usage: u64,
+    pub(crate) quota: u64,
+    pub(crate) requested: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Namespace {:?} is using {} of {} bytes, cannot write {} more", self.namespace, self.usage, self.quota, self.requested)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+// Aligned on-disk region of the data file
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Extent {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+impl Extent {
+    pub(crate) fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    pub(crate) fn overlaps(&self, other: &Extent) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+}
+
+// What looking up a record needs from a data file: its request map and the
+// file the map's offsets point into. Shared with the registry, so reads of
+// current records skip the file manager's lock. Swapping in another data file
+// bumps `swaps` before and after, so a lookup racing the swap is retried
+// rather than pairing offsets in one file with the other.
+pub(crate) struct Lookups {
+    request_map: Arc<RequestMap>,
+    file: std::sync::RwLock<Arc<dyn FileIO + Send + Sync>>,
+    swaps: AtomicU64,
+}
+
+impl Lookups {
+    // A record's live entry and the data file holding it
+    pub(crate) fn lookup(&self, key: &RecordKey) -> Option<(RequestMetadata, Arc<dyn FileIO + Send + Sync>)> {
+        loop {
+            let before = self.swaps.load(Ordering::SeqCst);
+            if before.is_multiple_of(2) {
+                let file = self.file.read().unwrap().clone();
+                let metadata = self.request_map.get(key).filter(|metadata| !metadata.is_expired(SystemTime::now()));
+                if self.swaps.load(Ordering::SeqCst) == before {
+                    return metadata.map(|metadata| (metadata, file));
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    // Whether a record is indexed and live
+    pub(crate) fn exists(&self, key: &RecordKey) -> bool {
+        self.request_map.get(key).is_some_and(|metadata| !metadata.is_expired(SystemTime::now()))
+    }
+}
+
+// File manager for O_DIRECT operations
+pub(crate) struct FileManager {
+    pub(crate) file: Arc<dyn FileIO + Send + Sync>,
+    pub(crate) file_path: String,
+    // Applied to the data file and to files that replace it
+    pub(crate) durability: Durability,
+    // Bytes of disk space reserved ahead of appends, if configured
+    pub(crate) preallocate: Option<u64>,
+    // End of the data, free extents and writes in flight; shared with the
+    // write path so it reserves space without locking the file manager
+    pub(crate) space: Arc<Space>,
+    pub(crate) request_map: Arc<RequestMap>,
+    // The request map and data file, for lookups that skip this lock
+    pub(crate) lookups: Arc<Lookups>,
+    pub(crate) versions: Versions,
+    // Which prior versions are kept
+    pub(crate) version_policy: VersionPolicy,
+    pub(crate) trash: Trash,
+    // How long deleted records stay in the trash; `None` frees them right away
+    pub(crate) trash_retention: Option<Duration>,
+    // Sidecar file holding the last checkpoint of the request map
+    pub(crate) index: IndexFile,
+    // Changes to the request map since that checkpoint
+    pub(crate) wal: Wal,
+    // Bytes of live record data per namespace, kept in step with the request map
+    pub(crate) usage: HashMap<String, u64>,
+    // Latest fencing token issued per namespace
+    pub(crate) fencing_tokens: HashMap<String, u64>,
+    // Record locks; expired entries are ignored and swept with expired records
+    pub(crate) locks: HashMap<RecordKey, RecordLock>,
+    // Index changes, fanned out to Watch subscribers
+    pub(crate) events: broadcast::Sender<RecordEvent>,
+    // Most recent index changes, oldest first
+    pub(crate) changes: VecDeque<RecordEvent>,
+    // Distinguishes this instance of the index in session tokens
+    pub(crate) epoch: String,
+    // Incremented on every index change; readers holding a session token wait on it
+    pub(crate) sequence: watch::Sender<u64>,
+    // Set while a compaction or migration of this file is running
+    pub(crate) compacting: bool,
+    // Durability epochs writes are acknowledged in
+    pub(crate) epochs: Epochs,
+    // Keys that may be indexed; shared with the registry so lookups can
+    // consult it without locking the file manager
+    pub(crate) filter: Arc<BloomFilter>,
+    // Where overwrites in place stage their record images, if enabled
+    pub(crate) double_write: Option<Arc<Mutex<DoubleWriteBuffer>>>,
+}
+
+// Open a data file, as a single file or as segments if it was created with
+// them or `segment_size` is set
+pub(crate) async fn open_data_file(file_path: &str, durability: Durability, segment_size: Option<u64>) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    Ok(match segment::resolve_segment_size(file_path, segment_size)? {
+        Some(segment_size) => Arc::new(SegmentedFileIO::open(file_path, segment_size, durability).await?),
+        None => create_file_io(file_path, durability).await?,
+    })
+}
+
+impl FileManager {
+    pub(crate) async fn new(file_path: &str, durability: Durability, segment_size: Option<u64>, preallocate: Option<u64>, version_policy: VersionPolicy, trash_retention: Option<Duration>, store: MetadataStoreKind) -> Result<Self> {
+        recover_compaction(file_path)?;
+        let file = open_data_file(file_path, durability, segment_size).await?;
+        Self::with_file(file_path, file, durability, preallocate, version_policy, trash_retention, store).await
+    }
+
+    // Recover the index of a data file already opened as `file`; the sidecar
+    // index and log are found next to `file_path`. The crash test runs the
+    // engine on a simulated device this way.
+    pub(crate) async fn with_file(file_path: &str, file: Arc<dyn FileIO + Send + Sync>, durability: Durability, preallocate: Option<u64>, version_policy: VersionPolicy, trash_retention: Option<Duration>, store: MetadataStoreKind) -> Result<Self> {
+        // Get file size for current offset
+        let current_offset = file.len().await?;
+        let index_path = index_store::index_path(file_path);
+        let index_missing = !Path::new(&index_path).exists();
+        let (index, contents) = IndexFile::open(&index_path)?;
+        let (wal, replay) = Wal::open(&wal::wal_path(file_path), index.sequence)?;
+        let space = Arc::new(Space::new(current_offset, file.segment_size()));
+        let request_map = Arc::new(metadata_store::open(store, file_path)?);
+        let lookups = Arc::new(Lookups { request_map: request_map.clone(), file: std::sync::RwLock::new(file.clone()), swaps: AtomicU64::new(0) });
+
+        let mut manager = Self {
+            file,
+            file_path: file_path.to_string(),
+            durability,
+            preallocate,
+            space,
+            request_map,
+            lookups,
+            versions: HashMap::new(),
+            version_policy,
+            trash: HashMap::new(),
+            trash_retention,
+            index,
+            wal,
+            usage: HashMap::new(),
+            fencing_tokens: HashMap::new(),
+            locks: HashMap::new(),
+            events: broadcast::channel(CHANGE_EVENT_CAPACITY).0,
+            changes: VecDeque::new(),
+            epoch: uuid::Uuid::new_v4().simple().to_string(),
+            sequence: watch::channel(0).0,
+            compacting: false,
+            epochs: match durability {
+                Durability::Interval(_) => Epochs::open(file_path)?,
+                Durability::NoSync | Durability::ODsync => Epochs::volatile(),
+            },
+            filter: Arc::new(BloomFilter::new()),
+            double_write: None,
+        };
+        // Images a previous run staged are restored whether or not double
+        // writes are still enabled
+        let staged = double_write::load(&double_write::double_write_path(file_path))?;
+        if index_missing && replay.is_empty() && current_offset > 0 {
+            // The sidecar index is gone; rebuild it from the record headers
+            warn!("No index for {}, rebuilding it by scanning the data file", file_path);
+            let entries = record_format::scan(manager.file.as_ref(), current_offset).await?;
+            manager.recover(IndexContents { entries, ..IndexContents::default() }, Vec::new(), staged).await?;
+        } else if current_offset > 0 || !contents.entries.is_empty() || !replay.is_empty() {
+            manager.recover(contents, replay, staged).await?;
+        }
+        // A data file written on a device with smaller sectors may end
+        // between two of ours
+        manager.space.set_end(align_up(manager.space.end()));
+        // Recovery may shrink the file, which gives up blocks past its end,
+        // so preallocate afterwards
+        if let Some(length) = preallocate {
+            manager.file.preallocate(length)?;
+            info!("Preallocated {} bytes for {}", length, file_path);
+        }
+        Ok(manager)
+    }
+
+    // Rebuild the request map at startup from the sidecar checkpoint plus the
+    // log entries after it, then checkpoint the result so the log starts
+    // empty. Overwrites in place torn by a crash are restored from the
+    // double-write buffer. Records that extend past the end of the data file
+    // cannot be read and are dropped, as are trailing records torn by a
+    // crash. The file is
+    // cut back to the end of the last intact record: anything after it is a
+    // torn or unacknowledged write, since every acknowledged write is in the
+    // log.
+    async fn recover(&mut self, contents: IndexContents, replay: Vec<WalOp>, staged: Vec<StagedExtent>) -> Result<()> {
+        let replayed = replay.len();
+        let end = self.space.end();
+        let mut dropped = 0;
+        self.load_entries(contents, replay);
+        self.request_map.retain(&mut |key, metadata| {
+            let readable = metadata.data_end() <= end;
+            if !readable {
+                warn!("Dropping {:?} from the index of {}: extends past the end of the file", key.request_id, self.file_path);
+                dropped += 1;
+            }
+            readable
+        });
+        // Versions outlive neither their data nor their record
+        for (key, history) in self.versions.iter_mut() {
+            let live = self.request_map.contains(key);
+            history.retain(|version| live && version.data_end() <= end);
+        }
+        self.versions.retain(|_, history| !history.is_empty());
+        self.trash.retain(|_, trashed| trashed.metadata.data_end() <= end);
+        let restored = self.restore_double_writes(staged).await?;
+        let torn = self.drop_torn_records().await?;
+        let tail = self.trim_tail().await?;
+        let expired = self.rebuild_free_extents(SystemTime::now())?;
+        self.checkpoint();
+        info!(
+            "Recovered {} records for {} from {} and {} log entries ({} expired, {} dropped, {} torn, {} restored, {} tail bytes trimmed)",
+            self.record_count(), self.file_path, self.index.path, replayed, expired, dropped, torn, restored, tail
+        );
+        Ok(())
+    }
+
+    // Record ending furthest into the data file
+    fn last_record(&self) -> Option<(RecordKey, RequestMetadata)> {
+        self.request_map.read().iter().max_by_key(|(_, metadata)| metadata.data_end())
+    }
+
+    // Rewrite the images staged in the double-write buffer whose in-place
+    // write did not fully reach the disk. Only images of records the index
+    // holds are rewritten: an overwrite that was never logged either had not
+    // started, leaving the old record intact, or tore it beyond repair. Once
+    // the data file is synced the buffer is cleared. Returns how many images
+    // were rewritten.
+    async fn restore_double_writes(&mut self, staged: Vec<StagedExtent>) -> Result<usize> {
+        if staged.is_empty() {
+            return Ok(0);
+        }
+        let indexed: HashMap<u64, (RecordKey, RequestMetadata)> = {
+            let request_map = self.request_map.read();
+            request_map
+                .iter()
+                .filter(|(_, metadata)| staged.iter().any(|extent| extent.offset + metadata.header_len == metadata.offset))
+                .map(|(key, metadata)| (metadata.offset - metadata.header_len, (key, metadata)))
+                .collect()
+        };
+        let mut restored = 0;
+        for extent in staged {
+            let Some((key, metadata)) = indexed.get(&extent.offset) else {
+                continue;
+            };
+            let header_len = metadata.header_len as usize;
+            let describes = extent.image.len() as u64 >= metadata.header_len + metadata.stored_size()
+                && record_format::check_header(&extent.image[..header_len], key, metadata).is_ok();
+            if !describes {
+                continue;
+            }
+            let current = record_format::read_span(self.file.as_ref(), extent.offset, extent.image.len() as u64).await?;
+            if current == extent.image {
+                continue;
+            }
+            warn!("Restoring {:?} at offset {} of {} from the double-write buffer", key.request_id, extent.offset, self.file_path);
+            self.file.write_at(extent.image, extent.offset).await?;
+            restored += 1;
+        }
+        self.file.sync_data().await?;
+        double_write::clear(&double_write::double_write_path(&self.file_path))?;
+        Ok(restored)
+    }
+
+    // Stage overwrites in place in a double-write buffer from now on
+    pub(crate) fn enable_double_write(&mut self) -> Result<()> {
+        self.double_write = Some(Arc::new(Mutex::new(DoubleWriteBuffer::open(&self.file_path)?)));
+        Ok(())
+    }
+
+    // Drop records at the end of the file whose payload fails its checksum:
+    // the index entry was logged but the data never fully reached the disk.
+    // Checking stops at the first intact record. Returns how many were dropped.
+    async fn drop_torn_records(&mut self) -> Result<usize> {
+        let mut torn = 0;
+        while let Some((key, metadata)) = self.last_record() {
+            if metadata.checksum.is_none() {
+                break;
+            }
+            let payload = record_format::read_stored(self.file.as_ref(), &metadata, false).await?;
+            let Err(mismatch) = record_format::verify(&key.request_id, &metadata, &payload) else {
+                break;
+            };
+            warn!("Dropping torn record from the index of {}: {}", self.file_path, mismatch);
+            self.request_map.remove(&key);
+            torn += 1;
+        }
+        Ok(torn)
+    }
+
+    // Truncate the data file to the end of its last record and append from
+    // there. The bytes cut off are first copied to a quarantine file next to
+    // the data file for inspection. Returns the number of bytes cut off.
+    async fn trim_tail(&mut self) -> Result<u64> {
+        let end = self
+            .retained()
+            .map(|(_, metadata)| align_up(metadata.data_end()))
+            .chain(self.last_record().map(|(_, metadata)| align_up(metadata.data_end())))
+            .max()
+            .unwrap_or(0);
+        let file_end = self.space.end();
+        if end >= file_end {
+            return Ok(0);
+        }
+        let trimmed = file_end - end;
+        let quarantine_path = format!("{}.torn-{}", self.file_path, crate::unix_millis(SystemTime::now()));
+        warn!("Trimming {} bytes after the last record of {} at offset {}, moved to {}", trimmed, self.file_path, end, quarantine_path);
+
+        let mut quarantine = std::fs::File::create(&quarantine_path)?;
+        let mut cursor = end;
+        while cursor < file_end {
+            let length = TAIL_COPY_CHUNK.min(file_end - cursor);
+            let mut data = self.file.read_at(align_up(length), cursor).await?;
+            data.truncate(length as usize);
+            quarantine.write_all(&data)?;
+            cursor += length;
+        }
+        quarantine.sync_all()?;
+
+        self.file.set_len(end)?;
+        self.space.set_end(end);
+        Ok(trimmed)
+    }
+
+    // Replace the request map, prior versions and trash with `contents` and
+    // the logged changes after them
+    fn load_entries(&mut self, contents: IndexContents, replay: Vec<WalOp>) {
+        {
+            // Lookups that skip the file manager's lock see the old entries
+            // or the new ones, never a mix
+            let mut request_map = self.request_map.write();
+            request_map.clear();
+            for (key, metadata) in contents.entries {
+                request_map.insert(key, metadata);
+            }
+        }
+        self.versions.clear();
+        for (key, metadata) in contents.versions {
+            self.versions.entry(key).or_default().push(metadata);
+        }
+        for history in self.versions.values_mut() {
+            history.sort_by_key(|version| version.generation);
+        }
+        self.trash = contents.trash.into_iter().collect();
+        for op in replay {
+            op.apply(&self.request_map, &mut self.versions, &mut self.trash);
+        }
+    }
+
+    // Stage a change to the request map in the write-ahead log, checkpointing
+    // once the log is long. The caller applies the change only once it is
+    // staged; it is durable once a commit covering it has been waited on (see
+    // `sync_log`). Once a flush has failed, the request map is first rolled
+    // back to what the log holds on disk and the change is refused.
+    fn log(&mut self, op: WalOp) -> Result<()> {
+        if self.wal.failed() {
+            let reason = match self.roll_back_log() {
+                Ok(()) => "an earlier append failed and the index was rolled back".to_string(),
+                Err(e) => format!("an earlier append failed and the index could not be rolled back: {}", e),
+            };
+            return Err(LogFailed { path: wal::wal_path(&self.file_path), reason }.into());
+        }
+        self.wal.append(op)?;
+        if self.wal.entries >= WAL_CHECKPOINT_ENTRIES {
+            self.checkpoint_log();
+        }
+        Ok(())
+    }
+
+    // Fold the log into the sidecar index by appending its entries, then
+    // empty it. Only changes made through the log can be checkpointed this
+    // way; anything else that changes the request map takes a full
+    // checkpoint.
+    fn checkpoint_log(&mut self) {
+        if self.index.needs_full() {
+            self.checkpoint();
+            return;
+        }
+        let appended = self
+            .wal
+            .flush()
+            .and_then(|()| wal::read(&wal::wal_path(&self.file_path), self.index.sequence))
+            .and_then(|(replay, _)| self.index.append(replay.sequence, replay.ops))
+            .and_then(|()| self.wal.reset());
+        if let Err(e) = appended {
+            error!("Failed to checkpoint index {}: {}", self.index.path, e);
+        }
+    }
+
+    // Commit point covering every change logged so far
+    pub(crate) fn log_commit(&self) -> wal::Commit {
+        self.wal.commit()
+    }
+
+    // Roll the request map, prior versions and trash back to the sidecar
+    // index plus the log entries that reached the disk, after a flush of the
+    // log failed. Changes whose entries were lost are undone; the writes they
+    // indexed are left unreferenced. Nothing happens if the log has not
+    // failed.
+    pub(crate) fn roll_back_log(&mut self) -> Result<()> {
+        if !self.wal.failed() {
+            return Ok(());
+        }
+        self.wal.discard_unsynced()?;
+        let (checkpoint, contents) = index_store::load(&self.index.path)?;
+        let (replay, _) = wal::read(&wal::wal_path(&self.file_path), checkpoint)?;
+        self.load_entries(contents, replay.ops);
+        self.rebuild_free_space();
+        let sequence = self.wal.roll_back();
+        warn!("Rolled the index of {} back to log sequence {} after an append to its log failed", self.file_path, sequence);
+        Ok(())
+    }
+
+    // Swap in another data file, with `load` pointing the index at it.
+    // Lookups racing the swap wait for it, so `load` should not do I/O.
+    fn swap_file<T>(&mut self, file: Arc<dyn FileIO + Send + Sync>, load: impl FnOnce(&mut Self) -> T) -> T {
+        let lookups = self.lookups.clone();
+        lookups.swaps.fetch_add(1, Ordering::SeqCst);
+        *lookups.file.write().unwrap() = file.clone();
+        self.file = file;
+        let loaded = load(self);
+        lookups.swaps.fetch_add(1, Ordering::SeqCst);
+        loaded
+    }
+
+    // Save the request map to the sidecar index and empty the log. On failure
+    // the log is kept and the next checkpoint retries. The bloom filter is
+    // rebuilt along the way, shedding deleted keys. The checkpoint covers
+    // changes still staged in the log, and those a failed flush lost, so it
+    // also makes them durable.
+    fn checkpoint(&mut self) {
+        self.rebuild_filter();
+        let contents = IndexContents {
+            entries: self.entries(SystemTime::now()),
+            versions: self.version_entries(),
+            trash: self.trash.iter().map(|(key, trashed)| (key.clone(), trashed.clone())).collect(),
+        };
+        let saved = self.index.save_full(self.wal.sequence, contents).and_then(|()| self.wal.reset());
+        if let Err(e) = saved {
+            error!("Failed to checkpoint index {}: {}", self.index.path, e);
+        }
+    }
+
+    fn rebuild_filter(&self) {
+        let keys: Vec<RecordKey> = self.request_map.read().iter().map(|(key, _)| key).collect();
+        self.filter.rebuild(keys.len(), keys.iter().map(|key| (key.namespace.as_str(), key.request_id.as_str())));
+    }
+
+    // Add a key just indexed to the bloom filter, rebuilding it once it holds
+    // more keys than it was sized for
+    fn remember(&self, key: &RecordKey) {
+        if !self.filter.insert(key) {
+            self.rebuild_filter();
+        }
+    }
+
+    // Subscribe to changes of this file's index
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<RecordEvent> {
+        self.events.subscribe()
+    }
+
+    // Number of index changes applied so far
+    pub(crate) fn sequence(&self) -> u64 {
+        *self.sequence.borrow()
+    }
+
+    // Token covering every index change applied so far
+    pub(crate) fn session_token(&self) -> String {
+        format!("{}:{}", self.epoch, self.sequence())
+    }
+
+    // Retained changes with a sequence of at least `from`, oldest first; 0
+    // starts at the oldest retained change. Fails if changes from `from` on
+    // have been dropped, or if `from` is ahead of the index (e.g. after a
+    // restart reset the sequence).
+    pub(crate) fn changes_since(&self, from: u64) -> Result<Vec<RecordEvent>> {
+        let current = self.sequence();
+        let oldest = current + 1 - self.changes.len() as u64;
+        if from > current + 1 {
+            anyhow::bail!("Sequence {} is ahead of the index, which is at {}", from, current);
+        }
+        if from != 0 && from < oldest {
+            anyhow::bail!("Changes before sequence {} are no longer retained", oldest);
+        }
+        Ok(self.changes.iter().filter(|event| event.sequence >= from).cloned().collect())
+    }
+
+    fn notify(&mut self, kind: ChangeKind, key: RecordKey, metadata: RequestMetadata) {
+        let mut sequence = 0;
+        self.sequence.send_modify(|current| {
+            *current += 1;
+            sequence = *current;
+        });
+        let event = RecordEvent { sequence, kind, key, metadata };
+        if self.changes.len() == CHANGE_LOG_CAPACITY {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(event.clone());
+        // An error only means nobody is watching
+        let _ = self.events.send(event);
+    }
+
+    // Reserve a caller-chosen extent, returning a description of the conflict
+    // if it overlaps another record, a prior version, a trashed record or an
+    // in-flight write.
+    // With versioning on, a record's own blocks are kept as its prior version,
+    // so they conflict too.
+    pub(crate) fn reserve_at(&mut self, key: &RecordKey, extent: Extent) -> Result<(), String> {
+        {
+            let versioning = self.version_policy.is_enabled();
+            let conflict = self.request_map.read().iter().find(|(other, metadata)| (versioning || other != key) && metadata.overlaps(&extent));
+            if let Some((other, metadata)) = conflict {
+                return Err(format!("overlaps request {} at offset {}", other.request_id, metadata.offset));
+            }
+        }
+        let retained = self.retained().find(|(_, metadata)| metadata.overlaps(&extent));
+        if let Some((other, version)) = retained {
+            return Err(format!("overlaps generation {} of request {} at offset {}", version.generation, other.request_id, version.offset));
+        }
+        self.space.reserve_at(extent)
+    }
+
+    // Index entry for a record, if it exists
+    pub(crate) fn lookup(&self, key: &RecordKey) -> Option<RequestMetadata> {
+        self.request_map.get(key).filter(|metadata| !metadata.is_expired(SystemTime::now()))
+    }
+
+    // Whether the index holds an entry for a record, expired or not
+    fn contains(&self, key: &RecordKey) -> bool {
+        self.request_map.contains(key)
+    }
+
+    // Index entry for one generation of a record: the current entry or a
+    // prior version kept by the version policy
+    pub(crate) fn lookup_version(&self, key: &RecordKey, generation: u64) -> Option<RequestMetadata> {
+        let current = self.lookup(key)?;
+        if current.generation == generation {
+            return Some(current);
+        }
+        self.versions.get(key)?.iter().find(|version| version.generation == generation).cloned()
+    }
+
+    // Take a record out of the request map and its namespace's usage, without
+    // logging or announcing the change
+    fn unlink(&mut self, key: &RecordKey) -> Option<RequestMetadata> {
+        let metadata = self.request_map.remove(key)?;
+        self.release_usage(&key.namespace, metadata.size);
+        Some(metadata)
+    }
+
+    // Whether a record, or a prior version or trashed copy of it, is still
+    // indexed where and as `metadata` describes, e.g. to tell a record moved
+    // or removed since it was looked up from a corrupt one
+    pub(crate) fn is_indexed_at(&self, key: &RecordKey, metadata: &RequestMetadata) -> bool {
+        let same = |other: &RequestMetadata| other.offset == metadata.offset && other.generation == metadata.generation;
+        self.request_map.get(key).is_some_and(|other| same(&other)) || self.retained().any(|(other_key, other)| other_key == key && same(other))
+    }
+
+    // Remove a record from the index, returning its entry
+    pub(crate) fn remove(&mut self, key: &RecordKey) -> Result<Option<RequestMetadata>> {
+        if !self.contains(key) {
+            return Ok(None);
+        }
+        self.log(WalOp::Delete {
+            namespace: key.namespace.clone(),
+            request_id: key.request_id.clone(),
+        })?;
+        let metadata = self.unlink(key).unwrap();
+        self.notify(ChangeKind::Deleted, key.clone(), metadata.clone());
+        Ok(Some(metadata))
+    }
+
+    // Remove a record with its prior versions and release the blocks only
+    // they occupied, returning the number of bytes freed
+    pub(crate) fn remove_and_release(&mut self, key: &RecordKey) -> Result<Option<u64>> {
+        let Some(metadata) = self.remove(key)? else {
+            return Ok(None);
+        };
+        let freed = self.drop_versions(key)?;
+        Ok(Some(freed + self.release_exclusive(&metadata)))
+    }
+
+    // Delete a record on behalf of a client: into the trash if a trash
+    // retention is configured, otherwise for good. Returns the number of bytes
+    // freed; the trashed record's own blocks are not freed until it is purged.
+    pub(crate) fn delete(&mut self, key: &RecordKey, now: SystemTime) -> Result<Option<u64>> {
+        if self.trash_retention.is_none() {
+            return self.remove_and_release(key);
+        }
+        let metadata = self.request_map.get(key);
+        let Some(metadata) = metadata else {
+            return Ok(None);
+        };
+        let trashed = TrashedRecord { metadata: metadata.clone(), deleted_at: now };
+        self.log(WalOp::Trash { trashed: PersistedTrash::new(key.clone(), trashed.clone()) })?;
+        self.unlink(key);
+        // A record deleted again replaces its older trashed copy
+        let mut freed = 0;
+        if let Some(older) = self.trash.insert(key.clone(), trashed) {
+            freed += self.release_exclusive(&older.metadata);
+        }
+        freed += self.drop_versions(key)?;
+        self.notify(ChangeKind::Deleted, key.clone(), metadata);
+        Ok(Some(freed))
+    }
+
+    // Put a trashed record back under its request ID, returning its entry.
+    // The caller makes sure the request ID is free.
+    pub(crate) fn undelete(&mut self, key: &RecordKey) -> Result<Option<RequestMetadata>> {
+        if !self.trash.contains_key(key) {
+            return Ok(None);
+        }
+        self.log(WalOp::Undelete {
+            namespace: key.namespace.clone(),
+            request_id: key.request_id.clone(),
+        })?;
+        let metadata = self.trash.remove(key).unwrap().metadata;
+        let replaced = self.request_map.insert(key.clone(), metadata.clone());
+        self.remember(key);
+        // Only an expired entry can still hold the request ID
+        self.drop_versions(key)?;
+        self.account_insert(&key.namespace, &metadata, replaced);
+        self.notify(ChangeKind::Written, key.clone(), metadata.clone());
+        Ok(Some(metadata))
+    }
+
+    // Permanently remove a record from the trash, returning the bytes freed
+    fn purge(&mut self, key: &RecordKey) -> Result<u64> {
+        if !self.trash.contains_key(key) {
+            return Ok(0);
+        }
+        self.log(WalOp::Purge {
+            namespace: key.namespace.clone(),
+            request_id: key.request_id.clone(),
+        })?;
+        let trashed = self.trash.remove(key).unwrap();
+        Ok(self.release_exclusive(&trashed.metadata))
+    }
+
+    // Purge every record deleted longer ago than the trash retention,
+    // returning how many were purged
+    pub(crate) fn purge_trash(&mut self, now: SystemTime) -> Result<usize> {
+        let Some(retention) = self.trash_retention else {
+            return Ok(0);
+        };
+        let due: Vec<RecordKey> = self
+            .trash
+            .iter()
+            .filter(|(_, trashed)| now.duration_since(trashed.deleted_at).is_ok_and(|age| age >= retention))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &due {
+            self.purge(key)?;
+        }
+        Ok(due.len())
+    }
+
+    // Prior versions and trashed records: no longer live, but still holding
+    // their blocks
+    fn retained(&self) -> impl Iterator<Item = (&RecordKey, &RequestMetadata)> {
+        self.versions
+            .iter()
+            .flat_map(|(key, history)| history.iter().map(move |version| (key, version)))
+            .chain(self.trash.iter().map(|(key, trashed)| (key, &trashed.metadata)))
+    }
+
+    // Keep the entry a write replaced as a prior version of the record if the
+    // version policy asks for it, then prune the record's versions. Returns
+    // the entry if it is not kept, for the caller to release. A record written
+    // anew, e.g. over an expired entry, starts without prior versions.
+    fn keep_version(&mut self, key: &RecordKey, replaced: Option<RequestMetadata>, now: SystemTime) -> Result<Option<RequestMetadata>> {
+        let previous = match replaced {
+            Some(previous) if !previous.is_expired(now) => previous,
+            replaced => {
+                self.drop_versions(key)?;
+                return Ok(replaced);
+            }
+        };
+        if !self.version_policy.is_enabled() {
+            return Ok(Some(previous));
+        }
+
+        self.log(WalOp::Version { record: PersistedRecord::new(key.clone(), previous.clone()) })?;
+        // Prior versions do not count towards namespace usage
+        self.release_usage(&key.namespace, previous.size);
+        self.versions.entry(key.clone()).or_default().push(previous);
+        self.prune_versions(key, now)?;
+        Ok(None)
+    }
+
+    // Drop the prior versions of a record the version policy no longer
+    // keeps, returning how many were dropped
+    fn prune_versions(&mut self, key: &RecordKey, now: SystemTime) -> Result<usize> {
+        let Some(history) = self.versions.get(key) else {
+            return Ok(0);
+        };
+        // Each version was superseded when the next one was written
+        let current = self.request_map.get(key).map(|metadata| metadata.written_at);
+        let superseded: Vec<SystemTime> = history.iter().skip(1).map(|version| version.written_at).chain(current).collect();
+        let excess = self.version_policy.excess(&superseded, now);
+        if excess == 0 {
+            return Ok(0);
+        }
+
+        let generations = history[..excess].iter().map(|version| version.generation).collect();
+        self.log_dropped_versions(key, generations)?;
+        let history = self.versions.get_mut(key).unwrap();
+        let dropped: Vec<RequestMetadata> = history.drain(..excess).collect();
+        if history.is_empty() {
+            self.versions.remove(key);
+        }
+        self.release_versions(dropped);
+        Ok(excess)
+    }
+
+    // Apply the version policy to every record, e.g. once versions have aged
+    // out. Returns how many versions were dropped.
+    pub(crate) fn prune_all_versions(&mut self, now: SystemTime) -> Result<usize> {
+        let keys: Vec<RecordKey> = self.versions.keys().cloned().collect();
+        let mut pruned = 0;
+        for key in &keys {
+            pruned += self.prune_versions(key, now)?;
+        }
+        Ok(pruned)
+    }
+
+    // Drop every prior version of a record, returning the bytes freed
+    fn drop_versions(&mut self, key: &RecordKey) -> Result<u64> {
+        let Some(history) = self.versions.get(key) else {
+            return Ok(0);
+        };
+        let generations = history.iter().map(|version| version.generation).collect();
+        self.log_dropped_versions(key, generations)?;
+        let history = self.versions.remove(key).unwrap();
+        Ok(self.release_versions(history))
+    }
+
+    fn log_dropped_versions(&mut self, key: &RecordKey, generations: Vec<u64>) -> Result<()> {
+        self.log(WalOp::DropVersions {
+            namespace: key.namespace.clone(),
+            request_id: key.request_id.clone(),
+            generations,
+        })
+    }
+
+    // Release the blocks only dropped versions occupied, returning the bytes
+    // freed
+    fn release_versions(&mut self, dropped: Vec<RequestMetadata>) -> u64 {
+        let mut freed = 0;
+        for version in &dropped {
+            freed += self.release_exclusive(version);
+        }
+        freed
+    }
+
+    // Request IDs in a namespace starting with `prefix`, in order
+    pub(crate) fn keys_with_prefix(&self, namespace: &str, prefix: &str) -> Vec<RecordKey> {
+        self.request_map
+            .read()
+            .range(namespace, Bound::Included(prefix))
+            .take_while(|(request_id, _)| request_id.starts_with(prefix))
+            .map(|(request_id, _)| RecordKey {
+                namespace: namespace.to_string(),
+                request_id,
+            })
+            .collect()
+    }
+
+    // Remove every record whose TTL has passed, returning how many were removed
+    pub(crate) fn sweep_expired(&mut self, now: SystemTime) -> Result<usize> {
+        let expired: Vec<RecordKey> = self.request_map.read().iter().filter(|(_, metadata)| metadata.is_expired(now)).map(|(key, _)| key).collect();
+
+        for key in &expired {
+            self.remove_and_release(key)?;
+        }
+        self.locks.retain(|_, lock| lock.expires_at > now);
+        Ok(expired.len())
+    }
+
+    // Every live entry of the index
+    pub(crate) fn entries(&self, now: SystemTime) -> Vec<(RecordKey, RequestMetadata)> {
+        self.request_map.read().iter().filter(|(_, metadata)| !metadata.is_expired(now)).collect()
+    }
+
+    // The request map, prior versions and trash as they are once `moves` are
+    // applied, for the compacted copy of the data file the moves point into.
+    // As in `relocate`, each move applies only to the entry still where and
+    // as it was when copied. Returns them with how many entries moved.
+    fn relocated_contents(&self, moves: &[(RecordKey, RequestMetadata, u64)]) -> (IndexContents, usize) {
+        let targets: HashMap<(&RecordKey, u64, u64), u64> = moves
+            .iter()
+            .map(|(key, copied, offset)| ((key, copied.offset, copied.generation), *offset))
+            .collect();
+        let mut moved = 0;
+        let mut relocate = |key: &RecordKey, metadata: &mut RequestMetadata| {
+            if let Some(&offset) = targets.get(&(key, metadata.offset, metadata.generation)) {
+                metadata.offset = offset;
+                moved += 1;
+            }
+        };
+        let mut contents = IndexContents {
+            entries: self.request_map.read().iter().collect(),
+            versions: self.version_entries(),
+            trash: self.trash.iter().map(|(key, trashed)| (key.clone(), trashed.clone())).collect(),
+        };
+        for (key, metadata) in contents.entries.iter_mut().chain(contents.versions.iter_mut()) {
+            relocate(key, metadata);
+        }
+        for (key, trashed) in contents.trash.iter_mut() {
+            relocate(key, &mut trashed.metadata);
+        }
+        (contents, moved)
+    }
+
+    // Every prior version kept, oldest first per record
+    fn version_entries(&self) -> Vec<(RecordKey, RequestMetadata)> {
+        self.versions
+            .iter()
+            .flat_map(|(key, history)| history.iter().map(move |version| (key.clone(), version.clone())))
+            .collect()
+    }
+
+    // Every prior version and trashed record, e.g. for compaction to move
+    pub(crate) fn retained_entries(&self) -> Vec<(RecordKey, RequestMetadata)> {
+        self.retained().map(|(key, metadata)| (key.clone(), metadata.clone())).collect()
+    }
+
+    // Number of records across all namespaces
+    pub(crate) fn record_count(&self) -> usize {
+        self.request_map.len()
+    }
+
+    // Number of namespaces holding at least one record
+    pub(crate) fn namespace_count(&self) -> usize {
+        self.request_map.namespace_count()
+    }
+
+    // Bytes of live record data stored under a namespace
+    pub(crate) fn namespace_usage(&self, namespace: &str) -> u64 {
+        self.usage.get(namespace).copied().unwrap_or(0)
+    }
+
+    // Reject a write that would add `additional` bytes to a namespace already
+    // at or near its quota
+    pub(crate) fn check_quota(&self, namespace: &str, additional: u64, quota: u64) -> Result<(), QuotaExceeded> {
+        let usage = self.namespace_usage(namespace);
+        if usage + additional > quota {
+            return Err(QuotaExceeded {
+                namespace: namespace.to_string(),
+                usage,
+                quota,
+                requested: additional,
+            });
+        }
+        Ok(())
+    }
+
+    // Update usage for a new index entry and release the entry it replaced,
+    // unless the new data was written over the replaced version's blocks
+    fn account_insert(&mut self, namespace: &str, metadata: &RequestMetadata, replaced: Option<RequestMetadata>) {
+        *self.usage.entry(namespace.to_string()).or_default() += metadata.size;
+        if let Some(previous) = replaced {
+            self.release_usage(namespace, previous.size);
+            if !metadata.extents().iter().any(|extent| previous.overlaps(extent)) {
+                self.release_exclusive(&previous);
+            }
+        }
+    }
+
+    fn release_usage(&mut self, namespace: &str, size: u64) {
+        if let Some(usage) = self.usage.get_mut(namespace) {
+            *usage = usage.saturating_sub(size);
+            if *usage == 0 {
+                self.usage.remove(namespace);
+            }
+        }
+    }
+
+    // Issue a new fencing token for a namespace, fencing off every holder of an
+    // older one. Tokens are seeded from the clock so they keep increasing
+    // across restarts.
+    pub(crate) fn acquire_fence(&mut self, namespace: &str, now: SystemTime) -> u64 {
+        let seed = now.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let token = self.fencing_tokens.entry(namespace.to_string()).or_default();
+        *token = (*token + 1).max(seed);
+        *token
+    }
+
+    // Check a write's fencing token. Writes without a token, and writes to
+    // namespaces nobody has taken a lease on, are not fenced.
+    pub(crate) fn check_fence(&self, namespace: &str, token: Option<u64>) -> Result<(), StaleFencingToken> {
+        match (token, self.fencing_tokens.get(namespace)) {
+            (Some(token), Some(&current)) if token != current => Err(StaleFencingToken {
+                namespace: namespace.to_string(),
+                token,
+                current,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Live lock on a record, if any
+    fn held_lock(&self, key: &RecordKey, now: SystemTime) -> Option<&RecordLock> {
+        self.locks.get(key).filter(|lock| lock.expires_at > now)
+    }
+
+    // Take the lock on a record, or renew it when `lock_id` names the lock
+    // currently held. The record does not need to exist.
+    pub(crate) fn lock(&mut self, key: &RecordKey, lock_id: Option<&str>, ttl: Duration, now: SystemTime) -> Result<RecordLock, RecordLocked> {
+        if let Some(held) = self.held_lock(key, now) {
+            if lock_id != Some(held.lock_id.as_str()) {
+                return Err(RecordLocked {
+                    request_id: key.request_id.clone(),
+                    expires_at: held.expires_at,
+                });
+            }
+        }
+
+        let lock = RecordLock {
+            lock_id: lock_id.map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string),
+            expires_at: now + ttl,
+        };
+        self.locks.insert(key.clone(), lock.clone());
+        Ok(lock)
+    }
+
+    // Release a lock. Returns whether a live lock was released.
+    pub(crate) fn unlock(&mut self, key: &RecordKey, lock_id: &str, now: SystemTime) -> Result<bool, RecordLocked> {
+        match self.held_lock(key, now) {
+            Some(held) if held.lock_id != lock_id => Err(RecordLocked {
+                request_id: key.request_id.clone(),
+                expires_at: held.expires_at,
+            }),
+            Some(_) => {
+                self.locks.remove(key);
+                Ok(true)
+            }
+            None => {
+                self.locks.remove(key);
+                Ok(false)
+            }
+        }
+    }
+
+    // Check that a change to a record is allowed under its lock, if it has one
+    pub(crate) fn check_lock(&self, key: &RecordKey, lock_id: &str) -> Result<(), RecordLocked> {
+        match self.held_lock(key, SystemTime::now()) {
+            Some(held) if held.lock_id != lock_id => Err(RecordLocked {
+                request_id: key.request_id.clone(),
+                expires_at: held.expires_at,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Current generation of a record, 0 if it does not exist
+    pub(crate) fn current_generation(&self, key: &RecordKey) -> u64 {
+        self.lookup(key).map_or(0, |metadata| metadata.generation)
+    }
+
+    // Index a completed write, enforcing the caller's expected generation.
+    // A replaced version is kept as a prior version if the version policy
+    // asks for it; otherwise its blocks are released unless the new data was
+    // written over them. Returns the generation assigned to the new entry.
+    pub(crate) fn commit_write(&mut self, key: &RecordKey, expected_generation: Option<u64>, mut metadata: RequestMetadata) -> Result<u64> {
+        let current = self
+            .request_map
+            .get(key)
+            .filter(|existing| !existing.is_expired(metadata.written_at))
+            .map_or(0, |existing| existing.generation);
+        if let Some(expected) = expected_generation {
+            if expected != current {
+                return Err(GenerationMismatch {
+                    request_id: key.request_id.clone(),
+                    expected,
+                    actual: current,
+                }
+                .into());
+            }
+        }
+
+        metadata.generation = current + 1;
+        let generation = metadata.generation;
+        self.log(WalOp::Put { record: PersistedRecord::new(key.clone(), metadata.clone()) })?;
+        let replaced = self.request_map.insert(key.clone(), metadata.clone());
+        self.remember(key);
+        let replaced = self.keep_version(key, replaced, metadata.written_at)?;
+        self.account_insert(&key.namespace, &metadata, replaced);
+        self.epochs.mark_dirty();
+
+        let kind = if current == 0 { ChangeKind::Written } else { ChangeKind::Overwritten };
+        self.notify(kind, key.clone(), metadata);
+        Ok(generation)
+    }
+
+    // Point records, prior versions and trashed records at copies made by
+    // compaction. Each move applies only if the entry is still where and as it
+    // was when copied; the rest are skipped. Moves are not changes to the
+    // records, so watchers are not notified. Returns how many entries moved.
+    pub(crate) fn relocate(&mut self, moves: Vec<(RecordKey, RequestMetadata, u64)>) -> Result<usize> {
+        let mut records = Vec::new();
+        let mut versions = Vec::new();
+        let mut trashed = Vec::new();
+        for (key, copied, offset) in moves {
+            let unchanged = |metadata: &RequestMetadata| metadata.offset == copied.offset && metadata.generation == copied.generation;
+            if let Some(mut metadata) = self.request_map.get(&key).filter(|metadata| unchanged(metadata)) {
+                metadata.move_to(offset);
+                self.request_map.insert(key.clone(), metadata.clone());
+                records.push(PersistedRecord::new(key, metadata));
+                continue;
+            }
+            let version = self.versions.get_mut(&key).and_then(|history| history.iter_mut().find(|version| unchanged(version)));
+            if let Some(version) = version {
+                version.move_to(offset);
+                versions.push(PersistedRecord::new(key, version.clone()));
+                continue;
+            }
+            if let Some(entry) = self.trash.get_mut(&key).filter(|entry| unchanged(&entry.metadata)) {
+                entry.metadata.move_to(offset);
+                trashed.push(PersistedTrash::new(key, entry.clone()));
+            }
+        }
+        let moved = records.len() + versions.len() + trashed.len();
+        if !records.is_empty() {
+            self.log(WalOp::PutMany { records })?;
+        }
+        for record in versions {
+            self.log(WalOp::Version { record })?;
+        }
+        for trashed in trashed {
+            self.log(WalOp::Trash { trashed })?;
+        }
+        Ok(moved)
+    }
+
+    // Index several completed writes as one unit. Every expected generation is
+    // checked before anything changes, and all entries are inserted under a
+    // lock of every shard of the request map, so readers see either none of
+    // them or all of them. The caller makes sure no request ID appears twice.
+    pub(crate) fn commit_batch(&mut self, writes: Vec<(RecordKey, Option<u64>, RequestMetadata)>) -> Result<Vec<u64>> {
+        let mut staged = Vec::with_capacity(writes.len());
+        for (key, expected_generation, mut metadata) in writes {
+            let current = self
+                .request_map
+                .get(&key)
+                .filter(|existing| !existing.is_expired(metadata.written_at))
+                .map_or(0, |existing| existing.generation);
+            if let Some(expected) = expected_generation {
+                if expected != current {
+                    return Err(GenerationMismatch {
+                        request_id: key.request_id,
+                        expected,
+                        actual: current,
+                    }
+                    .into());
+                }
+            }
+            metadata.generation = current + 1;
+            staged.push((key, metadata));
+        }
+
+        // One log entry for the whole batch keeps it atomic across a crash
+        let records = staged.iter().map(|(key, metadata)| PersistedRecord::new(key.clone(), metadata.clone())).collect();
+        self.log(WalOp::PutMany { records })?;
+        let mut applied = Vec::with_capacity(staged.len());
+        {
+            let mut request_map = self.request_map.write();
+            for (key, metadata) in staged {
+                let replaced = request_map.insert(key.clone(), metadata.clone());
+                applied.push((key, metadata, replaced));
+            }
+        }
+        self.epochs.mark_dirty();
+
+        let mut generations = Vec::with_capacity(applied.len());
+        for (key, metadata, replaced) in applied {
+            generations.push(metadata.generation);
+            self.remember(&key);
+            let replaced = self.keep_version(&key, replaced, metadata.written_at)?;
+            self.account_insert(&key.namespace, &metadata, replaced);
+            let kind = if metadata.generation == 1 { ChangeKind::Written } else { ChangeKind::Overwritten };
+            self.notify(kind, key, metadata);
+        }
+        Ok(generations)
+    }
+
+    // Re-key a record within its namespace without touching its data. The
+    // caller checks that the new request ID is free.
+    pub(crate) fn rename(&mut self, key: &RecordKey, new_request_id: &str) -> Result<Option<RequestMetadata>> {
+        if !self.contains(key) {
+            return Ok(None);
+        }
+        let new_key = RecordKey {
+            namespace: key.namespace.clone(),
+            request_id: new_request_id.to_string(),
+        };
+        // Only an expired entry can still hold the new ID
+        self.drop_versions(&new_key)?;
+        self.log(WalOp::Rename {
+            namespace: key.namespace.clone(),
+            request_id: key.request_id.clone(),
+            new_request_id: new_request_id.to_string(),
+        })?;
+        let (metadata, replaced) = {
+            let mut request_map = self.request_map.write();
+            let metadata = request_map.remove(key).unwrap();
+            let replaced = request_map.insert(new_key.clone(), metadata.clone());
+            (metadata, replaced)
+        };
+        self.remember(&new_key);
+        if let Some(previous) = replaced {
+            self.release_usage(&key.namespace, previous.size);
+            self.release_exclusive(&previous);
+        }
+        // Prior versions follow the record
+        if let Some(history) = self.versions.remove(key) {
+            self.versions.insert(new_key.clone(), history);
+        }
+
+        self.notify(ChangeKind::Deleted, key.clone(), metadata.clone());
+        self.notify(ChangeKind::Written, new_key, metadata.clone());
+        Ok(Some(metadata))
+    }
+
+    // Add a second request ID referencing a record's extent. The blocks stay
+    // allocated until every ID referencing them is gone. The caller checks
+    // that the alias is free.
+    pub(crate) fn alias(&mut self, key: &RecordKey, alias_id: &str) -> Result<Option<RequestMetadata>> {
+        let Some(mut metadata) = self.lookup(key) else {
+            return Ok(None);
+        };
+        metadata.generation = 1;
+
+        let alias_key = RecordKey {
+            namespace: key.namespace.clone(),
+            request_id: alias_id.to_string(),
+        };
+        // An alias starts a new history at generation 1
+        self.drop_versions(&alias_key)?;
+        self.log(WalOp::Put { record: PersistedRecord::new(alias_key.clone(), metadata.clone()) })?;
+        let replaced = self.request_map.insert(alias_key.clone(), metadata.clone());
+        self.remember(&alias_key);
+        self.account_insert(&key.namespace, &metadata, replaced);
+
+        self.notify(ChangeKind::Written, alias_key, metadata.clone());
+        Ok(Some(metadata))
+    }
+
+    // Number of index entries, across all namespaces, referencing the record
+    // stored at `offset`
+    pub(crate) fn reference_count(&self, offset: u64) -> usize {
+        self.request_map.read().iter().filter(|(_, metadata)| metadata.offset == offset).count()
+    }
+
+    // Shrink the data file to `offset`, dropping every record that extends
+    // past it. Returns the request IDs that were invalidated.
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<Vec<String>> {
+        if !offset.is_multiple_of(sector_size()) {
+            anyhow::bail!("Truncate offset {} is not aligned to {} bytes", offset, sector_size());
+        }
+        // Hold off new reservations so none lands past the truncation point
+        let space = self.space.clone();
+        let _frozen = space.freeze();
+        if offset > space.end() {
+            anyhow::bail!("Truncate offset {} is beyond the end of the file ({})", offset, space.end());
+        }
+        if let Some(pending) = space.in_flight().iter().find(|pending| pending.end() > offset) {
+            anyhow::bail!("A write is in flight at offset {} beyond the truncation point", pending.offset);
+        }
+
+        self.file.set_len(offset)?;
+        // Shrinking the file gives up the blocks preallocated past its end
+        if let Some(length) = self.preallocate {
+            self.file.preallocate(length)?;
+        }
+
+        let mut removed = Vec::new();
+        self.request_map.retain(&mut |key, metadata| {
+            let keep = metadata.data_end() <= offset;
+            if !keep {
+                removed.push((key.clone(), metadata.clone()));
+            }
+            keep
+        });
+        for (key, metadata) in &removed {
+            self.release_usage(&key.namespace, metadata.size);
+            self.drop_versions(key)?;
+        }
+        // Prior versions past the truncation point are gone with the data
+        for history in self.versions.values_mut() {
+            history.retain(|version| version.data_end() <= offset);
+        }
+        self.versions.retain(|_, history| !history.is_empty());
+        self.trash.retain(|_, trashed| trashed.metadata.data_end() <= offset);
+        let removed: Vec<String> = removed
+            .into_iter()
+            .map(|(key, metadata)| {
+                let request_id = key.request_id.clone();
+                self.notify(ChangeKind::Deleted, key, metadata);
+                request_id
+            })
+            .collect();
+        self.checkpoint();
+
+        // Free space past the new end of file no longer exists
+        space.carve(Extent { offset, length: u64::MAX - offset });
+        space.set_end(offset);
+
+        info!("Truncated data file to {} bytes, invalidated {} records", offset, removed.len());
+        Ok(removed)
+    }
+
+    // Mark an extent as free so later writes or a compaction can reuse it
+    pub(crate) fn release_extent(&mut self, extent: Extent) {
+        info!("Released extent at offset {} ({} bytes)", extent.offset, extent.length);
+        self.space.release(extent);
+    }
+
+    // Take a segment that no record or in-flight write overlaps off the free
+    // list, so no new write lands in it while it is being retired, and return
+    // its extent. Releasing the extent afterwards makes it free space again.
+    pub(crate) fn claim_segment(&mut self, index: u64, segment_size: u64) -> Option<Extent> {
+        let range = Extent {
+            offset: index * segment_size,
+            length: segment_size,
+        };
+        let space = self.space.clone();
+        let _frozen = space.freeze();
+        // Expired records not yet swept still own their extents
+        let busy = space.in_flight().iter().any(|pending| pending.overlaps(&range))
+            || self.entries(UNIX_EPOCH).iter().any(|(_, metadata)| metadata.overlaps(&range))
+            || self.retained().any(|(_, metadata)| metadata.overlaps(&range));
+        if busy {
+            return None;
+        }
+
+        space.carve(range);
+        Some(range)
+    }
+
+    // Blocks covering a removed record that no remaining record shares. Packed
+    // records can share a boundary block, which must stay allocated until all
+    // of its records are gone.
+    pub(crate) fn exclusive_extents(&self, metadata: &RequestMetadata) -> Vec<Extent> {
+        // Aliases of the record still reference all of its blocks, as does a
+        // trashed alias or a prior version left in place by a crash between
+        // log entries
+        if self.reference_count(metadata.offset) > 0 || self.retained().any(|(_, other)| other.offset == metadata.offset) {
+            return Vec::new();
+        }
+
+        let request_map = self.request_map.read();
+        let is_shared = |block: Extent| {
+            request_map.iter().any(|(_, other)| other.overlaps(&block)) || self.retained().any(|(_, other)| other.overlaps(&block))
+        };
+        let mut exclusive = Vec::new();
+        let sector = sector_size();
+        for covering in metadata.extents() {
+            let mut start = covering.offset;
+            let mut end = covering.end();
+            if is_shared(Extent { offset: start, length: sector }) {
+                start += sector;
+            }
+            if end > start && is_shared(Extent { offset: end - sector, length: sector }) {
+                end -= sector;
+            }
+            if end > start {
+                exclusive.push(Extent { offset: start, length: end - start });
+            }
+        }
+        exclusive
+    }
+
+    // Release the blocks only a removed record occupied, returning how many
+    // bytes were freed
+    fn release_exclusive(&mut self, metadata: &RequestMetadata) -> u64 {
+        let mut freed = 0;
+        for extent in self.exclusive_extents(metadata) {
+            self.release_extent(extent);
+            freed += extent.length;
+        }
+        freed
+    }
+
+    // Recompute the free extent list and per-namespace usage from the live
+    // records, prior versions and trashed records, dropping expired records
+    // first. Returns how many records expired.
+    pub(crate) fn rebuild_free_extents(&mut self, now: SystemTime) -> Result<usize> {
+        let space = self.space.clone();
+        let _frozen = space.freeze();
+        if let Some(pending) = space.in_flight().first() {
+            anyhow::bail!("A write is in flight at offset {}", pending.offset);
+        }
+        let expired = self.sweep_expired(now)?;
+        self.rebuild_free_space();
+        info!("Rebuilt free space for {}: {} extents, {} bytes", self.file_path, space.free_extent_count(), space.reclaimable_bytes());
+        Ok(expired)
+    }
+
+    // Recompute the free extent list and per-namespace usage from the live
+    // records and in-flight writes
+    fn rebuild_free_space(&mut self) {
+        let space = self.space.clone();
+        let _frozen = space.freeze();
+        let mut extents: Vec<Extent> = {
+            let mut usage = HashMap::new();
+            let mut extents = Vec::new();
+            for (key, metadata) in self.request_map.read().iter() {
+                *usage.entry(key.namespace).or_insert(0) += metadata.size;
+                extents.extend(metadata.extents());
+            }
+            self.usage = usage;
+            extents.extend(self.retained().flat_map(|(_, metadata)| metadata.extents()));
+            extents.extend(space.in_flight());
+            extents
+        };
+        extents.sort_by_key(|extent| extent.offset);
+
+        let mut free_extents = Vec::new();
+        let mut cursor = 0;
+        for extent in extents {
+            if extent.offset > cursor {
+                free_extents.push(Extent { offset: cursor, length: extent.offset - cursor });
+            }
+            cursor = cursor.max(extent.end());
+        }
+        if space.end() > cursor {
+            free_extents.push(Extent { offset: cursor, length: space.end() - cursor });
+        }
+        space.set_free(free_extents, false);
+    }
+
+    // Swap in a different data file and index wholesale, e.g. from a snapshot.
+    // The caller makes sure no write is in flight. Session tokens issued
+    // before the swap no longer apply, and prior versions and the trash are
+    // dropped.
+    // Returns how many records had expired.
+    pub(crate) fn replace(&mut self, file: Arc<dyn FileIO + Send + Sync>, file_size: u64, entries: Vec<(RecordKey, RequestMetadata)>, now: SystemTime) -> Result<usize> {
+        self.swap_file(file, |manager| {
+            let mut request_map = manager.request_map.write();
+            request_map.clear();
+            for (key, metadata) in entries {
+                request_map.insert(key, metadata);
+            }
+        });
+        self.versions.clear();
+        self.trash.clear();
+        self.space.set_end(file_size);
+        self.locks.clear();
+        self.space.set_free(Vec::new(), true);
+        // Retained changes describe the replaced index; feeds must resync
+        self.changes.clear();
+        self.epoch = uuid::Uuid::new_v4().simple().to_string();
+        self.sequence.send_modify(|sequence| *sequence += 1);
+        self.checkpoint();
+        self.rebuild_free_extents(now)
+    }
+
+    // Swap in a copy of the data file holding the records in `moves`, made
+    // while writes were held off, and point the index at it. Entries removed
+    // since the copy was made, by expiry say, are skipped and their copies
+    // left as free space. Returns how many entries moved.
+    pub(crate) fn adopt(&mut self, file: Arc<dyn FileIO + Send + Sync>, file_size: u64, moves: Vec<(RecordKey, RequestMetadata, u64)>, now: SystemTime) -> Result<usize> {
+        let moved = self.swap_file(file, |manager| manager.relocate(moves))?;
+        self.space.set_end(file_size);
+        // Reads still in progress use the old file
+        self.space.set_free(Vec::new(), true);
+        self.rebuild_free_extents(now)?;
+        self.checkpoint();
+        Ok(moved)
+    }
+
+    // Total bytes held by released extents
+    pub(crate) fn reclaimable_bytes(&self) -> u64 {
+        self.space.reclaimable_bytes()
+    }
+}
+
+// Outcome of a compaction pass
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CompactionStats {
+    pub(crate) records_moved: u64,
+    pub(crate) reclaimed_bytes: u64,
+    pub(crate) file_size: u64,
+}
+
+// Wait until every change logged to a file's index so far is on disk, so
+// changes are acknowledged only once they are durable. The write and sync run
+// outside the file manager's lock, shared with every change logged meanwhile.
+// If they fail, the index is rolled back to what reached the disk.
+pub(crate) async fn sync_log(manager: &Mutex<FileManager>) -> Result<()> {
+    let commit = manager.lock().unwrap().log_commit();
+    let synced = commit.wait().await;
+    if synced.is_err() {
+        let mut file_manager = manager.lock().unwrap();
+        if let Err(e) = file_manager.roll_back_log() {
+            error!("Failed to roll back the index of {}: {}", file_manager.file_path, e);
+        }
+    }
+    synced
+}
+
+// Reclaim the dead space of a data file. A single data file has all live
+// records rewritten contiguously into a new file that is atomically swapped
+// in; a segmented one has its sparse segments emptied and deleted. Fails if
+// the file is already being compacted.
+pub(crate) async fn compact(manager: &Mutex<FileManager>) -> Result<CompactionStats> {
+    let segment_size = {
+        let mut file_manager = manager.lock().unwrap();
+        if file_manager.compacting {
+            anyhow::bail!("{} is already being compacted or migrated", file_manager.file_path);
+        }
+        if let Some(kind) = file_manager.file.storage_kind() {
+            anyhow::bail!("Compacting {} is not supported on {}", file_manager.file_path, kind);
+        }
+        file_manager.compacting = true;
+        file_manager.file.segment_size()
+    };
+    // Copying is background work, kept out of the way of client I/O
+    let result = with_priority(IoPriority::Idle, async {
+        match segment_size {
+            Some(segment_size) => compact_segments(manager, segment_size).await,
+            None => copy_live_records(manager).await,
+        }
+    })
+    .await;
+    manager.lock().unwrap().compacting = false;
+    result
+}
+
+// Group records whose extents share blocks; each group is copied as one unit
+fn clusters(mut records: Vec<(RecordKey, RequestMetadata)>) -> Vec<(Extent, Vec<(RecordKey, RequestMetadata)>)> {
+    records.sort_by_key(|(_, metadata)| metadata.offset);
+    let mut clusters: Vec<(Extent, Vec<(RecordKey, RequestMetadata)>)> = Vec::new();
+    for (key, metadata) in records {
+        let extent = metadata.extent();
+        match clusters.last_mut() {
+            Some((cluster, members)) if extent.overlaps(cluster) => {
+                cluster.length = extent.end().max(cluster.end()) - cluster.offset;
+                members.push((key, metadata));
+            }
+            _ => clusters.push((extent, vec![(key, metadata)])),
+        }
+    }
+    clusters
+}
+
+// Empty every sealed segment (any before the one being appended to) that is
+// at least half free: its live records are copied to the end of the file and
+// repointed, then the segment file is deleted. Records are moved one cluster
+// at a time while writes continue; a record changed during its copy keeps its
+// old location and its segment is kept.
+async fn compact_segments(manager: &Mutex<FileManager>, segment_size: u64) -> Result<CompactionStats> {
+    let (segments, file, space, file_path) = {
+        let file_manager = manager.lock().unwrap();
+        let active = file_manager.space.end() / segment_size;
+        let mut segments: BTreeMap<u64, Vec<(RecordKey, RequestMetadata)>> = (0..active).map(|index| (index, Vec::new())).collect();
+        let mut spanning = Vec::new();
+        // Expired records not yet swept still own their extents
+        for (key, metadata) in file_manager.entries(UNIX_EPOCH).into_iter().chain(file_manager.retained_entries()) {
+            if !metadata.chunks.is_empty() {
+                for extent in metadata.extents() {
+                    spanning.extend(extent.offset / segment_size..=(extent.end() - 1) / segment_size);
+                }
+                continue;
+            }
+            let extent = metadata.extent();
+            let first = extent.offset / segment_size;
+            let last = (extent.end() - 1) / segment_size;
+            if first != last {
+                spanning.extend(first..=last);
+            } else if let Some(records) = segments.get_mut(&first) {
+                records.push((key, metadata));
+            }
+        }
+        // Segments holding part of a record larger than a segment, or of a
+        // large object split across extents, stay put
+        segments.retain(|index, records| {
+            let live: u64 = records.iter().map(|(_, metadata)| metadata.extent().length).sum();
+            !spanning.contains(index) && live * 2 <= segment_size
+        });
+        (segments, file_manager.file.clone(), file_manager.space.clone(), file_manager.file_path.clone())
+    };
+
+    let mut records_moved = 0;
+    let mut moved_bytes = 0;
+    let mut removed = 0;
+    for (index, records) in segments {
+        let mut emptied = true;
+        for (cluster, members) in clusters(records) {
+            let target = space.reserve_append(cluster.length);
+            let copied = async {
+                let data = file.read_at(cluster.length, cluster.offset).await?;
+                file.write_at(data.into(), target.offset).await?;
+                // The new copy must be durable before the index points at it
+                for target_segment in target.offset / segment_size..=(target.end() - 1) / segment_size {
+                    std::fs::File::open(segment::segment_path(&file_path, target_segment))?.sync_all()?;
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+            .await;
+
+            let mut file_manager = manager.lock().unwrap();
+            space.finish(target.offset);
+            if let Err(e) = copied {
+                file_manager.release_extent(target);
+                return Err(e);
+            }
+            let moves = members
+                .into_iter()
+                .map(|(key, metadata)| {
+                    let offset = metadata.offset - cluster.offset + target.offset;
+                    (key, metadata, offset)
+                })
+                .collect::<Vec<_>>();
+            let expected = moves.len();
+            let moved = file_manager.relocate(moves)?;
+            if moved == 0 {
+                file_manager.release_extent(target);
+            } else {
+                moved_bytes += target.length;
+            }
+            records_moved += moved as u64;
+            emptied &= moved == expected;
+        }
+
+        let mut file_manager = manager.lock().unwrap();
+        let claimed = if emptied { file_manager.claim_segment(index, segment_size) } else { None };
+        let Some(range) = claimed else {
+            info!("Keeping segment {} of {}: it changed while being compacted", index, file_path);
+            continue;
+        };
+
+        // The whole segment is now free space; a later write there recreates it
+        file_manager.release_extent(range);
+        if file_manager.file.remove_segment(index)? {
+            removed += 1;
+        }
+    }
+
+    let mut file_manager = manager.lock().unwrap();
+    file_manager.checkpoint();
+    let stats = CompactionStats {
+        records_moved,
+        reclaimed_bytes: (removed * segment_size).saturating_sub(moved_bytes),
+        file_size: space.end(),
+    };
+    info!("Compacted {}: deleted {} segments, moved {} records", file_path, removed, stats.records_moved);
+    Ok(stats)
+}
+
+// Copy records contiguously to the start of `target`, one cluster of records
+// sharing blocks at a time, then large objects one chunk at a time, laid out
+// as `move_to` expects. Returns the moves to hand to `relocate` and the
+// number of bytes written.
+pub(crate) async fn copy_records(
+    source: &(dyn FileIO + Send + Sync),
+    target: &(dyn FileIO + Send + Sync),
+    records: Vec<(RecordKey, RequestMetadata)>,
+) -> Result<(Vec<(RecordKey, RequestMetadata, u64)>, u64)> {
+    let (large, records): (Vec<_>, Vec<_>) = records.into_iter().partition(|(_, metadata)| !metadata.chunks.is_empty());
+    let mut new_offset = 0;
+    let mut relocated = Vec::new();
+    // Clusters are whole sectors, so a run of them adjacent in the source is
+    // read in one vectored read, and a batch of them goes out in one
+    // vectored write, without being joined
+    let mut batch = Vec::new();
+    let mut batch_start = 0;
+    let mut batched = 0;
+    let mut clusters = clusters(records).into_iter().peekable();
+    while let Some(first) = clusters.next() {
+        let start = first.0.offset;
+        let mut end = first.0.end();
+        let mut run = vec![first];
+        while let Some(next) = clusters.next_if(|(next, _)| next.offset == end && end - start < COPY_BATCH as u64) {
+            end = next.0.end();
+            run.push(next);
+        }
+        let buffers = source.read_vectored_at(run.iter().map(|(cluster, _)| cluster.length).collect(), start).await?;
+        for ((cluster, members), data) in run.into_iter().zip(buffers) {
+            batched += data.len();
+            batch.push(data);
+            for (key, metadata) in members {
+                let offset = metadata.offset - cluster.offset + new_offset;
+                relocated.push((key, metadata, offset));
+            }
+            new_offset += cluster.length;
+            if batched >= COPY_BATCH {
+                target.write_vectored_at(std::mem::take(&mut batch), batch_start).await?;
+                batch_start = new_offset;
+                batched = 0;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        target.write_vectored_at(batch, batch_start).await?;
+    }
+
+    // Aliases of a large object share its copy
+    let mut copies: HashMap<u64, u64> = HashMap::new();
+    for (key, metadata) in large {
+        if let Some(&offset) = copies.get(&metadata.offset) {
+            relocated.push((key, metadata, offset));
+            continue;
+        }
+        let mut copy = metadata.clone();
+        copy.move_to(new_offset + metadata.header_len);
+        let mut data = Vec::from(record_format::read_span(source, metadata.offset - metadata.header_len, metadata.header_len).await?);
+        let mut position = new_offset;
+        for (chunk, copied) in metadata.chunks.iter().zip(&copy.chunks) {
+            data.extend_from_slice(&record_format::read_span(source, chunk.offset, chunk.length).await?);
+            target.write_at(std::mem::take(&mut data), position).await?;
+            position = align_up(copied.offset + copied.length);
+        }
+        copies.insert(metadata.offset, copy.offset);
+        relocated.push((key, metadata, copy.offset));
+        new_offset = align_up(copy.data_end());
+    }
+    Ok((relocated, new_offset))
+}
+
+async fn copy_live_records(manager: &Mutex<FileManager>) -> Result<CompactionStats> {
+    let (records, source, start_sequence, old_size, file_path, durability, preallocate) = {
+        let file_manager = manager.lock().unwrap();
+        if !file_manager.space.in_flight().is_empty() {
+            anyhow::bail!("Cannot compact {} while writes are in flight", file_manager.file_path);
+        }
+
+        let mut records: Vec<(RecordKey, RequestMetadata)> = file_manager.request_map.read().iter().collect();
+        records.extend(file_manager.retained_entries());
+
+        (
+            records,
+            file_manager.file.clone(),
+            file_manager.sequence(),
+            file_manager.space.end(),
+            file_manager.file_path.clone(),
+            file_manager.durability,
+            file_manager.preallocate,
+        )
+    };
+
+    // A migrated data file is a symlink; rewrite it where it now lives
+    let data_path = std::fs::canonicalize(&file_path)?;
+    let compact_path = format!("{}.compact", data_path.display());
+    let _ = std::fs::remove_file(&compact_path);
+    let target = create_file_io(&compact_path, durability).await?;
+    if let Some(length) = preallocate {
+        target.preallocate(length)?;
+    }
+
+    if let Err(e) = source.advise(0, old_size, Advice::Sequential) {
+        warn!("Could not advise sequential reads of {}: {}", file_path, e);
+    }
+    let (relocated, new_offset) = copy_records(source.as_ref(), target.as_ref(), records).await?;
+    std::fs::File::open(&compact_path)?.sync_all()?;
+    // Neither the copy just written nor the file it replaces is read back
+    // soon, so their cached pages can go
+    for (file, length) in [(&target, new_offset), (&source, old_size)] {
+        if let Err(e) = file.advise(0, length, Advice::DontNeed) {
+            warn!("Could not drop cached pages of {}: {}", file_path, e);
+        }
+    }
+
+    let mut file_manager = manager.lock().unwrap();
+    // Writes reserve space without the lock, so they are held off until the
+    // new file and its end are in place
+    let space = file_manager.space.clone();
+    let _frozen = space.freeze();
+    if file_manager.sequence() != start_sequence || !space.in_flight().is_empty() || space.end() != old_size {
+        let _ = std::fs::remove_file(&compact_path);
+        anyhow::bail!("{} changed during compaction, retry", file_path);
+    }
+
+    // The index of the copy is saved beside the live one before the copy is
+    // renamed over the data file, and installed after, so a crash in between
+    // is rolled forward or back at startup (see `recover_compaction`)
+    let (contents, records_moved) = file_manager.relocated_contents(&relocated);
+    let sequence = file_manager.wal.sequence;
+    let side_path = compaction_index_path(&file_path);
+    let renamed = index_store::save(&side_path, sequence, contents.clone()).and_then(|len| {
+        std::fs::rename(&compact_path, &data_path)?;
+        Ok(len)
+    });
+    let index_len = match renamed {
+        Ok(len) => len,
+        Err(e) => {
+            let _ = std::fs::remove_file(&compact_path);
+            let _ = std::fs::remove_file(&side_path);
+            return Err(e);
+        }
+    };
+    // The rename is what swaps the files. Should the directory not sync, the
+    // saved index is left for recovery, since the rename may not survive a
+    // crash.
+    let installed = sync_parent_dir(&data_path)
+        .and_then(|()| file_manager.index.install(&side_path, sequence, index_len))
+        .and_then(|()| file_manager.wal.reset());
+    if let Err(e) = installed {
+        error!("Failed to install the index of compacted {}: {}", file_path, e);
+    }
+    file_manager.swap_file(target, |manager| manager.load_entries(contents, Vec::new()));
+    // Reads still in progress use the old file, so freed space in the new one
+    // can be reused right away
+    space.set_free(Vec::new(), true);
+    space.set_end(new_offset);
+
+    let stats = CompactionStats {
+        records_moved: records_moved as u64,
+        reclaimed_bytes: old_size.saturating_sub(new_offset),
+        file_size: new_offset,
+    };
+    info!("Compacted {}: {} records, reclaimed {} bytes", file_path, stats.records_moved, stats.reclaimed_bytes);
+    Ok(stats)
+}
+
+// Index of a compacted copy of a data file, saved while the copy is swapped in
+fn compaction_index_path(file_path: &str) -> String {
+    format!("{}.compact", index_store::index_path(file_path))
+}
+
+// Settle a swap of a data file for its compacted copy that a crash
+// interrupted. While the copy is still beside the data file the rename never
+// happened, and the copy and any index saved for it are dropped. Once it has
+// replaced the data file, its saved index is installed, unless a checkpoint
+// made since already covers it.
+fn recover_compaction(file_path: &str) -> Result<()> {
+    let side_path = compaction_index_path(file_path);
+    let compact_path = format!("{}.compact", file_path);
+    if Path::new(&compact_path).exists() {
+        warn!("Rolling back the compaction of {} a crash interrupted before the swap", file_path);
+        std::fs::remove_file(&compact_path)?;
+        if Path::new(&side_path).exists() {
+            std::fs::remove_file(&side_path)?;
+        }
+        return Ok(());
+    }
+    if !Path::new(&side_path).exists() {
+        return Ok(());
+    }
+    let index_path = index_store::index_path(file_path);
+    let (compacted, ..) = index_store::load(&side_path)?;
+    let (current, ..) = index_store::load(&index_path)?;
+    if compacted >= current {
+        warn!("Rolling forward the compaction of {} a crash interrupted after the swap", file_path);
+        std::fs::rename(&side_path, &index_path)?;
+    } else {
+        std::fs::remove_file(&side_path)?;
+    }
+    sync_parent_dir(Path::new(&index_path))
+}
+
+// Split a session token into the index epoch and sequence it requires
+pub(crate) fn parse_session_token(token: &str) -> Result<(&str, u64)> {
+    let (epoch, sequence) = token
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Malformed session token {:?}", token))?;
+    let sequence = sequence
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Malformed session token {:?}", token))?;
+    Ok((epoch, sequence))
+}
+
+// Registry of O_DIRECT data files under a data directory, opened on demand
+pub(crate) struct FileRegistry {
+    data_dir: PathBuf,
+    durability: Durability,
+    // Segment size for new data files; `None` keeps each in a single file
+    segment_size: Option<u64>,
+    // Bytes preallocated in each data file or segment
+    preallocate: Option<u64>,
+    version_policy: VersionPolicy,
+    // How long deleted records stay in the trash
+    trash_retention: Option<Duration>,
+    // Which implementation holds each data file's index
+    metadata_store: MetadataStoreKind,
+    // Whether overwrites in place go through a double-write buffer
+    double_write: bool,
+    // Raw block device holding the default data file instead of a file
+    block_device: Option<PathBuf>,
+    // NVMe namespace holding the default data file, driven through SPDK
+    #[cfg(feature = "spdk")]
+    nvme_namespace: Option<Arc<spdk::Namespace>>,
+    managers: tokio::sync::Mutex<HashMap<String, Arc<Mutex<FileManager>>>>,
+    // Bloom filter of each data file opened so far
+    filters: std::sync::RwLock<HashMap<String, Arc<BloomFilter>>>,
+    // Request map and data file of each data file opened so far
+    lookups: std::sync::RwLock<HashMap<String, Arc<Lookups>>>,
+}
+
+impl FileRegistry {
+    pub(crate) fn new(data_dir: impl Into<PathBuf>, durability: Durability, segment_size: Option<u64>, preallocate: Option<u64>, version_policy: VersionPolicy, trash_retention: Option<Duration>, metadata_store: MetadataStoreKind) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            durability,
+            segment_size,
+            preallocate,
+            version_policy,
+            trash_retention,
+            metadata_store,
+            double_write: false,
+            block_device: None,
+            #[cfg(feature = "spdk")]
+            nvme_namespace: None,
+            managers: tokio::sync::Mutex::new(HashMap::new()),
+            filters: std::sync::RwLock::new(HashMap::new()),
+            lookups: std::sync::RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Stage overwrites in place in a double-write buffer next to each data file
+    pub(crate) fn with_double_write(mut self, enabled: bool) -> Self {
+        self.double_write = enabled;
+        self
+    }
+
+    // Keep the default data file on a raw block device; its sidecar files
+    // stay in the data directory
+    pub(crate) fn with_block_device(mut self, device: Option<PathBuf>) -> Self {
+        self.block_device = device;
+        self
+    }
+
+    // Keep the default data file on an NVMe namespace attached through SPDK;
+    // its sidecar files stay in the data directory
+    #[cfg(feature = "spdk")]
+    pub(crate) fn with_nvme_namespace(mut self, namespace: Option<Arc<spdk::Namespace>>) -> Self {
+        self.nvme_namespace = namespace;
+        self
+    }
+
+    // Whether the default data file lives on a device rather than in the
+    // data directory
+    fn default_on_device(&self) -> bool {
+        #[cfg(feature = "spdk")]
+        if self.nvme_namespace.is_some() {
+            return true;
+        }
+        self.block_device.is_some()
+    }
+
+    // Path of the data file backing a file ID
+    pub(crate) fn path_for(&self, file_id: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.bin", file_id))
+    }
+
+    // Directory holding one subdirectory per snapshot
+    pub(crate) fn snapshots_dir(&self) -> PathBuf {
+        self.data_dir.join("snapshots")
+    }
+
+    // Directory holding the files of one snapshot
+    pub(crate) fn snapshot_dir(&self, snapshot_id: &str) -> PathBuf {
+        self.snapshots_dir().join(snapshot_id)
+    }
+
+    // All data files opened so far
+    pub(crate) async fn managers(&self) -> Vec<Arc<Mutex<FileManager>>> {
+        self.managers.lock().await.values().cloned().collect()
+    }
+
+    // Close every data file opened so far, waiting for its reads and writes
+    // in flight, at shutdown. Every file is closed even if one fails.
+    pub(crate) async fn close(&self) -> Result<()> {
+        let mut failed = 0;
+        for manager in self.managers().await {
+            let (file_path, file) = {
+                let file_manager = manager.lock().unwrap();
+                (file_manager.file_path.clone(), file_manager.file.clone())
+            };
+            match file.close().await {
+                Ok(()) => info!("Closed data file {}", file_path),
+                Err(e) => {
+                    error!("Could not close data file {}: {}", file_path, e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("Could not close {} data files", failed);
+        }
+        Ok(())
+    }
+
+    // Whether a data file opened so far certainly holds no record under
+    // `key`, going by its bloom filter alone
+    pub(crate) fn certainly_absent(&self, file_id: &str, key: &RecordKey) -> bool {
+        let Ok(file_id) = resolve_file_id(file_id) else {
+            return false;
+        };
+        let filters = self.filters.read().unwrap();
+        filters.get(file_id).is_some_and(|filter| !filter.may_contain(key))
+    }
+
+    // Lookups into a data file opened so far, which skip its file manager's lock
+    pub(crate) fn lookups(&self, file_id: &str) -> Option<Arc<Lookups>> {
+        let file_id = resolve_file_id(file_id).ok()?;
+        self.lookups.read().unwrap().get(file_id).cloned()
+    }
+
+    // IDs of all data files opened so far
+    pub(crate) async fn file_ids(&self) -> Vec<String> {
+        self.managers.lock().await.keys().cloned().collect()
+    }
+
+    // IDs of all data files in the data directory, opened or not, sorted
+    pub(crate) fn stored_file_ids(&self) -> Result<Vec<String>> {
+        let mut file_ids = Vec::new();
+        for entry in std::fs::read_dir(&self.data_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            // A segmented data file has a segment manifest instead of a single file
+            let Some(file_id) = name.strip_suffix(".bin").or_else(|| name.strip_suffix(".bin.segments")) else {
+                continue;
+            };
+            if resolve_file_id(file_id).is_ok_and(|resolved| resolved == file_id) && !file_ids.iter().any(|known| known == file_id) {
+                file_ids.push(file_id.to_string());
+            }
+        }
+        if self.default_on_device() && !file_ids.iter().any(|known| known == DEFAULT_FILE_ID) {
+            file_ids.push(DEFAULT_FILE_ID.to_string());
+        }
+        file_ids.sort();
+        Ok(file_ids)
+    }
+
+    // Look up the file manager for a file ID, creating its data file on first use
+    pub(crate) async fn get(&self, file_id: &str) -> Result<Arc<Mutex<FileManager>>> {
+        let file_id = resolve_file_id(file_id)?;
+
+        let mut managers = self.managers.lock().await;
+        if let Some(manager) = managers.get(file_id) {
+            return Ok(manager.clone());
+        }
+
+        let path = self.path_for(file_id);
+        let file_path = path.to_string_lossy();
+        let device_file = match &self.block_device {
+            Some(device) if file_id == DEFAULT_FILE_ID => Some(block_device::open(device, &file_path, self.durability).await?),
+            _ => None,
+        };
+        #[cfg(feature = "spdk")]
+        let device_file = match &self.nvme_namespace {
+            Some(namespace) if file_id == DEFAULT_FILE_ID => Some(spdk::open(namespace, &file_path, self.durability)?),
+            _ => device_file,
+        };
+        let mut manager = match device_file {
+            Some(file) => FileManager::with_file(&file_path, file, self.durability, self.preallocate, self.version_policy, self.trash_retention, self.metadata_store).await?,
+            None => FileManager::new(&file_path, self.durability, self.segment_size, self.preallocate, self.version_policy, self.trash_retention, self.metadata_store).await?,
+        };
+        if self.double_write {
+            manager.enable_double_write()?;
+        }
+        self.filters.write().unwrap().insert(file_id.to_string(), manager.filter.clone());
+        self.lookups.write().unwrap().insert(file_id.to_string(), manager.lookups.clone());
+        let manager = Arc::new(Mutex::new(manager));
+        managers.insert(file_id.to_string(), manager.clone());
+        info!("Opened data file {} for file ID {}", path.display(), file_id);
+        Ok(manager)
+    }
+}
+
+// Map an empty file ID to the default and reject IDs that could escape the
+// data directory
+pub(crate) fn resolve_file_id(file_id: &str) -> Result<&str> {
+    if file_id.is_empty() {
+        return Ok(DEFAULT_FILE_ID);
+    }
+    let valid = !file_id.starts_with('.')
+        && file_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        anyhow::bail!("Invalid file ID {:?}", file_id);
+    }
+    Ok(file_id)
+}
+
+// Compact every open data file whose reclaimable bytes exceed `threshold`
+// (a fraction of the file size) and AUTO_COMPACT_MIN_RECLAIMABLE. Files are
+// skipped while maintenance mode is on, so snapshots see a stable file; a
+// compaction that loses a race with writes is retried on the next tick.
+pub(crate) async fn run_auto_compactor(files: Arc<FileRegistry>, maintenance: Arc<AtomicBool>, interval: Duration, threshold: f64) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if maintenance.load(Ordering::SeqCst) {
+            continue;
+        }
+        for manager in files.managers().await {
+            let (file_path, reclaimable, file_size, on_device) = {
+                let file_manager = manager.lock().unwrap();
+                (file_manager.file_path.clone(), file_manager.reclaimable_bytes(), file_manager.space.end(), file_manager.file.storage_kind().is_some())
+            };
+            if on_device || reclaimable < AUTO_COMPACT_MIN_RECLAIMABLE || (reclaimable as f64) < threshold * file_size as f64 {
+                continue;
+            }
+
+            info!("Auto-compacting {}: {} of {} bytes reclaimable", file_path, reclaimable, file_size);
+            if let Err(e) = compact(&manager).await {
+                warn!("Auto-compaction of {} failed: {}", file_path, e);
+            }
+        }
+    }
+}
+
+// Periodically remove expired records, prior versions the version policy no
+// longer keeps and trashed records past the trash retention from every open
+// data file
+pub(crate) async fn run_expiration_sweeper(files: Arc<FileRegistry>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let now = SystemTime::now();
+        for manager in files.managers().await {
+            let mut file_manager = manager.lock().unwrap();
+            match file_manager.sweep_expired(now) {
+                Ok(0) => {}
+                Ok(swept) => info!("Expired {} records in {}, {} bytes now reclaimable", swept, file_manager.file_path, file_manager.reclaimable_bytes()),
+                Err(e) => error!("Failed to expire records in {}: {}", file_manager.file_path, e),
+            }
+            match file_manager.prune_all_versions(now) {
+                Ok(0) => {}
+                Ok(pruned) => info!("Pruned {} prior versions in {}, {} bytes now reclaimable", pruned, file_manager.file_path, file_manager.reclaimable_bytes()),
+                Err(e) => error!("Failed to prune prior versions in {}: {}", file_manager.file_path, e),
+            }
+            match file_manager.purge_trash(now) {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} deleted records from the trash of {}, {} bytes now reclaimable", purged, file_manager.file_path, file_manager.reclaimable_bytes()),
+                Err(e) => error!("Failed to purge the trash of {}: {}", file_manager.file_path, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::file_io::{Durability, FileIO};
+use crate::file_manager::{self, RecordKey, RequestMetadata, TrashedRecord};
+use crate::index_store::{self, IndexContents};
+use crate::record_format;
+use crate::wal;
+
+// Where in the index a record is kept
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Live,
+    Version,
+    Trashed { deleted_at: SystemTime },
+}
+
+struct Entry {
+    key: RecordKey,
+    metadata: RequestMetadata,
+    slot: Slot,
+}
+
+impl Entry {
+    // Byte ranges of the data file the record and its header occupy
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        let metadata = &self.metadata;
+        let header_start = metadata.offset.saturating_sub(metadata.header_len);
+        if metadata.chunks.is_empty() {
+            return vec![(header_start, metadata.offset + metadata.stored_size())];
+        }
+        std::iter::once((header_start, metadata.offset))
+            .chain(metadata.chunks.iter().map(|chunk| (chunk.offset, chunk.offset + chunk.length)))
+            .filter(|(start, end)| end > start)
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        match self.slot {
+            Slot::Live => format!("{:?} in namespace {:?}", self.key.request_id, self.key.namespace),
+            Slot::Version => format!("version {} of {:?} in namespace {:?}", self.metadata.generation, self.key.request_id, self.key.namespace),
+            Slot::Trashed { .. } => format!("trashed {:?} in namespace {:?}", self.key.request_id, self.key.namespace),
+        }
+    }
+}
+
+// Outcome of checking one data file
+#[derive(Debug, Default)]
+pub(crate) struct FsckReport {
+    pub(crate) records_checked: u64,
+    pub(crate) bytes_checked: u64,
+    // Corruption found; each names the record it is with, if any
+    pub(crate) problems: Vec<String>,
+    // Conditions the server recovers from on its own, such as a torn log tail
+    pub(crate) warnings: Vec<String>,
+    // Set once the index has been rewritten without the corrupt records
+    pub(crate) repaired: bool,
+}
+
+// Check that the record lies in the data file, that the header in front of
+// its payload describes it, and that its payload matches its checksum
+async fn check_entry(file: &(dyn FileIO + Send + Sync), file_size: u64, entry: &Entry) -> Result<(), String> {
+    let metadata = &entry.metadata;
+    if metadata.offset < metadata.header_len {
+        return Err(format!("{}-byte header would start before the data file at payload offset {}", metadata.header_len, metadata.offset));
+    }
+    if let Some((start, end)) = entry.ranges().into_iter().find(|&(_, end)| end > file_size) {
+        return Err(format!("bytes {}..{} are outside the {}-byte data file", start, end, file_size));
+    }
+    // Records written before headers were introduced have none
+    if metadata.header_len > 0 {
+        let header = record_format::read_span(file, metadata.offset - metadata.header_len, metadata.header_len).await.map_err(|e| e.to_string())?;
+        record_format::check_header(&header, &entry.key, metadata)?;
+    }
+    let payload = record_format::read_stored(file, metadata, false).await.map_err(|e| e.to_string())?;
+    record_format::verify(&entry.key.request_id, metadata, &payload).map_err(|mismatch| mismatch.to_string())
+}
+
+fn empty_log(path: &str) -> Result<()> {
+    match std::fs::OpenOptions::new().write(true).open(path) {
+        Ok(file) => {
+            file.set_len(0)?;
+            file.sync_all()?;
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Check a data file of a stopped server against its sidecar index and
+// write-ahead log, without changing anything unless `repair` is set. Every
+// indexed record, live, prior version or trashed, must lie within the file,
+// be preceded by a header naming it and match its checksum, and no two
+// records may overlap; of two overlapping records the older is the one
+// reported, as the newer was written over it. Repair rewrites the index
+// without the corrupt records and empties the log. An index or log that
+// cannot be read is rebuilt by scanning the data file for record headers,
+// which brings back deleted records whose data is still present.
+pub(crate) async fn check(data_path: &Path, repair: bool) -> Result<FsckReport> {
+    let data_path = data_path.to_string_lossy().into_owned();
+    let file = file_manager::open_data_file(&data_path, Durability::ODsync, None).await?;
+    let file_size = file.len().await?;
+    let index_path = index_store::index_path(&data_path);
+    let wal_path = wal::wal_path(&data_path);
+    let mut report = FsckReport::default();
+
+    let loaded = index_store::load(&index_path).and_then(|(checkpoint, contents)| Ok((contents, wal::read(&wal_path, checkpoint)?)));
+    let (contents, (replay, log_len)) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.problems.push(format!("index cannot be read: {}", e));
+            if repair {
+                let entries = record_format::scan(file.as_ref(), file_size).await?;
+                report.records_checked = entries.len() as u64;
+                index_store::save(&index_path, 0, IndexContents { entries, ..IndexContents::default() })?;
+                empty_log(&wal_path)?;
+                report.repaired = true;
+                info!("Rebuilt the index of {} from a scan of the data file", data_path);
+            }
+            return Ok(report);
+        }
+    };
+    if !Path::new(&index_path).exists() && replay.entries == 0 && file_size > 0 {
+        report.warnings.push("no index; the server rebuilds it by scanning the data file".to_string());
+    }
+    if replay.valid_len < log_len {
+        report.warnings.push(format!("{} bytes of torn or corrupt entries at the end of the log", log_len - replay.valid_len));
+    }
+
+    let contents = wal::replay_onto(contents, replay.ops);
+    let mut entries: Vec<Entry> = contents.entries.into_iter().map(|(key, metadata)| Entry { key, metadata, slot: Slot::Live }).collect();
+    entries.extend(contents.versions.into_iter().map(|(key, metadata)| Entry { key, metadata, slot: Slot::Version }));
+    entries.extend(contents.trash.into_iter().map(|(key, trashed)| Entry { key, metadata: trashed.metadata, slot: Slot::Trashed { deleted_at: trashed.deleted_at } }));
+    entries.sort_by_key(|entry| entry.metadata.offset);
+
+    let mut corrupt = vec![false; entries.len()];
+    for (index, entry) in entries.iter().enumerate() {
+        report.records_checked += 1;
+        report.bytes_checked += entry.metadata.stored_size();
+        if let Err(e) = check_entry(file.as_ref(), file_size, entry).await {
+            report.problems.push(format!("{}: {}", entry.describe(), e));
+            corrupt[index] = true;
+        }
+    }
+
+    // Records sharing a block is fine; sharing bytes is not
+    let mut ranges: Vec<(u64, u64, usize)> = entries
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !corrupt[*index])
+        .flat_map(|(index, entry)| entry.ranges().into_iter().map(move |(start, end)| (start, end, index)))
+        .collect();
+    ranges.sort_unstable();
+    // End of the range reaching furthest so far, and whose it is
+    let mut previous: Option<(u64, usize)> = None;
+    for (start, end, index) in ranges {
+        if corrupt[index] {
+            continue;
+        }
+        match previous {
+            Some((_, other)) if corrupt[other] => previous = Some((end, index)),
+            Some((previous_end, other)) if start < previous_end && other != index => {
+                let (older, newer) = if entries[index].metadata.written_at < entries[other].metadata.written_at { (index, other) } else { (other, index) };
+                report.problems.push(format!("{} overlaps {}, which was written after it", entries[older].describe(), entries[newer].describe()));
+                corrupt[older] = true;
+                if older == other {
+                    previous = Some((end, index));
+                }
+            }
+            Some((previous_end, _)) if end <= previous_end => {}
+            _ => previous = Some((end, index)),
+        }
+    }
+
+    if repair && (!report.problems.is_empty() || replay.valid_len < log_len) {
+        let mut kept = IndexContents::default();
+        for (entry, corrupt) in entries.into_iter().zip(corrupt) {
+            if corrupt {
+                continue;
+            }
+            match entry.slot {
+                Slot::Live => kept.entries.push((entry.key, entry.metadata)),
+                Slot::Version => kept.versions.push((entry.key, entry.metadata)),
+                Slot::Trashed { deleted_at } => kept.trash.push((entry.key, TrashedRecord { metadata: entry.metadata, deleted_at })),
+            }
+        }
+        // The new checkpoint covers every log entry, so the log can go
+        index_store::save(&index_path, replay.sequence, kept)?;
+        empty_log(&wal_path)?;
+        report.repaired = true;
+        info!("Repaired the index of {}: dropped {} corrupt records", data_path, report.problems.len());
+    }
+    Ok(report)
+}
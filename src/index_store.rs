@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::compression::Compression;
+use crate::file_manager::{Chunk, RecordKey, RequestMetadata, TrashedRecord};
+use crate::wal::{self, WalOp};
+
+// One request map entry as stored in the sidecar index and in snapshots
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PersistedRecord {
+    pub(crate) namespace: String,
+    pub(crate) request_id: String,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    #[serde(default)]
+    pub(crate) header_len: u64,
+    pub(crate) written_at_ms: u64,
+    pub(crate) checksum: Option<u32>,
+    #[serde(default)]
+    pub(crate) encrypted: bool,
+    #[serde(default)]
+    pub(crate) compression: Option<Compression>,
+    pub(crate) generation: u64,
+    pub(crate) expires_at_ms: Option<u64>,
+    pub(crate) metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) chunks: Vec<Chunk>,
+}
+
+impl PersistedRecord {
+    pub(crate) fn new(key: RecordKey, metadata: RequestMetadata) -> Self {
+        Self {
+            namespace: key.namespace,
+            request_id: key.request_id,
+            offset: metadata.offset,
+            size: metadata.size,
+            header_len: metadata.header_len,
+            written_at_ms: crate::unix_millis(metadata.written_at),
+            checksum: metadata.checksum,
+            encrypted: metadata.encrypted,
+            compression: metadata.compression,
+            generation: metadata.generation,
+            expires_at_ms: metadata.expires_at.map(crate::unix_millis),
+            metadata: metadata.user_metadata,
+            chunks: metadata.chunks,
+        }
+    }
+
+    pub(crate) fn into_entry(self) -> (RecordKey, RequestMetadata) {
+        let key = RecordKey {
+            namespace: self.namespace,
+            request_id: self.request_id,
+        };
+        let metadata = RequestMetadata {
+            offset: self.offset,
+            size: self.size,
+            header_len: self.header_len,
+            written_at: UNIX_EPOCH + Duration::from_millis(self.written_at_ms),
+            checksum: self.checksum,
+            encrypted: self.encrypted,
+            compression: self.compression,
+            generation: self.generation,
+            expires_at: self.expires_at_ms.map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            user_metadata: self.metadata,
+            chunks: self.chunks,
+        };
+        (key, metadata)
+    }
+}
+
+// A full checkpoint of the request map covering every write-ahead log entry
+// up to `sequence`
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedIndex {
+    sequence: u64,
+    records: Vec<PersistedRecord>,
+    // Prior versions of overwritten records
+    #[serde(default)]
+    versions: Vec<PersistedRecord>,
+    // Deleted records awaiting undelete or purge
+    #[serde(default)]
+    trash: Vec<PersistedTrash>,
+}
+
+// A record in the trash, as stored in the sidecar index and the log
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PersistedTrash {
+    #[serde(flatten)]
+    pub(crate) record: PersistedRecord,
+    pub(crate) deleted_at_ms: u64,
+}
+
+impl PersistedTrash {
+    pub(crate) fn new(key: RecordKey, trashed: TrashedRecord) -> Self {
+        Self {
+            record: PersistedRecord::new(key, trashed.metadata),
+            deleted_at_ms: crate::unix_millis(trashed.deleted_at),
+        }
+    }
+
+    pub(crate) fn into_entry(self) -> (RecordKey, TrashedRecord) {
+        let (key, metadata) = self.record.into_entry();
+        let deleted_at = UNIX_EPOCH + Duration::from_millis(self.deleted_at_ms);
+        (key, TrashedRecord { metadata, deleted_at })
+    }
+}
+
+// Everything a checkpoint of the index holds
+#[derive(Debug, Default, Clone)]
+pub(crate) struct IndexContents {
+    pub(crate) entries: Vec<(RecordKey, RequestMetadata)>,
+    pub(crate) versions: Vec<(RecordKey, RequestMetadata)>,
+    pub(crate) trash: Vec<(RecordKey, TrashedRecord)>,
+}
+
+// Sidecar index kept next to a data file
+pub(crate) fn index_path(data_path: &str) -> String {
+    format!("{}.index", data_path)
+}
+
+// The sidecar index starts with this, followed by framed checkpoints: a full
+// one, then the log entries folded in since. Indexes written before it was
+// introduced are a single JSON checkpoint and are rewritten on the next one.
+const INDEX_MAGIC: &[u8] = b"odx-index-2\n";
+
+// One frame of the sidecar index
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+enum IndexFrame {
+    Full(PersistedIndex),
+    // Log entries after the previous frame's sequence, up to `sequence`
+    Incremental { sequence: u64, ops: Vec<WalOp> },
+}
+
+fn persist(sequence: u64, contents: IndexContents) -> IndexFrame {
+    IndexFrame::Full(PersistedIndex {
+        sequence,
+        records: contents.entries.into_iter().map(|(key, metadata)| PersistedRecord::new(key, metadata)).collect(),
+        versions: contents.versions.into_iter().map(|(key, metadata)| PersistedRecord::new(key, metadata)).collect(),
+        trash: contents.trash.into_iter().map(|(key, trashed)| PersistedTrash::new(key, trashed)).collect(),
+    })
+}
+
+fn restore(index: PersistedIndex) -> IndexContents {
+    IndexContents {
+        entries: index.records.into_iter().map(PersistedRecord::into_entry).collect(),
+        versions: index.versions.into_iter().map(PersistedRecord::into_entry).collect(),
+        trash: index.trash.into_iter().map(PersistedTrash::into_entry).collect(),
+    }
+}
+
+// Sidecar index of an open data file. Checkpoints are incremental: the log
+// entries since the last one are appended as a frame, so a checkpoint costs
+// the changes it covers rather than the whole request map. Once the appended
+// frames outgrow the full checkpoint they follow, the next checkpoint
+// rewrites the file with a full one.
+pub(crate) struct IndexFile {
+    pub(crate) path: String,
+    // Sequence of the last log entry the index covers
+    pub(crate) sequence: u64,
+    // Length of the valid frames, which a torn append is cut back to; 0 if
+    // the file has to be rewritten before it can be appended to
+    len: u64,
+    // Length of the magic and the full checkpoint
+    full_len: u64,
+}
+
+impl IndexFile {
+    // Load the sidecar index; a missing file is an empty index at sequence 0.
+    // The file is memory-mapped rather than read into a buffer, so loading a
+    // large index does not need a second copy of it in memory. A frame torn
+    // by a crash mid-append is ignored: the log entries it held are still in
+    // the log, which is only emptied once the frame is synced.
+    pub(crate) fn open(path: &str) -> Result<(Self, IndexContents)> {
+        let mut index = Self { path: path.to_string(), sequence: 0, len: 0, full_len: 0 };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((index, IndexContents::default())),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() == 0 {
+            anyhow::bail!("Index {} is empty", path);
+        }
+        // SAFETY: the index is only changed by appending past the valid
+        // frames or by renaming a new file over it, neither of which changes
+        // mapped bytes; the map is dropped once parsed
+        let map = unsafe { Mmap::map(&file)? };
+        let corrupt = |e: serde_json::Error| anyhow::anyhow!("Index {} is corrupt: {}", path, e);
+
+        if !map.starts_with(INDEX_MAGIC) {
+            let full: PersistedIndex = serde_json::from_slice(&map).map_err(corrupt)?;
+            index.sequence = full.sequence;
+            return Ok((index, restore(full)));
+        }
+        let mut cursor = INDEX_MAGIC.len();
+        let Some(payload) = wal::decode_frame(&map, cursor) else {
+            anyhow::bail!("Index {} is corrupt: its full checkpoint is torn or fails its CRC-32", path);
+        };
+        let full = match serde_json::from_slice(payload).map_err(corrupt)? {
+            IndexFrame::Full(full) => full,
+            IndexFrame::Incremental { .. } => anyhow::bail!("Index {} is corrupt: it starts with an incremental checkpoint", path),
+        };
+        cursor += wal::FRAME_HEADER + payload.len();
+        index.full_len = cursor as u64;
+        index.sequence = full.sequence;
+
+        let mut ops = Vec::new();
+        while let Some(payload) = wal::decode_frame(&map, cursor) {
+            let Ok(IndexFrame::Incremental { sequence, ops: frame_ops }) = serde_json::from_slice(payload) else {
+                break;
+            };
+            index.sequence = sequence;
+            ops.extend(frame_ops);
+            cursor += wal::FRAME_HEADER + payload.len();
+        }
+        index.len = cursor as u64;
+        if cursor < map.len() {
+            warn!("Ignoring {} bytes of torn or corrupt checkpoints at the end of {}", map.len() - cursor, path);
+        }
+        Ok((index, wal::replay_onto(restore(full), ops)))
+    }
+
+    // Whether the next checkpoint has to be a full one
+    pub(crate) fn needs_full(&self) -> bool {
+        self.len == 0 || self.len - self.full_len >= self.full_len
+    }
+
+    // Replace the index with a full checkpoint of `contents`, as of log
+    // sequence `sequence`
+    pub(crate) fn save_full(&mut self, sequence: u64, contents: IndexContents) -> Result<()> {
+        self.full_len = save(&self.path, sequence, contents)?;
+        self.len = self.full_len;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    // Replace the index with a full checkpoint already written by `save` to
+    // `path`, as of log sequence `sequence` and `len` bytes long
+    pub(crate) fn install(&mut self, path: &str, sequence: u64, len: u64) -> Result<()> {
+        std::fs::rename(path, &self.path)?;
+        self.full_len = len;
+        self.len = len;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    // Append the log entries after the index's sequence, up to `sequence`.
+    // Anything after the valid frames, left by a torn append, is cut off
+    // first.
+    pub(crate) fn append(&mut self, sequence: u64, ops: Vec<WalOp>) -> Result<()> {
+        let frame = wal::encode_frame(&serde_json::to_vec(&IndexFrame::Incremental { sequence, ops })?);
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(self.len)?;
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(&frame)?;
+        file.sync_data()?;
+        self.len += frame.len() as u64;
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+// Replace the sidecar index with a full checkpoint of `contents`, as of log
+// sequence `sequence`, returning its length. It is written to a temporary
+// file and renamed into place, so a crash leaves either the old or the new
+// index, never a torn one.
+pub(crate) fn save(path: &str, sequence: u64, contents: IndexContents) -> Result<u64> {
+    let mut bytes = INDEX_MAGIC.to_vec();
+    bytes.extend_from_slice(&wal::encode_frame(&serde_json::to_vec(&persist(sequence, contents))?));
+    let temp_path = format!("{}.tmp", path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(bytes.len() as u64)
+}
+
+// Read the sidecar index and the log sequence it covers without changing it
+pub(crate) fn load(path: &str) -> Result<(u64, IndexContents)> {
+    let (index, contents) = IndexFile::open(path)?;
+    Ok((index.sequence, contents))
+}
@@ -0,0 +1,174 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::file_io::{io_priority, with_priority, Advice, FileIO};
+
+// A read, write or sync of a data file that did not complete in time
+#[derive(Debug)]
+pub(crate) struct IoTimedOut {
+    pub(crate) path: String,
+    pub(crate) operation: &'static str,
+    pub(crate) offset: u64,
+    pub(crate) timeout: Duration,
+}
+
+impl std::fmt::Display for IoTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {} of {} did not complete within {:?}", self.operation, self.offset, self.path, self.timeout)
+    }
+}
+
+impl std::error::Error for IoTimedOut {}
+
+// A write refused because earlier writes to the same data file timed out and
+// may still land
+#[derive(Debug)]
+pub(crate) struct WritesFenced {
+    pub(crate) path: String,
+    pub(crate) abandoned: usize,
+}
+
+impl std::fmt::Display for WritesFenced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Writes to {} are refused until {} timed-out writes complete", self.path, self.abandoned)
+    }
+}
+
+impl std::error::Error for WritesFenced {}
+
+// Decorator failing reads, writes and syncs of a data file that take longer
+// than `timeout`. Neither io_uring submissions nor blocking threads can be
+// stopped once started, so a timed-out operation is abandoned rather than
+// cancelled: a read's result is dropped when it arrives, and a write keeps
+// running in a task of its own. Until every abandoned write has completed the
+// file is fenced, refusing new writes, so a late write cannot land over data
+// written after it.
+pub(crate) struct TimeoutFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    path: String,
+    timeout: Duration,
+    // Timed-out writes still running
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl TimeoutFileIO {
+    pub(crate) fn new(inner: Arc<dyn FileIO + Send + Sync>, path: &str, timeout: Duration) -> Self {
+        Self { inner, path: path.to_string(), timeout, abandoned: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    fn timed_out(&self, operation: &'static str, offset: u64) -> anyhow::Error {
+        let timed_out = IoTimedOut { path: self.path.clone(), operation, offset, timeout: self.timeout };
+        warn!("{}", timed_out);
+        timed_out.into()
+    }
+
+    // Wait up to the timeout for an operation that changes nothing on disk
+    async fn bounded<T>(&self, operation: &'static str, offset: u64, op: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::time::timeout(self.timeout, op).await.map_err(|_| self.timed_out(operation, offset))?
+    }
+
+    // Run a write in a task of its own and wait up to the timeout for it. On
+    // timeout the task is left running and the file fenced until it ends.
+    async fn write(&self, offset: u64, write: impl Future<Output = Result<()>> + Send + 'static) -> Result<()> {
+        let abandoned = self.abandoned.load(Ordering::SeqCst);
+        if abandoned > 0 {
+            return Err(WritesFenced { path: self.path.clone(), abandoned }.into());
+        }
+        let mut task = tokio::spawn(with_priority(io_priority(), write));
+        match tokio::time::timeout(self.timeout, &mut task).await {
+            Ok(written) => written?,
+            Err(_) => {
+                self.abandoned.fetch_add(1, Ordering::SeqCst);
+                let abandoned = self.abandoned.clone();
+                let path = self.path.clone();
+                tokio::spawn(async move {
+                    let written = task.await;
+                    if abandoned.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        info!("Timed-out writes to {} have completed, accepting writes again", path);
+                    }
+                    if let Ok(Err(e)) = written {
+                        warn!("Timed-out write at offset {} of {} failed: {}", offset, path, e);
+                    }
+                });
+                Err(self.timed_out("Write", offset))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FileIO for TimeoutFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let inner = self.inner.clone();
+        self.write(offset, async move { inner.write_at(data, offset).await }).await
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        self.bounded("Read", offset, self.inner.read_at(size, offset)).await
+    }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        let inner = self.inner.clone();
+        self.write(offset, async move { inner.write_vectored_at(buffers, offset).await }).await
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        self.bounded("Read", offset, self.inner.read_vectored_at(sizes, offset)).await
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.bounded("Sync", 0, self.inner.sync_data()).await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.inner.set_len(size)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        self.inner.segment_size()
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        self.inner.storage_kind()
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        self.inner.preallocate(length)
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        self.inner.remove_segment(index)
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        self.inner.try_read_at(size, offset)
+    }
+
+    // Closing waits for abandoned operations too, however long they take
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
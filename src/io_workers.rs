@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicU64;
+#[cfg(target_os = "linux")]
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[cfg(target_os = "linux")]
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use tokio::sync::OwnedSemaphorePermit;
+#[cfg(target_os = "linux")]
+use tracing::{error, info, warn};
+
+#[cfg(target_os = "linux")]
+use crate::file_io::{io_priority, submission_priority, IoPriority, UringOp, SUBMIT_BATCH};
+#[cfg(target_os = "linux")]
+use crate::numa;
+#[cfg(target_os = "linux")]
+use crate::uring::{Ring, Waker};
+
+// Number of I/O workers; 0 until set, meaning one per CPU the process may
+// run on
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Run data file I/O on `count` workers instead of one per CPU. Takes effect
+// if set before the first data file is opened.
+pub(crate) fn set_count(count: usize) {
+    COUNT.store(count, Ordering::Relaxed);
+}
+
+// Message to an I/O worker
+#[cfg(target_os = "linux")]
+enum Message {
+    // Run `op` on data file `file`, its reads and writes at I/O priority
+    // `ioprio`, duplicating `source` the first time the worker sees the file
+    Run { file: u64, source: Arc<std::fs::File>, op: UringOp, ioprio: u16, permit: OwnedSemaphorePermit },
+    // Close the worker's descriptor of data file `file`, which is closed.
+    // Sent on both of the worker's queues, so it is the last message of the
+    // file on each.
+    Forget(u64),
+}
+
+// Bulk and idle-priority operations a worker takes off its queue at a time
+#[cfg(target_os = "linux")]
+const BULK_BATCH: usize = SUBMIT_BATCH / 8;
+
+#[cfg(target_os = "linux")]
+struct Worker {
+    node: Option<u32>,
+    messages: tokio::sync::mpsc::UnboundedSender<Message>,
+    // Operations at a priority below Normal
+    bulk: tokio::sync::mpsc::UnboundedSender<Message>,
+    // Wakes the worker to take what was sent
+    waker: Arc<Waker>,
+}
+
+// Threads running the reads, writes and syncs of every data file not on a
+// ring of its own. Each is pinned to a CPU and drives an io_uring ring of its
+// own, with its own registered buffers, apart from the tokio runtime serving
+// gRPC; a data file's operations are spread over them in turn, so one busy
+// file keeps every worker's ring busy. Workers open a descriptor of their own
+// on a data file the first time they run an operation on it.
+#[cfg(target_os = "linux")]
+pub(crate) struct IoWorkers {
+    workers: Vec<Worker>,
+}
+
+#[cfg(target_os = "linux")]
+static WORKERS: OnceLock<IoWorkers> = OnceLock::new();
+#[cfg(target_os = "linux")]
+static STARTING: Mutex<()> = Mutex::new(());
+
+// Data files given to the workers so far, which numbers them
+#[cfg(target_os = "linux")]
+static FILES: AtomicU64 = AtomicU64::new(0);
+
+// The I/O workers, started when the first data file needs them
+#[cfg(target_os = "linux")]
+pub(crate) fn workers() -> Result<&'static IoWorkers> {
+    if let Some(workers) = WORKERS.get() {
+        return Ok(workers);
+    }
+    let _starting = STARTING.lock().unwrap();
+    if let Some(workers) = WORKERS.get() {
+        return Ok(workers);
+    }
+    let workers = IoWorkers::start()?;
+    Ok(WORKERS.get_or_init(|| workers))
+}
+
+#[cfg(target_os = "linux")]
+impl IoWorkers {
+    // Start the workers, one per CPU unless set otherwise, pinned to the
+    // CPUs the process may run on in turn
+    fn start() -> Result<Self> {
+        let cpus = numa::allowed_cpus()?;
+        let count = match COUNT.load(Ordering::Relaxed) {
+            0 => cpus.len().max(1),
+            count => count,
+        };
+        let mut workers = Vec::with_capacity(count);
+        for index in 0..count {
+            let cpu = cpus.get(index % cpus.len().max(1)).copied();
+            workers.push(Self::spawn(index, cpu)?);
+        }
+        info!("Started {} I/O workers", count);
+        Ok(Self { workers })
+    }
+
+    fn spawn(index: usize, cpu: Option<usize>) -> Result<Worker> {
+        let (messages, mut queue) = tokio::sync::mpsc::unbounded_channel::<Message>();
+        let (bulk, mut bulk_queue) = tokio::sync::mpsc::unbounded_channel::<Message>();
+        let (ready, started) = std::sync::mpsc::sync_channel::<std::io::Result<()>>(1);
+        let waker = Waker::new()?;
+        let worker_waker = waker.clone();
+        std::thread::Builder::new().name(format!("io-worker-{}", index)).spawn(move || {
+            // Memory is allocated on the node of the CPU that asks for it, so
+            // pinning keeps the worker's buffers local too
+            if let Some(cpu) = cpu {
+                if let Err(e) = numa::pin_current_thread(&[cpu]) {
+                    warn!("Could not pin I/O worker {} to CPU {}: {}", index, cpu, e);
+                }
+            }
+            let mut ring = match Ring::new(&io_uring::IoUring::builder(), SUBMIT_BATCH as u32, format!("I/O worker {}", index), Some(worker_waker.clone())) {
+                Ok(ring) => ring,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            if let Err(e) = Self::drive(&mut ring, &mut queue, &mut bulk_queue, &worker_waker) {
+                error!("I/O worker {} stopped: {}", index, e);
+                ring.abandon(&e);
+            }
+        })?;
+        started.recv().map_err(|_| anyhow::anyhow!("I/O worker {} exited while starting", index))??;
+        Ok(Worker { node: cpu.and_then(numa::cpu_node), messages, bulk, waker })
+    }
+
+    // Run the operations sent to a worker. Every message queued since the
+    // last pass is taken and every operation started before the ring turns,
+    // so their submission queue entries are handed to the kernel together,
+    // in one io_uring_enter. Bulk operations are taken only while no others
+    // are queued, a few at a time, so others arriving meanwhile wait behind
+    // few of them. Each operation keeps its permit until it completes.
+    fn drive(
+        ring: &mut Ring,
+        queue: &mut tokio::sync::mpsc::UnboundedReceiver<Message>,
+        bulk_queue: &mut tokio::sync::mpsc::UnboundedReceiver<Message>,
+        waker: &Waker,
+    ) -> std::io::Result<()> {
+        // Files one of whose two Forget messages has arrived
+        let mut forgetting = std::collections::HashSet::new();
+        loop {
+            let mut taken = 0;
+            while taken < SUBMIT_BATCH {
+                let Ok(message) = queue.try_recv() else {
+                    break;
+                };
+                Self::take(ring, &mut forgetting, message)?;
+                taken += 1;
+            }
+            if taken == 0 {
+                while taken < BULK_BATCH {
+                    let Ok(message) = bulk_queue.try_recv() else {
+                        break;
+                    };
+                    Self::take(ring, &mut forgetting, message)?;
+                    taken += 1;
+                }
+            }
+            if taken > 0 {
+                ring.turn(false)?;
+                continue;
+            }
+            // Wait for a completion or for more to be sent
+            waker.sleep();
+            if queue.is_empty() && bulk_queue.is_empty() {
+                ring.turn(true)?;
+            }
+            waker.awake();
+        }
+    }
+
+    // Start an operation a worker has taken, or note a Forget
+    fn take(ring: &mut Ring, forgetting: &mut std::collections::HashSet<u64>, message: Message) -> std::io::Result<()> {
+        let (file, source, op, ioprio, permit) = match message {
+            Message::Run { file, source, op, ioprio, permit } => (file, source, op, ioprio, permit),
+            Message::Forget(file) => {
+                if !forgetting.remove(&file) {
+                    forgetting.insert(file);
+                } else {
+                    ring.forget_file(file);
+                }
+                return Ok(());
+            }
+        };
+        if !ring.has_file(file) {
+            match source.try_clone() {
+                Ok(duplicate) => ring.add_file(file, duplicate),
+                Err(e) => {
+                    op.fail(e);
+                    return Ok(());
+                }
+            }
+        }
+        ring.start(file, op, ioprio, Some(permit))
+    }
+
+    // Give the data file at `file_path` to the workers. A file with a NUMA
+    // node, see `numa::node_for`, only uses the workers on that node, or all
+    // of them if none are.
+    pub(crate) fn open(&'static self, file: &std::fs::File, file_path: &str) -> Result<WorkerFile> {
+        let mut workers: Vec<&'static Worker> = self.workers.iter().collect();
+        if let Some(node) = numa::node_for(std::path::Path::new(file_path)) {
+            let local: Vec<&'static Worker> = workers.iter().copied().filter(|worker| worker.node == Some(node)).collect();
+            if local.is_empty() {
+                warn!("No I/O worker runs on NUMA node {}, the node of {}", node, file_path);
+            } else {
+                info!("I/O of {} runs on the {} I/O workers of NUMA node {}", file_path, local.len(), node);
+                workers = local;
+            }
+        }
+        Ok(WorkerFile { id: FILES.fetch_add(1, Ordering::Relaxed), source: Arc::new(file.try_clone()?), workers, next: AtomicUsize::new(0) })
+    }
+}
+
+// A data file's handle on the I/O workers. The workers close their
+// descriptors of the file once it is dropped.
+#[cfg(target_os = "linux")]
+pub(crate) struct WorkerFile {
+    id: u64,
+    source: Arc<std::fs::File>,
+    workers: Vec<&'static Worker>,
+    // Worker the next operation goes to
+    next: AtomicUsize,
+}
+
+#[cfg(target_os = "linux")]
+impl WorkerFile {
+    // Send an operation to the next worker in turn, on the queue for its
+    // priority
+    pub(crate) fn send(&self, op: UringOp, permit: OwnedSemaphorePermit) -> Result<()> {
+        let worker = self.workers[self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()];
+        let priority = io_priority();
+        let queue = if priority == IoPriority::Normal { &worker.messages } else { &worker.bulk };
+        queue
+            .send(Message::Run { file: self.id, source: self.source.clone(), op, ioprio: submission_priority(priority), permit })
+            .map_err(|_| anyhow::anyhow!("I/O worker has stopped"))?;
+        worker.waker.wake();
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for WorkerFile {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            let _ = worker.messages.send(Message::Forget(self.id));
+            let _ = worker.bulk.send(Message::Forget(self.id));
+            worker.waker.wake();
+        }
+    }
+}
@@ -1,253 +1,3532 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::ops::Bound;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use tonic::{transport::Server, Request, Response, Status};
+use futures::Stream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use prost::Message;
+use prost::bytes::Bytes;
+use tonic::{transport::Server, Code, Request, Response, Status, Streaming};
 use anyhow::Result;
 use tracing::{info, error, warn};
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod aligned_buf;
 mod file_io;
-use file_io::{FileIO, create_file_io};
+use file_io::{FileIO, Durability, align_up, align_down, sector_size, with_priority};
+
+mod file_manager;
+use file_manager::{FileManager, FileRegistry, RequestMetadata, RecordKey, Extent, Chunk, GenerationMismatch};
+use file_manager::{ChangeKind, RecordEvent, QuotaExceeded, StaleFencingToken, RecordLocked};
+use space::Space;
+
+mod admin;
+use admin::AdminServiceImpl;
+
+mod upload;
+use upload::{Upload, UploadedPart, UploadMap};
+
+mod index_store;
+
+mod wal;
+use wal::LogFailed;
+
+mod snapshot;
+
+mod record_format;
+mod encryption;
+mod compression;
+use compression::{Compressor, Payload};
+use record_format::Framing;
+use encryption::{DecryptionFailed, EncryptionKeyMissing, RecordCipher};
+mod segment;
+mod retention;
+use retention::RetentionPolicy;
+mod versions;
+use versions::VersionPolicy;
+mod scrubber;
+use scrubber::{ScrubConfig, ScrubStatus};
+mod capacity;
+use capacity::Watermarks;
+mod migrate;
+mod archive;
+mod schedule;
+use schedule::Schedule;
+mod backup;
+use backup::{Backup, BackupConfig};
+mod fsck;
+mod dump;
+mod crash_test;
+mod commit;
+mod bloom;
+mod space;
+mod double_write;
+mod block_device;
+#[cfg(feature = "spdk")]
+mod spdk;
+mod mem_file_io;
+mod faulty_file_io;
+mod volume;
+mod numa;
+mod io_workers;
+mod coalesce;
+mod read_cache;
+mod admission;
+use admission::{Admission, Admitted, RequestClass};
+mod rate_limit;
+use rate_limit::{IdentifyBy, RateLimited, RateLimiter};
+mod io_timeout;
+mod metadata_store;
+#[cfg(target_os = "linux")]
+mod uring;
+use metadata_store::MetadataStoreKind;
+use commit::EpochState;
+use record_format::ChecksumMismatch;
+use io_timeout::{IoTimedOut, WritesFenced};
 
 // Include the generated protobuf code
 pub mod fileservice {
     tonic::include_proto!("fileservice");
 }
 
+pub mod adminservice {
+    tonic::include_proto!("admin");
+}
+
 mod client;
 
 use fileservice::file_service_server::{FileService, FileServiceServer};
+use adminservice::admin_service_server::AdminServiceServer;
 use fileservice::{WriteRequest, WriteResponse, ReadRequest, ReadResponse};
+use fileservice::{WriteAtRequest, OverwriteRequest};
+use fileservice::{BatchWriteRequest, BatchWriteResponse};
+use fileservice::{WriteBatchAtomicRequest, WriteBatchAtomicResponse};
+use fileservice::{BatchReadRequest, BatchReadResponse};
+use fileservice::{DeleteRequest, DeleteResponse, BulkDeleteRequest, BulkDeleteResponse};
+use fileservice::{UndeleteRequest, UndeleteResponse};
+use fileservice::{ListRequestsRequest, ListRequestsResponse, RequestInfo};
+use fileservice::{StatRequest, StatResponse};
+use fileservice::{RenameRequest, RenameResponse, AliasRequest, AliasResponse};
+use fileservice::{AcquireLeaseRequest, AcquireLeaseResponse};
+use fileservice::{LockRequest, LockResponse, UnlockRequest, UnlockResponse};
+use fileservice::{InitUploadRequest, InitUploadResponse, UploadPartRequest, UploadPartResponse};
+use fileservice::{CompleteUploadRequest, AbortUploadRequest, AbortUploadResponse};
+use fileservice::{ExistsRequest, ExistsResponse};
+use fileservice::{WaitForDurableRequest, WaitForDurableResponse};
+use fileservice::{ServerInfoRequest, ServerInfoResponse};
+use fileservice::{PipelineRequest, PipelineResponse, pipeline_request, pipeline_response};
+use fileservice::{WatchRequest, WatchEvent, TailChangesRequest};
+use fileservice::{QuotaExceededDetails, OverloadedDetails, RateLimitedDetails, DuplicatePolicy};
+
+// Maximum number of completed pipeline responses buffered per stream
+const PIPELINE_QUEUE_DEPTH: usize = 256;
+
+// Largest read BatchRead will build by coalescing adjacent extents
+const MAX_COALESCED_READ: u64 = 1024 * 1024;
+
+// Page size limits for ListRequests
+const DEFAULT_LIST_PAGE_SIZE: usize = 100;
+const MAX_LIST_PAGE_SIZE: usize = 1000;
+
+// Largest total size of the keys and values attached to one record
+const MAX_USER_METADATA_BYTES: usize = 8 * 1024;
+
+// Record lock lifetime when the request leaves it unset, and the longest allowed
+const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(30);
+const MAX_LOCK_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How long a read waits for the index to catch up with its session token
+const SESSION_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long WaitForDurable waits when the request sets no timeout
+const DURABLE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
 
-// Request metadata for tracking offsets
-#[derive(Debug, Clone)]
-struct RequestMetadata {
-    offset: u64,
-    size: u64,
+// How often expired records are swept from the request maps
+const EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long a multipart upload may stay open before its parts are discarded,
+// and how often uploads past that are swept
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+const UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often data files are checked against the automatic compaction threshold
+const AUTO_COMPACT_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often segment retention policies are applied
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Pause between checksum scrubber passes unless --scrub-interval is given
+const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How often free space on the data directory's filesystem is checked
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Per-write options carried from the request through to the index commit
+#[derive(Debug, Clone, Default)]
+struct WriteOptions {
+    expected_generation: Option<u64>,
+    ttl: Option<Duration>,
+    fencing_token: Option<u64>,
+    user_metadata: HashMap<String, String>,
+    // Lock ID the caller holds on the record, if it is locked
+    lock_id: String,
+    // Set for an overwrite in place, which writes over the live record
+    in_place: bool,
 }
 
-// File manager for O_DIRECT operations
-struct FileManager {
-    file: Box<dyn FileIO + Send + Sync>,
-    current_offset: u64,
-    request_map: Arc<Mutex<HashMap<String, RequestMetadata>>>,
+impl WriteOptions {
+    fn from_request(req: &WriteRequest) -> Self {
+        Self {
+            expected_generation: req.expected_generation,
+            ttl: req.ttl_ms.map(Duration::from_millis),
+            fencing_token: req.fencing_token,
+            user_metadata: req.metadata.clone(),
+            lock_id: req.lock_id.clone(),
+            in_place: false,
+        }
+    }
+
+    // Index entry for a record framed as `framing` whose payload starts at
+    // `offset`; the generation is assigned at commit
+    fn metadata(&self, offset: u64, framing: &Framing, written_at: SystemTime) -> RequestMetadata {
+        RequestMetadata {
+            offset,
+            size: framing.size,
+            header_len: framing.header_len,
+            written_at,
+            checksum: Some(framing.checksum),
+            encrypted: framing.encrypted,
+            compression: framing.compression,
+            generation: 0,
+            expires_at: self.ttl.map(|ttl| written_at + ttl),
+            user_metadata: self.user_metadata.clone(),
+            chunks: Vec::new(),
+        }
+    }
 }
 
-impl FileManager {
-    async fn new(file_path: &str) -> Result<Self> {
-        let file = create_file_io(file_path).await?;
-        
-        // Get file size for current offset
-        let metadata = file.metadata().await?;
-        let current_offset = metadata.len();
-        
-        Ok(Self {
-            file,
-            current_offset,
-            request_map: Arc::new(Mutex::new(HashMap::new())),
-        })
+// Reject user metadata too large to keep in the in-memory index
+#[allow(clippy::result_large_err)]
+fn validate_user_metadata(metadata: &HashMap<String, String>) -> Result<(), Status> {
+    let size: usize = metadata.iter().map(|(key, value)| key.len() + value.len()).sum();
+    if size > MAX_USER_METADATA_BYTES {
+        return Err(Status::invalid_argument(format!(
+            "User metadata is {} bytes, limit is {}", size, MAX_USER_METADATA_BYTES
+        )));
+    }
+    Ok(())
+}
+
+// Sparse records are only written one at a time, by WriteData
+#[allow(clippy::result_large_err)]
+fn reject_sparse(entry: &WriteRequest) -> Result<(), Status> {
+    if entry.logical_size.is_some() || !entry.ranges.is_empty() {
+        return Err(Status::invalid_argument(format!("Request ID {} is sparse; batches cannot hold sparse records", entry.request_id)));
+    }
+    Ok(())
+}
+
+// Maximum number of undelivered events buffered per Watch stream
+const WATCH_QUEUE_DEPTH: usize = 256;
+
+// Convert an index change into its wire representation
+fn watch_event(event: RecordEvent) -> WatchEvent {
+    let kind = match event.kind {
+        ChangeKind::Written => fileservice::ChangeKind::Written,
+        ChangeKind::Overwritten => fileservice::ChangeKind::Overwritten,
+        ChangeKind::Deleted => fileservice::ChangeKind::Deleted,
+    };
+    WatchEvent {
+        kind: kind as i32,
+        namespace: event.key.namespace,
+        request_id: event.key.request_id,
+        offset: event.metadata.offset,
+        size: event.metadata.size,
+        generation: event.metadata.generation,
+        sequence: event.sequence,
+    }
+}
+
+// Milliseconds since the Unix epoch, as exposed over the API
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// Report a client over its rate, attaching which rate as error details and
+// the wait as `grpc-retry-pushback-ms`
+fn rate_limited_status(limited: RateLimited) -> Status {
+    let retry_after_ms = limited.retry_after.as_millis().max(1) as u64;
+    let details = RateLimitedDetails { limit: limited.limit.to_string(), rate: limited.rate, retry_after_ms };
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    metadata.insert("grpc-retry-pushback-ms", retry_after_ms.into());
+    Status::with_details_and_metadata(Code::ResourceExhausted, limited.to_string(), Bytes::from(details.encode_to_vec()), metadata)
+}
+
+// Priority class a request asks for in its `x-priority` header:
+// `interactive`, the default, or `bulk`
+#[allow(clippy::result_large_err)]
+fn request_class<T>(request: &Request<T>) -> Result<RequestClass, Status> {
+    let Some(value) = request.metadata().get("x-priority") else {
+        return Ok(RequestClass::Interactive);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(RequestClass::parse)
+        .ok_or_else(|| Status::invalid_argument(format!("x-priority must be interactive or bulk, got {:?}", value)))
+}
+
+// Report an exceeded quota, attaching the namespace's usage as error details
+fn quota_exceeded_status(exceeded: QuotaExceeded) -> Status {
+    let details = QuotaExceededDetails {
+        namespace: exceeded.namespace.clone(),
+        usage: exceeded.usage,
+        quota: exceeded.quota,
+        requested: exceeded.requested,
+    };
+    Status::with_details(Code::ResourceExhausted, exceeded.to_string(), Bytes::from(details.encode_to_vec()))
+}
+
+// Status for a write that lost a race with a lock holder, a newer lease
+// holder or a concurrent write, if that is why it failed
+fn write_rejection(e: &anyhow::Error) -> Option<Status> {
+    if let Some(locked) = e.downcast_ref::<RecordLocked>() {
+        warn!("Write rejected: {}", locked);
+        return Some(Status::aborted(locked.to_string()));
+    }
+    let reason = e
+        .downcast_ref::<GenerationMismatch>()
+        .map(ToString::to_string)
+        .or_else(|| e.downcast_ref::<StaleFencingToken>().map(ToString::to_string))?;
+    warn!("Conditional write rejected: {}", reason);
+    Some(Status::failed_precondition(reason))
+}
+
+// Status for a change that was not acknowledged because its log entry did not
+// reach the disk; the index was rolled back to what did
+fn log_failed_status(e: &anyhow::Error) -> Option<Status> {
+    let failed = e.downcast_ref::<LogFailed>()?;
+    error!("{}", failed);
+    Some(Status::internal(failed.to_string()))
+}
+
+// Status for a change to an index that failed, which only happens when its
+// log entry could not be written
+fn index_status(e: anyhow::Error) -> Status {
+    log_failed_status(&e).unwrap_or_else(|| Status::internal(e.to_string()))
+}
+
+// Wait for the changes just made to a file's index to reach its log on disk
+// before acknowledging them
+async fn sync_index(manager: &Mutex<FileManager>) -> Result<(), Status> {
+    file_manager::sync_log(manager).await.map_err(index_status)
+}
+
+// Status for an operation that failed because data file I/O timed out, or
+// because a file is fenced until writes that timed out complete
+fn io_timeout_status(e: &anyhow::Error) -> Option<Status> {
+    if let Some(timed_out) = e.downcast_ref::<IoTimedOut>() {
+        return Some(Status::deadline_exceeded(timed_out.to_string()));
     }
+    e.downcast_ref::<WritesFenced>().map(|fenced| Status::unavailable(fenced.to_string()))
 }
 
 // gRPC service implementation
+#[derive(Clone)]
 pub struct FileServiceImpl {
-    file_manager: Arc<Mutex<FileManager>>,
+    files: Arc<FileRegistry>,
+    // Route namespaced requests without an explicit file_id to a data file
+    // named after the namespace
+    file_per_namespace: bool,
+    // Set through the admin API; rejects writes and deletes while true
+    maintenance: Arc<AtomicBool>,
+    // Set by the capacity monitor above the disk high watermark; rejects
+    // writes while true
+    disk_full: Arc<AtomicBool>,
+    // Maximum bytes of record data per namespace in each data file
+    namespace_quota: Option<u64>,
+    // Applied to writes of existing request IDs that do not choose a policy
+    duplicate_policy: DuplicatePolicy,
+    // Multipart uploads in progress
+    uploads: Arc<Mutex<UploadMap>>,
+    // Encrypts new records at rest and decrypts encrypted ones, if a key is configured
+    cipher: Option<Arc<RecordCipher>>,
+    // Compresses new records, if configured
+    compressor: Option<Compressor>,
+    // Records whose stored payload is larger than this are split into
+    // extents of at most this many bytes, if set
+    max_extent_size: Option<u64>,
+    // Bound the storage operations in flight, if set: interactive ones, and
+    // bulk ones if they have a budget of their own
+    admission: Option<Arc<Admission>>,
+    bulk_admission: Option<Arc<Admission>>,
+    // Per-client rates of storage operations and bytes, if configured
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+// Who made a storage request, and what it asks for
+#[derive(Clone)]
+struct Caller {
+    class: RequestClass,
+    // The client as rate limits know it, if any are configured
+    client: Option<String>,
+    // Size of the request message, counted against the client's byte rate
+    bytes: u64,
 }
 
 impl FileServiceImpl {
-    async fn new(file_path: &str) -> Result<Self> {
-        let file_manager = FileManager::new(file_path).await?;
+    async fn new(
+        files: FileRegistry,
+        file_per_namespace: bool,
+        namespace_quota: Option<u64>,
+        duplicate_policy: DuplicatePolicy,
+        cipher: Option<RecordCipher>,
+        compressor: Option<Compressor>,
+        max_extent_size: Option<u64>,
+    ) -> Result<Self> {
+        // Open the default file eagerly so startup fails fast on a bad data directory
+        files.get("").await?;
+
         Ok(Self {
-            file_manager: Arc::new(Mutex::new(file_manager)),
+            files: Arc::new(files),
+            file_per_namespace,
+            maintenance: Arc::new(AtomicBool::new(false)),
+            disk_full: Arc::new(AtomicBool::new(false)),
+            namespace_quota,
+            duplicate_policy,
+            uploads: Arc::new(Mutex::new(UploadMap::new())),
+            cipher: cipher.map(Arc::new),
+            compressor,
+            max_extent_size,
+            admission: None,
+            bulk_admission: None,
+            rate_limiter: None,
+        })
+    }
+
+    // Refuse storage operations beyond `limit` in flight at once, and bulk
+    // ones beyond `bulk_limit`, if set, instead of counting them with the
+    // others
+    fn with_admission_limits(mut self, limit: Option<usize>, bulk_limit: Option<usize>) -> Self {
+        self.admission = limit.map(Admission::new);
+        self.bulk_admission = bulk_limit.map(Admission::new);
+        self
+    }
+
+    // Admit a storage operation, if their number in flight is bounded. A
+    // refusal is RESOURCE_EXHAUSTED with how long to wait before retrying in
+    // its details and in `grpc-retry-pushback-ms`, which gRPC client retry
+    // policies honour.
+    #[allow(clippy::result_large_err)]
+    fn admit(&self, class: RequestClass) -> Result<Option<Admitted>, Status> {
+        let admission = match class {
+            RequestClass::Interactive => self.admission.as_ref(),
+            RequestClass::Bulk => self.bulk_admission.as_ref().or(self.admission.as_ref()),
+        };
+        let Some(admission) = admission else {
+            return Ok(None);
+        };
+        admission.admit().map(Some).map_err(|overloaded| {
+            let retry_after_ms = overloaded.retry_after.as_millis() as u64;
+            let details = OverloadedDetails { in_flight_limit: overloaded.limit as u64, retry_after_ms };
+            let mut metadata = tonic::metadata::MetadataMap::new();
+            metadata.insert("grpc-retry-pushback-ms", retry_after_ms.into());
+            Status::with_details_and_metadata(Code::ResourceExhausted, overloaded.to_string(), Bytes::from(details.encode_to_vec()), metadata)
+        })
+    }
+
+    // Limit each client's storage operations to the given rates
+    fn with_rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter.map(Arc::new);
+        self
+    }
+
+    // Class and client of a streaming request; each message on it is sized
+    // as it arrives
+    #[allow(clippy::result_large_err)]
+    fn stream_caller<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let client = self.rate_limiter.as_ref().map(|limiter| {
+            let token = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .filter(|_| limiter.identify_by() == IdentifyBy::Token);
+            match (token, request.remote_addr()) {
+                (Some(token), _) => token.to_string(),
+                (None, Some(address)) => address.ip().to_string(),
+                (None, None) => "unknown".to_string(),
+            }
+        });
+        Ok(Caller { class: request_class(request)?, client, bytes: 0 })
+    }
+
+    // Class, client and size of a storage request
+    #[allow(clippy::result_large_err)]
+    fn caller<T: Message>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        Ok(Caller { bytes: request.get_ref().encoded_len() as u64, ..self.stream_caller(request)? })
+    }
+
+    // Run a storage operation once its client's rates and the in-flight
+    // bounds let it through, its data file I/O at its class's priority. The
+    // response's size counts against the client's byte rate too.
+    async fn storage_op<T: Message>(&self, caller: &Caller, op: impl std::future::Future<Output = Result<T, Status>>) -> Result<T, Status> {
+        let limited = self.rate_limiter.as_ref().zip(caller.client.as_deref());
+        if let Some((limiter, client)) = limited {
+            limiter.admit(client, caller.bytes).map_err(rate_limited_status)?;
+        }
+        let _admitted = self.admit(caller.class)?;
+        let response = with_priority(caller.class.io_priority(), op).await?;
+        if let Some((limiter, client)) = limited {
+            limiter.charge(client, response.encoded_len() as u64);
+        }
+        Ok(response)
+    }
+
+    // Compress a payload for storage, if compression is configured
+    fn encode_payload(&self, data: Vec<u8>) -> Payload {
+        Payload::encode(data, self.compressor.as_ref())
+    }
+
+    // Length of the header written before a payload
+    fn header_len(&self, key: &RecordKey, payload: &Payload) -> u64 {
+        record_format::header_len(key, self.cipher.is_some(), payload.codec.is_some())
+    }
+
+    // Bytes a record takes in the data file before padding
+    fn record_len(&self, key: &RecordKey, payload: &Payload) -> u64 {
+        self.header_len(key, payload) + payload.data.len() as u64
+    }
+
+    // Reserve space for a record: one extent, or for a payload larger than
+    // the maximum extent size one extent per chunk, the first also holding
+    // the header
+    fn reserve_record(&self, space: &Space, key: &RecordKey, payload: &Payload) -> Vec<Extent> {
+        let stored_size = payload.data.len() as u64;
+        match self.max_extent_size {
+            Some(max) if stored_size > max => {
+                let mut extents = vec![space.reserve(self.header_len(key, payload) + max)];
+                let mut remaining = stored_size - max;
+                while remaining > 0 {
+                    let length = remaining.min(max);
+                    extents.push(space.reserve(length));
+                    remaining -= length;
+                }
+                extents
+            }
+            _ => vec![space.reserve(self.record_len(key, payload))],
+        }
+    }
+
+    // Write a record into the extents `reserve_record` reserved for it
+    async fn write_record(&self, manager: &Mutex<FileManager>, key: RecordKey, data: Payload, extents: Vec<Extent>, options: WriteOptions) -> Result<WriteResponse, Status> {
+        match extents.as_slice() {
+            [extent] => self.write_reserved(manager, key, data, extent.offset, options).await,
+            _ => self.write_chunked(manager, key, data, extents, options).await,
+        }
+    }
+
+    // Apply the duplicate policy to a write whose request ID may already exist.
+    // Returns the response to send instead of writing, if any. Conditional
+    // writes opt into replacing the record and skip the policy.
+    #[allow(clippy::result_large_err)]
+    fn resolve_duplicate(&self, file_manager: &FileManager, key: &RecordKey, on_duplicate: i32, options: &mut WriteOptions) -> Result<Option<WriteResponse>, Status> {
+        if options.expected_generation.is_some() {
+            return Ok(None);
+        }
+        let policy = match DuplicatePolicy::try_from(on_duplicate) {
+            Ok(DuplicatePolicy::Unspecified) => self.duplicate_policy,
+            Ok(policy) => policy,
+            Err(_) => return Err(Status::invalid_argument(format!("Unknown duplicate policy {}", on_duplicate))),
+        };
+
+        match (policy, file_manager.lookup(key)) {
+            (DuplicatePolicy::Reject, Some(existing)) => Err(Status::already_exists(format!(
+                "Request ID {} already exists at offset {}", key.request_id, existing.offset
+            ))),
+            // Also fail the commit if a concurrent write creates the record first
+            (DuplicatePolicy::Reject, None) => {
+                options.expected_generation = Some(0);
+                Ok(None)
+            }
+            (DuplicatePolicy::ReturnExisting, Some(existing)) => {
+                info!("Request ID {} already written, returning existing offset {}", key.request_id, existing.offset);
+                Ok(Some(WriteResponse {
+                    request_id: key.request_id.clone(),
+                    offset: existing.offset,
+                    success: true,
+                    error_message: String::new(),
+                    generation: existing.generation,
+                    session_token: file_manager.session_token(),
+                    durable_epoch: file_manager.epochs.current(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Hold a read until the index reflects every write covered by the
+    // caller's session token
+    async fn await_session(&self, manager: &Mutex<FileManager>, token: &str) -> Result<(), Status> {
+        if token.is_empty() {
+            return Ok(());
+        }
+        let (epoch, required) = file_manager::parse_session_token(token).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut applied = {
+            let file_manager = manager.lock().unwrap();
+            // Tokens from another file or an earlier run have nothing to wait for
+            if file_manager.epoch != epoch {
+                return Ok(());
+            }
+            file_manager.sequence.subscribe()
+        };
+
+        let caught_up = matches!(tokio::time::timeout(SESSION_WAIT_TIMEOUT, applied.wait_for(|sequence| *sequence >= required)).await, Ok(Ok(_)));
+        if !caught_up {
+            return Err(Status::unavailable(format!("Index has not caught up with session token {}", token)));
+        }
+        Ok(())
+    }
+
+    // Reject a write that would push its namespace over the configured quota
+    #[allow(clippy::result_large_err)]
+    fn check_quota(&self, file_manager: &FileManager, namespace: &str, additional: u64) -> Result<(), Status> {
+        match self.namespace_quota {
+            Some(quota) => file_manager.check_quota(namespace, additional, quota).map_err(quota_exceeded_status),
+            None => Ok(()),
+        }
+    }
+
+    // Data-path mutations are refused while the server is in maintenance mode
+    #[allow(clippy::result_large_err)]
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.maintenance.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("Server is in maintenance mode"));
+        }
+        Ok(())
+    }
+
+    // Writes are refused while the data directory's filesystem is above the
+    // disk high watermark; reads and deletes still go through
+    #[allow(clippy::result_large_err)]
+    fn check_capacity(&self) -> Result<(), Status> {
+        if self.disk_full.load(Ordering::SeqCst) {
+            return Err(Status::resource_exhausted("Disk usage is above the high watermark, writes are rejected"));
+        }
+        Ok(())
+    }
+
+    // File ID a request is routed to: its namespace, when each namespace has
+    // its own data file and none is named
+    fn routed_file_id<'a>(&self, file_id: &'a str, namespace: &'a str) -> &'a str {
+        if file_id.is_empty() && self.file_per_namespace { namespace } else { file_id }
+    }
+
+    // Resolve a request's file ID (or namespace, when each namespace has its
+    // own data file) to its file manager
+    async fn file_manager(&self, file_id: &str, namespace: &str) -> Result<Arc<Mutex<FileManager>>, Status> {
+        let file_id = self.routed_file_id(file_id, namespace);
+        file_manager::resolve_file_id(file_id).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.files.get(file_id).await.map_err(|e| {
+            Status::internal(format!("Failed to open file {}: {}", file_id, e))
         })
     }
-    
-    async fn perform_write(&self, mut file: Box<dyn FileIO + Send + Sync>, offset: u64, data: Vec<u8>, request_id: String) -> Result<()> {
+
+    // Write data as a record at `offset` and index its payload, which follows
+    // the record header
+    async fn perform_write(&self, manager: &Mutex<FileManager>, file: Arc<dyn FileIO + Send + Sync>, offset: u64, data: Payload, key: RecordKey, options: WriteOptions) -> Result<u64> {
         let start = Instant::now();
-        let size = data.len() as u64;
-        
+        let size = data.size;
+        let (record, framing) = record_format::frame(&key, data, self.cipher.as_deref());
+
+        // An overwrite in place stages its image first, so a crash tearing
+        // the live record can be repaired from it
+        let double_write = if options.in_place { manager.lock().unwrap().double_write.clone() } else { None };
+        let record = match &double_write {
+            Some(buffer) => double_write::stage(buffer, file.as_ref(), offset, record).await?,
+            None => record,
+        };
+
         // Use trait-based async I/O
-        file.write_at(data, offset).await?;
-        
-        // Update metadata
-        {
-            let mut file_manager = self.file_manager.lock().unwrap();
-            let mut request_map = file_manager.request_map.lock().unwrap();
-            request_map.insert(request_id.clone(), RequestMetadata { offset, size });
-            drop(request_map); // Release the request_map lock
-            file_manager.current_offset += size;
+        let written = file.write_at(record, offset).await;
+        if let Some(buffer) = &double_write {
+            buffer.lock().unwrap().finish();
         }
-        
+        written?;
+
+        // Update metadata
+        let generation = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.check_fence(&key.namespace, options.fencing_token)?;
+            file_manager.check_lock(&key, &options.lock_id)?;
+            let metadata = options.metadata(offset + framing.header_len, &framing, SystemTime::now());
+            file_manager.commit_write(&key, options.expected_generation, metadata)?
+        };
+        file_manager::sync_log(manager).await?;
+
         let duration = start.elapsed();
-        info!("Written {} bytes at offset {} for request {} in {:?}", size, offset, request_id, duration);
-        
+        info!("Written {} bytes at offset {} for request {} in {:?}", size, offset, key.request_id, duration);
+
         // Warn if operation takes too long (potential bottleneck)
         if duration.as_millis() > 100 {
-            warn!("Slow write operation: {}ms for request {}", duration.as_millis(), request_id);
+            warn!("Slow write operation: {}ms for request {}", duration.as_millis(), key.request_id);
         }
-        
-        Ok(())
+
+        Ok(generation)
     }
-    
-    async fn perform_read(&self, mut file: Box<dyn FileIO + Send + Sync>, offset: u64, size: u64, request_id: String) -> Result<Vec<u8>> {
-        let data = file.read_at(size, offset).await?;
-        info!("Read {} bytes from offset {} for request {}", size, offset, request_id);
+
+    // Read `length` bytes at `range_offset` within a record. A record with a
+    // checksum is read whole and verified before the range is sliced out; an
+    // encrypted one is read with its header, which holds its nonce and tag,
+    // and decrypted; a compressed one is decompressed. A large object is
+    // reassembled from its chunks, which are read in parallel. The bytes
+    // returned are a slice of the buffer read into wherever the payload is
+    // stored as is.
+    async fn perform_read(&self, file: Arc<dyn FileIO + Send + Sync>, metadata: &RequestMetadata, range_offset: u64, range_length: u64, request_id: &str) -> Result<Bytes> {
+        let range = range_offset as usize..(range_offset + range_length) as usize;
+        if !metadata.chunks.is_empty() {
+            let stored = record_format::read_stored(file.as_ref(), metadata, metadata.encrypted).await?;
+            let data = record_format::open_payload(self.cipher.as_deref(), request_id, metadata, stored)?.slice(range);
+            info!("Read {} bytes in {} chunks for request {}", metadata.stored_size(), metadata.chunks.len(), request_id);
+            return Ok(data);
+        }
+        let whole = metadata.checksum.is_some() || metadata.encrypted || metadata.compression.is_some();
+        let (start, length) = if metadata.encrypted {
+            (metadata.offset - metadata.header_len, metadata.header_len + metadata.stored_size())
+        } else if whole {
+            (metadata.offset, metadata.stored_size())
+        } else {
+            (metadata.offset + range_offset, range_length)
+        };
+
+        // Read the aligned blocks containing the bytes in one O_DIRECT read,
+        // then slice the bytes out of them. Blocks already in the page cache,
+        // once I/O has fallen back to buffered, are read without waiting.
+        let block_start = align_down(start);
+        let block_length = align_up(start + length) - block_start;
+        let skip = (start - block_start) as usize;
+        let blocks = match file.try_read_at(block_length, block_start)? {
+            Some(blocks) => blocks,
+            None => file.read_at(block_length, block_start).await?,
+        };
+        let mut data = blocks.slice(skip..skip + length as usize);
+
+        if whole {
+            data = record_format::open_payload(self.cipher.as_deref(), request_id, metadata, data)?.slice(range);
+        }
+        info!("Read {} bytes from offset {} for request {}", length, start, request_id);
         Ok(data)
     }
-    
 
-}
+    async fn handle_write(&self, req: WriteRequest) -> Result<WriteResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let mut options = WriteOptions::from_request(&req);
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        validate_user_metadata(&req.metadata)?;
+        let on_duplicate = req.on_duplicate;
+        if req.logical_size.is_some() || !req.ranges.is_empty() {
+            let ranges = std::iter::once((0, req.data)).chain(req.ranges.into_iter().map(|range| (range.offset, range.data))).collect();
+            return self.write_sparse(&manager, key, req.logical_size, ranges, on_duplicate, options).await;
+        }
+        let data = self.encode_payload(req.data);
 
-#[tonic::async_trait]
-impl FileService for FileServiceImpl {
-    async fn write_data(
-        &self,
-        request: Request<WriteRequest>,
-    ) -> Result<Response<WriteResponse>, Status> {
-        let req = request.into_inner();
-        let request_id = req.request_id;
-        let data = req.data;
-        
-        info!("Received write request: {}", request_id);
-        
-                // Get current offset
-        let offset = {
-            let file_manager = self.file_manager.lock().unwrap();
-            file_manager.current_offset
-        };
-        
-        // Get file handle
-        let file_clone = {
-            let file_manager = self.file_manager.lock().unwrap();
-            file_manager.file.try_clone().map_err(|e| {
-                Status::internal(format!("Failed to clone file: {}", e))
-            })?
+        info!("Received write request: {}", key.request_id);
+
+        let space = {
+            let file_manager = manager.lock().unwrap();
+            if let Some(response) = self.admit_write(&file_manager, &key, on_duplicate, data.size, &mut options)? {
+                return Ok(response);
+            }
+            file_manager.space.clone()
         };
-        
-        // Perform the actual write
-        let result = self.perform_write(file_clone, offset, data.clone(), request_id.clone()).await;
-        
+        // Reserve the aligned extents up front so concurrent writes never
+        // overlap; this takes no lock on the file manager
+        let extents = self.reserve_record(&space, &key, &data);
+
+        self.write_record(&manager, key, data, extents, options).await
+    }
+
+    // Checks a new record of `size` bytes must pass before space is reserved
+    // for it. Returns the response to send instead of writing, if the
+    // duplicate policy settles the write.
+    #[allow(clippy::result_large_err)]
+    fn admit_write(&self, file_manager: &FileManager, key: &RecordKey, on_duplicate: i32, size: u64, options: &mut WriteOptions) -> Result<Option<WriteResponse>, Status> {
+        // Fail fast on a stale fencing token, a lock held by someone else
+        // or a stale generation; all are checked again at commit
+        file_manager.check_fence(&key.namespace, options.fencing_token).map_err(|stale| {
+            Status::failed_precondition(stale.to_string())
+        })?;
+        file_manager.check_lock(key, &options.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+        if let Some(expected) = options.expected_generation {
+            let actual = file_manager.current_generation(key);
+            if actual != expected {
+                let request_id = key.request_id.clone();
+                return Err(Status::failed_precondition(GenerationMismatch { request_id, expected, actual }.to_string()));
+            }
+        }
+
+        if let Some(response) = self.resolve_duplicate(file_manager, key, on_duplicate, options)? {
+            return Ok(Some(response));
+        }
+
+        // Replacing a record only charges the growth against the quota
+        let replaced = file_manager.lookup(key).map_or(0, |existing| existing.size);
+        self.check_quota(file_manager, &key.namespace, size.saturating_sub(replaced))?;
+        Ok(None)
+    }
+
+    // Write a sparse record of `logical_size` bytes (by default, up to the
+    // end of the last range) holding the given ranges. The record takes an
+    // extent of its full size, but only the blocks holding written ranges
+    // are written; holes are punched for the rest, so they read as zeros and
+    // take no disk space. The header is padded to a whole block so holes
+    // line up with the file system's. Sparse records are not compressed, and
+    // not encrypted, as encrypted zeros would not read as zeros.
+    async fn write_sparse(&self, manager: &Mutex<FileManager>, key: RecordKey, logical_size: Option<u64>, ranges: Vec<(u64, Vec<u8>)>, on_duplicate: i32, mut options: WriteOptions) -> Result<WriteResponse, Status> {
+        if self.cipher.is_some() {
+            return Err(Status::failed_precondition("Sparse records cannot be written while encryption at rest is enabled"));
+        }
+        let ranges_end = ranges.iter().map(|(offset, data)| offset + data.len() as u64).max().unwrap_or(0);
+        let logical_size = logical_size.unwrap_or(ranges_end);
+        if ranges_end > logical_size {
+            return Err(Status::invalid_argument(format!("Ranges end at byte {}, past the logical size of {} bytes", ranges_end, logical_size)));
+        }
+        let written: u64 = ranges.iter().map(|(_, data)| data.len() as u64).sum();
+
+        info!("Received sparse write request: {} ({} bytes written of {})", key.request_id, written, logical_size);
+
+        let header_len = align_up(record_format::header_len(&key, false, false));
+        let space = {
+            let file_manager = manager.lock().unwrap();
+            if let Some(response) = self.admit_write(&file_manager, &key, on_duplicate, logical_size, &mut options)? {
+                return Ok(response);
+            }
+            file_manager.space.clone()
+        };
+        let extent = space.reserve(header_len + logical_size);
+        // Fetched after reserving: a compaction swaps the file only while no
+        // write is in flight
+        let file_clone = manager.lock().unwrap().file.clone();
+
+        let start = Instant::now();
+        let written = {
+            let file = file_clone;
+            record_format::write_sparse(file.as_ref(), &key, extent.offset, header_len, logical_size, &ranges).await
+        };
+
+        let offset = extent.offset + header_len;
+        let result = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.space.finish(extent.offset);
+            written.and_then(|checksum| {
+                file_manager.check_fence(&key.namespace, options.fencing_token)?;
+                file_manager.check_lock(&key, &options.lock_id)?;
+                let framing = Framing { header_len, size: logical_size, checksum, encrypted: false, compression: None };
+                let mut metadata = options.metadata(offset, &framing, SystemTime::now());
+                // Byte ranges are read without reading the whole record, so
+                // there is no checksum to verify them against; the header
+                // keeps it
+                metadata.checksum = None;
+                file_manager.commit_write(&key, options.expected_generation, metadata)
+            })
+        };
+        let result = match result {
+            Ok(generation) => file_manager::sync_log(manager).await.map(|()| generation),
+            Err(e) => Err(e),
+        };
+        let mut file_manager = manager.lock().unwrap();
         match result {
-            Ok(_) => {
-                let response = WriteResponse {
-                    request_id,
+            Ok(generation) => {
+                info!("Written sparse record {} of {} bytes at offset {} in {:?}", key.request_id, logical_size, offset, start.elapsed());
+                Ok(WriteResponse {
+                    request_id: key.request_id,
                     offset,
                     success: true,
                     error_message: String::new(),
-                };
-                Ok(Response::new(response))
+                    generation,
+                    session_token: file_manager.session_token(),
+                    durable_epoch: file_manager.epochs.current(),
+                })
             }
             Err(e) => {
-                error!("Write failed for request {}: {}", request_id, e);
-                let response = WriteResponse {
-                    request_id,
+                if let Some(status) = write_rejection(&e) {
+                    file_manager.release_extent(extent);
+                    return Err(status);
+                }
+                // A timed-out write may still land, so its extent is not
+                // released
+                if let Some(status) = io_timeout_status(&e) {
+                    return Err(status);
+                }
+                if let Some(status) = log_failed_status(&e) {
+                    return Err(status);
+                }
+
+                error!("Write failed for request {}: {}", key.request_id, e);
+                Ok(WriteResponse {
+                    request_id: key.request_id,
                     offset: 0,
                     success: false,
                     error_message: e.to_string(),
-                };
-                Ok(Response::new(response))
+                    generation: 0,
+                    session_token: String::new(),
+                    durable_epoch: 0,
+                })
             }
         }
     }
 
-    async fn read_data(
-        &self,
-        request: Request<ReadRequest>,
-    ) -> Result<Response<ReadResponse>, Status> {
-        let req = request.into_inner();
-        let request_id = req.request_id;
-        
-        info!("Received read request: {}", request_id);
-        
-        // Get metadata
-        let metadata = {
-            let file_manager = self.file_manager.lock().unwrap();
-            let request_map = file_manager.request_map.lock().unwrap();
-            let metadata = request_map.get(&request_id).cloned();
-            drop(request_map); // Release the request_map lock
-            
-            metadata.ok_or_else(|| {
-                Status::not_found(format!("Request ID {} not found", request_id))
-            })?
+    async fn handle_write_at(&self, req: WriteAtRequest) -> Result<WriteResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
         };
-        
-        // Get file handle
-        let file_clone = {
-            let file_manager = self.file_manager.lock().unwrap();
-            file_manager.file.try_clone().map_err(|e| {
-                Status::internal(format!("Failed to clone file: {}", e))
-            })?
+        let data = self.encode_payload(req.data);
+        let offset = req.offset;
+
+        info!("Received write-at request: {} at offset {}", key.request_id, offset);
+
+        if !offset.is_multiple_of(sector_size()) {
+            return Err(Status::invalid_argument(format!(
+                "Offset {} is not aligned to {} bytes", offset, sector_size()
+            )));
+        }
+
+        let extent = Extent {
+            offset,
+            length: align_up(self.record_len(&key, &data)),
         };
-        
-        // Perform the actual read
-        match self.perform_read(file_clone, metadata.offset, metadata.size, request_id.clone()).await {
-            Ok(data) => {
-                let response = ReadResponse {
-                    request_id,
-                    data,
-                    success: true,
-                    error_message: String::new(),
-                };
-                Ok(Response::new(response))
-            }
-            Err(e) => {
-                error!("Read failed for request {}: {}", request_id, e);
-                let response = ReadResponse {
-                    request_id,
-                    data: Vec::new(),
-                    success: false,
-                    error_message: e.to_string(),
-                };
-                Ok(Response::new(response))
-            }
+        {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.check_fence(&key.namespace, req.fencing_token).map_err(|stale| {
+                Status::failed_precondition(stale.to_string())
+            })?;
+            file_manager.check_lock(&key, &req.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+            let replaced = file_manager.lookup(&key).map_or(0, |existing| existing.size);
+            self.check_quota(&file_manager, &key.namespace, data.size.saturating_sub(replaced))?;
+            file_manager.reserve_at(&key, extent).map_err(|conflict| {
+                Status::already_exists(format!("Extent at offset {} {}", offset, conflict))
+            })?;
         }
-    }
-}
 
-#[tokio::main(worker_threads = 1024)]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
-    
-    // Configure custom thread pool for high IOPS
-    let _runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(1024)
-        .max_blocking_threads(2048) // Increase blocking thread pool for 2300 IOPS
-        .enable_all()
-        .build()?;
-    
-    let args: Vec<String> = std::env::args().collect();
-    
-    if args.len() > 1 && args[1] == "client" {
-        // Run as client
-        println!("Running as client...");
-        client::test_client().await?;
-        return Ok(());
+        let options = WriteOptions {
+            fencing_token: req.fencing_token,
+            lock_id: req.lock_id.clone(),
+            ..WriteOptions::default()
+        };
+        self.write_reserved(&manager, key, data, offset, options).await
     }
-    
-    // Run as server
-    let addr = "[::1]:50051".parse()?;
-    let file_path = "data.bin";
-    
-    // Create data directory if it doesn't exist
-    if let Some(parent) = Path::new(file_path).parent() {
-        std::fs::create_dir_all(parent)?;
+
+    async fn handle_overwrite(&self, req: OverwriteRequest) -> Result<WriteResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let data = self.encode_payload(req.data);
+
+        info!("Received overwrite request: {}", key.request_id);
+
+        let (extents, existing) = {
+            let mut file_manager = manager.lock().unwrap();
+            let existing = file_manager.lookup(&key).ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", key.request_id))
+            })?;
+            if let Some(expected) = req.expected_generation {
+                if expected != existing.generation {
+                    let request_id = key.request_id;
+                    let actual = existing.generation;
+                    return Err(Status::failed_precondition(GenerationMismatch { request_id, expected, actual }.to_string()));
+                }
+            }
+            file_manager.check_fence(&key.namespace, req.fencing_token).map_err(|stale| {
+                Status::failed_precondition(stale.to_string())
+            })?;
+            file_manager.check_lock(&key, &req.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+            self.check_quota(&file_manager, &key.namespace, data.size.saturating_sub(existing.size))?;
+
+            // Rewrite in place when the new record fits the blocks the old one
+            // already owns and versioning does not keep them; otherwise append,
+            // and the commit frees the old extent or keeps it as a prior version
+            let record_len = self.record_len(&key, &data);
+            let in_place = Extent {
+                offset: existing.offset - existing.header_len,
+                length: align_up(record_len),
+            };
+            let fits = existing.chunks.is_empty() && in_place.offset.is_multiple_of(sector_size()) && in_place.length <= existing.extent().length;
+            let extents = if fits && file_manager.reserve_at(&key, in_place).is_ok() {
+                vec![in_place]
+            } else {
+                self.reserve_record(&file_manager.space, &key, &data)
+            };
+            (extents, existing)
+        };
+
+        // The commit fails if the record changed while the write was in flight
+        let in_place = extents[0].offset == existing.offset - existing.header_len;
+        let options = WriteOptions {
+            expected_generation: Some(existing.generation),
+            fencing_token: req.fencing_token,
+            // Overwriting replaces the data but keeps the record's metadata
+            user_metadata: existing.user_metadata.clone(),
+            lock_id: req.lock_id.clone(),
+            in_place,
+            ..WriteOptions::default()
+        };
+        let response = self.write_record(&manager, key, data, extents, options).await?;
+        info!("Overwrite of {} {} at offset {}", response.request_id, if in_place { "in place" } else { "appended" }, response.offset);
+
+        Ok(response)
     }
-    
-    let file_service = FileServiceImpl::new(file_path).await?;
-    
-    info!("Starting gRPC server on {}", addr);
-    info!("Using O_DIRECT mode for file operations");
-    info!("Data file: {}", file_path);
-    
-    Server::builder()
-        .add_service(FileServiceServer::new(file_service))
-        .serve(addr)
-        .await?;
-    
-    Ok(())
-}
 
+    async fn handle_batch_write(&self, req: BatchWriteRequest) -> Result<BatchWriteResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let namespace = req.namespace;
+        let mut entries = req.entries;
+        if entries.is_empty() {
+            return Ok(BatchWriteResponse { results: Vec::new() });
+        }
+
+        info!("Received batch write request with {} entries", entries.len());
 
+        // Compress before taking the lock
+        let payloads: Vec<Payload> = entries.iter_mut().map(|entry| self.encode_payload(std::mem::take(&mut entry.data))).collect();
+
+        // Entries settled by the duplicate policy are answered without being
+        // written; `None` marks a slot filled from the batch write below
+        let mut slots: Vec<Option<WriteResponse>> = Vec::with_capacity(entries.len());
+        let mut pending = Vec::with_capacity(entries.len());
+        let (extent, total_size) = {
+            let file_manager = manager.lock().unwrap();
+            for (entry, data) in entries.into_iter().zip(payloads) {
+                let sparse = reject_sparse(&entry);
+                let mut options = WriteOptions::from_request(&entry);
+                let key = RecordKey {
+                    namespace: namespace.clone(),
+                    request_id: entry.request_id,
+                };
+                #[allow(clippy::result_large_err)]
+                let resolved = sparse
+                    .and_then(|()| validate_user_metadata(&entry.metadata))
+                    .and_then(|()| file_manager.check_fence(&key.namespace, options.fencing_token).map_err(|stale| {
+                        Status::failed_precondition(stale.to_string())
+                    }))
+                    .and_then(|()| file_manager.check_lock(&key, &options.lock_id).map_err(|locked| {
+                        Status::aborted(locked.to_string())
+                    }))
+                    .and_then(|()| self.resolve_duplicate(&file_manager, &key, entry.on_duplicate, &mut options));
+                match resolved {
+                    Ok(None) => {
+                        slots.push(None);
+                        pending.push((key, data, options));
+                    }
+                    Ok(Some(response)) => slots.push(Some(response)),
+                    Err(status) => slots.push(Some(WriteResponse {
+                        request_id: key.request_id,
+                        offset: 0,
+                        success: false,
+                        error_message: status.message().to_string(),
+                        generation: 0,
+                        session_token: String::new(),
+                        durable_epoch: 0,
+                    })),
+                }
+            }
+            if pending.is_empty() {
+                return Ok(BatchWriteResponse { results: slots.into_iter().flatten().collect() });
+            }
+
+            // Pack entries back to back so only the end of the batch is padded
+            let total_size: u64 = pending.iter().map(|(key, data, _)| self.record_len(key, data)).sum();
+            self.check_quota(&file_manager, &namespace, total_size)?;
+            (file_manager.space.reserve(total_size), total_size)
+        };
+
+        let mut buffer = Vec::with_capacity(total_size as usize);
+        let mut placements = Vec::with_capacity(pending.len());
+        for (key, data, options) in pending {
+            let (record, framing) = record_format::frame(&key, data, self.cipher.as_deref());
+            let offset = extent.offset + buffer.len() as u64 + framing.header_len;
+            buffer.extend_from_slice(&record);
+            placements.push((key, offset, framing, options));
+        }
+
+        let file_clone = manager.lock().unwrap().file.clone();
+
+        // One write for the whole batch
+        let start = Instant::now();
+        let result = file_clone.write_at(buffer, extent.offset).await;
+
+        // A failed log append rolls back the records committed before it, so
+        // it fails the whole batch
+        let mut log_failure = None;
+        let written: Vec<WriteResponse> = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.space.finish(extent.offset);
+
+            match result {
+                Ok(_) => {
+                    let written_at = SystemTime::now();
+                    // Entries written but not indexed
+                    let mut failed = Vec::new();
+                    let responses = placements
+                        .into_iter()
+                        .map(|(key, offset, framing, options)| {
+                            let metadata = options.metadata(offset, &framing, written_at);
+                            let admitted = file_manager
+                                .check_fence(&key.namespace, options.fencing_token)
+                                .map_err(|stale| stale.to_string())
+                                .and_then(|()| file_manager.check_lock(&key, &options.lock_id).map_err(|locked| locked.to_string()));
+                            if let Err(reason) = admitted {
+                                failed.push(metadata);
+                                return WriteResponse {
+                                    error_message: reason,
+                                    request_id: key.request_id,
+                                    offset: 0,
+                                    success: false,
+                                    generation: 0,
+                                    session_token: String::new(),
+                                    durable_epoch: 0,
+                                };
+                            }
+                            match file_manager.commit_write(&key, options.expected_generation, metadata.clone()) {
+                                Ok(generation) => WriteResponse {
+                                    request_id: key.request_id,
+                                    offset,
+                                    success: true,
+                                    error_message: String::new(),
+                                    generation,
+                                    session_token: file_manager.session_token(),
+                                    durable_epoch: file_manager.epochs.current(),
+                                },
+                                Err(e) => {
+                                    let response = WriteResponse {
+                                        error_message: e.to_string(),
+                                        request_id: key.request_id,
+                                        offset: 0,
+                                        success: false,
+                                        generation: e.downcast_ref::<GenerationMismatch>().map_or(0, |mismatch| mismatch.actual),
+                                        session_token: String::new(),
+                                        durable_epoch: 0,
+                                    };
+                                    if e.is::<LogFailed>() {
+                                        log_failure = Some(e);
+                                    }
+                                    failed.push(metadata);
+                                    response
+                                }
+                            }
+                        })
+                        .collect();
+                    // Release the blocks of the entries not indexed, except
+                    // those they share with packed neighbours that were. A
+                    // failed log append has rebuilt the free space already.
+                    if log_failure.is_none() {
+                        let mut unused: Vec<Extent> = failed.iter().flat_map(|metadata| file_manager.exclusive_extents(metadata)).collect();
+                        unused.sort_by_key(|extent| extent.offset);
+                        unused.dedup_by(|next, last| {
+                            let overlaps = next.offset <= last.end();
+                            if overlaps {
+                                last.length = next.end().max(last.end()) - last.offset;
+                            }
+                            overlaps
+                        });
+                        for extent in unused {
+                            file_manager.release_extent(extent);
+                        }
+                    }
+                    responses
+                }
+                Err(e) => {
+                    error!("Batch write at offset {} failed: {}", extent.offset, e);
+                    file_manager.release_extent(extent);
+                    placements
+                        .into_iter()
+                        .map(|(key, ..)| WriteResponse {
+                            request_id: key.request_id,
+                            offset: 0,
+                            success: false,
+                            error_message: e.to_string(),
+                            generation: 0,
+                            session_token: String::new(),
+                            durable_epoch: 0,
+                        })
+                        .collect()
+                }
+            }
+        };
+
+        if let Some(status) = log_failure.as_ref().and_then(log_failed_status) {
+            return Err(status);
+        }
+        sync_index(&manager).await?;
+
+        info!("Written batch of {} bytes at offset {} in {:?}", total_size, extent.offset, start.elapsed());
+
+        let mut written = written.into_iter();
+        let results = slots.into_iter().filter_map(|slot| slot.or_else(|| written.next())).collect();
+        Ok(BatchWriteResponse { results })
+    }
+
+    async fn handle_write_batch_atomic(&self, req: WriteBatchAtomicRequest) -> Result<WriteBatchAtomicResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let namespace = req.namespace;
+        let mut entries = req.entries;
+        if entries.is_empty() {
+            return Err(Status::invalid_argument("Atomic batch has no entries"));
+        }
+        let mut seen = HashSet::new();
+        for entry in &entries {
+            if !seen.insert(entry.request_id.as_str()) {
+                return Err(Status::invalid_argument(format!("Request ID {} appears more than once in the batch", entry.request_id)));
+            }
+            validate_user_metadata(&entry.metadata)?;
+            reject_sparse(entry)?;
+        }
+
+        info!("Received atomic batch write request with {} entries", entries.len());
+
+        // Compress before taking the lock
+        let payloads: Vec<Payload> = entries.iter_mut().map(|entry| self.encode_payload(std::mem::take(&mut entry.data))).collect();
+
+        // Every entry is checked up front and any failure rejects the whole
+        // batch. Entries settled by the duplicate policy are answered without
+        // being written; `None` marks a slot filled from the commit below.
+        let mut slots: Vec<Option<WriteResponse>> = Vec::with_capacity(entries.len());
+        let mut pending = Vec::with_capacity(entries.len());
+        let (extent, total_size) = {
+            let file_manager = manager.lock().unwrap();
+            for (entry, data) in entries.into_iter().zip(payloads) {
+                let mut options = WriteOptions::from_request(&entry);
+                let key = RecordKey {
+                    namespace: namespace.clone(),
+                    request_id: entry.request_id,
+                };
+                file_manager.check_fence(&key.namespace, options.fencing_token).map_err(|stale| {
+                    Status::failed_precondition(stale.to_string())
+                })?;
+                file_manager.check_lock(&key, &options.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+                if let Some(response) = self.resolve_duplicate(&file_manager, &key, entry.on_duplicate, &mut options)? {
+                    slots.push(Some(response));
+                    continue;
+                }
+                if let Some(expected) = options.expected_generation {
+                    let actual = file_manager.current_generation(&key);
+                    if expected != actual {
+                        let request_id = key.request_id;
+                        return Err(Status::failed_precondition(GenerationMismatch { request_id, expected, actual }.to_string()));
+                    }
+                }
+                slots.push(None);
+                pending.push((key, data, options));
+            }
+            if pending.is_empty() {
+                return Ok(WriteBatchAtomicResponse {
+                    success: true,
+                    error_message: String::new(),
+                    results: slots.into_iter().flatten().collect(),
+                    session_token: file_manager.session_token(),
+                });
+            }
+
+            let total_size: u64 = pending.iter().map(|(key, data, _)| self.record_len(key, data)).sum();
+            self.check_quota(&file_manager, &namespace, total_size)?;
+            (file_manager.space.reserve(total_size), total_size)
+        };
+
+        // Stage all data in one extent; none of it is reachable until the commit
+        let mut buffer = Vec::with_capacity(total_size as usize);
+        let mut placements = Vec::with_capacity(pending.len());
+        for (key, data, options) in pending {
+            let (record, framing) = record_format::frame(&key, data, self.cipher.as_deref());
+            let offset = extent.offset + buffer.len() as u64 + framing.header_len;
+            buffer.extend_from_slice(&record);
+            placements.push((key, offset, framing, options));
+        }
+
+        let file_clone = manager.lock().unwrap().file.clone();
+
+        let start = Instant::now();
+        let result = file_clone.write_at(buffer, extent.offset).await;
+
+        let (committed, generations, session_token, durable_epoch) = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.space.finish(extent.offset);
+            if let Err(e) = result {
+                error!("Atomic batch write at offset {} failed: {}", extent.offset, e);
+                file_manager.release_extent(extent);
+                return Ok(WriteBatchAtomicResponse {
+                    success: false,
+                    error_message: e.to_string(),
+                    results: Vec::new(),
+                    session_token: String::new(),
+                });
+            }
+
+            // Fences and locks may have moved while the data was written
+            #[allow(clippy::result_large_err)]
+            let admitted = placements.iter().try_for_each(|(key, .., options)| {
+                file_manager.check_fence(&key.namespace, options.fencing_token).map_err(|stale| {
+                    Status::failed_precondition(stale.to_string())
+                })?;
+                file_manager.check_lock(key, &options.lock_id).map_err(|locked| Status::aborted(locked.to_string()))
+            });
+            if let Err(status) = admitted {
+                warn!("Atomic batch rejected: {}", status.message());
+                file_manager.release_extent(extent);
+                return Err(status);
+            }
+
+            let written_at = SystemTime::now();
+            let mut committed = Vec::with_capacity(placements.len());
+            let writes = placements
+                .into_iter()
+                .map(|(key, offset, framing, options)| {
+                    committed.push((key.request_id.clone(), offset));
+                    (key, options.expected_generation, options.metadata(offset, &framing, written_at))
+                })
+                .collect();
+            let generations = match file_manager.commit_batch(writes) {
+                Ok(generations) => generations,
+                Err(e) => {
+                    // The index was rolled back, which took the extent back
+                    if let Some(status) = log_failed_status(&e) {
+                        return Err(status);
+                    }
+                    warn!("Atomic batch rejected: {}", e);
+                    file_manager.release_extent(extent);
+                    return Err(Status::failed_precondition(e.to_string()));
+                }
+            };
+            let session_token = file_manager.session_token();
+            let durable_epoch = file_manager.epochs.current();
+            (committed, generations, session_token, durable_epoch)
+        };
+        sync_index(&manager).await?;
+
+        info!("Committed atomic batch of {} records ({} bytes) at offset {} in {:?}", committed.len(), total_size, extent.offset, start.elapsed());
+
+        let mut written = committed.into_iter().zip(generations).map(|((request_id, offset), generation)| WriteResponse {
+            request_id,
+            offset,
+            success: true,
+            error_message: String::new(),
+            generation,
+            session_token: session_token.clone(),
+            durable_epoch,
+        });
+        let results = slots.into_iter().filter_map(|slot| slot.or_else(|| written.next())).collect();
+        Ok(WriteBatchAtomicResponse {
+            success: true,
+            error_message: String::new(),
+            results,
+            session_token,
+        })
+    }
+
+    // Write data as a record into an extent already reserved in the file
+    // manager; `offset` is where the record header goes
+    async fn write_reserved(&self, manager: &Mutex<FileManager>, key: RecordKey, data: Payload, offset: u64, options: WriteOptions) -> Result<WriteResponse, Status> {
+        let header_len = self.header_len(&key, &data);
+        let size = header_len + data.data.len() as u64;
+        let request_id = key.request_id.clone();
+
+        // Get file handle, after reserving: a compaction swaps the file only
+        // while no write is in flight
+        let (file_clone, space) = {
+            let file_manager = manager.lock().unwrap();
+            (file_manager.file.clone(), file_manager.space.clone())
+        };
+
+        // Perform the actual write
+        let result = self.perform_write(manager, file_clone, offset, data, key, options).await;
+        space.finish(offset);
+
+        match result {
+            Ok(generation) => {
+                let file_manager = manager.lock().unwrap();
+                Ok(WriteResponse {
+                    request_id,
+                    offset: offset + header_len,
+                    success: true,
+                    error_message: String::new(),
+                    generation,
+                    session_token: file_manager.session_token(),
+                    durable_epoch: file_manager.epochs.current(),
+                })
+            }
+            Err(e) => {
+                // Lost a race with a concurrent write, a newer lease holder or
+                // a lock holder; the data just written is unreferenced
+                if let Some(status) = write_rejection(&e) {
+                    manager.lock().unwrap().release_extent(Extent {
+                        offset,
+                        length: align_up(size),
+                    });
+                    return Err(status);
+                }
+                if let Some(status) = io_timeout_status(&e) {
+                    return Err(status);
+                }
+                if let Some(status) = log_failed_status(&e) {
+                    return Err(status);
+                }
+
+                error!("Write failed for request {}: {}", request_id, e);
+                Ok(WriteResponse {
+                    request_id,
+                    offset: 0,
+                    success: false,
+                    error_message: e.to_string(),
+                    generation: 0,
+                    session_token: String::new(),
+                    durable_epoch: 0,
+                })
+            }
+        }
+    }
+
+    // Write a large object into the extents reserved for it, in parallel: the
+    // header and first chunk of its payload into the first extent, each
+    // further chunk into one of its own. The chunks are recorded in the index.
+    async fn write_chunked(&self, manager: &Mutex<FileManager>, key: RecordKey, data: Payload, extents: Vec<Extent>, options: WriteOptions) -> Result<WriteResponse, Status> {
+        let start = Instant::now();
+        let request_id = key.request_id.clone();
+        let size = data.size;
+        let max = self.max_extent_size.unwrap_or(u64::MAX);
+        let (mut record, framing) = record_format::frame(&key, data, self.cipher.as_deref());
+        let offset = extents[0].offset + framing.header_len;
+
+        // Split the framed record back to front so each piece is moved out once
+        let stored_size = record.len() as u64 - framing.header_len;
+        let mut chunks: Vec<Chunk> = Vec::with_capacity(extents.len());
+        let mut pieces = Vec::with_capacity(extents.len());
+        for (index, extent) in extents.iter().enumerate().rev() {
+            let chunk_start = index as u64 * max;
+            let length = (stored_size - chunk_start).min(max);
+            if index == 0 {
+                chunks.push(Chunk { offset, length });
+                pieces.push(std::mem::take(&mut record));
+            } else {
+                chunks.push(Chunk { offset: extent.offset, length });
+                pieces.push(record.split_off((framing.header_len + chunk_start) as usize));
+            }
+        }
+        chunks.reverse();
+        pieces.reverse();
+
+        let file = manager.lock().unwrap().file.clone();
+        let writes = pieces.into_iter().zip(&extents).map(|(piece, extent)| file.write_at(piece, extent.offset));
+        let written = futures::future::join_all(writes).await.into_iter().collect::<Result<Vec<_>>>();
+
+        let result = {
+            let mut file_manager = manager.lock().unwrap();
+            for extent in &extents {
+                file_manager.space.finish(extent.offset);
+            }
+            written.and_then(|_| {
+                file_manager.check_fence(&key.namespace, options.fencing_token)?;
+                file_manager.check_lock(&key, &options.lock_id)?;
+                let mut metadata = options.metadata(offset, &framing, SystemTime::now());
+                metadata.chunks = chunks;
+                file_manager.commit_write(&key, options.expected_generation, metadata)
+            })
+        };
+        let result = match result {
+            Ok(generation) => file_manager::sync_log(manager).await.map(|()| generation),
+            Err(e) => Err(e),
+        };
+        let mut file_manager = manager.lock().unwrap();
+        match result {
+            Ok(generation) => {
+                info!("Written {} bytes in {} chunks at offset {} for request {} in {:?}", size, extents.len(), offset, request_id, start.elapsed());
+                Ok(WriteResponse {
+                    request_id,
+                    offset,
+                    success: true,
+                    error_message: String::new(),
+                    generation,
+                    session_token: file_manager.session_token(),
+                    durable_epoch: file_manager.epochs.current(),
+                })
+            }
+            Err(e) => {
+                if let Some(status) = write_rejection(&e) {
+                    for extent in extents {
+                        file_manager.release_extent(extent);
+                    }
+                    return Err(status);
+                }
+                if let Some(status) = io_timeout_status(&e) {
+                    return Err(status);
+                }
+                if let Some(status) = log_failed_status(&e) {
+                    return Err(status);
+                }
+
+                error!("Write failed for request {}: {}", request_id, e);
+                Ok(WriteResponse {
+                    request_id,
+                    offset: 0,
+                    success: false,
+                    error_message: e.to_string(),
+                    generation: 0,
+                    session_token: String::new(),
+                    durable_epoch: 0,
+                })
+            }
+        }
+    }
+
+    async fn handle_read(&self, req: ReadRequest) -> Result<ReadResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        self.await_session(&manager, &req.session_token).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let request_id = key.request_id.clone();
+
+        info!("Received read request: {}", request_id);
+        let file_id = self.routed_file_id(&req.file_id, &key.namespace);
+        if self.files.certainly_absent(file_id, &key) {
+            return Err(Status::not_found(format!("Request ID {} not found", request_id)));
+        }
+
+        // Get metadata and a file handle together, so a compaction swapping
+        // the data file cannot pair old offsets with the new file. The
+        // current entry is looked up without locking the file manager.
+        let (metadata, file_clone) = match (req.generation, self.files.lookups(file_id)) {
+            (None, Some(lookups)) => lookups.lookup(&key).ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", request_id))
+            })?,
+            (generation, _) => {
+                let file_manager = manager.lock().unwrap();
+                let metadata = match generation {
+                    Some(generation) => file_manager.lookup_version(&key, generation).ok_or_else(|| {
+                        Status::not_found(format!("Generation {} of request ID {} not found", generation, request_id))
+                    })?,
+                    None => file_manager.lookup(&key).ok_or_else(|| {
+                        Status::not_found(format!("Request ID {} not found", request_id))
+                    })?,
+                };
+                (metadata, file_manager.file.clone())
+            }
+        };
+
+        // Resolve the requested byte range, defaulting to the whole record
+        let range_offset = req.offset.unwrap_or(0);
+        let range_length = req.length.unwrap_or(metadata.size.saturating_sub(range_offset));
+        match range_offset.checked_add(range_length) {
+            Some(end) if end <= metadata.size => {}
+            _ => {
+                return Err(Status::out_of_range(format!(
+                    "Range {}+{} exceeds record {} of {} bytes",
+                    range_offset, range_length, request_id, metadata.size
+                )));
+            }
+        }
+
+        // Perform the actual read
+        match self.perform_read(file_clone, &metadata, range_offset, range_length, &request_id).await {
+            Ok(data) => Ok(ReadResponse {
+                request_id,
+                data,
+                success: true,
+                error_message: String::new(),
+                metadata: metadata.user_metadata,
+            }),
+            Err(e) => {
+                if let Some(mismatch) = e.downcast_ref::<ChecksumMismatch>() {
+                    error!("{}", mismatch);
+                    return Err(Status::data_loss(mismatch.to_string()));
+                }
+                if let Some(failed) = e.downcast_ref::<DecryptionFailed>() {
+                    error!("{}", failed);
+                    return Err(Status::data_loss(failed.to_string()));
+                }
+                if let Some(missing) = e.downcast_ref::<EncryptionKeyMissing>() {
+                    return Err(Status::failed_precondition(missing.to_string()));
+                }
+                if let Some(status) = io_timeout_status(&e) {
+                    return Err(status);
+                }
+                error!("Read failed for request {}: {}", request_id, e);
+                Ok(ReadResponse {
+                    request_id,
+                    data: Bytes::new(),
+                    success: false,
+                    error_message: e.to_string(),
+                    metadata: HashMap::new(),
+                })
+            }
+        }
+    }
+
+    async fn handle_batch_read(&self, req: BatchReadRequest) -> Result<BatchReadResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        self.await_session(&manager, &req.session_token).await?;
+        let request_ids = req.request_ids;
+
+        info!("Received batch read request with {} entries", request_ids.len());
+
+        let mut results: Vec<ReadResponse> = Vec::with_capacity(request_ids.len());
+        let mut found: Vec<(usize, RequestMetadata)> = Vec::new();
+        let file = {
+            let file_manager = manager.lock().unwrap();
+            let now = SystemTime::now();
+            for (index, request_id) in request_ids.into_iter().enumerate() {
+                let key = RecordKey {
+                    namespace: req.namespace.clone(),
+                    request_id: request_id.clone(),
+                };
+                let metadata = file_manager.request_map.get(&key).filter(|metadata| !metadata.is_expired(now));
+                match metadata {
+                    Some(metadata) => {
+                        results.push(ReadResponse {
+                            request_id,
+                            data: Bytes::new(),
+                            success: true,
+                            error_message: String::new(),
+                            metadata: metadata.user_metadata.clone(),
+                        });
+                        found.push((index, metadata));
+                    }
+                    None => results.push(ReadResponse {
+                        error_message: format!("Request ID {} not found", request_id),
+                        request_id,
+                        data: Bytes::new(),
+                        success: false,
+                        metadata: HashMap::new(),
+                    }),
+                }
+            }
+
+            file_manager.file.clone()
+        };
+
+        // Large objects are reassembled from their chunks on their own
+        let (chunked, mut found): (Vec<_>, Vec<_>) = found.into_iter().partition(|(_, metadata)| !metadata.chunks.is_empty());
+        for (index, metadata) in chunked {
+            let read = record_format::read_stored(file.as_ref(), &metadata, metadata.encrypted).await;
+            match read.and_then(|stored| record_format::open_payload(self.cipher.as_deref(), &results[index].request_id, &metadata, stored)) {
+                Ok(data) => results[index].data = data,
+                Err(e) => {
+                    error!("{}", e);
+                    results[index].success = false;
+                    results[index].error_message = e.to_string();
+                }
+            }
+        }
+
+        // Coalesce records whose extents touch or overlap into shared reads
+        found.sort_by_key(|(_, metadata)| metadata.offset);
+        let mut runs: Vec<(Extent, Vec<(usize, RequestMetadata)>)> = Vec::new();
+        for (index, metadata) in found {
+            let extent = metadata.extent();
+            match runs.last_mut() {
+                Some((run, members))
+                    if extent.offset <= run.end() && extent.end().max(run.end()) - run.offset <= MAX_COALESCED_READ =>
+                {
+                    run.length = extent.end().max(run.end()) - run.offset;
+                    members.push((index, metadata));
+                }
+                _ => runs.push((extent, vec![(index, metadata)])),
+            }
+        }
+
+        let mut reads = Vec::with_capacity(runs.len());
+        for (run, members) in runs {
+            let file = &file;
+            reads.push(async move { (run, members, file.read_at(run.length, run.offset).await) });
+        }
+
+        for (run, members, result) in futures::future::join_all(reads).await {
+            match result {
+                Ok(buffer) => {
+                    info!("Coalesced read of {} bytes at offset {} served {} records", run.length, run.offset, members.len());
+                    for (index, metadata) in members {
+                        // Encrypted records are opened with their header
+                        let stored_start = if metadata.encrypted { metadata.offset - metadata.header_len } else { metadata.offset };
+                        let start = (stored_start - run.offset) as usize;
+                        let stored = buffer.slice(start..(metadata.offset + metadata.stored_size() - run.offset) as usize);
+                        match record_format::open_payload(self.cipher.as_deref(), &results[index].request_id, &metadata, stored) {
+                            Ok(data) => results[index].data = data,
+                            Err(e) => {
+                                error!("{}", e);
+                                results[index].success = false;
+                                results[index].error_message = e.to_string();
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Batch read at offset {} failed: {}", run.offset, e);
+                    for (index, _) in members {
+                        results[index].success = false;
+                        results[index].error_message = e.to_string();
+                    }
+                }
+            }
+        }
+
+        Ok(BatchReadResponse { results })
+    }
+
+    async fn handle_delete(&self, req: DeleteRequest) -> Result<DeleteResponse, Status> {
+        self.check_writable()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let request_id = key.request_id.clone();
+
+        info!("Received delete request: {}", request_id);
+
+        let (freed_bytes, session_token) = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.check_lock(&key, &req.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+            let freed_bytes = file_manager.delete(&key, SystemTime::now()).map_err(index_status)?.ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", request_id))
+            })?;
+            info!("{} bytes now reclaimable", file_manager.reclaimable_bytes());
+            (freed_bytes, file_manager.session_token())
+        };
+        sync_index(&manager).await?;
+
+        Ok(DeleteResponse {
+            request_id,
+            success: true,
+            error_message: String::new(),
+            freed_bytes,
+            session_token,
+        })
+    }
+
+    async fn handle_bulk_delete(&self, req: BulkDeleteRequest) -> Result<BulkDeleteResponse, Status> {
+        self.check_writable()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let namespace = req.namespace;
+
+        if req.request_ids.is_empty() == req.prefix.is_none() {
+            return Err(Status::invalid_argument("Set exactly one of request_ids or prefix"));
+        }
+
+        // All records are removed under one lock, in a single pass
+        let (deleted, freed_bytes, not_found, locked, session_token) = {
+            let mut file_manager = manager.lock().unwrap();
+            let keys = match &req.prefix {
+                Some(prefix) => file_manager.keys_with_prefix(&namespace, prefix),
+                None => req
+                    .request_ids
+                    .into_iter()
+                    .map(|request_id| RecordKey {
+                        namespace: namespace.clone(),
+                        request_id,
+                    })
+                    .collect(),
+            };
+
+            let now = SystemTime::now();
+            let mut deleted = 0;
+            let mut freed_bytes = 0;
+            let mut not_found = Vec::new();
+            let mut locked = Vec::new();
+            for key in keys {
+                // Locked records are left alone; unlock them or delete them one by one
+                if file_manager.check_lock(&key, "").is_err() {
+                    locked.push(key.request_id);
+                    continue;
+                }
+                match file_manager.delete(&key, now).map_err(index_status)? {
+                    Some(freed) => {
+                        deleted += 1;
+                        freed_bytes += freed;
+                    }
+                    None => not_found.push(key.request_id),
+                }
+            }
+            (deleted, freed_bytes, not_found, locked, file_manager.session_token())
+        };
+        sync_index(&manager).await?;
+
+        info!("Bulk deleted {} records from namespace {:?}, freed {} bytes", deleted, namespace, freed_bytes);
+
+        Ok(BulkDeleteResponse {
+            deleted,
+            freed_bytes,
+            not_found,
+            session_token,
+            locked,
+        })
+    }
+
+    async fn handle_undelete(&self, req: UndeleteRequest) -> Result<UndeleteResponse, Status> {
+        self.check_writable()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let request_id = key.request_id.clone();
+
+        info!("Received undelete request: {}", request_id);
+
+        // Checked and applied under one lock so nothing takes the ID in between
+        let (metadata, session_token) = {
+            let mut file_manager = manager.lock().unwrap();
+            if file_manager.trash_retention.is_none() {
+                return Err(Status::failed_precondition("The trash is disabled; deletes are permanent"));
+            }
+            let trashed = file_manager.trash.get(&key).filter(|trashed| !trashed.metadata.is_expired(SystemTime::now())).ok_or_else(|| {
+                Status::not_found(format!("Request ID {} is not in the trash", request_id))
+            })?;
+            let size = trashed.metadata.size;
+            if file_manager.lookup(&key).is_some() {
+                return Err(Status::already_exists(format!("Request ID {} already exists", request_id)));
+            }
+            self.check_quota(&file_manager, &key.namespace, size)?;
+            let metadata = file_manager.undelete(&key).map_err(index_status)?.ok_or_else(|| {
+                Status::not_found(format!("Request ID {} is not in the trash", request_id))
+            })?;
+            (metadata, file_manager.session_token())
+        };
+        sync_index(&manager).await?;
+
+        Ok(UndeleteResponse {
+            request_id,
+            offset: metadata.offset,
+            generation: metadata.generation,
+            session_token,
+        })
+    }
+
+    async fn handle_list(&self, req: ListRequestsRequest) -> Result<ListRequestsResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        self.await_session(&manager, &req.session_token).await?;
+        let page_size = match req.page_size as usize {
+            0 => DEFAULT_LIST_PAGE_SIZE,
+            n => n.min(MAX_LIST_PAGE_SIZE),
+        };
+
+        // Entries are returned in request_id order; the page token is the last
+        // request_id of the previous page. Only the range of IDs starting with
+        // the prefix is scanned.
+        let lower = if !req.page_token.is_empty() && req.page_token >= req.prefix {
+            Bound::Excluded(req.page_token.clone())
+        } else {
+            Bound::Included(req.prefix.clone())
+        };
+        let now = SystemTime::now();
+        // Listing only reads the request map, which has locks of its own
+        let request_map = manager.lock().unwrap().request_map.clone();
+        let mut entries: Vec<(String, RequestMetadata)> = request_map
+            .read()
+            .range(&req.namespace, lower.as_ref().map(String::as_str))
+            .take_while(|(request_id, _)| request_id.starts_with(&req.prefix))
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .take(page_size + 1)
+            .collect();
+
+        let next_page_token = if entries.len() > page_size {
+            entries.truncate(page_size);
+            entries.last().map(|(request_id, _)| request_id.clone()).unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let requests = entries
+            .into_iter()
+            .map(|(request_id, metadata)| RequestInfo {
+                request_id,
+                offset: metadata.offset,
+                size: metadata.size,
+                written_at_ms: unix_millis(metadata.written_at),
+            })
+            .collect();
+
+        Ok(ListRequestsResponse { requests, next_page_token })
+    }
+
+    async fn handle_stat(&self, req: StatRequest) -> Result<StatResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        self.await_session(&manager, &req.session_token).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+
+        // Served entirely from the request map, no disk I/O
+        let (metadata, references) = {
+            let file_manager = manager.lock().unwrap();
+            let metadata = file_manager.lookup(&key).ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", key.request_id))
+            })?;
+            let references = file_manager.reference_count(metadata.offset) as u32;
+            (metadata, references)
+        };
+        let request_id = key.request_id;
+
+        Ok(StatResponse {
+            request_id,
+            offset: metadata.offset,
+            size: metadata.size,
+            aligned_size: metadata.aligned_size(),
+            checksum: metadata.checksum,
+            written_at_ms: unix_millis(metadata.written_at),
+            generation: metadata.generation,
+            expires_at_ms: metadata.expires_at.map(unix_millis),
+            references,
+            metadata: metadata.user_metadata,
+        })
+    }
+
+    async fn handle_init_upload(&self, req: InitUploadRequest) -> Result<InitUploadResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        validate_user_metadata(&req.metadata)?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let upload = Upload {
+            manager,
+            key: RecordKey {
+                namespace: req.namespace,
+                request_id: req.request_id,
+            },
+            ttl: req.ttl_ms.map(Duration::from_millis),
+            user_metadata: req.metadata,
+            parts: BTreeMap::new(),
+            deadline: Instant::now() + UPLOAD_TIMEOUT,
+        };
+
+        info!("Started upload {} for request {}", upload_id, upload.key.request_id);
+
+        self.uploads.lock().unwrap().insert(upload_id.clone(), upload);
+        Ok(InitUploadResponse { upload_id })
+    }
+
+    async fn handle_upload_part(&self, req: UploadPartRequest) -> Result<UploadPartResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        if req.part_number == 0 {
+            return Err(Status::invalid_argument("Part numbers start at 1"));
+        }
+        let manager = {
+            let uploads = self.uploads.lock().unwrap();
+            let upload = uploads.get(&req.upload_id).ok_or_else(|| {
+                Status::not_found(format!("Upload {} not found", req.upload_id))
+            })?;
+            // The sweeper discards it shortly
+            if upload.is_expired(Instant::now()) {
+                return Err(Status::not_found(format!("Upload {} has expired", req.upload_id)));
+            }
+            upload.manager.clone()
+        };
+        let part_number = req.part_number;
+        let size = req.data.len() as u64;
+
+        // The part's extent stays in flight until the upload finishes
+        let (part, file_clone) = {
+            let file_manager = manager.lock().unwrap();
+            let extent = file_manager.space.reserve(size);
+            (UploadedPart { offset: extent.offset, size }, file_manager.file.clone())
+        };
+
+        let file = file_clone;
+        if let Err(e) = file.write_at(req.data, part.offset).await {
+            error!("Upload {} part {} failed: {}", req.upload_id, part_number, e);
+            upload::discard_part(&mut manager.lock().unwrap(), &part);
+            return Ok(UploadPartResponse {
+                upload_id: req.upload_id,
+                part_number,
+                success: false,
+                error_message: e.to_string(),
+            });
+        }
+
+        // A retried part replaces the earlier attempt
+        let recorded = {
+            let mut uploads = self.uploads.lock().unwrap();
+            uploads.get_mut(&req.upload_id).map(|upload| upload.parts.insert(part_number, part))
+        };
+        match recorded {
+            Some(replaced) => {
+                if let Some(replaced) = replaced {
+                    upload::discard_part(&mut manager.lock().unwrap(), &replaced);
+                }
+            }
+            None => {
+                upload::discard_part(&mut manager.lock().unwrap(), &part);
+                return Err(Status::not_found(format!("Upload {} was completed or aborted", req.upload_id)));
+            }
+        }
+
+        info!("Stored part {} of upload {} ({} bytes) at offset {}", part_number, req.upload_id, size, part.offset);
+
+        Ok(UploadPartResponse {
+            upload_id: req.upload_id,
+            part_number,
+            success: true,
+            error_message: String::new(),
+        })
+    }
+
+    async fn handle_complete_upload(&self, req: CompleteUploadRequest) -> Result<WriteResponse, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let upload_id = req.upload_id;
+
+        // Parts are stitched back to back, so all but the last must fill whole blocks
+        let upload = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let upload = uploads.get(&upload_id).ok_or_else(|| {
+                Status::not_found(format!("Upload {} not found", upload_id))
+            })?;
+            if upload.parts.is_empty() {
+                return Err(Status::failed_precondition(format!("Upload {} has no parts", upload_id)));
+            }
+            let last = upload.parts.len() - 1;
+            if let Some((number, part)) = upload.parts.iter().take(last).find(|(_, part)| !part.size.is_multiple_of(sector_size())) {
+                return Err(Status::failed_precondition(format!(
+                    "Part {} is {} bytes; every part but the last must be a multiple of {} bytes", number, part.size, sector_size()
+                )));
+            }
+            uploads.remove(&upload_id).unwrap()
+        };
+        let manager = upload.manager.clone();
+        let key = upload.key.clone();
+        let total_size: u64 = upload.parts.values().map(|part| part.size).sum();
+        // The header is padded to whole blocks so the parts stay aligned.
+        // Uploads are not compressed: that would need the whole payload in memory.
+        let header_len = align_up(record_format::header_len(&key, self.cipher.is_some(), false));
+        let mut options = WriteOptions {
+            ttl: upload.ttl,
+            user_metadata: upload.user_metadata.clone(),
+            lock_id: req.lock_id,
+            ..WriteOptions::default()
+        };
+
+        info!("Completing upload {} for request {}: {} parts, {} bytes", upload_id, key.request_id, upload.parts.len(), total_size);
+
+        // Settle duplicates and quota before copying anything; on failure the
+        // upload stays open so it can be retried or aborted
+        let prepared = {
+            let file_manager = manager.lock().unwrap();
+            #[allow(clippy::result_large_err)]
+            let resolved = self.resolve_duplicate(&file_manager, &key, DuplicatePolicy::Unspecified as i32, &mut options).and_then(|existing| {
+                file_manager.check_lock(&key, &options.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+                let replaced = file_manager.lookup(&key).map_or(0, |existing| existing.size);
+                self.check_quota(&file_manager, &key.namespace, total_size.saturating_sub(replaced))?;
+                Ok(existing)
+            });
+            match resolved {
+                Ok(None) => {
+                    let extent = file_manager.space.reserve(header_len + total_size);
+                    Ok(Ok((extent, file_manager.file.clone())))
+                }
+                Ok(Some(existing)) => Ok(Err(existing)),
+                Err(status) => Err(status),
+            }
+        };
+        let (extent, file) = match prepared {
+            Ok(Ok(reserved)) => reserved,
+            Ok(Err(existing)) => {
+                upload.discard();
+                return Ok(existing);
+            }
+            Err(status) => {
+                self.uploads.lock().unwrap().insert(upload_id, upload);
+                return Err(status);
+            }
+        };
+
+        // Copy the parts into one contiguous extent after the header, one part
+        // in memory at a time; the header goes last, once the CRC is known. An
+        // encrypted payload is sealed as a whole, so it is assembled in memory
+        // and written once sealed.
+        let start = Instant::now();
+        let copied = async {
+            let mut cursor = extent.offset + header_len;
+            let mut crc = snapshot::Crc32::new();
+            let mut assembled = Vec::new();
+            for part in upload.parts.values() {
+                let mut data = file.read_at(align_up(part.size), part.offset).await?;
+                data.truncate(part.size as usize);
+                if self.cipher.is_some() {
+                    assembled.extend_from_slice(&data);
+                    continue;
+                }
+                crc.update(&data);
+                file.write_at(data.into(), cursor).await?;
+                cursor += part.size;
+            }
+            let (checksum, seal) = match &self.cipher {
+                Some(cipher) => {
+                    let seal = cipher.seal(&record_format::key_bytes(&key), &mut assembled);
+                    let checksum = record_format::checksum(&assembled);
+                    file.write_at(assembled, extent.offset + header_len).await?;
+                    (checksum, Some(seal))
+                }
+                None => (crc.finish(), None),
+            };
+            let header = record_format::encode_header(&key, total_size, checksum, None, seal.as_ref(), header_len);
+            file.write_at(header, extent.offset).await?;
+            Ok::<u32, anyhow::Error>(checksum)
+        }
+        .await;
+
+        let (offset, generation, session_token, durable_epoch) = {
+            let mut file_manager = manager.lock().unwrap();
+            file_manager.space.finish(extent.offset);
+            let checksum = match copied {
+                Ok(checksum) => checksum,
+                Err(e) => {
+                    error!("Completing upload {} failed: {}", upload_id, e);
+                    file_manager.release_extent(extent);
+                    drop(file_manager);
+                    self.uploads.lock().unwrap().insert(upload_id, upload);
+                    return Ok(WriteResponse {
+                        request_id: key.request_id,
+                        offset: 0,
+                        success: false,
+                        error_message: e.to_string(),
+                        generation: 0,
+                        session_token: String::new(),
+                        durable_epoch: 0,
+                    });
+                }
+            };
+
+            let offset = extent.offset + header_len;
+            let framing = Framing {
+                header_len,
+                size: total_size,
+                checksum,
+                encrypted: self.cipher.is_some(),
+                compression: None,
+            };
+            let metadata = options.metadata(offset, &framing, SystemTime::now());
+            let committed = match file_manager.check_lock(&key, &options.lock_id) {
+                Ok(()) => file_manager.commit_write(&key, options.expected_generation, metadata),
+                Err(locked) => Err(locked.into()),
+            };
+            for part in upload.parts.values() {
+                upload::discard_part(&mut file_manager, part);
+            }
+            let generation = match committed {
+                Ok(generation) => generation,
+                Err(e) => {
+                    // The index was rolled back, which took the extent back
+                    if let Some(status) = log_failed_status(&e) {
+                        return Err(status);
+                    }
+                    let status = match e.downcast_ref::<RecordLocked>() {
+                        Some(locked) => Status::aborted(locked.to_string()),
+                        None => Status::failed_precondition(e.to_string()),
+                    };
+                    warn!("Upload {} rejected: {}", upload_id, status.message());
+                    file_manager.release_extent(extent);
+                    return Err(status);
+                }
+            };
+            (offset, generation, file_manager.session_token(), file_manager.epochs.current())
+        };
+        sync_index(&manager).await?;
+
+        info!("Completed upload {} as {} at offset {} in {:?}", upload_id, key.request_id, offset, start.elapsed());
+
+        Ok(WriteResponse {
+            request_id: key.request_id,
+            offset,
+            success: true,
+            error_message: String::new(),
+            generation,
+            session_token,
+            durable_epoch,
+        })
+    }
+
+    async fn handle_abort_upload(&self, req: AbortUploadRequest) -> Result<AbortUploadResponse, Status> {
+        let upload = self.uploads.lock().unwrap().remove(&req.upload_id).ok_or_else(|| {
+            Status::not_found(format!("Upload {} not found", req.upload_id))
+        })?;
+        upload.discard();
+
+        info!("Aborted upload {}, discarded {} parts", req.upload_id, upload.parts.len());
+
+        Ok(AbortUploadResponse { parts_discarded: upload.parts.len() as u32 })
+    }
+
+    async fn handle_acquire_lease(&self, req: AcquireLeaseRequest) -> Result<AcquireLeaseResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let fencing_token = manager.lock().unwrap().acquire_fence(&req.namespace, SystemTime::now());
+
+        info!("Issued fencing token {} for namespace {:?} to {:?}", fencing_token, req.namespace, req.holder);
+
+        Ok(AcquireLeaseResponse { fencing_token })
+    }
+
+    async fn handle_lock(&self, req: LockRequest) -> Result<LockResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let ttl = match req.ttl_ms {
+            0 => DEFAULT_LOCK_TTL,
+            ms => Duration::from_millis(ms),
+        };
+        if ttl > MAX_LOCK_TTL {
+            return Err(Status::invalid_argument(format!("Lock TTL may not exceed {}ms", MAX_LOCK_TTL.as_millis())));
+        }
+        let renewing = (!req.lock_id.is_empty()).then_some(req.lock_id.as_str());
+
+        let lock = manager
+            .lock()
+            .unwrap()
+            .lock(&key, renewing, ttl, SystemTime::now())
+            .map_err(|locked| Status::aborted(locked.to_string()))?;
+
+        info!("{} lock {} on {} for {:?}", if renewing.is_some() { "Renewed" } else { "Granted" }, lock.lock_id, key.request_id, ttl);
+
+        Ok(LockResponse {
+            lock_id: lock.lock_id,
+            expires_at_ms: unix_millis(lock.expires_at),
+        })
+    }
+
+    async fn handle_unlock(&self, req: UnlockRequest) -> Result<UnlockResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+
+        let released = manager
+            .lock()
+            .unwrap()
+            .unlock(&key, &req.lock_id, SystemTime::now())
+            .map_err(|locked| Status::aborted(locked.to_string()))?;
+
+        info!("Unlock of {} with lock {}: released = {}", key.request_id, req.lock_id, released);
+
+        Ok(UnlockResponse { released })
+    }
+
+    async fn handle_rename(&self, req: RenameRequest) -> Result<RenameResponse, Status> {
+        self.check_writable()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let new_request_id = req.new_request_id;
+
+        info!("Received rename request: {} -> {}", key.request_id, new_request_id);
+
+        if new_request_id == key.request_id {
+            return Err(Status::invalid_argument("New request ID is the same as the old one"));
+        }
+
+        // Checked and applied under one lock so the rename is atomic
+        let metadata = {
+            let mut file_manager = manager.lock().unwrap();
+            let target = RecordKey {
+                namespace: key.namespace.clone(),
+                request_id: new_request_id.clone(),
+            };
+            if file_manager.lookup(&target).is_some() {
+                return Err(Status::already_exists(format!("Request ID {} already exists", new_request_id)));
+            }
+            if file_manager.lookup(&key).is_none() {
+                return Err(Status::not_found(format!("Request ID {} not found", key.request_id)));
+            }
+            file_manager.check_lock(&key, &req.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+            file_manager.check_lock(&target, &req.lock_id).map_err(|locked| Status::aborted(locked.to_string()))?;
+            file_manager.rename(&key, &new_request_id).map_err(index_status)?.ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", key.request_id))
+            })?
+        };
+        sync_index(&manager).await?;
+
+        Ok(RenameResponse {
+            request_id: new_request_id,
+            offset: metadata.offset,
+            generation: metadata.generation,
+        })
+    }
+
+    async fn handle_alias(&self, req: AliasRequest) -> Result<AliasResponse, Status> {
+        self.check_writable()?;
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+        let alias_id = req.alias_id;
+
+        info!("Received alias request: {} -> {}", alias_id, key.request_id);
+
+        let (metadata, references) = {
+            let mut file_manager = manager.lock().unwrap();
+            let alias = RecordKey {
+                namespace: key.namespace.clone(),
+                request_id: alias_id.clone(),
+            };
+            if file_manager.lookup(&alias).is_some() {
+                return Err(Status::already_exists(format!("Request ID {} already exists", alias_id)));
+            }
+            let existing = file_manager.lookup(&key).ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", key.request_id))
+            })?;
+            self.check_quota(&file_manager, &key.namespace, existing.size)?;
+
+            let metadata = file_manager.alias(&key, &alias_id).map_err(index_status)?.ok_or_else(|| {
+                Status::not_found(format!("Request ID {} not found", key.request_id))
+            })?;
+            let references = file_manager.reference_count(metadata.offset) as u32;
+            (metadata, references)
+        };
+        sync_index(&manager).await?;
+
+        Ok(AliasResponse {
+            alias_id,
+            offset: metadata.offset,
+            references,
+        })
+    }
+
+    // Wait until the writes acknowledged in a durability epoch are on stable
+    // storage. With `odsync` every acknowledged write already is; with
+    // `none` no data file is ever synced, so nothing can be waited for.
+    async fn handle_wait_for_durable(&self, req: WaitForDurableRequest) -> Result<WaitForDurableResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let epoch = req.epoch;
+        let mut durable = {
+            let file_manager = manager.lock().unwrap();
+            match file_manager.durability {
+                Durability::NoSync => return Err(Status::failed_precondition("Data files are never synced with --durability none")),
+                Durability::ODsync if epoch <= file_manager.epochs.current() => {
+                    return Ok(WaitForDurableResponse { durable_epoch: file_manager.epochs.current() });
+                }
+                Durability::ODsync | Durability::Interval(_) => {}
+            }
+            match file_manager.epochs.state(epoch) {
+                EpochState::Lost => {
+                    return Err(Status::data_loss(format!("Epoch {} was open when the server stopped; its writes may not have survived", epoch)));
+                }
+                EpochState::Unknown => {
+                    return Err(Status::invalid_argument(format!("Epoch {} has not been issued; the current epoch is {}", epoch, file_manager.epochs.current())));
+                }
+                EpochState::Durable | EpochState::Pending => file_manager.epochs.durable.subscribe(),
+            }
+        };
+
+        let timeout = if req.timeout_ms > 0 { Duration::from_millis(req.timeout_ms) } else { DURABLE_WAIT_TIMEOUT };
+        let durable_epoch = match tokio::time::timeout(timeout, durable.wait_for(|durable| *durable >= epoch)).await {
+            Ok(Ok(durable)) => *durable,
+            _ => return Err(Status::deadline_exceeded(format!("Epoch {} did not become durable within {:?}", epoch, timeout))),
+        };
+        Ok(WaitForDurableResponse { durable_epoch })
+    }
+
+    async fn handle_exists(&self, req: ExistsRequest) -> Result<ExistsResponse, Status> {
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        self.await_session(&manager, &req.session_token).await?;
+        let key = RecordKey {
+            namespace: req.namespace,
+            request_id: req.request_id,
+        };
+
+        // A miss in the bloom filter needs no lock at all
+        let file_id = self.routed_file_id(&req.file_id, &key.namespace);
+        if self.files.certainly_absent(file_id, &key) {
+            return Ok(ExistsResponse { request_id: key.request_id, exists: false });
+        }
+        // Only consults the request map, no disk I/O, and only the lock of
+        // the shard holding the key
+        let exists = match self.files.lookups(file_id) {
+            Some(lookups) => lookups.exists(&key),
+            None => manager.lock().unwrap().lookup(&key).is_some(),
+        };
+
+        Ok(ExistsResponse { request_id: key.request_id, exists })
+    }
+
+    async fn handle_server_info(&self, req: ServerInfoRequest) -> Result<ServerInfoResponse, Status> {
+        let manager = self.file_manager(&req.file_id, "").await?;
+        let file_manager = manager.lock().unwrap();
+        let record_count = file_manager.record_count() as u64;
+
+        Ok(ServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            block_size: sector_size(),
+            io_backend: file_manager.file.backend_name().to_string(),
+            data_file: file_manager.file_path.clone(),
+            file_size: file_manager.space.end(),
+            record_count,
+            direct_io: file_io::direct_io(),
+        })
+    }
+
+    // Execute one tagged pipeline op. Per-op failures are reported inside the
+    // response so that a single bad op does not tear down the whole stream.
+    async fn handle_pipeline_op(&self, message: PipelineRequest, caller: &Caller) -> Result<PipelineResponse, Status> {
+        let caller = Caller { bytes: message.encoded_len() as u64, ..caller.clone() };
+        let tag = message.tag;
+        let op = match message.op {
+            Some(pipeline_request::Op::Write(req)) => {
+                let request_id = req.request_id.clone();
+                let written = self.storage_op(&caller, self.handle_write(req)).await;
+                let response = written.unwrap_or_else(|status| WriteResponse {
+                    request_id,
+                    offset: 0,
+                    success: false,
+                    error_message: status.message().to_string(),
+                    generation: 0,
+                    session_token: String::new(),
+                    durable_epoch: 0,
+                });
+                pipeline_response::Op::Write(response)
+            }
+            Some(pipeline_request::Op::Read(req)) => {
+                let request_id = req.request_id.clone();
+                let read = self.storage_op(&caller, self.handle_read(req)).await;
+                let response = read.unwrap_or_else(|status| ReadResponse {
+                    request_id,
+                    data: Bytes::new(),
+                    success: false,
+                    error_message: status.message().to_string(),
+                    metadata: HashMap::new(),
+                });
+                pipeline_response::Op::Read(response)
+            }
+            None => {
+                return Err(Status::invalid_argument(format!("Pipeline op {} has no operation set", tag)));
+            }
+        };
+
+        Ok(PipelineResponse { tag, op: Some(op) })
+    }
+}
+
+#[tonic::async_trait]
+impl FileService for FileServiceImpl {
+    type PipelineStream = Pin<Box<dyn Stream<Item = Result<PipelineResponse, Status>> + Send + 'static>>;
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send + 'static>>;
+    type TailChangesStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send + 'static>>;
+
+    async fn write_data(
+        &self,
+        request: Request<WriteRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_write(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn read_data(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> Result<Response<ReadResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_read(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn delete_data(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let response = self.handle_delete(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn bulk_delete(
+        &self,
+        request: Request<BulkDeleteRequest>,
+    ) -> Result<Response<BulkDeleteResponse>, Status> {
+        let response = self.handle_bulk_delete(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn undelete(
+        &self,
+        request: Request<UndeleteRequest>,
+    ) -> Result<Response<UndeleteResponse>, Status> {
+        let response = self.handle_undelete(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn list_requests(
+        &self,
+        request: Request<ListRequestsRequest>,
+    ) -> Result<Response<ListRequestsResponse>, Status> {
+        let response = self.handle_list(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn stat_data(
+        &self,
+        request: Request<StatRequest>,
+    ) -> Result<Response<StatResponse>, Status> {
+        let response = self.handle_stat(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn init_upload(
+        &self,
+        request: Request<InitUploadRequest>,
+    ) -> Result<Response<InitUploadResponse>, Status> {
+        let response = self.handle_init_upload(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn upload_part(
+        &self,
+        request: Request<UploadPartRequest>,
+    ) -> Result<Response<UploadPartResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_upload_part(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn complete_upload(
+        &self,
+        request: Request<CompleteUploadRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_complete_upload(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn abort_upload(
+        &self,
+        request: Request<AbortUploadRequest>,
+    ) -> Result<Response<AbortUploadResponse>, Status> {
+        let response = self.handle_abort_upload(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn acquire_lease(
+        &self,
+        request: Request<AcquireLeaseRequest>,
+    ) -> Result<Response<AcquireLeaseResponse>, Status> {
+        let response = self.handle_acquire_lease(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn write_batch_atomic(
+        &self,
+        request: Request<WriteBatchAtomicRequest>,
+    ) -> Result<Response<WriteBatchAtomicResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_write_batch_atomic(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn lock(
+        &self,
+        request: Request<LockRequest>,
+    ) -> Result<Response<LockResponse>, Status> {
+        let response = self.handle_lock(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn unlock(
+        &self,
+        request: Request<UnlockRequest>,
+    ) -> Result<Response<UnlockResponse>, Status> {
+        let response = self.handle_unlock(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn rename(
+        &self,
+        request: Request<RenameRequest>,
+    ) -> Result<Response<RenameResponse>, Status> {
+        let response = self.handle_rename(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn alias(
+        &self,
+        request: Request<AliasRequest>,
+    ) -> Result<Response<AliasResponse>, Status> {
+        let response = self.handle_alias(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn write_at(
+        &self,
+        request: Request<WriteAtRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_write_at(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn overwrite(
+        &self,
+        request: Request<OverwriteRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_overwrite(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<BatchWriteRequest>,
+    ) -> Result<Response<BatchWriteResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_batch_write(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn batch_read(
+        &self,
+        request: Request<BatchReadRequest>,
+    ) -> Result<Response<BatchReadResponse>, Status> {
+        let response = self.storage_op(&self.caller(&request)?, self.handle_batch_read(request.into_inner())).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn exists(
+        &self,
+        request: Request<ExistsRequest>,
+    ) -> Result<Response<ExistsResponse>, Status> {
+        let response = self.handle_exists(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn wait_for_durable(
+        &self,
+        request: Request<WaitForDurableRequest>,
+    ) -> Result<Response<WaitForDurableResponse>, Status> {
+        let response = self.handle_wait_for_durable(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_server_info(
+        &self,
+        request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let response = self.handle_server_info(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let req = request.into_inner();
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+        let mut events = manager.lock().unwrap().subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_QUEUE_DEPTH);
+
+        info!("Watch opened for prefix {:?} in namespace {:?}", req.prefix, req.namespace);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Watcher for prefix {:?} fell behind by {} events", req.prefix, missed);
+                        let status = Status::data_loss(format!("Watcher fell behind and missed {} events", missed));
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if event.key.namespace != req.namespace || !event.key.request_id.starts_with(&req.prefix) {
+                    continue;
+                }
+                if tx.send(Ok(watch_event(event))).await.is_err() {
+                    // Client went away
+                    break;
+                }
+            }
+            info!("Watch closed for prefix {:?}", req.prefix);
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn tail_changes(
+        &self,
+        request: Request<TailChangesRequest>,
+    ) -> Result<Response<Self::TailChangesStream>, Status> {
+        let req = request.into_inner();
+        let manager = self.file_manager(&req.file_id, &req.namespace).await?;
+
+        // Subscribe under the same lock as reading the backlog, so no change
+        // falls between the two; live events already replayed are skipped
+        let (backlog, mut events) = {
+            let file_manager = manager.lock().unwrap();
+            let backlog = file_manager.changes_since(req.from_sequence).map_err(|e| Status::out_of_range(e.to_string()))?;
+            (backlog, file_manager.subscribe())
+        };
+        let (tx, rx) = mpsc::channel(WATCH_QUEUE_DEPTH);
+
+        info!("Change feed opened from sequence {} in namespace {:?}", req.from_sequence, req.namespace);
+
+        tokio::spawn(async move {
+            let mut next = req.from_sequence;
+            let mut pending = backlog;
+            'feed: loop {
+                for event in pending.drain(..) {
+                    if event.sequence < next {
+                        continue;
+                    }
+                    next = event.sequence + 1;
+                    if event.key.namespace != req.namespace {
+                        continue;
+                    }
+                    if tx.send(Ok(watch_event(event))).await.is_err() {
+                        // Client went away
+                        break 'feed;
+                    }
+                }
+
+                match events.recv().await {
+                    Ok(event) => pending.push(event),
+                    // Fell behind the live channel; catch up from the retained log
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let caught_up = manager.lock().unwrap().changes_since(next);
+                        match caught_up {
+                            Ok(changes) => pending = changes,
+                            Err(e) => {
+                                warn!("Change feed in namespace {:?} fell behind: {}", req.namespace, e);
+                                let _ = tx.send(Err(Status::data_loss(e.to_string()))).await;
+                                break;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            info!("Change feed closed at sequence {}", next);
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn pipeline(
+        &self,
+        request: Request<Streaming<PipelineRequest>>,
+    ) -> Result<Response<Self::PipelineStream>, Status> {
+        // Every op on the stream runs in the stream's class and counts
+        // against its client's rates
+        let caller = self.stream_caller(&request)?;
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::channel(PIPELINE_QUEUE_DEPTH);
+        let service = self.clone();
+
+        info!("Pipeline stream opened");
+
+        // Each inbound op runs on its own task so responses are streamed back
+        // as soon as they complete, regardless of submission order
+        tokio::spawn(async move {
+            loop {
+                let message = match inbound.message().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(status) => {
+                        warn!("Pipeline stream error: {}", status);
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                };
+
+                let service = service.clone();
+                let tx = tx.clone();
+                let caller = caller.clone();
+                tokio::spawn(async move {
+                    let response = service.handle_pipeline_op(message, &caller).await;
+                    let _ = tx.send(response).await;
+                });
+            }
+            info!("Pipeline stream closed by client");
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+// Resolve once the process is asked to stop, by SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Cannot listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C, shutting down"),
+    }
+}
+
+// gRPC handlers run on this plain tokio runtime. io_uring rings are driven
+// from threads of their own instead, the I/O workers' and those of polled
+// data files, and handlers reach them over channels; see `UringHandle`.
+#[tokio::main(worker_threads = 1024)]
+async fn main() -> Result<()> {
+    // Initialize logging
+    tracing_subscriber::fmt::init();
+
+    // Configure custom thread pool for high IOPS
+    let _runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1024)
+        .max_blocking_threads(2048) // Increase blocking thread pool for 2300 IOPS
+        .enable_all()
+        .build()?;
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() > 1 && args[1] == "client" {
+        // Run as client
+        println!("Running as client...");
+        client::test_client().await?;
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "migrate" {
+        // Move a data file of the running server to another path
+        let target = args
+            .get(2)
+            .ok_or_else(|| anyhow::anyhow!("Usage: migrate <target path> [--file-id <id>] [--admin-token <token>]"))?;
+        // The server resolves nothing relative to our working directory
+        let target = std::env::current_dir()?.join(target);
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+        let file_id = flag_value("--file-id").unwrap_or_default();
+        let admin_token = flag_value("--admin-token")
+            .or_else(|| std::env::var("ADMIN_TOKEN").ok())
+            .ok_or_else(|| anyhow::anyhow!("migrate requires --admin-token or ADMIN_TOKEN"))?;
+        client::migrate(&file_id, &target.to_string_lossy(), &admin_token).await?;
+        return Ok(());
+    }
+
+    if args.len() > 1 && (args[1] == "export" || args[1] == "import") {
+        // Copy live records between a stopped server's data directory and a
+        // portable archive file
+        let usage = match args[1].as_str() {
+            "export" => "Usage: export <archive path> [--data-dir <dir>] [--file-id <id>]",
+            _ => "Usage: import <archive path> [--data-dir <dir>] [--compression <codec>]",
+        };
+        let archive_path = args.get(2).ok_or_else(|| anyhow::anyhow!(usage))?;
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+        let data_dir = flag_value("--data-dir").unwrap_or_else(|| ".".to_string());
+        file_io::detect_sector_size(std::path::Path::new(&data_dir));
+        file_io::probe_direct_io(std::path::Path::new(&data_dir));
+        let cipher = encryption::load_key(
+            flag_value("--encryption-key-command").as_deref(),
+            flag_value("--encryption-key-file").as_deref(),
+            std::env::var("ENCRYPTION_KEY").ok().as_deref(),
+        )?;
+        // Imported records are synced as they are written
+        let files = FileRegistry::new(&data_dir, Durability::ODsync, None, None, VersionPolicy::default(), None, MetadataStoreKind::Memory);
+
+        if args[1] == "export" {
+            let file_ids = match flag_value("--file-id") {
+                Some(file_id) => vec![file_id],
+                None => files.stored_file_ids()?,
+            };
+            let mut out = std::io::BufWriter::new(std::fs::File::create(archive_path)?);
+            let stats = archive::export(&files, &file_ids, cipher.as_ref(), &mut out).await?;
+            out.into_inner()?.sync_all()?;
+            println!("Exported {} records ({} bytes) from {} data files to {}", stats.records, stats.bytes, file_ids.len(), archive_path);
+        } else {
+            std::fs::create_dir_all(&data_dir)?;
+            let compressor = flag_value("--compression").map(|value| Compressor::parse(&value)).transpose()?;
+            let mut input = std::io::BufReader::new(std::fs::File::open(archive_path)?);
+            let stats = archive::import(&files, cipher.as_ref(), compressor.as_ref(), &mut input).await?;
+            println!("Imported {} records ({} bytes) into {}, skipped {} expired", stats.records, stats.bytes, data_dir, stats.expired);
+        }
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "fsck" {
+        // Check a stopped server's data files against their indexes, exiting
+        // nonzero if any is corrupt
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+        let data_dir = flag_value("--data-dir").unwrap_or_else(|| ".".to_string());
+        file_io::detect_sector_size(std::path::Path::new(&data_dir));
+        file_io::probe_direct_io(std::path::Path::new(&data_dir));
+        let repair = args.iter().any(|arg| arg == "--repair");
+        let files = FileRegistry::new(&data_dir, Durability::ODsync, None, None, VersionPolicy::default(), None, MetadataStoreKind::Memory);
+        let mut file_ids = files.stored_file_ids()?;
+        if let Some(file_id) = flag_value("--file-id") {
+            if !file_ids.contains(&file_id) {
+                anyhow::bail!("No data file {:?} in {}", file_id, data_dir);
+            }
+            file_ids = vec![file_id];
+        }
+
+        let mut corrupt = 0;
+        for file_id in &file_ids {
+            let report = fsck::check(&files.path_for(file_id), repair).await?;
+            for warning in &report.warnings {
+                println!("{}: warning: {}", file_id, warning);
+            }
+            for problem in &report.problems {
+                println!("{}: {}", file_id, problem);
+            }
+            let outcome = match (report.problems.is_empty(), report.repaired) {
+                (true, _) => "ok",
+                (false, true) => "corrupt, repaired",
+                (false, false) => "corrupt",
+            };
+            println!("{}: {} records ({} bytes) checked, {}", file_id, report.records_checked, report.bytes_checked, outcome);
+            if !report.problems.is_empty() {
+                corrupt += 1;
+            }
+        }
+        if corrupt > 0 {
+            println!("{} of {} data files are corrupt", corrupt, file_ids.len());
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "dump" {
+        // Print the records of a data file and where they sit, for debugging
+        // layouts
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+        let data_dir = flag_value("--data-dir").unwrap_or_else(|| ".".to_string());
+        file_io::detect_sector_size(std::path::Path::new(&data_dir));
+        file_io::probe_direct_io(std::path::Path::new(&data_dir));
+        let file_id = file_manager::resolve_file_id(&flag_value("--file-id").unwrap_or_default())?.to_string();
+        let preview = match flag_value("--preview") {
+            Some(format) => {
+                let bytes = match flag_value("--preview-bytes") {
+                    Some(value) => value.parse::<usize>().map_err(|e| anyhow::anyhow!("Invalid --preview-bytes {:?}: {}", value, e))?,
+                    None => 64,
+                };
+                Some(dump::Preview { format: dump::PreviewFormat::parse(&format)?, bytes })
+            }
+            None => None,
+        };
+        let cipher = encryption::load_key(
+            flag_value("--encryption-key-command").as_deref(),
+            flag_value("--encryption-key-file").as_deref(),
+            std::env::var("ENCRYPTION_KEY").ok().as_deref(),
+        )?;
+        let files = FileRegistry::new(&data_dir, Durability::ODsync, None, None, VersionPolicy::default(), None, MetadataStoreKind::Memory);
+        if !files.stored_file_ids()?.contains(&file_id) {
+            anyhow::bail!("No data file {:?} in {}", file_id, data_dir);
+        }
+
+        let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+        let stats = dump::dump(&files.path_for(&file_id), cipher.as_ref(), preview, &mut out).await?;
+        out.flush()?;
+        println!("{} records in {} aligned bytes of a {}-byte data file", stats.records, stats.aligned_bytes, stats.file_size);
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "crash-test" {
+        // Run the engine on a simulated device that loses power at random
+        // points, exiting nonzero if recovery loses or corrupts an
+        // acknowledged write
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+        let count = |flag: &str, default: u64| match flag_value(flag) {
+            Some(value) => value.parse::<u64>().map_err(|e| anyhow::anyhow!("Invalid {} {:?}: {}", flag, value, e)),
+            None => Ok(default),
+        };
+        let data_dir = flag_value("--data-dir").unwrap_or_else(|| ".".to_string());
+        let config = crash_test::CrashTestConfig {
+            crashes: count("--crashes", 100)?,
+            max_ops: count("--ops", 50)?,
+            seed: count("--seed", unix_millis(SystemTime::now()))?,
+        };
+        if config.max_ops == 0 {
+            anyhow::bail!("--ops must be positive");
+        }
+        std::fs::create_dir_all(&data_dir)?;
+
+        println!("Crash test with seed {}", config.seed);
+        let report = crash_test::run(std::path::Path::new(&data_dir), config).await?;
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+        println!(
+            "{} crashes ({} torn record writes, {} torn log entries) after {} acknowledged operations",
+            report.crashes, report.torn_writes, report.torn_logs, report.acknowledged
+        );
+        if !report.problems.is_empty() {
+            println!("Recovery lost or corrupted acknowledged writes; rerun with --seed {} to reproduce", config.seed);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Run as server
+    let addr = "[::1]:50051".parse()?;
+    let data_dir = args
+        .iter()
+        .position(|arg| arg == "--data-dir")
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+        .unwrap_or(".");
+
+    // Keep the default data file on a raw block device rather than in the
+    // data directory
+    let block_device = args
+        .iter()
+        .position(|arg| arg == "--block-device")
+        .map(|index| args.get(index + 1).map(std::path::PathBuf::from).ok_or_else(|| anyhow::anyhow!("--block-device requires a device path")))
+        .transpose()?;
+
+    // Or on an NVMe namespace driven from user space through SPDK, given by
+    // the controller's transport ID
+    let spdk_device = args
+        .iter()
+        .position(|arg| arg == "--spdk-device")
+        .map(|index| args.get(index + 1).cloned().ok_or_else(|| anyhow::anyhow!("--spdk-device requires an NVMe transport ID")))
+        .transpose()?;
+    if spdk_device.is_some() && block_device.is_some() {
+        anyhow::bail!("--spdk-device cannot be combined with --block-device");
+    }
+    #[cfg(not(feature = "spdk"))]
+    if spdk_device.is_some() {
+        anyhow::bail!("--spdk-device requires a build with the spdk feature");
+    }
+    #[cfg(feature = "spdk")]
+    let nvme_namespace = match &spdk_device {
+        Some(transport) => {
+            let namespace_id = match args.iter().position(|arg| arg == "--spdk-namespace") {
+                Some(index) => {
+                    let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--spdk-namespace requires a namespace ID"))?;
+                    value.parse::<u32>().map_err(|e| anyhow::anyhow!("Invalid --spdk-namespace {:?}: {}", value, e))?
+                }
+                None => 1,
+            };
+            Some(spdk::attach(transport, namespace_id)?)
+        }
+        None => None,
+    };
+
+    // Create data directory if it doesn't exist
+    std::fs::create_dir_all(data_dir)?;
+    // Alignment, maximum I/O size and queue depth per volume, where they
+    // should not be detected or left at their defaults
+    if let Some(index) = args.iter().position(|arg| arg == "--io-config") {
+        let path = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--io-config requires a JSON file"))?;
+        volume::load(std::path::Path::new(path))?;
+    }
+    // Align records to the sectors of the data directory, or of the block
+    // device or NVMe namespace, before any data file is opened; sizes given
+    // on the command line must be multiples of them. Then check that
+    // O_DIRECT works in the data directory at that alignment.
+    file_io::detect_sector_size(block_device.as_deref().unwrap_or(std::path::Path::new(data_dir)));
+    #[cfg(feature = "spdk")]
+    if let Some(namespace) = &nvme_namespace {
+        file_io::use_sector_size(namespace.sector_size(), &namespace.to_string());
+    }
+    file_io::probe_direct_io(std::path::Path::new(data_dir));
+
+    let file_per_namespace = args.iter().any(|arg| arg == "--file-per-namespace");
+
+    let namespace_quota = match args.iter().position(|arg| arg == "--namespace-quota") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--namespace-quota requires a byte count"))?;
+            Some(value.parse::<u64>().map_err(|e| anyhow::anyhow!("Invalid --namespace-quota {:?}: {}", value, e))?)
+        }
+        None => None,
+    };
+
+    let duplicate_policy = match args.iter().position(|arg| arg == "--on-duplicate") {
+        Some(index) => match args.get(index + 1).map(String::as_str) {
+            Some("reject") => DuplicatePolicy::Reject,
+            Some("return-existing") => DuplicatePolicy::ReturnExisting,
+            Some("version") => DuplicatePolicy::Version,
+            other => anyhow::bail!("--on-duplicate must be reject, return-existing or version, got {:?}", other),
+        },
+        None => DuplicatePolicy::Version,
+    };
+
+    let durability = match args.iter().position(|arg| arg == "--durability") {
+        Some(index) => match args.get(index + 1).map(String::as_str) {
+            Some("none") => Durability::NoSync,
+            Some("odsync") => Durability::ODsync,
+            Some(value) if value.starts_with("interval:") => {
+                let ms = &value["interval:".len()..];
+                match ms.parse::<u64>() {
+                    Ok(ms) if ms > 0 => Durability::Interval(Duration::from_millis(ms)),
+                    _ => anyhow::bail!("--durability interval must be a positive number of milliseconds, got {:?}", ms),
+                }
+            }
+            other => anyhow::bail!("--durability must be none, odsync or interval:<ms>, got {:?}", other),
+        },
+        None => Durability::NoSync,
+    };
+
+    // Fraction of a data file that must be reclaimable before it is compacted
+    // in the background; unset disables automatic compaction
+    let compact_threshold = match args.iter().position(|arg| arg == "--compact-threshold") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--compact-threshold requires a fraction"))?;
+            match value.parse::<f64>() {
+                Ok(threshold) if threshold > 0.0 && threshold <= 1.0 => Some(threshold),
+                _ => anyhow::bail!("--compact-threshold must be a fraction in (0, 1], got {:?}", value),
+            }
+        }
+        None => None,
+    };
+
+    // Split new data files into segment files of this many bytes; existing
+    // data files keep the layout they were created with
+    let segment_size = match args.iter().position(|arg| arg == "--segment-size") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--segment-size requires a size in bytes"))?;
+            match value.parse::<u64>() {
+                Ok(size) if size > 0 && size.is_multiple_of(sector_size()) => Some(size),
+                _ => anyhow::bail!("--segment-size must be a positive multiple of {} bytes, got {:?}", sector_size(), value),
+            }
+        }
+        None => None,
+    };
+
+    // Store records larger than this in several extents of at most this size
+    let max_extent_size = match args.iter().position(|arg| arg == "--max-extent-size") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--max-extent-size requires a size in bytes"))?;
+            match value.parse::<u64>() {
+                Ok(size) if size > 0 && size.is_multiple_of(sector_size()) => Some(size),
+                _ => anyhow::bail!("--max-extent-size must be a positive multiple of {} bytes, got {:?}", sector_size(), value),
+            }
+        }
+        None => None,
+    };
+
+    // Keep recently read blocks of data files in memory
+    if let Some(index) = args.iter().position(|arg| arg == "--read-cache") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--read-cache requires a size in bytes"))?;
+        match value.parse::<u64>() {
+            Ok(size) if size > 0 => read_cache::set_capacity(size),
+            _ => anyhow::bail!("--read-cache must be a positive size in bytes, got {:?}", value),
+        }
+    }
+
+    // Back large aligned buffers with huge pages
+    if args.iter().any(|arg| arg == "--huge-pages") {
+        aligned_buf::enable_huge_pages();
+    }
+
+    // Largest aligned buffer a single read or write may use; larger payloads
+    // are transferred in pieces
+    if let Some(index) = args.iter().position(|arg| arg == "--max-buffer-size") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--max-buffer-size requires a size in bytes"))?;
+        match value.parse::<u64>() {
+            Ok(size) if size > 0 && size % sector_size() == 0 => aligned_buf::set_max_buffer_size(size),
+            _ => anyhow::bail!("--max-buffer-size must be a positive multiple of {} bytes, got {:?}", sector_size(), value),
+        }
+    }
+
+    // Disk space to reserve up front in each data file, or in each segment of
+    // a segmented one
+    let preallocate = match args.iter().position(|arg| arg == "--preallocate") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--preallocate requires a size in bytes"))?;
+            match value.parse::<u64>() {
+                Ok(size) if size > 0 => Some(size),
+                _ => anyhow::bail!("--preallocate must be a positive size in bytes, got {:?}", value),
+            }
+        }
+        None => None,
+    };
+
+    // Which dead segments of segmented data files to retire, and how to
+    // archive them first
+    let mut retention = RetentionPolicy::default();
+    if let Some(index) = args.iter().position(|arg| arg == "--retention-max-age") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--retention-max-age requires a number of seconds"))?;
+        match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => retention.max_age = Some(Duration::from_secs(secs)),
+            _ => anyhow::bail!("--retention-max-age must be a positive number of seconds, got {:?}", value),
+        }
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--retention-max-size") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--retention-max-size requires a size in bytes"))?;
+        match value.parse::<u64>() {
+            Ok(size) => retention.max_size = Some(size),
+            Err(_) => anyhow::bail!("--retention-max-size must be a size in bytes, got {:?}", value),
+        }
+    }
+    retention.archive_dir = args
+        .iter()
+        .position(|arg| arg == "--archive-dir")
+        .map(|index| args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--archive-dir requires a directory")))
+        .transpose()?
+        .map(std::path::PathBuf::from);
+    retention.archive_command = args
+        .iter()
+        .position(|arg| arg == "--archive-command")
+        .map(|index| args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--archive-command requires a command")))
+        .transpose()?
+        .cloned();
+    if !retention.is_enabled() && (retention.archive_dir.is_some() || retention.archive_command.is_some()) {
+        warn!("Segment archiving is configured but no retention limit is set, nothing will be archived");
+    }
+
+    // Which prior versions of overwritten records to keep
+    let mut version_policy = VersionPolicy::default();
+    if let Some(index) = args.iter().position(|arg| arg == "--keep-versions") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--keep-versions requires a count"))?;
+        match value.parse::<usize>() {
+            Ok(count) if count > 0 => version_policy.max_versions = Some(count),
+            _ => anyhow::bail!("--keep-versions must be a positive count, got {:?}", value),
+        }
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--version-max-age") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--version-max-age requires a number of seconds"))?;
+        match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => version_policy.max_age = Some(Duration::from_secs(secs)),
+            _ => anyhow::bail!("--version-max-age must be a positive number of seconds, got {:?}", value),
+        }
+    }
+
+    // How long deleted records stay in the trash before they are purged
+    let trash_retention = match args.iter().position(|arg| arg == "--trash-retention") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--trash-retention requires a number of seconds"))?;
+            match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                _ => anyhow::bail!("--trash-retention must be a positive number of seconds, got {:?}", value),
+            }
+        }
+        None => None,
+    };
+
+    // Which implementation holds each data file's index
+    let metadata_store = match args.iter().position(|arg| arg == "--metadata-store") {
+        Some(index) => MetadataStoreKind::parse(args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--metadata-store requires memory or lsm"))?)?,
+        None => MetadataStoreKind::Memory,
+    };
+
+    // Re-read and verify every record in the background at this many bytes per
+    // second, if set
+    let scrub = match args.iter().position(|arg| arg == "--scrub-rate") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--scrub-rate requires a number of bytes per second"))?;
+            let rate = match value.parse::<u64>() {
+                Ok(rate) if rate > 0 => rate,
+                _ => anyhow::bail!("--scrub-rate must be a positive number of bytes per second, got {:?}", value),
+            };
+            let interval = match args.iter().position(|arg| arg == "--scrub-interval") {
+                Some(index) => {
+                    let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--scrub-interval requires a number of seconds"))?;
+                    match value.parse::<u64>() {
+                        Ok(secs) => Duration::from_secs(secs),
+                        Err(_) => anyhow::bail!("--scrub-interval must be a number of seconds, got {:?}", value),
+                    }
+                }
+                None => DEFAULT_SCRUB_INTERVAL,
+            };
+            Some(ScrubConfig { rate, interval })
+        }
+        None => None,
+    };
+
+    // Reject writes once the data directory's filesystem is this full, until it
+    // drops below the low watermark; unset disables the check
+    let watermarks = match args.iter().position(|arg| arg == "--disk-high-watermark") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--disk-high-watermark requires a fraction"))?;
+            let high = match value.parse::<f64>() {
+                Ok(high) if high > 0.0 && high <= 1.0 => high,
+                _ => anyhow::bail!("--disk-high-watermark must be a fraction in (0, 1], got {:?}", value),
+            };
+            let low = match args.iter().position(|arg| arg == "--disk-low-watermark") {
+                Some(index) => {
+                    let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--disk-low-watermark requires a fraction"))?;
+                    match value.parse::<f64>() {
+                        Ok(low) if low > 0.0 && low <= high => low,
+                        _ => anyhow::bail!("--disk-low-watermark must be a fraction in (0, {}], got {:?}", high, value),
+                    }
+                }
+                None => (high - 0.05).max(0.0),
+            };
+            Some(Watermarks { high, low })
+        }
+        None => None,
+    };
+
+    // Records are encrypted at rest only if a key is configured
+    let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned();
+    let cipher = encryption::load_key(
+        flag_value("--encryption-key-command").as_deref(),
+        flag_value("--encryption-key-file").as_deref(),
+        std::env::var("ENCRYPTION_KEY").ok().as_deref(),
+    )?;
+    let encrypted = cipher.is_some();
+
+    // Records are compressed only if a codec is configured
+    let compressor = flag_value("--compression").map(|value| Compressor::parse(&value)).transpose()?;
+
+    // Snapshots are uploaded to object storage only if a bucket is configured;
+    // credentials come from the standard AWS environment variables
+    let backup = match flag_value("--backup-bucket") {
+        Some(bucket) => {
+            let config = BackupConfig {
+                endpoint: flag_value("--backup-endpoint").ok_or_else(|| anyhow::anyhow!("--backup-bucket requires --backup-endpoint"))?,
+                region: flag_value("--backup-region").unwrap_or_else(|| "us-east-1".to_string()),
+                bucket,
+                prefix: flag_value("--backup-prefix").unwrap_or_default(),
+                access_key: std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| anyhow::anyhow!("--backup-bucket requires AWS_ACCESS_KEY_ID"))?,
+                secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| anyhow::anyhow!("--backup-bucket requires AWS_SECRET_ACCESS_KEY"))?,
+            };
+            info!("Backing up snapshots to bucket {} at {}", config.bucket, config.endpoint);
+            Some(Arc::new(Backup::new(&config)?))
+        }
+        None => None,
+    };
+    let backup_schedule = flag_value("--backup-schedule").map(|value| Schedule::parse(&value)).transpose()?;
+    if backup_schedule.is_some() && backup.is_none() {
+        anyhow::bail!("--backup-schedule requires --backup-bucket");
+    }
+    // Scheduled backups between two full ones that only copy what changed
+    let backup_incrementals = match flag_value("--backup-incrementals") {
+        Some(value) => match value.parse::<u32>() {
+            Ok(count) => count,
+            Err(_) => anyhow::bail!("--backup-incrementals must be a number of backups, got {:?}", value),
+        },
+        None => 0,
+    };
+
+    // Hold data files in memory, for tests and demos; sidecar files still go
+    // to the data directory
+    if args.iter().any(|arg| arg == "--in-memory") {
+        if segment_size.is_some() || block_device.is_some() || spdk_device.is_some() {
+            anyhow::bail!("--in-memory cannot be combined with --segment-size, --block-device or --spdk-device");
+        }
+        file_io::use_memory_backend();
+    }
+
+    // Make data file I/O fail on purpose, for resilience testing
+    if let Some(index) = args.iter().position(|arg| arg == "--inject-faults") {
+        let spec = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--inject-faults requires a list of faults"))?;
+        let config = faulty_file_io::FaultConfig::parse(spec)?;
+        warn!("Injecting faults into data file I/O: {:?}", config);
+        file_io::inject_faults(config);
+    }
+
+    // Threads running data file I/O, each with an io_uring of its own
+    if let Some(index) = args.iter().position(|arg| arg == "--io-workers") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--io-workers requires a number of threads"))?;
+        match value.parse::<usize>() {
+            Ok(count) if count > 0 => io_workers::set_count(count),
+            _ => anyhow::bail!("--io-workers must be a positive number of threads, got {:?}", value),
+        }
+    }
+
+    // Write small writes made together out in batches
+    if args.iter().any(|arg| arg == "--coalesce-writes") {
+        file_io::enable_write_coalescing();
+    }
+
+    // Poll for read and write completions on devices that support it
+    if args.iter().any(|arg| arg == "--iopoll") {
+        file_io::enable_iopoll();
+    }
+
+    // Fail data file reads, writes and syncs that take longer than this
+    if let Some(index) = args.iter().position(|arg| arg == "--io-timeout") {
+        let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--io-timeout requires a timeout in milliseconds"))?;
+        match value.parse::<u64>() {
+            Ok(millis) if millis > 0 => file_io::set_io_timeout(Duration::from_millis(millis)),
+            _ => anyhow::bail!("--io-timeout must be a positive number of milliseconds, got {:?}", value),
+        }
+    }
+
+    // Run each data file's I/O on its device's NUMA node
+    if args.iter().any(|arg| arg == "--numa") {
+        numa::enable_auto();
+    }
+
+    // Read every data file write back and compare it with what was written
+    if args.iter().any(|arg| arg == "--verify-writes") {
+        info!("Reading back every data file write to verify it");
+        file_io::enable_write_verification();
+    }
+
+    // Stage overwrites in place in a double-write buffer before writing over
+    // the live record
+    let double_write = args.iter().any(|arg| arg == "--double-write");
+
+    let files = FileRegistry::new(data_dir, durability, segment_size, preallocate, version_policy, trash_retention, metadata_store).with_double_write(double_write).with_block_device(block_device);
+    #[cfg(feature = "spdk")]
+    let files = files.with_nvme_namespace(nvme_namespace);
+    // Per-client rates of storage operations and bytes
+    let rate_limiter = match args.iter().position(|arg| arg == "--rate-limits") {
+        Some(index) => {
+            let path = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--rate-limits requires a JSON file"))?;
+            Some(RateLimiter::load(std::path::Path::new(path))?)
+        }
+        None => None,
+    };
+
+    // Storage operations in flight at once; more are refused
+    let max_in_flight = match args.iter().position(|arg| arg == "--max-in-flight") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--max-in-flight requires a number of operations"))?;
+            match value.parse::<usize>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => anyhow::bail!("--max-in-flight must be a positive number of operations, got {:?}", value),
+            }
+        }
+        None => None,
+    };
+    // Bulk-class operations in flight at once, if they have a budget apart
+    // from the others
+    let max_in_flight_bulk = match args.iter().position(|arg| arg == "--max-in-flight-bulk") {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--max-in-flight-bulk requires a number of operations"))?;
+            match value.parse::<usize>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => anyhow::bail!("--max-in-flight-bulk must be a positive number of operations, got {:?}", value),
+            }
+        }
+        None => None,
+    };
+
+    let file_service = FileServiceImpl::new(files, file_per_namespace, namespace_quota, duplicate_policy, cipher, compressor, max_extent_size)
+        .await?
+        .with_admission_limits(max_in_flight, max_in_flight_bulk)
+        .with_rate_limiter(rate_limiter);
+
+    // Roll a data file back to a snapshot before serving; a snapshot that
+    // fails validation stops startup
+    if let Some(index) = args.iter().position(|arg| arg == "--restore") {
+        let snapshot_id = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("--restore requires a snapshot ID"))?;
+        let manifest = snapshot::restore(&file_service.files, snapshot_id).await?;
+        info!("Restored file {:?} from snapshot {}", manifest.file_id, snapshot_id);
+    }
+    let scrub_status = Arc::new(Mutex::new(ScrubStatus { enabled: scrub.is_some(), ..ScrubStatus::default() }));
+    let admin_service = AdminServiceImpl::new(file_service.files.clone(), file_service.maintenance.clone(), scrub_status.clone(), backup.clone());
+
+    // Admin RPCs stay disabled unless a token is configured
+    let admin_token = args
+        .iter()
+        .position(|arg| arg == "--admin-token")
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+        .or_else(|| std::env::var("ADMIN_TOKEN").ok());
+    if admin_token.is_none() {
+        warn!("No admin token configured, AdminService calls will be rejected");
+    }
+
+    tokio::spawn(file_manager::run_expiration_sweeper(file_service.files.clone(), EXPIRATION_SWEEP_INTERVAL));
+    tokio::spawn(upload::run_upload_sweeper(file_service.uploads.clone(), UPLOAD_SWEEP_INTERVAL));
+    if let Durability::Interval(interval) = durability {
+        tokio::spawn(commit::run_commit_markers(file_service.files.clone(), interval));
+    }
+    if let Some(threshold) = compact_threshold {
+        info!("Compacting data files once {:.0}% of a file is reclaimable", threshold * 100.0);
+        tokio::spawn(file_manager::run_auto_compactor(
+            file_service.files.clone(),
+            file_service.maintenance.clone(),
+            AUTO_COMPACT_INTERVAL,
+            threshold,
+        ));
+    }
+    if retention.is_enabled() {
+        info!("Segment retention: {:?}", retention);
+        tokio::spawn(retention::run_retention(
+            file_service.files.clone(),
+            file_service.maintenance.clone(),
+            retention,
+            RETENTION_INTERVAL,
+        ));
+    }
+    if let Some(config) = scrub {
+        info!("Scrubbing records at {} bytes per second, every {:?}", config.rate, config.interval);
+        tokio::spawn(scrubber::run_scrubber(file_service.files.clone(), config, scrub_status));
+    }
+    if let (Some(backup), Some(schedule)) = (backup, backup_schedule) {
+        info!("Backing up every open data file on schedule {:?}", flag_value("--backup-schedule").unwrap_or_default());
+        tokio::spawn(backup::run_backup_scheduler(
+            file_service.files.clone(),
+            file_service.maintenance.clone(),
+            backup,
+            schedule,
+            backup_incrementals,
+        ));
+    }
+    if let Some(watermarks) = watermarks {
+        info!(
+            "Rejecting writes above {:.0}% disk usage until usage drops below {:.0}%",
+            watermarks.high * 100.0,
+            watermarks.low * 100.0
+        );
+        tokio::spawn(capacity::run_capacity_monitor(
+            std::path::PathBuf::from(data_dir),
+            watermarks,
+            file_service.disk_full.clone(),
+            DISK_CHECK_INTERVAL,
+        ));
+    }
+
+    info!("Starting gRPC server on {}", addr);
+    if file_io::direct_io() {
+        info!("Using O_DIRECT mode for file operations");
+    } else {
+        warn!("Using buffered I/O for file operations; O_DIRECT is not supported in {}", data_dir);
+    }
+    info!("Data directory: {}", data_dir);
+    info!("Durability: {:?}", durability);
+    if let Some(size) = segment_size {
+        info!("Segment size: {} bytes", size);
+    }
+    if let Some(size) = max_extent_size {
+        info!("Maximum extent size: {} bytes", size);
+    }
+    if let Some(size) = preallocate {
+        info!("Preallocation: {} bytes", size);
+    }
+    if version_policy.is_enabled() {
+        info!("Record versioning: {:?}", version_policy);
+    }
+    if let Some(retention) = trash_retention {
+        info!("Keeping deleted records in the trash for {:?}", retention);
+    }
+    if encrypted {
+        info!("Encrypting records at rest with AES-256-GCM");
+    }
+    if let Some(compressor) = compressor {
+        info!("Compressing records: {:?}", compressor);
+    }
+    if let Some(quota) = namespace_quota {
+        info!("Namespace quota: {} bytes", quota);
+    }
+
+    // On shutdown the server stops taking requests and lets those in
+    // progress finish; then every data file is drained of the reads and
+    // writes still in flight and flushed before the process exits
+    let files = file_service.files.clone();
+    Server::builder()
+        .add_service(FileServiceServer::new(file_service))
+        .add_service(AdminServiceServer::with_interceptor(admin_service, admin::authorize(admin_token)))
+        .serve_with_shutdown(addr, shutdown_signal())
+        .await?;
+    files.close().await?;
+    info!("Shut down cleanly");
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::file_io::{align_up, sector_size, FileIO};
+
+// Bytes of an in-memory data file, shared by every handle on its path
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+// Contents of every in-memory data file by path, so a path opened again,
+// such as a data file reopened after its manager was dropped, sees the same
+// data
+static FILES: Mutex<Option<HashMap<String, Contents>>> = Mutex::new(None);
+
+// Data file held in a growable buffer instead of on disk, so the full server
+// runs on any OS, in tests, CI and demos, without a device that supports
+// O_DIRECT. Offsets must be sector aligned and writes are padded to whole
+// sectors, as O_DIRECT requires, so misaligned I/O fails here as it would on
+// a device. The contents are gone once the process exits.
+pub(crate) struct MemFileIO {
+    path: String,
+    contents: Contents,
+}
+
+impl MemFileIO {
+    pub(crate) fn open(path: &str) -> Self {
+        let mut files = FILES.lock().unwrap();
+        let contents = files.get_or_insert_with(HashMap::new).entry(path.to_string()).or_default().clone();
+        Self { path: path.to_string(), contents }
+    }
+
+    fn check_aligned(&self, offset: u64) -> Result<()> {
+        if !offset.is_multiple_of(sector_size()) {
+            anyhow::bail!("Offset {} in {} is not aligned to {} bytes, which O_DIRECT would reject", offset, self.path, sector_size());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileIO for MemFileIO {
+    async fn write_at(&self, mut data: Vec<u8>, offset: u64) -> Result<()> {
+        self.check_aligned(offset)?;
+        data.resize(align_up(data.len() as u64) as usize, 0);
+        let mut contents = self.contents.write().unwrap();
+        let end = offset as usize + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(&data);
+        Ok(())
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        self.check_aligned(offset)?;
+        let contents = self.contents.read().unwrap();
+        // Past the end of the file reads as zeros
+        let mut data = vec![0; size as usize];
+        let start = (offset as usize).min(contents.len());
+        let end = (offset as usize + size as usize).min(contents.len());
+        data[..end - start].copy_from_slice(&contents[start..end]);
+        Ok(data.into())
+    }
+
+    async fn len(&self) -> Result<u64> {
+        Ok(self.contents.read().unwrap().len() as u64)
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.contents.write().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "memory"
+    }
+
+    // Memory needs no reserving
+    fn preallocate(&self, _length: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        let mut contents = self.contents.write().unwrap();
+        let start = (offset as usize).min(contents.len());
+        let end = (offset as usize + length as usize).min(contents.len());
+        contents[start..end].fill(0);
+        Ok(())
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        Some("memory")
+    }
+}
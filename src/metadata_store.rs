@@ -0,0 +1,632 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::bloom::BloomFilter;
+use crate::file_manager::{RecordKey, RequestMetadata};
+use crate::index_store::PersistedRecord;
+
+// Shards each data file's index is split into
+const INDEX_SHARDS: usize = 16;
+
+// Entries the LSM stores of an index buffer in memory, across its shards,
+// before flushing them to runs
+const MEMTABLE_ENTRIES: usize = 64 * 1024;
+
+// Runs after which the LSM store merges them all into one
+const MAX_RUNS: usize = 8;
+
+// Entries between two keys of a run's sparse index; a point lookup reads at
+// most this many entries from disk
+const SPARSE_INTERVAL: usize = 64;
+
+// Which implementation holds a data file's index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MetadataStoreKind {
+    Memory,
+    Lsm,
+}
+
+impl MetadataStoreKind {
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        match value {
+            "memory" => Ok(Self::Memory),
+            "lsm" => Ok(Self::Lsm),
+            other => anyhow::bail!("--metadata-store must be memory or lsm, got {:?}", other),
+        }
+    }
+}
+
+// Index of a data file's live records, keyed by namespace and request ID and
+// ordered by both, so IDs sharing a prefix can be listed as a range. Entries
+// are returned as copies, since a store may keep them on disk.
+pub(crate) trait MetadataStore: Send + Sync {
+    fn get(&self, key: &RecordKey) -> Option<RequestMetadata>;
+
+    // Insert or replace an entry, returning the one it replaced
+    fn insert(&mut self, key: RecordKey, metadata: RequestMetadata) -> Option<RequestMetadata>;
+
+    fn remove(&mut self, key: &RecordKey) -> Option<RequestMetadata>;
+
+    // Entries of one namespace with request IDs from `start` on, in order
+    fn range<'a>(&'a self, namespace: &str, start: Bound<&str>) -> Box<dyn Iterator<Item = (String, RequestMetadata)> + 'a>;
+
+    // Every entry, ordered by namespace and then request ID
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (RecordKey, RequestMetadata)> + 'a>;
+
+    fn len(&self) -> usize;
+
+    // Namespaces holding at least one entry
+    fn namespaces(&self) -> Vec<String>;
+
+    fn clear(&mut self);
+
+    fn contains(&self, key: &RecordKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Keep only the entries `keep` returns true for
+    fn retain(&mut self, keep: &mut dyn FnMut(&RecordKey, &RequestMetadata) -> bool) {
+        let doomed: Vec<RecordKey> = self.iter().filter(|(key, metadata)| !keep(key, metadata)).map(|(key, _)| key).collect();
+        for key in doomed {
+            self.remove(&key);
+        }
+    }
+}
+
+// Open an empty index of the given kind for the data file at `data_path`
+pub(crate) fn open(kind: MetadataStoreKind, data_path: &str) -> Result<ShardedStore> {
+    match kind {
+        MetadataStoreKind::Memory => Ok(ShardedStore::in_memory()),
+        MetadataStoreKind::Lsm => {
+            // Each shard keeps its runs in a directory of its own
+            let dir = PathBuf::from(format!("{}.lsm", data_path));
+            match std::fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let shards = (0..INDEX_SHARDS)
+                .map(|index| Ok(Box::new(LsmStore::open(dir.join(format!("shard-{:02}", index)))?) as Box<dyn MetadataStore>))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(ShardedStore::new(shards))
+        }
+    }
+}
+
+// A data file's index split by key hash into shards, each a store of its own
+// behind a read-write lock, so lookups and changes of different keys do not
+// contend. Scans of the whole index, and changes to several keys that must
+// appear at once, lock every shard, always in the same order.
+pub(crate) struct ShardedStore {
+    shards: Vec<RwLock<Box<dyn MetadataStore>>>,
+    hasher: RandomState,
+}
+
+impl ShardedStore {
+    fn new(shards: Vec<Box<dyn MetadataStore>>) -> Self {
+        Self { shards: shards.into_iter().map(RwLock::new).collect(), hasher: RandomState::new() }
+    }
+
+    // An empty index held in memory
+    pub(crate) fn in_memory() -> Self {
+        Self::new((0..INDEX_SHARDS).map(|_| Box::new(MemoryStore::default()) as Box<dyn MetadataStore>).collect())
+    }
+
+    fn shard_index(&self, key: &RecordKey) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    pub(crate) fn get(&self, key: &RecordKey) -> Option<RequestMetadata> {
+        self.shards[self.shard_index(key)].read().unwrap().get(key)
+    }
+
+    pub(crate) fn contains(&self, key: &RecordKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Insert or replace an entry, returning the one it replaced
+    pub(crate) fn insert(&self, key: RecordKey, metadata: RequestMetadata) -> Option<RequestMetadata> {
+        self.shards[self.shard_index(&key)].write().unwrap().insert(key, metadata)
+    }
+
+    pub(crate) fn remove(&self, key: &RecordKey) -> Option<RequestMetadata> {
+        self.shards[self.shard_index(key)].write().unwrap().remove(key)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    // Namespaces holding at least one entry
+    pub(crate) fn namespace_count(&self) -> usize {
+        let mut namespaces = HashSet::new();
+        for shard in &self.shards {
+            namespaces.extend(shard.read().unwrap().namespaces());
+        }
+        namespaces.len()
+    }
+
+    // Keep only the entries `keep` returns true for, one shard at a time
+    pub(crate) fn retain(&self, keep: &mut dyn FnMut(&RecordKey, &RequestMetadata) -> bool) {
+        for shard in &self.shards {
+            shard.write().unwrap().retain(keep);
+        }
+    }
+
+    // Lock every shard for reading, to scan the whole index
+    pub(crate) fn read(&self) -> ShardsRead<'_> {
+        ShardsRead { shards: self.shards.iter().map(|shard| shard.read().unwrap()).collect() }
+    }
+
+    // Lock every shard for writing, so changes to several keys appear at once
+    pub(crate) fn write(&self) -> ShardsWrite<'_> {
+        ShardsWrite { store: self, shards: self.shards.iter().map(|shard| shard.write().unwrap()).collect() }
+    }
+}
+
+// Every shard of an index locked for reading
+pub(crate) struct ShardsRead<'a> {
+    shards: Vec<RwLockReadGuard<'a, Box<dyn MetadataStore>>>,
+}
+
+impl ShardsRead<'_> {
+    // Every entry, ordered by namespace and then request ID
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (RecordKey, RequestMetadata)> + '_ {
+        let sources = self.shards.iter().map(|shard| Box::new(shard.iter().map(|(key, metadata)| (key, Some(metadata)))) as Source).collect();
+        Merge::new(sources).filter_map(|(key, metadata)| Some((key, metadata?)))
+    }
+
+    // Entries of one namespace with request IDs from `start` on, in order
+    pub(crate) fn range<'b>(&'b self, namespace: &'b str, start: Bound<&'b str>) -> impl Iterator<Item = (String, RequestMetadata)> + 'b {
+        let sources = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let entries = shard.range(namespace, start).map(|(request_id, metadata)| (RecordKey { namespace: namespace.to_string(), request_id }, Some(metadata)));
+                Box::new(entries) as Source<'b>
+            })
+            .collect();
+        Merge::new(sources).filter_map(|(key, metadata)| Some((key.request_id, metadata?)))
+    }
+}
+
+// Every shard of an index locked for writing
+pub(crate) struct ShardsWrite<'a> {
+    store: &'a ShardedStore,
+    shards: Vec<RwLockWriteGuard<'a, Box<dyn MetadataStore>>>,
+}
+
+impl ShardsWrite<'_> {
+    pub(crate) fn insert(&mut self, key: RecordKey, metadata: RequestMetadata) -> Option<RequestMetadata> {
+        let index = self.store.shard_index(&key);
+        self.shards[index].insert(key, metadata)
+    }
+
+    pub(crate) fn remove(&mut self, key: &RecordKey) -> Option<RequestMetadata> {
+        let index = self.store.shard_index(key);
+        self.shards[index].remove(key)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.clear();
+        }
+    }
+}
+
+// Every entry held in memory, partitioned by namespace
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    partitions: HashMap<String, BTreeMap<String, RequestMetadata>>,
+    len: usize,
+}
+
+impl MetadataStore for MemoryStore {
+    fn get(&self, key: &RecordKey) -> Option<RequestMetadata> {
+        self.partitions.get(&key.namespace)?.get(&key.request_id).cloned()
+    }
+
+    fn insert(&mut self, key: RecordKey, metadata: RequestMetadata) -> Option<RequestMetadata> {
+        let replaced = self.partitions.entry(key.namespace).or_default().insert(key.request_id, metadata);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    fn remove(&mut self, key: &RecordKey) -> Option<RequestMetadata> {
+        let partition = self.partitions.get_mut(&key.namespace)?;
+        let removed = partition.remove(&key.request_id)?;
+        if partition.is_empty() {
+            self.partitions.remove(&key.namespace);
+        }
+        self.len -= 1;
+        Some(removed)
+    }
+
+    fn range<'a>(&'a self, namespace: &str, start: Bound<&str>) -> Box<dyn Iterator<Item = (String, RequestMetadata)> + 'a> {
+        match self.partitions.get(namespace) {
+            Some(partition) => Box::new(
+                partition
+                    .range::<str, _>((start, Bound::Unbounded))
+                    .map(|(request_id, metadata)| (request_id.clone(), metadata.clone())),
+            ),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (RecordKey, RequestMetadata)> + 'a> {
+        let mut namespaces: Vec<&String> = self.partitions.keys().collect();
+        namespaces.sort();
+        Box::new(namespaces.into_iter().flat_map(move |namespace| {
+            self.partitions[namespace].iter().map(move |(request_id, metadata)| {
+                (RecordKey { namespace: namespace.clone(), request_id: request_id.clone() }, metadata.clone())
+            })
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn namespaces(&self) -> Vec<String> {
+        self.partitions.keys().cloned().collect()
+    }
+
+    fn clear(&mut self) {
+        self.partitions.clear();
+        self.len = 0;
+    }
+}
+
+// One entry of a run: an insert, or a tombstone shadowing older runs
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RunEntry {
+    Put { record: PersistedRecord },
+    Delete { namespace: String, request_id: String },
+}
+
+impl RunEntry {
+    fn new(key: RecordKey, metadata: Option<RequestMetadata>) -> Self {
+        match metadata {
+            Some(metadata) => RunEntry::Put { record: PersistedRecord::new(key, metadata) },
+            None => RunEntry::Delete { namespace: key.namespace, request_id: key.request_id },
+        }
+    }
+
+    fn into_entry(self) -> (RecordKey, Option<RequestMetadata>) {
+        match self {
+            RunEntry::Put { record } => {
+                let (key, metadata) = record.into_entry();
+                (key, Some(metadata))
+            }
+            RunEntry::Delete { namespace, request_id } => (RecordKey { namespace, request_id }, None),
+        }
+    }
+}
+
+// Sorted entries of a memtable, run or merge, tombstones included
+type Source<'a> = Box<dyn Iterator<Item = (RecordKey, Option<RequestMetadata>)> + 'a>;
+
+// Entries are stored as a little-endian u32 length followed by JSON
+fn read_entry(reader: &mut impl Read) -> Result<Option<(RecordKey, Option<RequestMetadata>)>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut payload = vec![0; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice::<RunEntry>(&payload)?.into_entry()))
+}
+
+// Immutable sorted file of entries flushed from the memtable or merged from
+// older runs. Its sparse index and bloom filter stay in memory.
+struct Run {
+    path: PathBuf,
+    file: File,
+    // Every `SPARSE_INTERVAL`th key and the offset of its entry
+    sparse: Vec<(RecordKey, u64)>,
+    filter: BloomFilter,
+}
+
+impl Run {
+    // Write sorted entries, about `expected` of them, to a new run file
+    fn write(path: PathBuf, expected: usize, entries: impl Iterator<Item = (RecordKey, Option<RequestMetadata>)>) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut sparse = Vec::new();
+        let filter = BloomFilter::with_capacity(expected);
+        let mut offset = 0;
+        for (index, (key, metadata)) in entries.enumerate() {
+            if index % SPARSE_INTERVAL == 0 {
+                sparse.push((key.clone(), offset));
+            }
+            filter.insert(&key);
+            let payload = serde_json::to_vec(&RunEntry::new(key, metadata))?;
+            writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+            writer.write_all(&payload)?;
+            offset += 4 + payload.len() as u64;
+        }
+        writer.flush()?;
+        let file = File::open(&path)?;
+        Ok(Self { path, file, sparse, filter })
+    }
+
+    // Offset to start reading at to find `key` or the first key after it
+    fn seek_offset(&self, key: &RecordKey) -> u64 {
+        match self.sparse.partition_point(|(sparse_key, _)| sparse_key <= key) {
+            0 => 0,
+            after => self.sparse[after - 1].1,
+        }
+    }
+
+    // The run's entry for `key`: an insert, a tombstone, or nothing
+    fn get(&self, key: &RecordKey) -> Result<Option<Option<RequestMetadata>>> {
+        if self.sparse.is_empty() || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(PositionedReader { file: &self.file, offset: self.seek_offset(key) });
+        for _ in 0..SPARSE_INTERVAL {
+            match read_entry(&mut reader)? {
+                Some((found, metadata)) if &found == key => return Ok(Some(metadata)),
+                Some((found, _)) if &found < key => continue,
+                _ => break,
+            }
+        }
+        Ok(None)
+    }
+
+    // Entries from `start` on, in key order
+    fn scan(&self, start: &RecordKey) -> Result<RunScan> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.seek_offset(start)))?;
+        Ok(RunScan { reader: BufReader::new(file), path: self.path.clone() })
+    }
+}
+
+// Reads a file from an offset without moving a shared cursor
+struct PositionedReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+struct RunScan {
+    reader: BufReader<File>,
+    path: PathBuf,
+}
+
+impl Iterator for RunScan {
+    type Item = (RecordKey, Option<RequestMetadata>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_entry(&mut self.reader).unwrap_or_else(|e| panic!("Metadata store run {} is unreadable: {}", self.path.display(), e))
+    }
+}
+
+// Log-structured store for indexes too large to hold in memory. Changes go to
+// a sorted memtable, which is flushed to an immutable run file once it holds
+// `MEMTABLE_ENTRIES` entries; removals are recorded as tombstones. A lookup
+// checks the memtable and then the runs from newest to oldest, each behind a
+// bloom filter and a sparse index, so it reads at most a few entries from
+// disk. Once there are `MAX_RUNS` runs they are merged into one, dropping
+// shadowed entries and tombstones. The runs are scratch space: the sidecar
+// index and log stay the source of truth, and the store is rebuilt from them
+// whenever the data file is opened. A run that cannot be read is fatal, as
+// the index would otherwise silently lose entries.
+pub(crate) struct LsmStore {
+    dir: PathBuf,
+    memtable: BTreeMap<RecordKey, Option<RequestMetadata>>,
+    // Newest first
+    runs: Vec<Run>,
+    next_run: u64,
+    len: usize,
+    // Live entries per namespace
+    namespaces: HashMap<String, usize>,
+}
+
+impl LsmStore {
+    pub(crate) fn open(dir: PathBuf) -> Result<Self> {
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir, memtable: BTreeMap::new(), runs: Vec::new(), next_run: 0, len: 0, namespaces: HashMap::new() })
+    }
+
+    fn run_path(&mut self) -> PathBuf {
+        self.next_run += 1;
+        self.dir.join(format!("run-{:08}", self.next_run))
+    }
+
+    // Apply a change to the memtable, returning the entry it replaced
+    fn write(&mut self, key: RecordKey, metadata: Option<RequestMetadata>) -> Option<RequestMetadata> {
+        let previous = self.get(&key);
+        match (&previous, &metadata) {
+            (None, Some(_)) => {
+                self.len += 1;
+                *self.namespaces.entry(key.namespace.clone()).or_default() += 1;
+            }
+            (Some(_), None) => {
+                self.len -= 1;
+                if let Some(count) = self.namespaces.get_mut(&key.namespace) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.namespaces.remove(&key.namespace);
+                    }
+                }
+            }
+            _ => {}
+        }
+        // A removal needs no tombstone if no run can hold the key
+        if metadata.is_none() && self.runs.is_empty() {
+            self.memtable.remove(&key);
+        } else {
+            self.memtable.insert(key, metadata);
+        }
+        if self.memtable.len() >= MEMTABLE_ENTRIES / INDEX_SHARDS {
+            self.flush().unwrap_or_else(|e| panic!("Failed to flush metadata store {}: {}", self.dir.display(), e));
+        }
+        previous
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let path = self.run_path();
+        let memtable = std::mem::take(&mut self.memtable);
+        let run = Run::write(path, memtable.len(), memtable.into_iter())?;
+        self.runs.insert(0, run);
+        if self.runs.len() >= MAX_RUNS {
+            self.merge_runs()?;
+        }
+        Ok(())
+    }
+
+    // Merge every run into one. Nothing older remains for tombstones to
+    // shadow, so they are dropped.
+    fn merge_runs(&mut self) -> Result<()> {
+        let start = RecordKey { namespace: String::new(), request_id: String::new() };
+        let mut sources: Vec<Source> = Vec::new();
+        for run in &self.runs {
+            sources.push(Box::new(run.scan(&start)?));
+        }
+        let path = self.run_path();
+        let merged = Run::write(path, self.len, Merge::new(sources).filter(|(_, metadata)| metadata.is_some()))?;
+        for run in std::mem::replace(&mut self.runs, vec![merged]) {
+            std::fs::remove_file(&run.path)?;
+        }
+        info!("Merged metadata store {} into one run of {} entries", self.dir.display(), self.len);
+        Ok(())
+    }
+
+    // Every source's entries from `start` on, newest first, merged
+    fn merged_from<'a>(&'a self, start: RecordKey) -> Merge<'a> {
+        let memtable: Source<'a> = Box::new(self.memtable.range(start.clone()..).map(|(key, metadata)| (key.clone(), metadata.clone())));
+        let mut sources = vec![memtable];
+        for run in &self.runs {
+            let scan = run.scan(&start).unwrap_or_else(|e| panic!("Metadata store run {} is unreadable: {}", run.path.display(), e));
+            let from = start.clone();
+            sources.push(Box::new(scan.skip_while(move |(key, _)| key < &from)));
+        }
+        Merge::new(sources)
+    }
+}
+
+impl MetadataStore for LsmStore {
+    fn get(&self, key: &RecordKey) -> Option<RequestMetadata> {
+        if let Some(metadata) = self.memtable.get(key) {
+            return metadata.clone();
+        }
+        for run in &self.runs {
+            match run.get(key) {
+                Ok(Some(metadata)) => return metadata,
+                Ok(None) => {}
+                Err(e) => panic!("Metadata store run {} is unreadable: {}", run.path.display(), e),
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, key: RecordKey, metadata: RequestMetadata) -> Option<RequestMetadata> {
+        self.write(key, Some(metadata))
+    }
+
+    fn remove(&mut self, key: &RecordKey) -> Option<RequestMetadata> {
+        if !self.contains(key) {
+            return None;
+        }
+        self.write(key.clone(), None)
+    }
+
+    fn range<'a>(&'a self, namespace: &str, start: Bound<&str>) -> Box<dyn Iterator<Item = (String, RequestMetadata)> + 'a> {
+        let (first, exclude) = match start {
+            Bound::Included(request_id) => (request_id.to_string(), None),
+            Bound::Excluded(request_id) => (request_id.to_string(), Some(request_id.to_string())),
+            Bound::Unbounded => (String::new(), None),
+        };
+        let namespace = namespace.to_string();
+        let start = RecordKey { namespace: namespace.clone(), request_id: first };
+        Box::new(
+            self.merged_from(start)
+                .take_while(move |(key, _)| key.namespace == namespace)
+                .filter(move |(key, _)| exclude.as_ref() != Some(&key.request_id))
+                .filter_map(|(key, metadata)| metadata.map(|metadata| (key.request_id, metadata))),
+        )
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (RecordKey, RequestMetadata)> + 'a> {
+        let start = RecordKey { namespace: String::new(), request_id: String::new() };
+        Box::new(self.merged_from(start).filter_map(|(key, metadata)| metadata.map(|metadata| (key, metadata))))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn namespaces(&self) -> Vec<String> {
+        self.namespaces.keys().cloned().collect()
+    }
+
+    fn clear(&mut self) {
+        for run in self.runs.drain(..) {
+            if let Err(e) = std::fs::remove_file(&run.path) {
+                warn!("Failed to remove metadata store run {}: {}", run.path.display(), e);
+            }
+        }
+        self.memtable.clear();
+        self.namespaces.clear();
+        self.len = 0;
+    }
+}
+
+// Sorted sources merged into one sorted stream. Where several hold the same
+// key, the entry of the earliest source, the newest, wins.
+struct Merge<'a> {
+    sources: Vec<std::iter::Peekable<Source<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        Self { sources: sources.into_iter().map(Iterator::peekable).collect() }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = (RecordKey, Option<RequestMetadata>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Smallest key, and of the sources holding it the newest
+        let (_, newest) = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, source)| source.peek().map(|(key, _)| (key.clone(), index)))
+            .min()?;
+        let entry = self.sources[newest].next()?;
+        for source in self.sources.iter_mut() {
+            while source.peek().is_some_and(|(key, _)| key == &entry.0) {
+                source.next();
+            }
+        }
+        Some(entry)
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::file_io::{create_file_io, logical_sector_size, sector_size, FileIO};
+use crate::file_manager::{self, FileManager, RecordKey, RequestMetadata};
+
+// How long a migration waits for writes already in flight to finish
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Outcome of a migration
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MigrationStats {
+    pub(crate) records_moved: u64,
+    pub(crate) data_size: u64,
+}
+
+// Move a data file to `target`, typically on a bigger disk. The caller keeps
+// new writes out (maintenance mode); reads go on against the old copy. Live
+// records, prior versions and trashed records are copied with O_DIRECT in file
+// order, packed together as compaction would, and synced. The cutover then
+// atomically replaces the data file's path with a symlink to `target` and
+// points the index at the copy, so restarts find the new file. The index and
+// write-ahead log stay in the data directory.
+pub(crate) async fn migrate(manager: &Mutex<FileManager>, target: &Path) -> Result<MigrationStats> {
+    if !target.is_absolute() {
+        anyhow::bail!("Migration target {} must be an absolute path", target.display());
+    }
+    if target.symlink_metadata().is_ok() {
+        anyhow::bail!("Migration target {} already exists", target.display());
+    }
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let (records, source, file_path) = loop {
+        {
+            let mut file_manager = manager.lock().unwrap();
+            if file_manager.file.segment_size().is_some() {
+                anyhow::bail!("Migrating segmented data files is not supported");
+            }
+            if let Some(kind) = file_manager.file.storage_kind() {
+                anyhow::bail!("Migrating data off {} is not supported", kind);
+            }
+            if file_manager.compacting {
+                anyhow::bail!("{} is already being compacted or migrated", file_manager.file_path);
+            }
+            if file_manager.space.in_flight().is_empty() {
+                // Expired records not yet swept still own their extents
+                let mut records = file_manager.entries(UNIX_EPOCH);
+                records.extend(file_manager.retained_entries());
+                let source = file_manager.file.clone();
+                file_manager.compacting = true;
+                break (records, source, file_manager.file_path.clone());
+            }
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("Writes to {} did not drain within {:?}", manager.lock().unwrap().file_path, DRAIN_TIMEOUT);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    let start = Instant::now();
+    let migrated = copy_and_switch(manager, records, source, &file_path, target).await;
+    manager.lock().unwrap().compacting = false;
+    match migrated {
+        Ok(stats) => {
+            info!("Migrated {} to {}: {} records, {} bytes in {:?}", file_path, target.display(), stats.records_moved, stats.data_size, start.elapsed());
+            Ok(stats)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(target);
+            Err(e)
+        }
+    }
+}
+
+async fn copy_and_switch(
+    manager: &Mutex<FileManager>,
+    records: Vec<(RecordKey, RequestMetadata)>,
+    source: Arc<dyn FileIO + Send + Sync>,
+    file_path: &str,
+    target: &Path,
+) -> Result<MigrationStats> {
+    let (old_size, durability, preallocate) = {
+        let file_manager = manager.lock().unwrap();
+        (file_manager.space.end(), file_manager.durability, file_manager.preallocate)
+    };
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+        // Records are aligned to the data directory's sectors; a device with
+        // larger ones could not read or write them with O_DIRECT
+        if let Ok(target_sector) = logical_sector_size(parent) {
+            if target_sector > sector_size() {
+                anyhow::bail!("{} has {}-byte sectors, larger than the {}-byte sectors records are aligned to", parent.display(), target_sector, sector_size());
+            }
+        }
+    }
+    let file = create_file_io(&target.to_string_lossy(), durability).await?;
+    if let Some(length) = preallocate {
+        file.preallocate(length)?;
+    }
+    let (moves, data_size) = file_manager::copy_records(source.as_ref(), file.as_ref(), records).await?;
+    std::fs::File::open(target)?.sync_all()?;
+
+    // A file migrated before is reached through a symlink; its old target is
+    // removed once the new one is in place
+    let data_dir = match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let previous: Option<PathBuf> = std::fs::read_link(file_path).ok().map(|previous| data_dir.join(previous));
+    let link_path = format!("{}.migrate", file_path);
+    let _ = std::fs::remove_file(&link_path);
+    std::os::unix::fs::symlink(target, &link_path)?;
+
+    let records_moved = {
+        let mut file_manager = manager.lock().unwrap();
+        // Writes reserve space without the lock; hold them off until the new
+        // file is in place
+        let space = file_manager.space.clone();
+        let _frozen = space.freeze();
+        // Truncation or a restore rewrites the file under the copy
+        if space.end() != old_size || !space.in_flight().is_empty() {
+            let _ = std::fs::remove_file(&link_path);
+            anyhow::bail!("{} changed during the migration, retry", file_path);
+        }
+        std::fs::rename(&link_path, file_path)?;
+        // Cannot fail: no write is in flight
+        file_manager.adopt(file, data_size, moves, SystemTime::now())? as u64
+    };
+    // The new file is live from here on, so failures only warn
+    if let Err(e) = std::fs::File::open(&data_dir).and_then(|dir| dir.sync_all()) {
+        warn!("Failed to sync {} after migrating {}: {}", data_dir.display(), file_path, e);
+    }
+
+    if let Some(previous) = previous.filter(|previous| previous != target) {
+        if let Err(e) = std::fs::remove_file(&previous) {
+            warn!("Failed to remove {}, the data file's previous location: {}", previous.display(), e);
+        }
+    }
+    Ok(MigrationStats { records_moved, data_size })
+}
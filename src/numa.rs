@@ -0,0 +1,109 @@
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "linux")]
+use anyhow::Result;
+
+#[cfg(target_os = "linux")]
+use crate::volume;
+
+// Whether data files without a configured node are placed on the node their
+// device is attached to
+static AUTO: AtomicBool = AtomicBool::new(false);
+
+// Place every data file's I/O on the NUMA node of its device, for data files
+// opened from now on
+pub(crate) fn enable_auto() {
+    AUTO.store(true, Ordering::Relaxed);
+}
+
+// NUMA node to run the I/O of the data file at `path` on: its volume's
+// configured node, else with `--numa` the node its device is attached to.
+// `None` leaves placement to the scheduler.
+#[cfg(target_os = "linux")]
+pub(crate) fn node_for(path: &Path) -> Option<u32> {
+    volume::for_path(path).and_then(|volume| volume.numa_node).or_else(|| AUTO.load(Ordering::Relaxed).then(|| device_node(path)).flatten())
+}
+
+// NUMA node of the PCIe device behind the file or block device at `path`, or
+// behind the directory a file not yet created goes in, from sysfs. A
+// partition's node is its disk's; devices sysfs gives no node, such as
+// virtual disks on single-node machines, have none.
+#[cfg(target_os = "linux")]
+fn device_node(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let metadata = std::fs::metadata(path).ok().or_else(|| std::fs::metadata(path.parent()?).ok())?;
+    let dev = if metadata.file_type().is_block_device() { metadata.rdev() } else { metadata.dev() };
+    let device = format!("/sys/dev/block/{}:{}", nix::sys::stat::major(dev), nix::sys::stat::minor(dev));
+    // The node is on the disk's `device`, or for an NVMe namespace, whose
+    // `device` is its controller, on the PCIe function one level further;
+    // -1 means the device is on no particular node
+    ["device/numa_node", "device/device/numa_node", "../device/numa_node", "../device/device/numa_node"].iter().find_map(|attribute| {
+        let value = std::fs::read_to_string(format!("{}/{}", device, attribute)).ok()?;
+        u32::try_from(value.trim().parse::<i32>().ok()?).ok()
+    })
+}
+
+// CPUs of NUMA node `node`, from its sysfs cpulist such as `0-7,16-23`
+#[cfg(target_os = "linux")]
+pub(crate) fn node_cpus(node: u32) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let list = std::fs::read_to_string(&path).map_err(|e| anyhow::anyhow!("NUMA node {} is unknown ({}: {})", node, path, e))?;
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let parse = |cpu: &str| cpu.parse::<usize>().map_err(|_| anyhow::anyhow!("Unexpected CPU list {:?} in {}", list.trim(), path));
+        cpus.extend(parse(first)?..=parse(last)?);
+    }
+    if cpus.is_empty() {
+        anyhow::bail!("NUMA node {} has no CPUs", node);
+    }
+    Ok(cpus)
+}
+
+// NUMA node of CPU `cpu`, from the node directory sysfs lists under it
+#[cfg(target_os = "linux")]
+pub(crate) fn cpu_node(cpu: usize) -> Option<u32> {
+    std::fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu)).ok()?.find_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix("node")?.parse().ok())
+}
+
+// CPUs the process may run on
+#[cfg(target_os = "linux")]
+pub(crate) fn allowed_cpus() -> Result<Vec<usize>> {
+    let cpu_set = nix::sched::sched_getaffinity(nix::unistd::Pid::from_raw(0))?;
+    Ok((0..nix::sched::CpuSet::count()).filter(|&cpu| cpu_set.is_set(cpu).unwrap_or(false)).collect())
+}
+
+// Pin the calling thread to `cpus`
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpus: &[usize]) -> Result<()> {
+    let mut cpu_set = nix::sched::CpuSet::new();
+    for &cpu in cpus {
+        cpu_set.set(cpu)?;
+    }
+    nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpu_set)?;
+    Ok(())
+}
+
+// set_mempolicy(2) mode, which libc does not define
+#[cfg(target_os = "linux")]
+const MPOL_PREFERRED: libc::c_int = 1;
+
+// Pin the calling thread to the CPUs of `node` and have the memory it
+// allocates from now on come from that node where there is room
+#[cfg(target_os = "linux")]
+pub(crate) fn bind_current_thread(node: u32) -> Result<()> {
+    pin_current_thread(&node_cpus(node)?)?;
+
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+    // SAFETY: `mask` holds `mask.len() * bits` node bits, one more than
+    // passed being the kernel's convention for the mask length
+    let result = unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_PREFERRED, mask.as_ptr(), mask.len() * bits + 1) };
+    if result != 0 {
+        anyhow::bail!("set_mempolicy for NUMA node {} failed: {}", node, std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::info;
+
+// Clients whose buckets are kept before idle ones are dropped
+const TRACKED_CLIENTS: usize = 4096;
+
+// How clients are told apart
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IdentifyBy {
+    // By the IP address they connect from
+    #[default]
+    Peer,
+    // By the token they send as `authorization: Bearer <token>`, or by
+    // address if they send none
+    Token,
+}
+
+// Rates a client may not exceed; a rate left out is unlimited
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limits {
+    #[serde(default)]
+    pub(crate) ops_per_second: Option<f64>,
+    #[serde(default)]
+    pub(crate) bytes_per_second: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    #[serde(default)]
+    identify_by: IdentifyBy,
+    // Limits of clients not listed
+    #[serde(default)]
+    default: Limits,
+    // Limits of particular clients, by address or token
+    #[serde(default)]
+    clients: HashMap<String, Limits>,
+}
+
+// A request refused because its client is over one of its rates
+#[derive(Debug)]
+pub(crate) struct RateLimited {
+    // "operations" or "bytes"
+    pub(crate) limit: &'static str,
+    pub(crate) rate: f64,
+    pub(crate) retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Client is over its rate of {} {} per second, retry after {:?}", self.rate, self.limit, self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+// Token bucket refilled at `rate` tokens a second, holding at most a second's
+// worth. Bytes are taken once known, so the bucket can go into debt, which
+// is paid off before anything else is let through.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    // How long until the bucket holds `needed` tokens; zero if it does
+    fn wait(&self, needed: f64) -> Duration {
+        Duration::from_secs_f64(((needed - self.tokens) / self.rate).max(0.0))
+    }
+
+    fn full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+}
+
+struct ClientBuckets {
+    ops: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+// Per-client rates of storage operations and of the bytes they carry, in
+// and out, from `--rate-limits`
+pub(crate) struct RateLimiter {
+    identify_by: IdentifyBy,
+    default: Limits,
+    clients: HashMap<String, Limits>,
+    buckets: Mutex<HashMap<String, ClientBuckets>>,
+}
+
+impl RateLimiter {
+    // Load and check the rate limits at `path`
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let config: RateLimitConfig =
+            serde_json::from_slice(&std::fs::read(path)?).map_err(|e| anyhow::anyhow!("Invalid rate limits in {}: {}", path.display(), e))?;
+        for (client, limits) in std::iter::once(("default", &config.default)).chain(config.clients.iter().map(|(client, limits)| (client.as_str(), limits))) {
+            if limits.ops_per_second.into_iter().chain(limits.bytes_per_second).any(|rate| rate < 1.0 || !rate.is_finite()) {
+                anyhow::bail!("Rates of {} in {} must be at least 1 per second", client, path.display());
+            }
+        }
+        info!("Rate limiting clients by {:?}: {} listed, others {:?}", config.identify_by, config.clients.len(), config.default);
+        Ok(Self { identify_by: config.identify_by, default: config.default, clients: config.clients, buckets: Mutex::new(HashMap::new()) })
+    }
+
+    pub(crate) fn identify_by(&self) -> IdentifyBy {
+        self.identify_by
+    }
+
+    // Let an operation by `client` carrying `size` bytes through, or refuse
+    // it if the client is over a rate
+    pub(crate) fn admit(&self, client: &str, size: u64) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= TRACKED_CLIENTS && !buckets.contains_key(client) {
+            // A full bucket is what a new client starts with
+            buckets.retain(|_, client| {
+                client.ops.iter_mut().chain(client.bytes.iter_mut()).any(|bucket| {
+                    bucket.refill(now);
+                    !bucket.full()
+                })
+            });
+        }
+        let limits = self.clients.get(client).unwrap_or(&self.default);
+        let client = buckets.entry(client.to_string()).or_insert_with(|| ClientBuckets {
+            ops: limits.ops_per_second.map(|rate| Bucket::new(rate, now)),
+            bytes: limits.bytes_per_second.map(|rate| Bucket::new(rate, now)),
+        });
+        for bucket in client.ops.iter_mut().chain(client.bytes.iter_mut()) {
+            bucket.refill(now);
+        }
+        if let Some(ops) = client.ops.as_ref().filter(|ops| ops.tokens < 1.0) {
+            return Err(RateLimited { limit: "operations", rate: ops.rate, retry_after: ops.wait(1.0) });
+        }
+        if let Some(bytes) = client.bytes.as_ref().filter(|bytes| bytes.tokens <= 0.0) {
+            return Err(RateLimited { limit: "bytes", rate: bytes.rate, retry_after: bytes.wait(1.0) });
+        }
+        if let Some(ops) = &mut client.ops {
+            ops.tokens -= 1.0;
+        }
+        if let Some(bytes) = &mut client.bytes {
+            bytes.tokens -= size as f64;
+        }
+        Ok(())
+    }
+
+    // Take the `size` bytes of a response to `client` from its byte rate
+    pub(crate) fn charge(&self, client: &str, size: u64) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bytes) = buckets.get_mut(client).and_then(|client| client.bytes.as_mut()) {
+            bytes.refill(Instant::now());
+            bytes.tokens -= size as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(default: Limits, clients: &[(&str, Limits)]) -> RateLimiter {
+        RateLimiter {
+            identify_by: IdentifyBy::Peer,
+            default,
+            clients: clients.iter().map(|(client, limits)| (client.to_string(), *limits)).collect(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ops(rate: f64) -> Limits {
+        Limits { ops_per_second: Some(rate), bytes_per_second: None }
+    }
+
+    fn bytes(rate: f64) -> Limits {
+        Limits { ops_per_second: None, bytes_per_second: Some(rate) }
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_a_second_worth() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10.0, start);
+        assert!(bucket.full());
+        bucket.tokens = 0.0;
+        bucket.refill(start + Duration::from_millis(500));
+        assert_eq!(bucket.tokens, 5.0);
+        assert!(!bucket.full());
+        bucket.refill(start + Duration::from_secs(5));
+        assert_eq!(bucket.tokens, 10.0);
+        assert!(bucket.full());
+    }
+
+    #[test]
+    fn bucket_waits_out_its_debt() {
+        let mut bucket = Bucket::new(100.0, Instant::now());
+        assert_eq!(bucket.wait(50.0), Duration::ZERO);
+        bucket.tokens = -150.0;
+        assert_eq!(bucket.wait(50.0), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn admit_refuses_operations_over_the_rate() {
+        let limiter = limiter(ops(2.0), &[]);
+        limiter.admit("a", 0).unwrap();
+        limiter.admit("a", 0).unwrap();
+        let refused = limiter.admit("a", 0).unwrap_err();
+        assert_eq!(refused.limit, "operations");
+        assert_eq!(refused.rate, 2.0);
+        assert!(refused.retry_after > Duration::ZERO && refused.retry_after <= Duration::from_millis(500));
+        // Each client has buckets of its own
+        limiter.admit("b", 0).unwrap();
+    }
+
+    #[test]
+    fn admit_lets_bytes_into_debt_and_refuses_until_it_is_paid() {
+        let limiter = limiter(bytes(100.0), &[]);
+        limiter.admit("a", 1000).unwrap();
+        let refused = limiter.admit("a", 1).unwrap_err();
+        assert_eq!(refused.limit, "bytes");
+        // Until the 900-byte debt is paid and a byte is back
+        assert!(refused.retry_after > Duration::from_millis(8900) && refused.retry_after <= Duration::from_millis(9010));
+    }
+
+    #[test]
+    fn charge_takes_response_bytes_from_the_byte_rate() {
+        let limiter = limiter(bytes(100.0), &[]);
+        limiter.admit("a", 0).unwrap();
+        limiter.charge("a", 500);
+        assert_eq!(limiter.admit("a", 0).unwrap_err().limit, "bytes");
+        // Clients not seen yet have nothing to charge
+        limiter.charge("b", 500);
+        limiter.admit("b", 0).unwrap();
+    }
+
+    #[test]
+    fn admit_applies_the_limits_of_listed_clients_and_the_default_to_others() {
+        let limiter = limiter(Limits::default(), &[("listed", ops(1.0))]);
+        limiter.admit("listed", 0).unwrap();
+        assert!(limiter.admit("listed", 0).is_err());
+        for _ in 0..100 {
+            limiter.admit("other", u64::MAX).unwrap();
+        }
+    }
+
+    #[test]
+    fn admit_drops_idle_clients_once_too_many_are_tracked() {
+        let unlimited = limiter(Limits::default(), &[]);
+        for client in 0..TRACKED_CLIENTS {
+            unlimited.admit(&client.to_string(), 0).unwrap();
+        }
+        unlimited.admit("new", 0).unwrap();
+        assert_eq!(unlimited.buckets.lock().unwrap().len(), 1);
+
+        // Clients that have spent tokens are kept until their buckets refill
+        let limited = limiter(ops(1.0), &[]);
+        for client in 0..TRACKED_CLIENTS {
+            limited.admit(&client.to_string(), 0).unwrap();
+        }
+        limited.admit("new", 0).unwrap();
+        assert_eq!(limited.buckets.lock().unwrap().len(), TRACKED_CLIENTS + 1);
+    }
+
+    #[test]
+    fn load_rejects_rates_below_one_per_second() {
+        let path = std::env::temp_dir().join(format!("rate-limits-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"identify_by": "token", "clients": {"slow": {"ops_per_second": 0.5}}}"#).unwrap();
+        let loaded = RateLimiter::load(&path);
+        std::fs::write(&path, r#"{"identify_by": "token", "default": {"bytes_per_second": 1024}}"#).unwrap();
+        let valid = RateLimiter::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.err().unwrap().to_string().contains("must be at least 1 per second"));
+        assert_eq!(valid.unwrap().identify_by(), IdentifyBy::Token);
+    }
+}
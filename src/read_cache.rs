@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use tracing::info;
+
+use crate::file_io::{align_up, check_vectored, io_priority, sector_size, Advice, FileIO, IoPriority};
+
+// Reads larger than this bypass the cache, so a large object read once does
+// not push out every hot record
+const MAX_CACHED_READ: u64 = 1024 * 1024;
+
+// Open data files so far, which numbers them in the cache
+static FILES: AtomicU64 = AtomicU64::new(0);
+
+// The block cache, if `--read-cache` set its capacity
+static CACHE: OnceLock<Mutex<BlockCache>> = OnceLock::new();
+
+// Cache up to `capacity` bytes of blocks read from data files opened from now
+// on, see `CachingFileIO`
+pub(crate) fn set_capacity(capacity: u64) {
+    info!("Caching up to {} bytes of data file reads", capacity);
+    let _ = CACHE.set(Mutex::new(BlockCache { capacity, ..BlockCache::default() }));
+}
+
+// The block cache, if one is configured
+pub(crate) fn cache() -> Option<&'static Mutex<BlockCache>> {
+    CACHE.get()
+}
+
+// Block of a data file: the file's number and the block's index in it
+type BlockKey = (u64, u64);
+
+// Blocks of data files, evicting the least recently used once their total
+// size passes the capacity
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    capacity: u64,
+    used: u64,
+    // Each block's contents and when it was last used
+    blocks: HashMap<BlockKey, (Bytes, u64)>,
+    // Blocks by when they were last used
+    by_use: BTreeMap<u64, BlockKey>,
+    clock: u64,
+}
+
+impl BlockCache {
+    fn get(&mut self, key: BlockKey) -> Option<Bytes> {
+        let (data, used) = self.blocks.get_mut(&key)?;
+        self.by_use.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.by_use.insert(self.clock, key);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, data: Bytes) {
+        self.remove(key);
+        self.clock += 1;
+        self.used += data.len() as u64;
+        self.blocks.insert(key, (data, self.clock));
+        self.by_use.insert(self.clock, key);
+        while self.used > self.capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((data, _)) = self.blocks.remove(&oldest) {
+                self.used -= data.len() as u64;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: BlockKey) {
+        if let Some((data, used)) = self.blocks.remove(&key) {
+            self.by_use.remove(&used);
+            self.used -= data.len() as u64;
+        }
+    }
+
+    // Drop every block of data file `file`
+    fn purge(&mut self, file: u64) {
+        let keys: Vec<BlockKey> = self.blocks.keys().filter(|key| key.0 == file).copied().collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
+
+// Decorator keeping the blocks recently read from a data file in the shared
+// block cache, as O_DIRECT reads bypass the page cache. A read is served from
+// the cache if every block it covers is there; otherwise its blocks are read
+// whole and cached. A vectored read is served and cached as one read of the
+// range its buffers cover. Writes, holes and size changes drop the blocks they
+// touch once they are made, and a read that was in flight meanwhile does not
+// cache what it read, as it may predate them. Idle-priority reads, such as
+// scrubbing's, which must see what is on the device, bypass the cache. The
+// cache is per open file: blocks written through another handle on the same
+// path, such as a restore's, are not dropped here.
+pub(crate) struct CachingFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    cache: &'static Mutex<BlockCache>,
+    file: u64,
+    block_size: u64,
+    // Bumped by every change to the file
+    changes: AtomicU64,
+}
+
+impl CachingFileIO {
+    pub(crate) fn new(inner: Arc<dyn FileIO + Send + Sync>, cache: &'static Mutex<BlockCache>) -> Self {
+        Self { inner, cache, file: FILES.fetch_add(1, Ordering::Relaxed), block_size: sector_size().max(4096), changes: AtomicU64::new(0) }
+    }
+
+    // Indexes of the first and last block `size` bytes at `offset` cover
+    fn blocks(&self, size: u64, offset: u64) -> (u64, u64) {
+        (offset / self.block_size, (offset + size.max(1) - 1) / self.block_size)
+    }
+
+    // `size` bytes at `offset` from the cache, if every block is there
+    fn cached(&self, size: u64, offset: u64) -> Option<Bytes> {
+        let (first, last) = self.blocks(size, offset);
+        let start = (offset - first * self.block_size) as usize;
+        let mut cache = self.cache.lock().unwrap();
+        if first == last {
+            return Some(cache.get((self.file, first))?.slice(start..start + size as usize));
+        }
+        let mut data = BytesMut::with_capacity(((last - first + 1) * self.block_size) as usize);
+        for block in first..=last {
+            data.extend_from_slice(&cache.get((self.file, block))?);
+        }
+        Some(data.freeze().slice(start..start + size as usize))
+    }
+
+    // Drop the cached blocks of `length` bytes at `offset`, after a change
+    fn changed(&self, length: u64, offset: u64) {
+        let mut cache = self.cache.lock().unwrap();
+        self.changes.fetch_add(1, Ordering::SeqCst);
+        let (first, last) = self.blocks(length, offset);
+        for block in first..=last {
+            cache.remove((self.file, block));
+        }
+    }
+
+    // Drop every cached block of the file, after a change to its size
+    fn purge(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        self.changes.fetch_add(1, Ordering::SeqCst);
+        cache.purge(self.file);
+    }
+
+    fn bypass(&self, size: u64) -> bool {
+        size == 0 || size > MAX_CACHED_READ || io_priority() == IoPriority::Idle
+    }
+}
+
+#[async_trait]
+impl FileIO for CachingFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let length = align_up(data.len() as u64);
+        let written = self.inner.write_at(data, offset).await;
+        self.changed(length, offset);
+        written
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        if self.bypass(size) {
+            return self.inner.read_at(size, offset).await;
+        }
+        if let Some(data) = self.cached(size, offset) {
+            return Ok(data);
+        }
+        let changes = self.changes.load(Ordering::SeqCst);
+        let (first, last) = self.blocks(size, offset);
+        let start = first * self.block_size;
+        let blocks = self.inner.read_at((last - first + 1) * self.block_size, start).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if self.changes.load(Ordering::SeqCst) == changes {
+            for (index, block) in blocks.chunks(self.block_size as usize).enumerate() {
+                // Copied, so an evicted block frees its memory
+                cache.insert((self.file, first + index as u64), Bytes::copy_from_slice(block));
+            }
+        }
+        let skip = (offset - start) as usize;
+        Ok(blocks.slice(skip..skip + size as usize))
+    }
+
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        let length: u64 = buffers.iter().map(|buffer| align_up(buffer.len() as u64)).sum();
+        let written = self.inner.write_vectored_at(buffers, offset).await;
+        self.changed(length, offset);
+        written
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        check_vectored(sizes.iter().copied())?;
+        let total: u64 = sizes.iter().sum();
+        if self.bypass(total) {
+            return self.inner.read_vectored_at(sizes, offset).await;
+        }
+        let data = self.read_at(total, offset).await?;
+        let mut start = 0;
+        Ok(sizes.iter().map(|&size| {
+            let buffer = data[start..start + size as usize].to_vec();
+            start += size as usize;
+            buffer
+        }).collect())
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        let set = self.inner.set_len(size);
+        self.purge();
+        set
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        self.inner.segment_size()
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        self.inner.storage_kind()
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        self.inner.preallocate(length)
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        let removed = self.inner.remove_segment(index);
+        self.purge();
+        removed
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        let punched = self.inner.punch_hole(offset, length);
+        self.changed(length, offset);
+        punched
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        if !self.bypass(size) {
+            if let Some(data) = self.cached(size, offset) {
+                return Ok(Some(data));
+            }
+        }
+        self.inner.try_read_at(size, offset)
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+impl Drop for CachingFileIO {
+    fn drop(&mut self) {
+        self.cache.lock().unwrap().purge(self.file);
+    }
+}
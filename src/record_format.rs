@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::compression::{self, Codec, Compression, Payload, COMPRESSION_LEN};
+use crate::encryption::{DecryptionFailed, EncryptionKeyMissing, RecordCipher, Seal, NONCE_LEN, TAG_LEN};
+use crate::file_io::{align_down, align_up, FileIO, BLOCK_SIZE};
+use crate::file_manager::{RecordKey, RequestMetadata};
+use crate::snapshot::Crc32;
+
+// Every record in a data file is preceded by a header describing it, so the
+// file can be scanned and the index rebuilt without the sidecar index:
+//
+//   magic          u32  "ODR1"; "ODE1" if encrypted, "ODZ1" if compressed,
+//                       "ODC1" if both
+//   header_len     u32  bytes from the magic to the payload
+//   key_len        u32  length of the key bytes that follow the fixed part
+//   payload_crc    u32  CRC-32 of the stored payload
+//   length         u64  length of the stored payload
+//   key_hash       u64  FNV-1a of the key bytes
+//   written_at_ns  u64  write time, used to pick the newest version
+//   header_crc     u32  CRC-32 of everything above plus the fields below
+//   key            namespace, NUL, request_id
+//   codec          u32  compression codec, compressed records only
+//   original_len   u64  uncompressed length, compressed records only
+//   seal           AES-GCM nonce (12 bytes) and tag (16 bytes), encrypted
+//                  records only
+//   padding        zeros up to header_len
+//
+// All integers are little endian. Index entries point at the payload, so
+// reads never see the header. Payloads are compressed before they are
+// encrypted. The payload CRC covers the stored bytes, so an encrypted record
+// can be checked without the key.
+const RECORD_MAGIC: &[u8; 4] = b"ODR1";
+const SEALED_MAGIC: &[u8; 4] = b"ODE1";
+const COMPRESSED_MAGIC: &[u8; 4] = b"ODZ1";
+const COMPRESSED_SEALED_MAGIC: &[u8; 4] = b"ODC1";
+const FIXED_HEADER: usize = 44;
+const SEAL_LEN: usize = NONCE_LEN + TAG_LEN;
+
+// Largest header the scanner will consider; longer ones are treated as garbage
+const MAX_HEADER: u64 = 64 * 1024;
+
+// Amount of the data file read per step while scanning
+const SCAN_CHUNK: u64 = 1024 * 1024;
+
+// Zeros written per step where a hole cannot be punched
+const ZERO_FILL_CHUNK: u64 = 1024 * 1024;
+
+pub(crate) fn key_bytes(key: &RecordKey) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(key.namespace.len() + 1 + key.request_id.len());
+    bytes.extend_from_slice(key.namespace.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(key.request_id.as_bytes());
+    bytes
+}
+
+// Magic for a record that is encrypted and/or compressed
+fn magic(sealed: bool, compressed: bool) -> &'static [u8; 4] {
+    match (sealed, compressed) {
+        (false, false) => RECORD_MAGIC,
+        (true, false) => SEALED_MAGIC,
+        (false, true) => COMPRESSED_MAGIC,
+        (true, true) => COMPRESSED_SEALED_MAGIC,
+    }
+}
+
+// Whether a magic marks an encrypted and/or compressed record; None if it is
+// not a record magic
+fn features(magic: &[u8]) -> Option<(bool, bool)> {
+    [(false, false), (true, false), (false, true), (true, true)]
+        .into_iter()
+        .find(|&(sealed, compressed)| magic == self::magic(sealed, compressed).as_slice())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// Size of the unpadded header for a key
+pub(crate) fn header_len(key: &RecordKey, encrypted: bool, compressed: bool) -> u64 {
+    let seal = if encrypted { SEAL_LEN } else { 0 };
+    let compression = if compressed { COMPRESSION_LEN } else { 0 };
+    (FIXED_HEADER + key.namespace.len() + 1 + key.request_id.len() + compression + seal) as u64
+}
+
+// Encode the header for a stored payload of `length` bytes with CRC
+// `payload_crc`, compressed from `original` (codec and length) and sealed with
+// `seal` if given, zero padded to `padded_len` (at least `header_len(key, ..)`)
+pub(crate) fn encode_header(key: &RecordKey, length: u64, payload_crc: u32, original: Option<(Codec, u64)>, seal: Option<&Seal>, padded_len: u64) -> Vec<u8> {
+    let key = key_bytes(key);
+    let written_at_ns = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let mut extra = Vec::new();
+    if let Some((codec, original_len)) = original {
+        extra.extend_from_slice(&codec.id().to_le_bytes());
+        extra.extend_from_slice(&original_len.to_le_bytes());
+    }
+    if let Some(seal) = seal {
+        extra.extend_from_slice(&seal.nonce);
+        extra.extend_from_slice(&seal.tag);
+    }
+
+    let mut header = Vec::with_capacity(padded_len as usize);
+    header.extend_from_slice(magic(seal.is_some(), original.is_some()));
+    header.extend_from_slice(&(padded_len as u32).to_le_bytes());
+    header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    header.extend_from_slice(&payload_crc.to_le_bytes());
+    header.extend_from_slice(&length.to_le_bytes());
+    header.extend_from_slice(&fnv1a(&key).to_le_bytes());
+    header.extend_from_slice(&written_at_ns.to_le_bytes());
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(&key);
+    crc.update(&extra);
+    header.extend_from_slice(&crc.finish().to_le_bytes());
+    header.extend_from_slice(&key);
+    header.extend_from_slice(&extra);
+    header.resize(padded_len as usize, 0);
+    header
+}
+
+// Checksum stored in the header and the index, verified on every read
+pub(crate) fn checksum(payload: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    crc.finish()
+}
+
+// A record's payload no longer matches the checksum recorded when it was written
+#[derive(Debug)]
+pub(crate) struct ChecksumMismatch {
+    pub(crate) request_id: String,
+    pub(crate) expected: u32,
+    pub(crate) actual: u32,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request ID {} is corrupt: checksum is {:08x}, expected {:08x}", self.request_id, self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+// Check a record's whole payload against its recorded checksum. Records
+// indexed before checksums were recorded pass unchecked.
+pub(crate) fn verify(request_id: &str, metadata: &RequestMetadata, payload: &[u8]) -> Result<(), ChecksumMismatch> {
+    let Some(expected) = metadata.checksum else {
+        return Ok(());
+    };
+    let actual = checksum(payload);
+    if actual != expected {
+        return Err(ChecksumMismatch { request_id: request_id.to_string(), expected, actual });
+    }
+    Ok(())
+}
+
+// How a framed record is stored, as its index entry records it
+pub(crate) struct Framing {
+    // Length of the header; the payload's offset in the frame
+    pub(crate) header_len: u64,
+    // Length of the payload as written by the client
+    pub(crate) size: u64,
+    // Checksum of the stored payload
+    pub(crate) checksum: u32,
+    pub(crate) encrypted: bool,
+    pub(crate) compression: Option<Compression>,
+}
+
+// Header followed by the payload, encrypted if a cipher is given, ready to be
+// written in one piece
+pub(crate) fn frame(key: &RecordKey, payload: Payload, cipher: Option<&RecordCipher>) -> (Vec<u8>, Framing) {
+    let header_len = header_len(key, cipher.is_some(), payload.codec.is_some());
+    let stored_size = payload.data.len() as u64;
+    let mut bytes = Vec::with_capacity((header_len + stored_size) as usize);
+    bytes.resize(header_len as usize, 0);
+    bytes.extend_from_slice(&payload.data);
+    drop(payload.data);
+
+    let seal = cipher.map(|cipher| cipher.seal(&key_bytes(key), &mut bytes[header_len as usize..]));
+    let crc = checksum(&bytes[header_len as usize..]);
+    let original = payload.codec.map(|codec| (codec, payload.size));
+    let header = encode_header(key, stored_size, crc, original, seal.as_ref(), header_len);
+    bytes[..header_len as usize].copy_from_slice(&header);
+    let framing = Framing {
+        header_len,
+        size: payload.size,
+        checksum: crc,
+        encrypted: seal.is_some(),
+        compression: payload.codec.map(|codec| Compression { codec, stored_size }),
+    };
+    (bytes, framing)
+}
+
+// Recover a record's payload from what was read for it: its payload, or for
+// an encrypted record its header followed by its payload. The stored payload
+// is verified against its checksum, then decrypted and decompressed as needed;
+// a payload stored as is comes back as the bytes read, without a copy.
+pub(crate) fn open_payload(cipher: Option<&RecordCipher>, request_id: &str, metadata: &RequestMetadata, stored: Bytes) -> Result<Bytes> {
+    let payload = if metadata.encrypted {
+        let Some(cipher) = cipher else {
+            return Err(EncryptionKeyMissing { request_id: request_id.to_string() }.into());
+        };
+        let (header, payload) = stored.split_at(metadata.header_len as usize);
+        verify(request_id, metadata, payload)?;
+        let failed = || DecryptionFailed { request_id: request_id.to_string() };
+        let Ok(Some(RecordHeader { key, seal: Some(seal), .. })) = decode_header(header) else {
+            return Err(failed().into());
+        };
+        let mut payload = payload.to_vec();
+        cipher.open(&key_bytes(&key), &seal, &mut payload).map_err(|_| failed())?;
+        Bytes::from(payload)
+    } else {
+        verify(request_id, metadata, &stored)?;
+        stored
+    };
+
+    match metadata.compression {
+        Some(compression) => compression::decompress(compression.codec, &payload, metadata.size)
+            .map(Bytes::from)
+            .map_err(|e| anyhow::anyhow!("Request ID {} could not be decompressed: {}", request_id, e)),
+        None => Ok(payload),
+    }
+}
+
+// A header found while scanning
+struct RecordHeader {
+    header_len: u64,
+    key: RecordKey,
+    length: u64,
+    payload_crc: u32,
+    written_at_ns: u64,
+    // Codec and uncompressed length of a compressed payload
+    original: Option<(Codec, u64)>,
+    seal: Option<Seal>,
+}
+
+// Decode a header at the start of `bytes`. Returns None if there is no valid
+// header there, and Err with the bytes needed if `bytes` is too short to tell.
+fn decode_header(bytes: &[u8]) -> Result<Option<RecordHeader>, usize> {
+    let Some(fixed) = bytes.get(..FIXED_HEADER) else {
+        return match bytes.get(..4) {
+            Some(magic) if features(magic).is_none() => Ok(None),
+            _ => Err(FIXED_HEADER),
+        };
+    };
+    let Some((sealed, compressed)) = features(&fixed[..4]) else {
+        return Ok(None);
+    };
+    let extra_len = (if sealed { SEAL_LEN } else { 0 }) + (if compressed { COMPRESSION_LEN } else { 0 });
+    let u32_at = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(fixed[at..at + 8].try_into().unwrap());
+    let header_len = u32_at(4) as u64;
+    let key_len = u32_at(8) as usize;
+    if (FIXED_HEADER + key_len + extra_len) as u64 > header_len || header_len > MAX_HEADER {
+        return Ok(None);
+    }
+    let Some(variable) = bytes.get(FIXED_HEADER..FIXED_HEADER + key_len + extra_len) else {
+        return Err(FIXED_HEADER + key_len + extra_len);
+    };
+    let (key, mut extra) = variable.split_at(key_len);
+
+    let mut crc = Crc32::new();
+    crc.update(&fixed[..FIXED_HEADER - 4]);
+    crc.update(variable);
+    if crc.finish() != u32_at(FIXED_HEADER - 4) || fnv1a(key) != u64_at(24) {
+        return Ok(None);
+    }
+    let Some(split) = key.iter().position(|&byte| byte == 0) else {
+        return Ok(None);
+    };
+    let (Ok(namespace), Ok(request_id)) = (std::str::from_utf8(&key[..split]), std::str::from_utf8(&key[split + 1..])) else {
+        return Ok(None);
+    };
+    let mut original = None;
+    if compressed {
+        let Some(codec) = Codec::from_id(u32::from_le_bytes(extra[..4].try_into().unwrap())) else {
+            return Ok(None);
+        };
+        original = Some((codec, u64::from_le_bytes(extra[4..COMPRESSION_LEN].try_into().unwrap())));
+        extra = &extra[COMPRESSION_LEN..];
+    }
+    Ok(Some(RecordHeader {
+        header_len,
+        key: RecordKey {
+            namespace: namespace.to_string(),
+            request_id: request_id.to_string(),
+        },
+        length: u64_at(16),
+        payload_crc: u32_at(12),
+        written_at_ns: u64_at(32),
+        original,
+        seal: sealed.then(|| Seal {
+            nonce: extra[..NONCE_LEN].try_into().unwrap(),
+            tag: extra[NONCE_LEN..].try_into().unwrap(),
+        }),
+    }))
+}
+
+// Check that `header`, the bytes in front of a record's payload, is a valid
+// header describing the indexed record, describing the mismatch if not
+pub(crate) fn check_header(header: &[u8], key: &RecordKey, metadata: &RequestMetadata) -> Result<(), String> {
+    let Ok(Some(decoded)) = decode_header(header) else {
+        return Err("no valid record header in front of the payload".to_string());
+    };
+    if decoded.header_len != metadata.header_len {
+        return Err(format!("header is {} bytes, index says {}", decoded.header_len, metadata.header_len));
+    }
+    if decoded.key != *key {
+        return Err(format!("header belongs to {:?} in namespace {:?}", decoded.key.request_id, decoded.key.namespace));
+    }
+    if decoded.length != metadata.stored_size() {
+        return Err(format!("header gives a {}-byte payload, index says {}", decoded.length, metadata.stored_size()));
+    }
+    if metadata.checksum.is_some_and(|checksum| checksum != decoded.payload_crc) {
+        return Err(format!("header has CRC-32 {:08x}, index says {:08x}", decoded.payload_crc, metadata.checksum.unwrap()));
+    }
+    if decoded.seal.is_some() != metadata.encrypted || decoded.original.is_some() != metadata.compression.is_some() {
+        return Err("header and index disagree on encryption or compression".to_string());
+    }
+    Ok(())
+}
+
+// Read `length` bytes at an arbitrary offset through aligned O_DIRECT reads
+pub(crate) async fn read_span(file: &(dyn FileIO + Send + Sync), offset: u64, length: u64) -> Result<Bytes> {
+    let start = align_down(offset);
+    let data = file.read_at(align_up(offset + length) - start, start).await?;
+    let skip = (offset - start) as usize;
+    Ok(data.slice(skip..skip + length as usize))
+}
+
+// Read a record's stored payload, preceded by its header if `with_header` is
+// set, as `open_payload` takes it for an encrypted record. The chunks of a
+// large object are read in parallel and joined.
+pub(crate) async fn read_stored(file: &(dyn FileIO + Send + Sync), metadata: &RequestMetadata, with_header: bool) -> Result<Bytes> {
+    if metadata.chunks.is_empty() {
+        let (start, length) = if with_header {
+            (metadata.offset - metadata.header_len, metadata.header_len + metadata.stored_size())
+        } else {
+            (metadata.offset, metadata.stored_size())
+        };
+        return if length == 0 { Ok(Bytes::new()) } else { read_span(file, start, length).await };
+    }
+
+    let mut stored = if with_header {
+        Vec::from(read_span(file, metadata.offset - metadata.header_len, metadata.header_len).await?)
+    } else {
+        Vec::new()
+    };
+    let mut reads = Vec::with_capacity(metadata.chunks.len());
+    for chunk in &metadata.chunks {
+        reads.push(read_span(file, chunk.offset, chunk.length));
+    }
+    for chunk in futures::future::try_join_all(reads).await? {
+        stored.extend_from_slice(&chunk);
+    }
+    Ok(stored.into())
+}
+
+// Free an aligned run of blocks so it reads as zeros, writing zeros where the
+// file system cannot punch holes
+async fn zero_blocks(file: &(dyn FileIO + Send + Sync), offset: u64, length: u64) -> Result<()> {
+    if file.punch_hole(offset, length).is_ok() {
+        return Ok(());
+    }
+    let end = offset + length;
+    let mut cursor = offset;
+    while cursor < end {
+        let piece = (end - cursor).min(ZERO_FILL_CHUNK);
+        file.write_at(vec![0; piece as usize], cursor).await?;
+        cursor += piece;
+    }
+    Ok(())
+}
+
+// Write a sparse record with its header at `offset`. Only the blocks holding
+// written ranges are written, later ranges over earlier ones; the blocks
+// between them are punched out so they read as zeros. The block holding the
+// last byte is always written, so the file covers the whole record.
+// `header_len` must be a whole number of blocks. Returns the CRC-32 of the
+// payload, holes included.
+pub(crate) async fn write_sparse(file: &(dyn FileIO + Send + Sync), key: &RecordKey, offset: u64, header_len: u64, logical_size: u64, ranges: &[(u64, Vec<u8>)]) -> Result<u32> {
+    // Blocks of the payload to write, merged where they touch
+    let mut spans: Vec<(u64, u64)> = ranges
+        .iter()
+        .filter(|(_, data)| !data.is_empty())
+        .map(|(start, data)| (align_down(*start), align_up(start + data.len() as u64)))
+        .collect();
+    if logical_size > 0 {
+        spans.push((align_down(logical_size - 1), align_up(logical_size)));
+    }
+    spans.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let payload = offset + header_len;
+    let mut crc = Crc32::new();
+    let mut cursor = 0;
+    for (start, end) in merged {
+        if start > cursor {
+            zero_blocks(file, payload + cursor, start - cursor).await?;
+            crc.update_zeros(start - cursor);
+        }
+        let mut blocks = vec![0u8; (end - start) as usize];
+        for (range_start, data) in ranges.iter().filter(|(range_start, data)| !data.is_empty() && (start..end).contains(range_start)) {
+            let within = (range_start - start) as usize;
+            blocks[within..within + data.len()].copy_from_slice(data);
+        }
+        crc.update(&blocks[..(end.min(logical_size) - start) as usize]);
+        file.write_at(blocks, payload + start).await?;
+        cursor = end;
+    }
+
+    let checksum = crc.finish();
+    file.write_at(encode_header(key, logical_size, checksum, None, None, header_len), offset).await?;
+    Ok(checksum)
+}
+
+// Rebuild index entries by scanning a data file for record headers. Headers
+// are looked for at every block boundary and directly after each record's
+// payload, where packed batch entries follow one another. Records whose
+// payload fails its CRC are skipped; when a request ID was written more than
+// once, the newest version wins. Deletes are not recorded in the data file,
+// so deleted records whose data is still present come back.
+pub(crate) async fn scan(file: &(dyn FileIO + Send + Sync), file_size: u64) -> Result<Vec<(RecordKey, RequestMetadata)>> {
+    let mut found: HashMap<RecordKey, (u64, RequestMetadata)> = HashMap::new();
+    let mut corrupt = 0;
+    let mut position = 0;
+    let mut window = Bytes::new();
+    let mut window_start = 0;
+
+    while position < file_size {
+        // Keep the bytes from `position` on in the window, reading more as needed
+        let mut needed = FIXED_HEADER;
+        let decoded = loop {
+            let available = (window_start + window.len() as u64).saturating_sub(position) as usize;
+            if position < window_start || available < needed {
+                let read_start = align_down(position);
+                let read_length = SCAN_CHUNK.max(align_up(position - read_start + needed as u64)).min(align_up(file_size) - read_start);
+                window = file.read_at(read_length, read_start).await?;
+                window.truncate((file_size - read_start) as usize);
+                window_start = read_start;
+            }
+            let slice = &window[(position - window_start) as usize..];
+            match decode_header(slice) {
+                Err(more) if slice.len() < more && position + (slice.len() as u64) < file_size => needed = more,
+                Err(_) => break None,
+                Ok(header) => break header,
+            }
+        };
+
+        // Records written before sector size detection, or on a device with
+        // smaller sectors, start on any `BLOCK_SIZE` boundary
+        let next_block = (position / BLOCK_SIZE + 1) * BLOCK_SIZE;
+        let Some(header) = decoded else {
+            position = next_block;
+            continue;
+        };
+        let payload_offset = position + header.header_len;
+        let payload_end = payload_offset + header.length;
+        if payload_end > file_size {
+            position = next_block;
+            continue;
+        }
+        let payload = read_span(file, payload_offset, header.length).await?;
+        if checksum(&payload) != header.payload_crc {
+            corrupt += 1;
+            position = next_block;
+            continue;
+        }
+
+        let written_at = UNIX_EPOCH + Duration::from_nanos(header.written_at_ns);
+        let metadata = RequestMetadata {
+            offset: payload_offset,
+            size: header.original.map_or(header.length, |(_, original_len)| original_len),
+            header_len: header.header_len,
+            written_at,
+            checksum: Some(header.payload_crc),
+            encrypted: header.seal.is_some(),
+            compression: header.original.map(|(codec, _)| Compression { codec, stored_size: header.length }),
+            generation: 1,
+            expires_at: None,
+            user_metadata: Default::default(),
+            chunks: Vec::new(),
+        };
+        match found.get(&header.key) {
+            Some((newest, _)) if *newest >= header.written_at_ns => {}
+            _ => {
+                found.insert(header.key, (header.written_at_ns, metadata));
+            }
+        }
+
+        // A packed neighbour may start right after the payload; if not, the
+        // next header is looked for at the following block boundary
+        position = payload_end;
+    }
+
+    if corrupt > 0 {
+        warn!("Skipped {} records with corrupt payloads while scanning", corrupt);
+    }
+    info!("Scan found {} records in {} bytes", found.len(), file_size);
+    Ok(found.into_iter().map(|(key, (_, metadata))| (key, metadata)).collect())
+}
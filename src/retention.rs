@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::file_manager::{self, Extent, FileManager, FileRegistry};
+use crate::segment;
+
+// When whole segments of a segmented data file are retired, and what is done
+// with them before they are deleted. Only dead segments are retired: sealed
+// segments whose records have all been deleted or have expired.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetentionPolicy {
+    // Retire dead segments last written to longer ago than this
+    pub(crate) max_age: Option<Duration>,
+    // Retire dead segments, oldest first, while the segments of a file take up
+    // more than this many bytes
+    pub(crate) max_size: Option<u64>,
+    // Copy retired segments into this directory before deleting them
+    pub(crate) archive_dir: Option<PathBuf>,
+    // Shell command run with the archived copy (or, without an archive
+    // directory, the segment itself) as `$1` before the segment is deleted,
+    // e.g. to upload it to external storage; a failure keeps the segment
+    pub(crate) archive_command: Option<String>,
+}
+
+impl RetentionPolicy {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_size.is_some()
+    }
+}
+
+// Take a sealed segment out of use if every record in it has been deleted or
+// has expired. Expired records still in the index are removed first.
+fn claim_dead_segment(file_manager: &mut FileManager, index: u64, segment_size: u64, now: SystemTime) -> Result<Option<Extent>> {
+    let range = Extent {
+        offset: index * segment_size,
+        length: segment_size,
+    };
+    let records: Vec<_> = file_manager
+        .entries(UNIX_EPOCH)
+        .into_iter()
+        .filter(|(_, metadata)| metadata.overlaps(&range))
+        .collect();
+    if records.iter().any(|(_, metadata)| !metadata.is_expired(now)) {
+        return Ok(None);
+    }
+    for (key, _) in &records {
+        file_manager.remove_and_release(key)?;
+    }
+    Ok(file_manager.claim_segment(index, segment_size))
+}
+
+// Archive a segment as the policy asks; an error keeps the segment
+async fn archive(policy: &RetentionPolicy, segment_path: &str) -> Result<()> {
+    let mut path = PathBuf::from(segment_path);
+    if let Some(dir) = &policy.archive_dir {
+        let name = Path::new(segment_path).file_name().ok_or_else(|| anyhow::anyhow!("Invalid segment path {}", segment_path))?;
+        let archived = dir.join(name);
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::copy(segment_path, &archived).await?;
+        std::fs::File::open(&archived)?.sync_all()?;
+        info!("Archived segment {} to {}", segment_path, archived.display());
+        path = archived;
+    }
+
+    if let Some(command) = &policy.archive_command {
+        let status = tokio::process::Command::new("sh").arg("-c").arg(command).arg("sh").arg(&path).status().await?;
+        if !status.success() {
+            anyhow::bail!("Archive command failed for {}: {}", path.display(), status);
+        }
+    }
+    Ok(())
+}
+
+// Retire the dead segments of one data file that the policy no longer keeps,
+// returning how many were deleted. Does nothing for single data files or
+// while the file is being compacted.
+pub(crate) async fn apply(manager: &Mutex<FileManager>, policy: &RetentionPolicy) -> Result<usize> {
+    let (segment_size, file_path) = {
+        let mut file_manager = manager.lock().unwrap();
+        let Some(segment_size) = file_manager.file.segment_size() else {
+            return Ok(0);
+        };
+        // Compaction deletes emptied segments itself, without archiving them
+        if file_manager.compacting {
+            return Ok(0);
+        }
+        file_manager.compacting = true;
+        (segment_size, file_manager.file_path.clone())
+    };
+    let result = retire_segments(manager, policy, segment_size, &file_path).await;
+    manager.lock().unwrap().compacting = false;
+    result
+}
+
+async fn retire_segments(manager: &Mutex<FileManager>, policy: &RetentionPolicy, segment_size: u64, file_path: &str) -> Result<usize> {
+    let now = SystemTime::now();
+    let mut segments = segment::segment_files(file_path)?;
+    segments.sort_by_key(|&(_, _, modified)| modified);
+    let mut total: u64 = segments.iter().map(|&(_, length, _)| length).sum();
+
+    let mut retired = 0;
+    for (index, length, modified) in segments {
+        let too_old = policy.max_age.is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+        let too_big = policy.max_size.is_some_and(|max_size| total > max_size);
+        if !too_old && !too_big {
+            continue;
+        }
+
+        let claimed = {
+            let mut file_manager = manager.lock().unwrap();
+            // The segment being appended to is never retired
+            if index >= file_manager.space.end() / segment_size {
+                continue;
+            }
+            claim_dead_segment(&mut file_manager, index, segment_size, now)?
+        };
+        let Some(range) = claimed else {
+            continue;
+        };
+        // The records removed must stay removed before their data goes
+        file_manager::sync_log(manager).await?;
+
+        let segment_path = segment::segment_path(file_path, index);
+        let archived = archive(policy, &segment_path).await;
+
+        // Either way the whole segment is free space again; a later write
+        // there recreates it
+        let mut file_manager = manager.lock().unwrap();
+        file_manager.release_extent(range);
+        match archived {
+            Ok(()) => {
+                if file_manager.file.remove_segment(index)? {
+                    retired += 1;
+                    total = total.saturating_sub(length);
+                }
+            }
+            Err(e) => warn!("Keeping segment {}: {}", segment_path, e),
+        }
+    }
+
+    if retired > 0 {
+        info!("Retired {} segments of {}", retired, file_path);
+    }
+    Ok(retired)
+}
+
+// Apply the retention policy to every open data file. Files are skipped while
+// maintenance mode is on.
+pub(crate) async fn run_retention(files: Arc<FileRegistry>, maintenance: Arc<AtomicBool>, policy: RetentionPolicy, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if maintenance.load(Ordering::SeqCst) {
+            continue;
+        }
+        for manager in files.managers().await {
+            if let Err(e) = apply(&manager, &policy).await {
+                let file_path = manager.lock().unwrap().file_path.clone();
+                warn!("Applying the retention policy to {} failed: {}", file_path, e);
+            }
+        }
+    }
+}
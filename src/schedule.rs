@@ -0,0 +1,138 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+// How far ahead a schedule is searched for its next match; covers the leap
+// year cycle, so a schedule with no match in it never matches
+const SEARCH_DAYS: u64 = 4 * 366;
+
+// A cron-style schedule of five fields: minute, hour, day of month, month and
+// day of week (0 or 7 is Sunday), evaluated in UTC. Each field is `*`, a
+// value, a range `a-b`, any of those with a step `/n`, or a comma-separated
+// list of them.
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day fields were restricted; as in cron, if both are then a
+    // day matching either one matches
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+// Parse one field into a bitmask of the values it matches
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| anyhow::anyhow!("Invalid step in {:?}", part))?;
+                if step == 0 {
+                    anyhow::bail!("Invalid step in {:?}", part);
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            let start = start.parse().map_err(|_| anyhow::anyhow!("Invalid range {:?}", part))?;
+            let end = end.parse().map_err(|_| anyhow::anyhow!("Invalid range {:?}", part))?;
+            (start, end)
+        } else {
+            let value = range.parse().map_err(|_| anyhow::anyhow!("Invalid value {:?}", part))?;
+            // A single value with a step runs to the end of the field
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            anyhow::bail!("{:?} is outside {}-{}", part, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+// Month and day of month of a day counted from the Unix epoch
+fn month_day(days: u64) -> (u32, u32) {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (month as u32, day as u32)
+}
+
+impl Schedule {
+    pub(crate) fn parse(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            anyhow::bail!("Schedule {:?} must have five fields: minute hour day-of-month month day-of-week", expression);
+        };
+        let mut weekday_mask = parse_field(weekdays, 0, 7)?;
+        if weekday_mask & (1 << 7) != 0 {
+            weekday_mask = (weekday_mask | 1) & !(1 << 7);
+        }
+        let schedule = Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_mask,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        };
+        if schedule.next_after(UNIX_EPOCH).is_none() {
+            anyhow::bail!("Schedule {:?} never matches", expression);
+        }
+        Ok(schedule)
+    }
+
+    fn matches_day(&self, days: u64) -> bool {
+        let (month, day) = month_day(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // The Unix epoch was a Thursday
+        let weekday = (days + 4) % 7;
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+
+    // The first minute strictly after `after` that the schedule matches
+    pub(crate) fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let seconds = after.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let mut minute = seconds / 60 + 1;
+        let limit = minute + SEARCH_DAYS * 24 * 60;
+        while minute < limit {
+            let days = minute / (24 * 60);
+            if !self.matches_day(days) {
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            let hour = minute / 60 % 24;
+            if self.hours & (1 << hour) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) != 0 {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+            }
+            minute += 1;
+        }
+        None
+    }
+}
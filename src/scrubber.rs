@@ -0,0 +1,157 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tracing::{error, info, warn};
+
+use crate::file_io::{with_priority, FileIO, IoPriority};
+use crate::file_manager::{FileManager, FileRegistry, RecordKey, RequestMetadata};
+use crate::record_format;
+
+// Corrupt records reported by GetScrubStatus; older reports are dropped first
+const CORRUPT_REPORT_LIMIT: usize = 1000;
+
+// How fast the scrubber reads and how often it starts a new pass
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScrubConfig {
+    // Bytes read per second, across all data files
+    pub(crate) rate: u64,
+    // Pause between the end of one pass and the start of the next
+    pub(crate) interval: Duration,
+}
+
+// A record whose payload failed its checksum when the scrubber re-read it
+#[derive(Debug, Clone)]
+pub(crate) struct CorruptRecord {
+    pub(crate) data_file: String,
+    pub(crate) key: RecordKey,
+    pub(crate) generation: u64,
+    pub(crate) offset: u64,
+    pub(crate) error: String,
+    pub(crate) detected_at: SystemTime,
+}
+
+// Progress of the scrubber, shared with the admin API
+#[derive(Debug, Default)]
+pub(crate) struct ScrubStatus {
+    pub(crate) enabled: bool,
+    // Set while a pass is running
+    pub(crate) pass_started_at: Option<SystemTime>,
+    pub(crate) current_file: Option<String>,
+    pub(crate) passes_completed: u64,
+    pub(crate) last_pass_completed_at: Option<SystemTime>,
+    // Of the running pass, or of the last one between passes
+    pub(crate) records_checked: u64,
+    pub(crate) bytes_checked: u64,
+    // Corrupt records found so far, oldest first
+    pub(crate) corrupt: Vec<CorruptRecord>,
+}
+
+impl ScrubStatus {
+    fn report(&mut self, corrupt: CorruptRecord) {
+        // A record still corrupt on a later pass is reported once
+        let known = self.corrupt.iter().any(|other| {
+            other.data_file == corrupt.data_file && other.key == corrupt.key && other.generation == corrupt.generation && other.offset == corrupt.offset
+        });
+        if known {
+            return;
+        }
+        if self.corrupt.len() >= CORRUPT_REPORT_LIMIT {
+            self.corrupt.remove(0);
+        }
+        self.corrupt.push(corrupt);
+    }
+}
+
+// Read a record's stored payload and check it against its checksum,
+// describing the failure if the data cannot be read or does not match
+async fn check(file: &(dyn FileIO + Send + Sync), key: &RecordKey, metadata: &RequestMetadata) -> Result<(), String> {
+    let payload = record_format::read_stored(file, metadata, false).await.map_err(|e| e.to_string())?;
+    record_format::verify(&key.request_id, metadata, &payload).map_err(|mismatch| mismatch.to_string())
+}
+
+// Scrub every checksummed record of one data file, live records, prior
+// versions and trashed records alike, in file order. A failure is confirmed
+// against a fresh handle while the record is still indexed at the same place,
+// so records moved or removed mid-pass are not reported.
+async fn scrub_file(manager: &Mutex<FileManager>, config: &ScrubConfig, status: &Mutex<ScrubStatus>) -> anyhow::Result<()> {
+    let (mut records, file, data_file) = {
+        let file_manager = manager.lock().unwrap();
+        let mut records = file_manager.entries(SystemTime::now());
+        records.extend(file_manager.retained_entries());
+        (records, file_manager.file.clone(), file_manager.file_path.clone())
+    };
+    records.retain(|(_, metadata)| metadata.checksum.is_some());
+    records.sort_by_key(|(_, metadata)| metadata.offset);
+    status.lock().unwrap().current_file = Some(data_file.clone());
+
+    for (key, metadata) in records {
+        let mut outcome = check(file.as_ref(), &key, &metadata).await;
+        if outcome.is_err() {
+            let fresh = {
+                let file_manager = manager.lock().unwrap();
+                if file_manager.is_indexed_at(&key, &metadata) {
+                    Some(file_manager.file.clone())
+                } else {
+                    None
+                }
+            };
+            outcome = match fresh {
+                Some(fresh) => check(fresh.as_ref(), &key, &metadata).await,
+                None => Ok(()),
+            };
+        }
+
+        let length = metadata.stored_size();
+        {
+            let mut status = status.lock().unwrap();
+            status.records_checked += 1;
+            status.bytes_checked += length;
+            if let Err(e) = outcome {
+                error!("Scrubber found corrupt record {:?} in {}: {}", key.request_id, data_file, e);
+                status.report(CorruptRecord {
+                    data_file: data_file.clone(),
+                    key,
+                    generation: metadata.generation,
+                    offset: metadata.offset,
+                    error: e,
+                    detected_at: SystemTime::now(),
+                });
+            }
+        }
+        // Throttle to the configured rate
+        tokio::time::sleep(Duration::from_secs_f64(length as f64 / config.rate as f64)).await;
+    }
+    Ok(())
+}
+
+// Re-read and verify every record of every open data file, one pass after
+// another. Corrupt records are logged and reported through the admin API;
+// there is no second copy to repair them from.
+pub(crate) async fn run_scrubber(files: Arc<FileRegistry>, config: ScrubConfig, status: Arc<Mutex<ScrubStatus>>) {
+    loop {
+        {
+            let mut status = status.lock().unwrap();
+            status.pass_started_at = Some(SystemTime::now());
+            status.records_checked = 0;
+            status.bytes_checked = 0;
+        }
+        for manager in files.managers().await {
+            // At idle priority, so scrubbing never delays client I/O
+            if let Err(e) = with_priority(IoPriority::Idle, scrub_file(&manager, &config, &status)).await {
+                let file_path = manager.lock().unwrap().file_path.clone();
+                warn!("Scrubbing {} failed: {}", file_path, e);
+            }
+        }
+
+        let (records, corrupt) = {
+            let mut status = status.lock().unwrap();
+            status.pass_started_at = None;
+            status.current_file = None;
+            status.passes_completed += 1;
+            status.last_pass_completed_at = Some(SystemTime::now());
+            (status.records_checked, status.corrupt.len())
+        };
+        info!("Scrub pass finished: {} records checked, {} corrupt records reported", records, corrupt);
+        tokio::time::sleep(config.interval).await;
+    }
+}
@@ -0,0 +1,383 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::file_io::{create_file_io, sector_size, Advice, Durability, FileIO};
+
+// A data file split into fixed-size segment files, `<data file>.00000`,
+// `<data file>.00001` and so on. Offsets stay global: byte `offset` lives in
+// segment `offset / segment_size` at `offset % segment_size`, so an index
+// offset identifies both the segment and the position inside it. Segments no
+// record lives in can be deleted; reads of a missing segment return zeros.
+pub(crate) struct SegmentedFileIO {
+    data_path: String,
+    segment_size: u64,
+    durability: Durability,
+    segments: Mutex<Segments>,
+    // Held while a missing segment file is created, so concurrent writers to
+    // it create it once
+    creating: tokio::sync::Mutex<()>,
+    backend_name: &'static str,
+    // Bytes preallocated in every segment; 0 for none
+    preallocate: AtomicU64,
+}
+
+struct Segments {
+    // Open segment files by index; `None` for segments never written or deleted
+    files: Vec<Option<Arc<dyn FileIO + Send + Sync>>>,
+    // Known length of each open segment file; reads past it return zeros
+    lengths: Vec<u64>,
+}
+
+// Sidecar recording the segment size a data file was created with
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentManifest {
+    segment_size: u64,
+}
+
+fn manifest_path(data_path: &str) -> String {
+    format!("{}.segments", data_path)
+}
+
+pub(crate) fn segment_path(data_path: &str, index: u64) -> String {
+    format!("{}.{:05}", data_path, index)
+}
+
+// Decide whether a data file is segmented and with what segment size. A file
+// created with segments keeps its original segment size whatever is
+// configured now; a new file gets segments if `configured` is set. An existing
+// single data file cannot be converted.
+pub(crate) fn resolve_segment_size(data_path: &str, configured: Option<u64>) -> Result<Option<u64>> {
+    let manifest_path = manifest_path(data_path);
+    match std::fs::read(&manifest_path) {
+        Ok(bytes) => {
+            let manifest: SegmentManifest = serde_json::from_slice(&bytes)?;
+            if let Some(configured) = configured.filter(|&size| size != manifest.segment_size) {
+                warn!("{} uses {}-byte segments, ignoring configured segment size {}", data_path, manifest.segment_size, configured);
+            }
+            return Ok(Some(manifest.segment_size));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let Some(segment_size) = configured else {
+        return Ok(None);
+    };
+    if segment_size == 0 || !segment_size.is_multiple_of(sector_size()) {
+        anyhow::bail!("Segment size {} is not a positive multiple of {} bytes", segment_size, sector_size());
+    }
+    if std::fs::metadata(data_path).is_ok_and(|metadata| metadata.len() > 0) {
+        anyhow::bail!("{} is a single data file and cannot be opened with segments", data_path);
+    }
+
+    let temp_path = format!("{}.tmp", manifest_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec(&SegmentManifest { segment_size })?)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, &manifest_path)?;
+    info!("Created {} with {}-byte segments", data_path, segment_size);
+    Ok(Some(segment_size))
+}
+
+// Indexes of the segment files present for a data file
+fn existing_segments(data_path: &str) -> Result<Vec<u64>> {
+    let path = Path::new(data_path);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let prefix = format!("{}.", path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default());
+
+    let mut indexes = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .filter(|suffix| suffix.len() == 5 && suffix.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|suffix| suffix.parse::<u64>().ok());
+        indexes.extend(index);
+    }
+    indexes.sort_unstable();
+    Ok(indexes)
+}
+
+// Index, size and last modification time of each segment file of a data file
+pub(crate) fn segment_files(data_path: &str) -> Result<Vec<(u64, u64, SystemTime)>> {
+    existing_segments(data_path)?
+        .into_iter()
+        .map(|index| {
+            let metadata = std::fs::metadata(segment_path(data_path, index))?;
+            Ok((index, metadata.len(), metadata.modified()?))
+        })
+        .collect()
+}
+
+impl SegmentedFileIO {
+    // Open every existing segment of a data file, creating the first if there
+    // are none
+    pub(crate) async fn open(data_path: &str, segment_size: u64, durability: Durability) -> Result<Self> {
+        let mut indexes = existing_segments(data_path)?;
+        if indexes.is_empty() {
+            indexes.push(0);
+        }
+
+        let mut segments = Segments { files: Vec::new(), lengths: Vec::new() };
+        for index in indexes {
+            let file = create_file_io(&segment_path(data_path, index), durability).await?;
+            let length = file.len().await?;
+            segments.insert(index as usize, file, length);
+        }
+        let backend_name = segments.files.iter().flatten().next().map_or("unknown", |file| file.backend_name());
+
+        info!("Opened {} segments of {}", segments.files.iter().flatten().count(), data_path);
+        Ok(Self {
+            data_path: data_path.to_string(),
+            segment_size,
+            durability,
+            segments: Mutex::new(segments),
+            creating: tokio::sync::Mutex::new(()),
+            backend_name,
+            preallocate: AtomicU64::new(0),
+        })
+    }
+
+    // The segment file at `index` and its known length, if it is open
+    fn open_segment(&self, index: usize) -> Option<(Arc<dyn FileIO + Send + Sync>, u64)> {
+        let segments = self.segments.lock().unwrap();
+        let file = segments.files.get(index)?.clone()?;
+        Some((file, segments.lengths[index]))
+    }
+
+    // The segment file at `index`, creating it if needed
+    async fn segment_for_write(&self, index: usize) -> Result<Arc<dyn FileIO + Send + Sync>> {
+        if let Some((file, _)) = self.open_segment(index) {
+            return Ok(file);
+        }
+        // One writer creates a missing segment; the others find it open
+        let _creating = self.creating.lock().await;
+        if let Some((file, _)) = self.open_segment(index) {
+            return Ok(file);
+        }
+        let file = create_file_io(&segment_path(&self.data_path, index as u64), self.durability).await?;
+        let preallocate = self.preallocate.load(Ordering::SeqCst);
+        if preallocate > 0 {
+            file.preallocate(preallocate)?;
+        }
+        let length = file.len().await?;
+        self.segments.lock().unwrap().insert(index, file.clone(), length);
+        Ok(file)
+    }
+
+    // Record that a write to segment `index` reached `end` within it
+    fn extend_segment(&self, index: usize, end: u64) {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(length) = segments.lengths.get_mut(index) {
+            *length = (*length).max(end);
+        }
+    }
+}
+
+impl Segments {
+    fn insert(&mut self, index: usize, file: Arc<dyn FileIO + Send + Sync>, length: u64) {
+        if self.files.len() <= index {
+            self.files.resize_with(index + 1, || None);
+            self.lengths.resize(index + 1, 0);
+        }
+        self.files[index] = Some(file);
+        self.lengths[index] = length;
+    }
+
+    // Close and delete the segment file at `index`, if it is open
+    fn remove(&mut self, data_path: &str, index: u64) -> Result<bool> {
+        let Some(slot) = self.files.get_mut(index as usize) else {
+            return Ok(false);
+        };
+        if slot.take().is_none() {
+            return Ok(false);
+        }
+        self.lengths[index as usize] = 0;
+        let path = segment_path(data_path, index);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        info!("Deleted segment {}", path);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl FileIO for SegmentedFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let end = offset + data.len() as u64;
+
+        // Most writes fit in one segment and are passed through whole
+        let index = (offset / self.segment_size) as usize;
+        let within = offset % self.segment_size;
+        if within + data.len() as u64 <= self.segment_size {
+            self.segment_for_write(index).await?.write_at(data, within).await?;
+            self.extend_segment(index, within + end - offset);
+            return Ok(());
+        }
+
+        let mut cursor = offset;
+        while cursor < end {
+            let index = (cursor / self.segment_size) as usize;
+            let within = cursor % self.segment_size;
+            let length = (self.segment_size - within).min(end - cursor);
+            let piece = data[(cursor - offset) as usize..(cursor - offset + length) as usize].to_vec();
+            self.segment_for_write(index).await?.write_at(piece, within).await?;
+            self.extend_segment(index, within + length);
+            cursor += length;
+        }
+        Ok(())
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        let end = offset + size;
+
+        // A read of written bytes in one segment is passed through whole
+        let index = (offset / self.segment_size) as usize;
+        let within = offset % self.segment_size;
+        if let Some((file, segment_length)) = self.open_segment(index).filter(|_| within + size <= self.segment_size) {
+            if within + size <= segment_length {
+                return file.read_at(size, within).await;
+            }
+        }
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut cursor = offset;
+        while cursor < end {
+            let index = (cursor / self.segment_size) as usize;
+            let within = cursor % self.segment_size;
+            let length = (self.segment_size - within).min(end - cursor);
+            if let Some((file, segment_length)) = self.open_segment(index) {
+                let readable = segment_length.saturating_sub(within).min(length);
+                if readable > 0 {
+                    data.extend_from_slice(&file.read_at(readable, within).await?);
+                }
+            }
+            data.resize((cursor - offset + length) as usize, 0);
+            cursor += length;
+        }
+        Ok(data.into())
+    }
+
+    async fn len(&self) -> Result<u64> {
+        let last = {
+            let segments = self.segments.lock().unwrap();
+            segments.files.iter().enumerate().rev().find_map(|(index, file)| file.clone().map(|file| (index, file)))
+        };
+        match last {
+            Some((index, file)) => Ok(index as u64 * self.segment_size + file.len().await?),
+            None => Ok(0),
+        }
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        let files: Vec<_> = self.segments.lock().unwrap().files.iter().flatten().cloned().collect();
+        for file in files {
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    // Truncate the segment containing `size` and delete every later one
+    fn set_len(&self, size: u64) -> Result<()> {
+        let keep = (size / self.segment_size) as usize;
+        let mut segments = self.segments.lock().unwrap();
+        while segments.files.len() > keep + 1 {
+            let index = segments.files.len() - 1;
+            segments.remove(&self.data_path, index as u64)?;
+            segments.files.pop();
+            segments.lengths.pop();
+        }
+        if let Some(file) = segments.files.get(keep).and_then(Option::as_ref) {
+            let within = size % self.segment_size;
+            file.set_len(within)?;
+            segments.lengths[keep] = within;
+        }
+        Ok(())
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.backend_name
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        Some(self.segment_size)
+    }
+
+    // Preallocate up to `length` bytes of every segment, including segments
+    // created later
+    fn preallocate(&self, length: u64) -> Result<()> {
+        let length = length.min(self.segment_size);
+        self.preallocate.store(length, Ordering::SeqCst);
+        for file in self.segments.lock().unwrap().files.iter().flatten() {
+            file.preallocate(length)?;
+        }
+        Ok(())
+    }
+
+    // Punch the part of the hole in each segment; segments never created
+    // hold no data to free
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        let end = offset + length;
+        let mut cursor = offset;
+        while cursor < end {
+            let index = (cursor / self.segment_size) as usize;
+            let within = cursor % self.segment_size;
+            let length = (self.segment_size - within).min(end - cursor);
+            if let Some((file, _)) = self.open_segment(index) {
+                file.punch_hole(within, length)?;
+            }
+            cursor += length;
+        }
+        Ok(())
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        let end = offset + length;
+        let mut cursor = offset;
+        while cursor < end {
+            let index = (cursor / self.segment_size) as usize;
+            let within = cursor % self.segment_size;
+            let length = (self.segment_size - within).min(end - cursor);
+            if let Some((file, _)) = self.open_segment(index) {
+                file.advise(within, length, advice)?;
+            }
+            cursor += length;
+        }
+        Ok(())
+    }
+
+    // Only reads of written bytes within one segment are tried
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        let within = offset % self.segment_size;
+        match self.open_segment((offset / self.segment_size) as usize) {
+            Some((file, segment_length)) if within + size <= segment_length.min(self.segment_size) => file.try_read_at(size, within),
+            _ => Ok(None),
+        }
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        self.segments.lock().unwrap().remove(&self.data_path, index)
+    }
+
+    async fn close(&self) -> Result<()> {
+        let files: Vec<_> = self.segments.lock().unwrap().files.iter().flatten().cloned().collect();
+        for file in files {
+            file.close().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::file_io::create_file_io;
+use crate::file_manager::{Extent, FileManager, FileRegistry, RecordKey, RequestMetadata};
+use crate::index_store::PersistedRecord;
+
+// Files making up a snapshot directory
+pub(crate) const DATA_FILE: &str = "data.bin";
+pub(crate) const INDEX_FILE: &str = "index.json";
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+// How long a snapshot waits for writes into indexed extents to finish
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Chunk size used when copying the data file
+const COPY_CHUNK: usize = 1024 * 1024;
+
+// Describes a snapshot and the checksums used to validate it on restore
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SnapshotManifest {
+    pub(crate) snapshot_id: String,
+    pub(crate) file_id: String,
+    pub(crate) created_at_ms: u64,
+    pub(crate) data_size: u64,
+    pub(crate) data_crc32: u32,
+    pub(crate) index_crc32: u32,
+    pub(crate) record_count: u64,
+    // Snapshot this one is an increment on; `None` for a full snapshot
+    #[serde(default)]
+    pub(crate) parent_id: Option<String>,
+    // For an incremental snapshot, the ranges of the data file that changed
+    // since the parent; its data file holds them back to back
+    #[serde(default)]
+    pub(crate) extents: Vec<CopiedExtent>,
+}
+
+impl SnapshotManifest {
+    // Bytes of the snapshot's own data file
+    pub(crate) fn copied_bytes(&self) -> u64 {
+        match self.parent_id {
+            Some(_) => self.extents.iter().map(|extent| extent.length).sum(),
+            None => self.data_size,
+        }
+    }
+}
+
+// A range of the data file copied into an incremental snapshot
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct CopiedExtent {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+// CRC-32 (IEEE), computed incrementally over the snapshot files
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    // Feed `length` zero bytes without touching each one: a zero byte maps
+    // the register through a fixed linear operator, which is raised to the
+    // `length`th power by repeated squaring
+    pub(crate) fn update_zeros(&mut self, mut length: u64) {
+        let mut operator: [u32; 32] = std::array::from_fn(|bit| {
+            let register = 1u32 << bit;
+            CRC32_TABLE[(register & 0xff) as usize] ^ (register >> 8)
+        });
+        while length > 0 {
+            if length & 1 == 1 {
+                self.0 = apply_operator(&operator, self.0);
+            }
+            operator = std::array::from_fn(|bit| apply_operator(&operator, operator[bit]));
+            length >>= 1;
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+// Apply a linear operator on CRC registers, given as the images of each bit
+fn apply_operator(operator: &[u32; 32], register: u32) -> u32 {
+    (0..32).filter(|bit| (register >> bit) & 1 == 1).fold(0, |image, bit| image ^ operator[bit])
+}
+
+// Copy `size` bytes from `source` to `target`, returning their CRC-32
+fn copy_with_crc(source: &Path, target: &Path, size: u64) -> Result<u32> {
+    let mut reader = File::open(source)?.take(size);
+    let mut writer = File::create(target)?;
+    let mut crc = Crc32::new();
+    let mut buffer = vec![0u8; COPY_CHUNK];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+    }
+    writer.sync_all()?;
+    Ok(crc.finish())
+}
+
+// Copy `extents` of `source` back to back into `target`, returning their CRC-32
+fn copy_extents_with_crc(source: &Path, target: &Path, extents: &[CopiedExtent]) -> Result<u32> {
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(target)?;
+    let mut crc = Crc32::new();
+    let mut buffer = vec![0u8; COPY_CHUNK];
+    for extent in extents {
+        reader.seek(SeekFrom::Start(extent.offset))?;
+        let mut remaining = extent.length;
+        while remaining > 0 {
+            let n = remaining.min(COPY_CHUNK as u64) as usize;
+            reader.read_exact(&mut buffer[..n])?;
+            crc.update(&buffer[..n]);
+            writer.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+    }
+    writer.sync_all()?;
+    Ok(crc.finish())
+}
+
+// Ranges of the data file holding records that are new or different since
+// the parent snapshot: written, overwritten or moved by compaction. Adjacent
+// and overlapping extents are merged.
+fn changed_extents(entries: &[(RecordKey, RequestMetadata)], parent: &[PersistedRecord]) -> Vec<CopiedExtent> {
+    let unchanged: HashMap<(&str, &str), (u64, u64, u64)> = parent
+        .iter()
+        .map(|record| ((record.namespace.as_str(), record.request_id.as_str()), (record.offset, record.size, record.generation)))
+        .collect();
+    let mut extents: Vec<Extent> = entries
+        .iter()
+        .filter(|(key, metadata)| {
+            unchanged.get(&(key.namespace.as_str(), key.request_id.as_str())) != Some(&(metadata.offset, metadata.size, metadata.generation))
+        })
+        .flat_map(|(_, metadata)| metadata.extents())
+        .collect();
+    extents.sort_by_key(|extent| extent.offset);
+
+    let mut merged: Vec<CopiedExtent> = Vec::new();
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last) if extent.offset <= last.offset + last.length => {
+                last.length = extent.end().max(last.offset + last.length) - last.offset;
+            }
+            _ => merged.push(CopiedExtent { offset: extent.offset, length: extent.length }),
+        }
+    }
+    merged
+}
+
+// Write the snapshot files into `dir`. Runs on a blocking thread.
+fn write_snapshot(dir: &Path, source: &Path, data_size: u64, records: &[PersistedRecord], mut manifest: SnapshotManifest) -> Result<SnapshotManifest> {
+    std::fs::create_dir_all(dir)?;
+    manifest.data_crc32 = match manifest.parent_id {
+        Some(_) => copy_extents_with_crc(source, &dir.join(DATA_FILE), &manifest.extents)?,
+        None => copy_with_crc(source, &dir.join(DATA_FILE), data_size)?,
+    };
+
+    let index = serde_json::to_vec(records)?;
+    let mut crc = Crc32::new();
+    crc.update(&index);
+    manifest.index_crc32 = crc.finish();
+    let mut index_file = File::create(dir.join(INDEX_FILE))?;
+    index_file.write_all(&index)?;
+    index_file.sync_all()?;
+
+    // The manifest goes last; a directory without one is an incomplete snapshot
+    let mut manifest_file = File::create(dir.join(MANIFEST_FILE))?;
+    manifest_file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    manifest_file.sync_all()?;
+    Ok(manifest)
+}
+
+// Copy a data file and its request map into `dir`. The caller keeps new
+// writes out (maintenance mode); this waits for writes already in flight over
+// indexed extents, such as in-place overwrites, so the copy is consistent with
+// the index captured alongside it. With a parent snapshot of the same file,
+// only the extents of records that changed since the parent are copied; the
+// whole request map is still saved.
+pub(crate) async fn create(manager: &Mutex<FileManager>, file_id: &str, snapshot_id: &str, dir: PathBuf, parent_id: Option<&str>) -> Result<SnapshotManifest> {
+    let parent = match parent_id {
+        Some(parent_id) => {
+            uuid::Uuid::parse_str(parent_id).map_err(|_| anyhow::anyhow!("Invalid snapshot ID {:?}", parent_id))?;
+            let parent_dir = dir.with_file_name(parent_id);
+            let (manifest, records) = tokio::task::spawn_blocking(move || load(&parent_dir)).await??;
+            if manifest.file_id != file_id {
+                anyhow::bail!("Snapshot {} is of file {:?}, not {:?}", parent_id, manifest.file_id, file_id);
+            }
+            Some((manifest.snapshot_id, records))
+        }
+        None => None,
+    };
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let (entries, data_size, file_path) = loop {
+        {
+            let file_manager = manager.lock().unwrap();
+            if file_manager.file.segment_size().is_some() {
+                anyhow::bail!("Snapshots of segmented data files are not supported");
+            }
+            if let Some(kind) = file_manager.file.storage_kind() {
+                anyhow::bail!("Snapshots of data on {} are not supported", kind);
+            }
+            let entries = file_manager.entries(SystemTime::now());
+            let _frozen = file_manager.space.freeze();
+            let busy = file_manager
+                .space
+                .in_flight()
+                .iter()
+                .any(|pending| entries.iter().any(|(_, metadata)| metadata.overlaps(pending)));
+            if !busy {
+                break (entries, file_manager.space.end(), file_manager.file_path.clone());
+            }
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("Writes to {} did not drain within {:?}", file_id, DRAIN_TIMEOUT);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    let start = Instant::now();
+    let (parent_id, extents) = match parent {
+        Some((parent_id, parent_records)) => (Some(parent_id), changed_extents(&entries, &parent_records)),
+        None => (None, Vec::new()),
+    };
+    let records: Vec<PersistedRecord> = entries.into_iter().map(|(key, metadata)| PersistedRecord::new(key, metadata)).collect();
+    let manifest = SnapshotManifest {
+        snapshot_id: snapshot_id.to_string(),
+        file_id: file_id.to_string(),
+        created_at_ms: crate::unix_millis(SystemTime::now()),
+        data_size,
+        data_crc32: 0,
+        index_crc32: 0,
+        record_count: records.len() as u64,
+        parent_id,
+        extents,
+    };
+
+    let target = dir.clone();
+    let written = tokio::task::spawn_blocking(move || write_snapshot(&target, Path::new(&file_path), data_size, &records, manifest)).await?;
+    let manifest = match written {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+    };
+
+    // Truncation or compaction rewrites the file under the copy
+    if manager.lock().unwrap().space.end() != data_size {
+        let _ = std::fs::remove_dir_all(&dir);
+        anyhow::bail!("{} changed during the snapshot, retry", file_id);
+    }
+
+    info!(
+        "Snapshot {} of {}: {} records, {} of {} bytes copied in {:?}",
+        snapshot_id, file_id, manifest.record_count, manifest.copied_bytes(), data_size, start.elapsed()
+    );
+    Ok(manifest)
+}
+
+// Read a snapshot's manifest and index, checking the index against its CRC-32
+pub(crate) fn load(dir: &Path) -> Result<(SnapshotManifest, Vec<PersistedRecord>)> {
+    let manifest = std::fs::read(dir.join(MANIFEST_FILE))
+        .map_err(|e| anyhow::anyhow!("Snapshot {} has no readable manifest: {}", dir.display(), e))?;
+    let manifest: SnapshotManifest = serde_json::from_slice(&manifest)?;
+
+    let index = std::fs::read(dir.join(INDEX_FILE))?;
+    let mut crc = Crc32::new();
+    crc.update(&index);
+    if crc.finish() != manifest.index_crc32 {
+        anyhow::bail!("Index of snapshot {} is corrupt: CRC-32 {:08x}, expected {:08x}", manifest.snapshot_id, crc.finish(), manifest.index_crc32);
+    }
+    let records: Vec<PersistedRecord> = serde_json::from_slice(&index)?;
+    if records.len() as u64 != manifest.record_count {
+        anyhow::bail!("Index of snapshot {} holds {} records, expected {}", manifest.snapshot_id, records.len(), manifest.record_count);
+    }
+    Ok((manifest, records))
+}
+
+// Copy a snapshot's data file to `target`, checking its size and CRC-32
+fn stage_data(dir: &Path, manifest: &SnapshotManifest, target: &Path) -> Result<()> {
+    let source = dir.join(DATA_FILE);
+    let size = std::fs::metadata(&source)?.len();
+    if size != manifest.data_size {
+        anyhow::bail!("Data file of snapshot {} is {} bytes, expected {}", manifest.snapshot_id, size, manifest.data_size);
+    }
+    let crc = copy_with_crc(&source, target, size)?;
+    if crc != manifest.data_crc32 {
+        let _ = std::fs::remove_file(target);
+        anyhow::bail!("Data file of snapshot {} is corrupt: CRC-32 {:08x}, expected {:08x}", manifest.snapshot_id, crc, manifest.data_crc32);
+    }
+    Ok(())
+}
+
+// Write an incremental snapshot's extents over a staged data file, checking
+// its data file against the manifest, and resize the file to the size it had
+// when the snapshot was taken
+fn apply_increment(dir: &Path, manifest: &SnapshotManifest, target: &Path) -> Result<()> {
+    let source = dir.join(DATA_FILE);
+    let size = std::fs::metadata(&source)?.len();
+    if size != manifest.copied_bytes() {
+        anyhow::bail!("Data file of snapshot {} is {} bytes, expected {}", manifest.snapshot_id, size, manifest.copied_bytes());
+    }
+    let mut reader = File::open(&source)?;
+    let mut writer = OpenOptions::new().write(true).open(target)?;
+    let mut crc = Crc32::new();
+    let mut buffer = vec![0u8; COPY_CHUNK];
+    for extent in &manifest.extents {
+        writer.seek(SeekFrom::Start(extent.offset))?;
+        let mut remaining = extent.length;
+        while remaining > 0 {
+            let n = remaining.min(COPY_CHUNK as u64) as usize;
+            reader.read_exact(&mut buffer[..n])?;
+            crc.update(&buffer[..n]);
+            writer.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+    }
+    if crc.finish() != manifest.data_crc32 {
+        anyhow::bail!("Data file of snapshot {} is corrupt: CRC-32 {:08x}, expected {:08x}", manifest.snapshot_id, crc.finish(), manifest.data_crc32);
+    }
+    writer.set_len(manifest.data_size)?;
+    writer.sync_all()?;
+    Ok(())
+}
+
+// Directories and manifests of a snapshot and those it is an increment on,
+// base first
+type Chain = Vec<(PathBuf, SnapshotManifest)>;
+
+// Load a snapshot and every snapshot it is an increment on, returning their
+// chain and the requested snapshot's index
+fn load_chain(snapshots_dir: &Path, snapshot_id: &str) -> Result<(Chain, Vec<PersistedRecord>)> {
+    let dir = snapshots_dir.join(snapshot_id);
+    let (manifest, records) = load(&dir)?;
+    let mut seen = HashSet::from([manifest.snapshot_id.clone()]);
+    let mut chain = vec![(dir, manifest)];
+    while let Some(parent_id) = chain.last().and_then(|(_, manifest)| manifest.parent_id.clone()) {
+        uuid::Uuid::parse_str(&parent_id).map_err(|_| anyhow::anyhow!("Invalid snapshot ID {:?}", parent_id))?;
+        if !seen.insert(parent_id.clone()) {
+            anyhow::bail!("Snapshot chain of {} loops back to {}", snapshot_id, parent_id);
+        }
+        let dir = snapshots_dir.join(&parent_id);
+        let (parent, _) = load(&dir).map_err(|e| anyhow::anyhow!("Parent snapshot {} of {} is unusable: {}", parent_id, snapshot_id, e))?;
+        if parent.file_id != chain[0].1.file_id {
+            anyhow::bail!("Parent snapshot {} is of file {:?}, not {:?}", parent_id, parent.file_id, chain[0].1.file_id);
+        }
+        chain.push((dir, parent));
+    }
+    chain.reverse();
+    Ok((chain, records))
+}
+
+// Rebuild a snapshot's data file at `target`: the full snapshot at the base
+// of its chain, then each increment in order
+fn stage_chain(mut chain: Chain, target: &Path) -> Result<SnapshotManifest> {
+    let staged = chain.iter().enumerate().try_for_each(|(position, (dir, manifest))| match position {
+        0 => stage_data(dir, manifest, target),
+        _ => apply_increment(dir, manifest, target),
+    });
+    if let Err(e) = staged {
+        let _ = std::fs::remove_file(target);
+        return Err(e);
+    }
+    Ok(chain.pop().expect("chain holds the requested snapshot").1)
+}
+
+// Replace the live data file and request map of the snapshot's file ID with
+// the snapshot's contents. An incremental snapshot is applied on top of the
+// snapshots it builds on, back to the last full one. Every snapshot file
+// involved is validated against its manifest before anything live is
+// touched. The caller keeps new writes out; this fails if any are in flight,
+// including open multipart uploads.
+pub(crate) async fn restore(files: &FileRegistry, snapshot_id: &str) -> Result<SnapshotManifest> {
+    // Snapshot IDs are generated UUIDs; anything else could escape the snapshot directory
+    uuid::Uuid::parse_str(snapshot_id).map_err(|_| anyhow::anyhow!("Invalid snapshot ID {:?}", snapshot_id))?;
+
+    let start = Instant::now();
+    let (chain, records) = {
+        let snapshots_dir = files.snapshots_dir();
+        let snapshot_id = snapshot_id.to_string();
+        tokio::task::spawn_blocking(move || load_chain(&snapshots_dir, &snapshot_id)).await??
+    };
+    let file_id = chain[0].1.file_id.clone();
+    let manager = files.get(&file_id).await?;
+    let (file_path, durability, preallocate) = {
+        let file_manager = manager.lock().unwrap();
+        if file_manager.file.segment_size().is_some() {
+            anyhow::bail!("Snapshots of segmented data files are not supported");
+        }
+        if let Some(kind) = file_manager.file.storage_kind() {
+            anyhow::bail!("Restoring snapshots onto {} is not supported", kind);
+        }
+        (file_manager.file_path.clone(), file_manager.durability, file_manager.preallocate)
+    };
+
+    // A migrated data file is a symlink; restore it where it now lives
+    let data_path = std::fs::canonicalize(&file_path)?;
+    let staged_path = format!("{}.restore", data_path.display());
+    let increments = chain.len() - 1;
+    let manifest = tokio::task::spawn_blocking({
+        let staged_path = staged_path.clone();
+        move || stage_chain(chain, Path::new(&staged_path))
+    })
+    .await??;
+    let staged = async {
+        let file = create_file_io(&staged_path, durability).await?;
+        if let Some(length) = preallocate {
+            file.preallocate(length)?;
+        }
+        Ok::<_, anyhow::Error>(file)
+    };
+    let file = match staged.await {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = std::fs::remove_file(&staged_path);
+            return Err(e);
+        }
+    };
+
+    let mut file_manager = manager.lock().unwrap();
+    // Writes reserve space without the lock; hold them off until the restored
+    // file is in place
+    let space = file_manager.space.clone();
+    let _frozen = space.freeze();
+    if let Some(pending) = space.in_flight().first() {
+        let _ = std::fs::remove_file(&staged_path);
+        anyhow::bail!("Cannot restore {} while a write is in flight at offset {}", file_path, pending.offset);
+    }
+    std::fs::rename(&staged_path, &data_path)?;
+    let entries = records.into_iter().map(PersistedRecord::into_entry).collect();
+    let expired = file_manager.replace(file, manifest.data_size, entries, SystemTime::now())?;
+
+    info!(
+        "Restored {} from snapshot {} ({} increments on a full snapshot): {} records ({} expired since), {} bytes in {:?}",
+        file_path, snapshot_id, increments, manifest.record_count, expired, manifest.data_size, start.elapsed()
+    );
+    Ok(manifest)
+}
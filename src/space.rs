@@ -0,0 +1,261 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::file_io::align_up;
+use crate::file_manager::Extent;
+
+// How long a released extent is kept from new writes, so reads that looked up
+// the record before it was removed can finish
+const EXTENT_REUSE_DELAY: Duration = Duration::from_secs(5);
+
+// Space in a data file: where its data ends, the extents freed for reuse and
+// the extents reserved by writes not indexed yet. It is shared outside the
+// file manager, so writes reserve space without taking the file manager's
+// lock: an append claims its extent with a fetch-add on the end offset, and
+// only reusing a freed extent takes the free list's own lock.
+//
+// Truncation, compaction and anything else that needs the end and the writes
+// in flight to stand still freezes the space, which holds off reservations
+// until the freeze is dropped.
+pub(crate) struct Space {
+    // End of the data, sector aligned; the next append starts here
+    end: AtomicU64,
+    segment_size: Option<u64>,
+    free: Mutex<FreeList>,
+    // Whether the free list holds any extent, so appends skip its lock while
+    // it is empty
+    reusable: AtomicBool,
+    // Extents reserved by writes that have not been indexed or released yet
+    in_flight: Mutex<Vec<Extent>>,
+    // Reservations between claiming their extent and listing it in flight
+    claiming: AtomicUsize,
+    // Freezes held; a holder may nest them
+    frozen: AtomicUsize,
+    thaw: Mutex<()>,
+    thawed: Condvar,
+}
+
+#[derive(Default)]
+struct FreeList {
+    // Extents released by deletes, reused by new writes or reclaimed by compaction
+    extents: Vec<Extent>,
+    // Extents released within EXTENT_REUSE_DELAY; reads that looked up the old
+    // record may still be in progress, so they are not handed out yet
+    recently_freed: Vec<(Extent, Instant)>,
+}
+
+// Holds off reservations until dropped; see `Space::freeze`
+pub(crate) struct Frozen<'a> {
+    space: &'a Space,
+}
+
+impl Drop for Frozen<'_> {
+    fn drop(&mut self) {
+        if self.space.frozen.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _thaw = self.space.thaw.lock().unwrap();
+            self.space.thawed.notify_all();
+        }
+    }
+}
+
+impl Space {
+    pub(crate) fn new(end: u64, segment_size: Option<u64>) -> Self {
+        Self {
+            end: AtomicU64::new(end),
+            segment_size,
+            free: Mutex::new(FreeList::default()),
+            reusable: AtomicBool::new(false),
+            in_flight: Mutex::new(Vec::new()),
+            claiming: AtomicUsize::new(0),
+            frozen: AtomicUsize::new(0),
+            thaw: Mutex::new(()),
+            thawed: Condvar::new(),
+        }
+    }
+
+    // End of the data
+    pub(crate) fn end(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    // Move the end of the data, as truncation and swapping in another file
+    // do. The caller holds a freeze, so no reservation straddles the old end.
+    pub(crate) fn set_end(&self, end: u64) {
+        self.end.store(end, Ordering::SeqCst);
+    }
+
+    // Hold off reservations until the returned guard is dropped, waiting for
+    // those already claiming an extent to list it in flight. While frozen, the
+    // end and the extents in flight only change through the holder.
+    pub(crate) fn freeze(&self) -> Frozen<'_> {
+        self.frozen.fetch_add(1, Ordering::SeqCst);
+        while self.claiming.load(Ordering::SeqCst) > 0 {
+            std::thread::yield_now();
+        }
+        Frozen { space: self }
+    }
+
+    // Reserve an aligned extent for a write of `size` bytes: the smallest free
+    // extent it fits in, or else the end of the data. It stays in flight
+    // until `finish` is called with its offset.
+    pub(crate) fn reserve(&self, size: u64) -> Extent {
+        let length = align_up(size);
+        self.claim(|| self.reuse(length).unwrap_or_else(|| self.append(length)))
+    }
+
+    // Reserve the next aligned extent at the end of the data
+    pub(crate) fn reserve_append(&self, size: u64) -> Extent {
+        let length = align_up(size);
+        self.claim(|| self.append(length))
+    }
+
+    // Reserve a caller-chosen extent, returning a description of the conflict
+    // if it overlaps an in-flight write. The caller has checked it against
+    // the index.
+    pub(crate) fn reserve_at(&self, extent: Extent) -> Result<(), String> {
+        let _frozen = self.freeze();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(pending) = in_flight.iter().find(|pending| pending.overlaps(&extent)) {
+            return Err(format!("overlaps an in-flight write at offset {}", pending.offset));
+        }
+        // Space being rewritten is no longer reclaimable
+        self.carve(extent);
+        self.end.fetch_max(extent.end(), Ordering::SeqCst);
+        in_flight.push(extent);
+        Ok(())
+    }
+
+    // Run a reservation and list its extent in flight, first waiting out any
+    // freeze
+    fn claim(&self, reserve: impl FnOnce() -> Extent) -> Extent {
+        loop {
+            self.claiming.fetch_add(1, Ordering::SeqCst);
+            if self.frozen.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            self.claiming.fetch_sub(1, Ordering::SeqCst);
+            let mut thaw = self.thaw.lock().unwrap();
+            while self.frozen.load(Ordering::SeqCst) > 0 {
+                thaw = self.thawed.wait(thaw).unwrap();
+            }
+        }
+        let extent = reserve();
+        self.in_flight.lock().unwrap().push(extent);
+        self.claiming.fetch_sub(1, Ordering::SeqCst);
+        extent
+    }
+
+    // Take the smallest free extent `length` bytes fit in, keeping records
+    // within a segment
+    fn reuse(&self, length: u64) -> Option<Extent> {
+        if !self.reusable.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut free = self.free.lock().unwrap();
+        let now = Instant::now();
+        free.recently_freed.retain(|(_, freed_at)| now.duration_since(*freed_at) < EXTENT_REUSE_DELAY);
+
+        let recently_freed = &free.recently_freed;
+        let best_fit = free
+            .extents
+            .iter()
+            .enumerate()
+            .filter(|(_, extent)| extent.length >= length && !recently_freed.iter().any(|(recent, _)| recent.overlaps(extent)))
+            .filter(|(_, extent)| self.segment_size.is_none_or(|segment_size| extent.offset / segment_size == (extent.offset + length - 1) / segment_size))
+            .min_by_key(|(_, extent)| extent.length)
+            .map(|(index, _)| index)?;
+
+        let chosen = &mut free.extents[best_fit];
+        let extent = Extent { offset: chosen.offset, length };
+        chosen.offset += length;
+        chosen.length -= length;
+        if chosen.length == 0 {
+            free.extents.swap_remove(best_fit);
+        }
+        self.reusable.store(!free.extents.is_empty(), Ordering::SeqCst);
+        Some(extent)
+    }
+
+    // Claim the next `length` bytes at the end of the data. In a segmented
+    // file a record that does not fit in the rest of the current segment
+    // starts the next one, leaving the rest free for smaller writes; only
+    // records larger than a segment span segments.
+    fn append(&self, length: u64) -> Extent {
+        let Some(segment_size) = self.segment_size else {
+            return Extent { offset: self.end.fetch_add(length, Ordering::SeqCst), length };
+        };
+        let skip = |end: u64| {
+            let remaining = segment_size - end % segment_size;
+            if length > remaining && length <= segment_size { remaining } else { 0 }
+        };
+        // Cannot fail: the closure always returns a new end
+        let end = self.end.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |end| Some(end + skip(end) + length)).unwrap();
+        let skipped = skip(end);
+        if skipped > 0 {
+            let mut free = self.free.lock().unwrap();
+            free.extents.push(Extent { offset: end, length: skipped });
+            self.reusable.store(true, Ordering::SeqCst);
+        }
+        Extent { offset: end + skipped, length }
+    }
+
+    // Drop the in-flight reservation at `offset` once its write has completed
+    // or failed
+    pub(crate) fn finish(&self, offset: u64) {
+        self.in_flight.lock().unwrap().retain(|pending| pending.offset != offset);
+    }
+
+    // Extents reserved by writes in flight. Unless the space is frozen, a
+    // reservation being claimed may be missing.
+    pub(crate) fn in_flight(&self) -> Vec<Extent> {
+        self.in_flight.lock().unwrap().clone()
+    }
+
+    // Mark an extent as free so later writes or a compaction can reuse it
+    pub(crate) fn release(&self, extent: Extent) {
+        let mut free = self.free.lock().unwrap();
+        free.extents.push(extent);
+        free.recently_freed.push((extent, Instant::now()));
+        self.reusable.store(true, Ordering::SeqCst);
+    }
+
+    // Replace the free list, e.g. with one rebuilt from the index. With
+    // `reuse_now`, none of it is held back for reads still in progress, as
+    // when those reads use a file that has since been swapped out.
+    pub(crate) fn set_free(&self, extents: Vec<Extent>, reuse_now: bool) {
+        let mut free = self.free.lock().unwrap();
+        self.reusable.store(!extents.is_empty(), Ordering::SeqCst);
+        free.extents = extents;
+        if reuse_now {
+            free.recently_freed.clear();
+        }
+    }
+
+    // Take `range` off the free list, splitting free extents it cuts through
+    pub(crate) fn carve(&self, range: Extent) {
+        let mut free = self.free.lock().unwrap();
+        let mut extents = Vec::new();
+        for extent in free.extents.drain(..) {
+            if extent.offset < range.offset {
+                extents.push(Extent { offset: extent.offset, length: extent.end().min(range.offset) - extent.offset });
+            }
+            if extent.end() > range.end() {
+                let start = extent.offset.max(range.end());
+                extents.push(Extent { offset: start, length: extent.end() - start });
+            }
+        }
+        self.reusable.store(!extents.is_empty(), Ordering::SeqCst);
+        free.extents = extents;
+    }
+
+    // Number of free extents
+    pub(crate) fn free_extent_count(&self) -> usize {
+        self.free.lock().unwrap().extents.len()
+    }
+
+    // Total bytes held by free extents
+    pub(crate) fn reclaimable_bytes(&self) -> u64 {
+        self.free.lock().unwrap().extents.iter().map(|extent| extent.length).sum()
+    }
+}
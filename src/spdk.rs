@@ -0,0 +1,537 @@
+use std::collections::VecDeque;
+use std::ffi::{c_void, CString};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+use tracing::{error, info};
+
+use crate::block_device;
+use crate::file_io::{self, read_in_pieces, sector_size, write_in_pieces, Durability, FileIO, InFlight};
+
+// Bindings to the parts of SPDK's NVMe driver and environment used here, as
+// of SPDK 24.x. The options and transport ID structures change size between
+// releases, so they are passed as opaque buffers larger than any release's.
+mod ffi {
+    use std::ffi::{c_char, c_int, c_void};
+
+    #[repr(C)]
+    pub(super) struct Ctrlr {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub(super) struct Ns {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub(super) struct Qpair {
+        _private: [u8; 0],
+    }
+
+    // Completion queue entry, as the NVMe specification lays it out; only
+    // the status is read
+    #[repr(C)]
+    pub(super) struct Cpl {
+        _cdw0: u32,
+        _cdw1: u32,
+        _sqhd: u16,
+        _sqid: u16,
+        _cid: u16,
+        pub(super) status: u16,
+    }
+
+    pub(super) type CommandCallback = extern "C" fn(*mut c_void, *const Cpl);
+
+    // spdk_nvme_ns_flags
+    pub(super) const NS_FLUSH_SUPPORTED: u32 = 1 << 1;
+    pub(super) const NS_WRITE_ZEROES_SUPPORTED: u32 = 1 << 3;
+    pub(super) const NS_EXTENDED_LBA_SUPPORTED: u32 = 1 << 5;
+    pub(super) const IO_FLAGS_FORCE_UNIT_ACCESS: u32 = 1 << 30;
+    pub(super) const ENV_SOCKET_ID_ANY: c_int = -1;
+    pub(super) const MALLOC_DMA: u32 = 1;
+
+    extern "C" {
+        pub(super) fn spdk_env_opts_init(opts: *mut c_void);
+        pub(super) fn spdk_env_init(opts: *const c_void) -> c_int;
+        pub(super) fn spdk_zmalloc(size: usize, align: usize, phys_addr: *mut u64, socket_id: c_int, flags: u32) -> *mut c_void;
+        pub(super) fn spdk_free(buf: *mut c_void);
+        pub(super) fn spdk_nvme_transport_id_parse(trid: *mut c_void, str: *const c_char) -> c_int;
+        pub(super) fn spdk_nvme_connect(trid: *const c_void, opts: *const c_void, opts_size: usize) -> *mut Ctrlr;
+        pub(super) fn spdk_nvme_detach(ctrlr: *mut Ctrlr) -> c_int;
+        pub(super) fn spdk_nvme_ctrlr_get_ns(ctrlr: *mut Ctrlr, ns_id: u32) -> *mut Ns;
+        pub(super) fn spdk_nvme_ctrlr_alloc_io_qpair(ctrlr: *mut Ctrlr, opts: *const c_void, opts_size: usize) -> *mut Qpair;
+        pub(super) fn spdk_nvme_ctrlr_free_io_qpair(qpair: *mut Qpair) -> c_int;
+        pub(super) fn spdk_nvme_ns_is_active(ns: *mut Ns) -> bool;
+        pub(super) fn spdk_nvme_ns_get_sector_size(ns: *mut Ns) -> u32;
+        pub(super) fn spdk_nvme_ns_get_num_sectors(ns: *mut Ns) -> u64;
+        pub(super) fn spdk_nvme_ns_get_max_io_xfer_size(ns: *mut Ns) -> u32;
+        pub(super) fn spdk_nvme_ns_get_flags(ns: *mut Ns) -> u32;
+        pub(super) fn spdk_nvme_ns_cmd_read(ns: *mut Ns, qpair: *mut Qpair, payload: *mut c_void, lba: u64, lba_count: u32, cb_fn: CommandCallback, cb_arg: *mut c_void, io_flags: u32) -> c_int;
+        pub(super) fn spdk_nvme_ns_cmd_write(ns: *mut Ns, qpair: *mut Qpair, payload: *mut c_void, lba: u64, lba_count: u32, cb_fn: CommandCallback, cb_arg: *mut c_void, io_flags: u32) -> c_int;
+        pub(super) fn spdk_nvme_ns_cmd_write_zeroes(ns: *mut Ns, qpair: *mut Qpair, lba: u64, lba_count: u32, cb_fn: CommandCallback, cb_arg: *mut c_void, io_flags: u32) -> c_int;
+        pub(super) fn spdk_nvme_ns_cmd_flush(ns: *mut Ns, qpair: *mut Qpair, cb_fn: CommandCallback, cb_arg: *mut c_void) -> c_int;
+        pub(super) fn spdk_nvme_qpair_process_completions(qpair: *mut Qpair, max_completions: u32) -> i32;
+    }
+}
+
+// Larger than `struct spdk_env_opts` and `struct spdk_nvme_transport_id`
+const OPAQUE_SIZE: usize = 4096;
+
+// Most sectors one command can cover, as its sector count is 16 bits
+const MAX_COMMAND_SECTORS: u64 = 65536;
+
+// Alignment of DMA buffers, so every command's payload starts on a page
+const DMA_ALIGN: usize = 4096;
+
+#[repr(C, align(8))]
+struct Opaque([u8; OPAQUE_SIZE]);
+
+impl Opaque {
+    fn zeroed() -> Box<Self> {
+        Box::new(Self([0; OPAQUE_SIZE]))
+    }
+}
+
+// SPDK's environment (hugepage memory, PCI access), set up once per process
+static ENV: OnceLock<Result<(), String>> = OnceLock::new();
+
+fn init_env() -> Result<()> {
+    ENV.get_or_init(|| {
+        let mut opts = Opaque::zeroed();
+        // SAFETY: the buffer is larger than `struct spdk_env_opts`, which
+        // these fill in with the defaults and read
+        let initialized = unsafe {
+            ffi::spdk_env_opts_init(opts.0.as_mut_ptr().cast());
+            ffi::spdk_env_init(opts.0.as_ptr().cast())
+        };
+        if initialized < 0 {
+            return Err("Cannot initialize SPDK's environment; are hugepages reserved?".to_string());
+        }
+        Ok(())
+    })
+    .clone()
+    .map_err(|e| anyhow::anyhow!(e))
+}
+
+// An NVMe namespace attached through SPDK's user-space driver. The
+// controller is detached once the last handle on it is dropped.
+pub(crate) struct Namespace {
+    transport: String,
+    id: u32,
+    ctrlr: *mut ffi::Ctrlr,
+    ns: *mut ffi::Ns,
+    sector_size: u64,
+    size: u64,
+    // Bytes one command may carry at most, a whole number of sectors
+    max_transfer: u64,
+    flags: u32,
+}
+
+// SAFETY: the controller and namespace are only read through, apart from
+// allocating queue pairs, which SPDK serializes per controller
+unsafe impl Send for Namespace {}
+unsafe impl Sync for Namespace {}
+
+impl std::fmt::Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NVMe namespace {} at {}", self.id, self.transport)
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        // SAFETY: the controller was attached by `attach` and nothing uses it
+        // once the last handle is gone
+        unsafe { ffi::spdk_nvme_detach(self.ctrlr) };
+    }
+}
+
+// Attach namespace `id` of the controller at `transport`, an SPDK transport
+// ID such as "trtype:PCIe traddr:0000:01:00.0". The controller must already
+// be unbound from the kernel's nvme driver.
+pub(crate) fn attach(transport: &str, id: u32) -> Result<Arc<Namespace>> {
+    init_env()?;
+    let mut trid = Opaque::zeroed();
+    let transport_id = CString::new(transport)?;
+    // SAFETY: the buffer is larger than `struct spdk_nvme_transport_id` and
+    // the string is NUL-terminated
+    if unsafe { ffi::spdk_nvme_transport_id_parse(trid.0.as_mut_ptr().cast(), transport_id.as_ptr()) } != 0 {
+        anyhow::bail!("Invalid NVMe transport ID {:?}", transport);
+    }
+    // SAFETY: the transport ID was filled in above; a null pointer asks for
+    // the default controller options
+    let ctrlr = unsafe { ffi::spdk_nvme_connect(trid.0.as_ptr().cast(), std::ptr::null(), 0) };
+    if ctrlr.is_null() {
+        anyhow::bail!("Cannot attach the NVMe controller at {}; is it bound to vfio-pci or uio_pci_generic?", transport);
+    }
+    // SAFETY: the controller is attached
+    let ns = unsafe { ffi::spdk_nvme_ctrlr_get_ns(ctrlr, id) };
+    // SAFETY: the namespace belongs to the attached controller
+    if ns.is_null() || !unsafe { ffi::spdk_nvme_ns_is_active(ns) } {
+        // SAFETY: the controller is attached and not used past here
+        unsafe { ffi::spdk_nvme_detach(ctrlr) };
+        anyhow::bail!("NVMe controller at {} has no active namespace {}", transport, id);
+    }
+    // SAFETY: the namespace is active on the attached controller
+    let (sector_size, sectors, max_transfer, flags) = unsafe {
+        (
+            u64::from(ffi::spdk_nvme_ns_get_sector_size(ns)),
+            ffi::spdk_nvme_ns_get_num_sectors(ns),
+            u64::from(ffi::spdk_nvme_ns_get_max_io_xfer_size(ns)),
+            ffi::spdk_nvme_ns_get_flags(ns),
+        )
+    };
+    let namespace = Namespace {
+        transport: transport.to_string(),
+        id,
+        ctrlr,
+        ns,
+        sector_size,
+        size: sectors * sector_size,
+        max_transfer: (max_transfer / sector_size).clamp(1, MAX_COMMAND_SECTORS) * sector_size,
+        flags,
+    };
+    if flags & ffi::NS_EXTENDED_LBA_SUPPORTED != 0 {
+        anyhow::bail!("{} is formatted with metadata in its sectors, which is not supported", namespace);
+    }
+    info!("Attached {}: {} sectors of {} bytes, up to {} bytes per command", namespace, sectors, sector_size, namespace.max_transfer);
+    Ok(Arc::new(namespace))
+}
+
+impl Namespace {
+    pub(crate) fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn supports(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+// Zeroed buffer in hugepage memory the device can reach by DMA, freed when
+// the last `Bytes` viewing it is dropped
+struct DmaBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the buffer owns its memory exclusively, like a Vec<u8>, and SPDK
+// frees it from any thread
+unsafe impl Send for DmaBuf {}
+unsafe impl Sync for DmaBuf {}
+
+impl DmaBuf {
+    fn zeroed(len: usize) -> Result<Self> {
+        // SAFETY: a plain allocation; a null result is checked below
+        let ptr = unsafe { ffi::spdk_zmalloc(len, DMA_ALIGN, std::ptr::null_mut(), ffi::ENV_SOCKET_ID_ANY, ffi::MALLOC_DMA) };
+        let ptr = NonNull::new(ptr.cast()).ok_or_else(|| anyhow::anyhow!("Cannot allocate a {}-byte DMA buffer; are enough hugepages reserved?", len))?;
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        // SAFETY: the memory was allocated by spdk_zmalloc and is not used
+        // past here
+        unsafe { ffi::spdk_free(self.ptr.as_ptr().cast()) };
+    }
+}
+
+impl Deref for DmaBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the buffer spans `len` zeroed or written bytes it owns
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for DmaBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the buffer spans `len` zeroed or written bytes it owns
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for DmaBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+// An NVMe command for the namespace's thread to submit, with the buffer it
+// transfers, which lives until the command completes
+enum Command {
+    Read { lba: u64, sectors: u32, buffer: DmaBuf, size: usize, done: oneshot::Sender<Result<Bytes>> },
+    Write { lba: u64, sectors: u32, buffer: DmaBuf, done: oneshot::Sender<Result<()>> },
+    WriteZeroes { lba: u64, sectors: u32, done: oneshot::Sender<Result<()>> },
+    Flush { done: oneshot::Sender<Result<()>> },
+}
+
+impl Command {
+    fn finish(self, status: Result<()>) {
+        match self {
+            Command::Read { buffer, size, done, .. } => {
+                let _ = done.send(status.map(|()| Bytes::from_owner(buffer).slice(..size)));
+            }
+            Command::Write { done, .. } | Command::WriteZeroes { done, .. } | Command::Flush { done } => {
+                let _ = done.send(status);
+            }
+        }
+    }
+}
+
+// A command handed to the namespace's thread. It holds a permit of the data
+// file's operations in flight until it completes, so closing the file can
+// wait it out.
+struct Pending {
+    command: Command,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// Status of a completion: bit 0 is the phase tag, then 8 bits of status code
+// and 3 of status code type, all zero on success
+fn check_status(status: u16) -> Result<()> {
+    let code = (status >> 1) & 0xff;
+    let code_type = (status >> 9) & 0x7;
+    if code != 0 || code_type != 0 {
+        anyhow::bail!("NVMe command failed with status code type {} and status code {:#04x}", code_type, code);
+    }
+    Ok(())
+}
+
+extern "C" fn complete(arg: *mut c_void, cpl: *const ffi::Cpl) {
+    // SAFETY: `arg` is the command `start` leaked for this submission, and
+    // SPDK calls this once per submission with a valid completion entry
+    let (pending, status) = unsafe { (Box::from_raw(arg.cast::<Pending>()), (*cpl).status) };
+    pending.command.finish(check_status(status));
+}
+
+enum Started {
+    Submitted,
+    Failed,
+    // The queue pair has no room; submit again once commands complete
+    QueueFull(Box<Pending>),
+}
+
+// Submit a command to `qpair`
+fn start(namespace: &Namespace, qpair: *mut ffi::Qpair, io_flags: u32, pending: Box<Pending>) -> Started {
+    let ns = namespace.ns;
+    let arg = Box::into_raw(pending);
+    // SAFETY: `arg` and the buffer it holds stay valid until `complete`
+    // takes it back, which SPDK calls once if the submission succeeds; the
+    // queue pair is only used from this thread
+    let submitted = unsafe {
+        match &mut (*arg).command {
+            Command::Read { lba, sectors, buffer, .. } => ffi::spdk_nvme_ns_cmd_read(ns, qpair, buffer.as_mut_ptr().cast(), *lba, *sectors, complete, arg.cast(), 0),
+            Command::Write { lba, sectors, buffer, .. } => ffi::spdk_nvme_ns_cmd_write(ns, qpair, buffer.as_mut_ptr().cast(), *lba, *sectors, complete, arg.cast(), io_flags),
+            Command::WriteZeroes { lba, sectors, .. } => ffi::spdk_nvme_ns_cmd_write_zeroes(ns, qpair, *lba, *sectors, complete, arg.cast(), io_flags),
+            Command::Flush { .. } => ffi::spdk_nvme_ns_cmd_flush(ns, qpair, complete, arg.cast()),
+        }
+    };
+    if submitted == 0 {
+        return Started::Submitted;
+    }
+    // SAFETY: the command was not submitted, so SPDK holds no reference to it
+    let pending = unsafe { Box::from_raw(arg) };
+    if submitted == -libc::ENOMEM {
+        return Started::QueueFull(pending);
+    }
+    pending.command.finish(Err(std::io::Error::from_raw_os_error(-submitted).into()));
+    Started::Failed
+}
+
+// Submit the commands sent to a queue pair and poll it for their completions
+// until every handle on the data file is dropped. Nothing wakes a polled
+// queue pair, so the thread waits on its queue whenever nothing is in flight.
+fn drive(namespace: &Namespace, qpair: *mut ffi::Qpair, io_flags: u32, queue: &mut UnboundedReceiver<Box<Pending>>) -> Result<()> {
+    let mut in_flight = 0;
+    let mut waiting = VecDeque::new();
+    loop {
+        if in_flight == 0 && waiting.is_empty() {
+            let Some(pending) = queue.blocking_recv() else {
+                return Ok(());
+            };
+            waiting.push_back(pending);
+        }
+        while let Ok(pending) = queue.try_recv() {
+            waiting.push_back(pending);
+        }
+        while let Some(pending) = waiting.pop_front() {
+            match start(namespace, qpair, io_flags, pending) {
+                Started::Submitted => in_flight += 1,
+                Started::Failed => {}
+                Started::QueueFull(pending) => {
+                    waiting.push_front(pending);
+                    break;
+                }
+            }
+        }
+        // SAFETY: the queue pair is only used from this thread
+        let completed = unsafe { ffi::spdk_nvme_qpair_process_completions(qpair, 0) };
+        if completed < 0 {
+            anyhow::bail!("Queue pair failed: {}", std::io::Error::from_raw_os_error(-completed));
+        }
+        in_flight -= completed as usize;
+    }
+}
+
+// Start the thread that owns the data file's queue pair
+fn spawn(namespace: Arc<Namespace>, io_flags: u32) -> Result<UnboundedSender<Box<Pending>>> {
+    let (ops, mut queue) = tokio::sync::mpsc::unbounded_channel();
+    let (started, ready) = std::sync::mpsc::channel();
+    let name = namespace.to_string();
+    std::thread::Builder::new().name("spdk".to_string()).spawn(move || {
+        // SAFETY: the controller is attached; a null pointer asks for the
+        // default queue pair options
+        let qpair = unsafe { ffi::spdk_nvme_ctrlr_alloc_io_qpair(namespace.ctrlr, std::ptr::null(), 0) };
+        if qpair.is_null() {
+            let _ = started.send(Err(anyhow::anyhow!("Cannot allocate an I/O queue pair on {}", namespace)));
+            return;
+        }
+        let _ = started.send(Ok(()));
+        if let Err(e) = drive(&namespace, qpair, io_flags, &mut queue) {
+            error!("I/O on {} stopped: {}", namespace, e);
+        }
+        // Completes whatever is still outstanding on it as aborted
+        // SAFETY: the queue pair was allocated above and is not used past here
+        unsafe { ffi::spdk_nvme_ctrlr_free_io_qpair(qpair) };
+    })?;
+    ready.recv().map_err(|_| anyhow::anyhow!("SPDK thread for {} exited while starting", name))??;
+    Ok(ops)
+}
+
+// Data file on an NVMe namespace driven from user space through SPDK, with
+// no kernel in the path. Byte offsets map directly onto the namespace's
+// LBAs, so the extents of the data file are runs of LBAs; commands are
+// submitted to a queue pair of the file's own, which a dedicated thread
+// polls for completions.
+pub(crate) struct SpdkFileIO {
+    namespace: Arc<Namespace>,
+    ops: UnboundedSender<Box<Pending>>,
+    in_flight: InFlight,
+}
+
+// Open `namespace` as the data file at `data_path`, whose sidecar files stay
+// in the data directory. It is managed as one flat extent space, like a raw
+// block device.
+pub(crate) fn open(namespace: &Arc<Namespace>, data_path: &str, durability: Durability) -> Result<Arc<dyn FileIO + Send + Sync>> {
+    if !sector_size().is_multiple_of(namespace.sector_size) {
+        anyhow::bail!("Records are aligned to {}-byte sectors, which {} with its {}-byte sectors cannot address", sector_size(), namespace, namespace.sector_size);
+    }
+    // Every write is on stable storage once it completes, as with O_DSYNC
+    let io_flags = if durability == Durability::ODsync { ffi::IO_FLAGS_FORCE_UNIT_ACCESS } else { 0 };
+    let ops = spawn(namespace.clone(), io_flags)?;
+    let name = namespace.to_string();
+    let file = file_io::add_layers(Arc::new(SpdkFileIO { namespace: namespace.clone(), ops, in_flight: InFlight::new() }), &name);
+    block_device::flat(file, namespace.size, data_path, &name, "an NVMe namespace driven by SPDK")
+}
+
+impl SpdkFileIO {
+    // First LBA and number of sectors of `length` bytes at `offset`
+    fn lbas(&self, offset: u64, length: u64) -> Result<(u64, u32)> {
+        let sector_size = self.namespace.sector_size;
+        let sectors = length.div_ceil(sector_size);
+        if !offset.is_multiple_of(sector_size) || offset + sectors * sector_size > self.namespace.size {
+            anyhow::bail!("{}-byte I/O at offset {} does not fit the sectors of {}", length, offset, self.namespace);
+        }
+        Ok((offset / sector_size, sectors as u32))
+    }
+
+    fn send(&self, command: Command, permit: Option<OwnedSemaphorePermit>) -> Result<()> {
+        self.ops.send(Box::new(Pending { command, _permit: permit })).map_err(|_| anyhow::anyhow!("SPDK thread for {} has stopped", self.namespace))
+    }
+
+    // Hand a command to the namespace's thread and wait for it to complete
+    async fn submit<T>(&self, command: impl FnOnce(oneshot::Sender<Result<T>>) -> Command) -> Result<T> {
+        let permit = self.in_flight.enter()?;
+        let (done, result) = oneshot::channel();
+        self.send(command(done), Some(permit))?;
+        result.await.map_err(|_| anyhow::anyhow!("SPDK thread for {} dropped a command", self.namespace))?
+    }
+
+    async fn write_piece(&self, data: Bytes, offset: u64) -> Result<()> {
+        let (lba, sectors) = self.lbas(offset, data.len() as u64)?;
+        let mut buffer = DmaBuf::zeroed((u64::from(sectors) * self.namespace.sector_size) as usize)?;
+        buffer[..data.len()].copy_from_slice(&data);
+        self.submit(|done| Command::Write { lba, sectors, buffer, done }).await
+    }
+
+    async fn read_piece(&self, size: u64, offset: u64) -> Result<Bytes> {
+        let (lba, sectors) = self.lbas(offset, size)?;
+        let buffer = DmaBuf::zeroed((u64::from(sectors) * self.namespace.sector_size) as usize)?;
+        self.submit(|done| Command::Read { lba, sectors, buffer, size: size as usize, done }).await
+    }
+
+    // Zero `length` bytes at `offset`, with Write Zeroes where the namespace
+    // supports it, which lets the device deallocate them
+    async fn zero(&self, offset: u64, length: u64) -> Result<()> {
+        if !self.namespace.supports(ffi::NS_WRITE_ZEROES_SUPPORTED) {
+            return write_in_pieces(vec![0; length as usize], offset, self.namespace.max_transfer, |piece, offset| self.write_piece(piece, offset)).await;
+        }
+        let pieces = file_io::io_pieces(offset, length, MAX_COMMAND_SECTORS * self.namespace.sector_size).map(|(offset, length)| async move {
+            let (lba, sectors) = self.lbas(offset, length)?;
+            self.submit(|done| Command::WriteZeroes { lba, sectors, done }).await
+        });
+        futures::future::try_join_all(pieces).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileIO for SpdkFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        write_in_pieces(data, offset, self.namespace.max_transfer, |piece, offset| self.write_piece(piece, offset)).await
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        read_in_pieces(size, offset, self.namespace.max_transfer, |size, offset| self.read_piece(size, offset)).await
+    }
+
+    async fn len(&self) -> Result<u64> {
+        Ok(self.namespace.size)
+    }
+
+    // Flush the device's volatile write cache, if it has one
+    async fn sync_data(&self) -> Result<()> {
+        if !self.namespace.supports(ffi::NS_FLUSH_SUPPORTED) {
+            return Ok(());
+        }
+        self.submit(|done| Command::Flush { done }).await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        anyhow::bail!("Cannot resize {} to {} bytes", self.namespace, size)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "spdk"
+    }
+
+    fn preallocate(&self, _length: u64) -> Result<()> {
+        Ok(())
+    }
+
+    // Waits for the device, as fallocate does on the other backends
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        futures::executor::block_on(self.zero(offset, length))
+    }
+
+    // Commands still on the queue pair complete before the device is flushed
+    async fn close(&self) -> Result<()> {
+        self.in_flight.drain().await;
+        if !self.namespace.supports(ffi::NS_FLUSH_SUPPORTED) {
+            return Ok(());
+        }
+        let (done, flushed) = oneshot::channel();
+        self.send(Command::Flush { done }, None)?;
+        flushed.await.map_err(|_| anyhow::anyhow!("SPDK thread for {} dropped a command", self.namespace))?
+    }
+}
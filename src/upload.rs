@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::file_io::align_up;
+use crate::file_manager::{Extent, FileManager, RecordKey};
+
+// A part stored in the data file. Its extent stays reserved as in flight until
+// the upload is completed or aborted, so nothing else can reuse or move it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UploadedPart {
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+impl UploadedPart {
+    pub(crate) fn extent(&self) -> Extent {
+        Extent {
+            offset: self.offset,
+            length: align_up(self.size),
+        }
+    }
+}
+
+// Multipart upload in progress
+pub(crate) struct Upload {
+    pub(crate) manager: Arc<Mutex<FileManager>>,
+    // Record the parts are stitched into on completion
+    pub(crate) key: RecordKey,
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) user_metadata: HashMap<String, String>,
+    // Keyed by part number; parts are stitched in ascending order
+    pub(crate) parts: BTreeMap<u32, UploadedPart>,
+    // When the upload is discarded unless completed or aborted first
+    pub(crate) deadline: Instant,
+}
+
+impl Upload {
+    // Whether the upload is past its deadline
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    // Release the blocks of every part
+    pub(crate) fn discard(&self) {
+        let mut file_manager = self.manager.lock().unwrap();
+        for part in self.parts.values() {
+            discard_part(&mut file_manager, part);
+        }
+    }
+}
+
+// Release the blocks of a single part
+pub(crate) fn discard_part(file_manager: &mut FileManager, part: &UploadedPart) {
+    file_manager.space.finish(part.offset);
+    file_manager.release_extent(part.extent());
+}
+
+// Uploads in progress, keyed by upload ID
+pub(crate) type UploadMap = HashMap<String, Upload>;
+
+// Periodically discard uploads left open past their deadline, releasing the
+// blocks of their parts
+pub(crate) async fn run_upload_sweeper(uploads: Arc<Mutex<UploadMap>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let expired: Vec<(String, Upload)> = {
+            let mut uploads = uploads.lock().unwrap();
+            let ids: Vec<String> = uploads.iter().filter(|(_, upload)| upload.is_expired(now)).map(|(id, _)| id.clone()).collect();
+            ids.into_iter().filter_map(|id| uploads.remove(&id).map(|upload| (id, upload))).collect()
+        };
+        for (id, upload) in expired {
+            upload.discard();
+            warn!("Upload {} for request {} expired, discarded {} parts", id, upload.key.request_id, upload.parts.len());
+        }
+    }
+}
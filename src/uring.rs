@@ -0,0 +1,677 @@
+// io_uring rings driven directly, each by a thread of its own: those of the
+// I/O workers and of polled data files. The thread starts the operations it
+// takes off its queues with `Ring::start` and turns the ring with
+// `Ring::turn`, which hands new submissions to the kernel together and
+// finishes operations as they complete, resubmitting whatever part of a
+// transfer the kernel left undone.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::oneshot::Sender;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::warn;
+
+use crate::aligned_buf::AlignedBuf;
+use crate::file_io::{align_up, check_written, verify_writes, UringOp};
+
+// Buffers registered with each ring, and the size of each. Reads
+// and writes that fit in one go through it, so the kernel does not map and
+// unmap their memory on every operation; larger ones, and any made while
+// every registered buffer is in use, use a buffer of their own.
+const FIXED_BUFFERS: usize = 32;
+const FIXED_BUFFER_SIZE: usize = 64 * 1024;
+
+// Files registered with each ring at once. Files are registered as the ring
+// first runs an operation on them and unregistered once forgotten, so the
+// kernel takes no reference on the descriptor per submission; any beyond
+// these are used by descriptor.
+const FILE_SLOTS: u32 = 1024;
+
+// Most buffers a vectored transfer hands the kernel in one submission; the
+// rest follow once those are done, as after a short transfer
+const MAX_IOVECS: usize = 1024;
+
+// User data of the waker's poll, which no operation's slot reaches
+const WAKE: u64 = u64::MAX;
+
+// Wakes a ring's thread while it waits for completions, so operations queued
+// for it meanwhile are started. The ring polls an eventfd, which is written
+// only while the thread is waiting or about to.
+pub(crate) struct Waker {
+    eventfd: OwnedFd,
+    sleeping: AtomicBool,
+}
+
+impl Waker {
+    pub(crate) fn new() -> io::Result<Arc<Self>> {
+        // SAFETY: eventfd takes plain integers and returns a new descriptor
+        // or -1
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it
+        Ok(Arc::new(Self { eventfd: unsafe { OwnedFd::from_raw_fd(fd) }, sleeping: AtomicBool::new(false) }))
+    }
+
+    // Wake the thread if it is waiting, after queueing something for it
+    pub(crate) fn wake(&self) {
+        // Pairs with the fence in `sleep`: either the thread sees what was
+        // queued before it waits, or this sees that it is waiting
+        fence(Ordering::SeqCst);
+        if self.sleeping.swap(false, Ordering::SeqCst) {
+            let one = 1u64;
+            // SAFETY: writes 8 bytes from a live u64 to the eventfd; a full
+            // counter only means a wake-up is already pending
+            unsafe { libc::write(self.eventfd.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+        }
+    }
+
+    // Mark the thread as about to wait. It checks its queues again after
+    // this, as what was queued before is not woken for.
+    pub(crate) fn sleep(&self) {
+        self.sleeping.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+    }
+
+    // Mark the thread as running again
+    pub(crate) fn awake(&self) {
+        self.sleeping.store(false, Ordering::Relaxed);
+    }
+
+    // Clear the eventfd once its poll completes
+    fn drain(&self) {
+        let mut count = 0u64;
+        // SAFETY: reads at most 8 bytes into a live u64; the eventfd is
+        // non-blocking, so an empty one fails with EAGAIN, which is ignored
+        unsafe { libc::read(self.eventfd.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+// How submissions name a file: by its slot among the ring's registered files,
+// or by descriptor if it could not be registered
+#[derive(Clone, Copy)]
+enum Target {
+    Fixed(types::Fixed),
+    Fd(types::Fd),
+}
+
+// Build the same submission for either kind of target; `$fd` takes the type
+// of each in turn
+macro_rules! on_target {
+    ($target:expr, |$fd:ident| $build:expr) => {
+        match $target {
+            Target::Fixed($fd) => $build,
+            Target::Fd($fd) => $build,
+        }
+    };
+}
+
+// Memory a transfer moves to or from
+enum Buffers {
+    // Registered buffer at this index
+    Fixed(u16),
+    // Buffers of the operation's own, one after another in the file
+    Owned(Vec<AlignedBuf>),
+}
+
+// Bytes moving between a file and memory, continued from where the kernel
+// stopped until all are moved
+struct Transfer {
+    buffers: Buffers,
+    offset: u64,
+    len: usize,
+    // Bytes moved so far
+    moved: usize,
+    // Whether a read reached the end of the file
+    ended: bool,
+    // What a vectored submission hands the kernel, kept until it completes
+    iovecs: Vec<libc::iovec>,
+}
+
+impl Transfer {
+    fn new(buffers: Buffers, offset: u64, len: usize) -> Self {
+        Self { buffers, offset, len, moved: 0, ended: false, iovecs: Vec::new() }
+    }
+
+    fn owned(buffers: Vec<AlignedBuf>, offset: u64) -> Self {
+        let len = buffers.iter().map(|buffer| buffer.len()).sum();
+        Self::new(Buffers::Owned(buffers), offset, len)
+    }
+
+    // Submission for what is left of the transfer, at I/O priority `ioprio`
+    fn entry(&mut self, target: Target, fixed: &mut [AlignedBuf], write: bool, ioprio: u16) -> squeue::Entry {
+        let offset = self.offset + self.moved as u64;
+        let left = (self.len - self.moved) as u32;
+        match &mut self.buffers {
+            Buffers::Fixed(index) => {
+                let buffer = fixed[*index as usize][self.moved..].as_mut_ptr();
+                if write {
+                    on_target!(target, |fd| opcode::WriteFixed::new(fd, buffer, left, *index).offset(offset).ioprio(ioprio).build())
+                } else {
+                    on_target!(target, |fd| opcode::ReadFixed::new(fd, buffer, left, *index).offset(offset).ioprio(ioprio).build())
+                }
+            }
+            Buffers::Owned(buffers) if buffers.len() == 1 => {
+                let buffer = buffers[0][self.moved..].as_mut_ptr();
+                if write {
+                    on_target!(target, |fd| opcode::Write::new(fd, buffer, left).offset(offset).ioprio(ioprio).build())
+                } else {
+                    on_target!(target, |fd| opcode::Read::new(fd, buffer, left).offset(offset).ioprio(ioprio).build())
+                }
+            }
+            Buffers::Owned(buffers) => {
+                self.iovecs.clear();
+                let mut skip = self.moved;
+                for buffer in buffers.iter_mut() {
+                    if skip >= buffer.len() {
+                        skip -= buffer.len();
+                        continue;
+                    }
+                    let rest = &mut buffer[skip..];
+                    self.iovecs.push(libc::iovec { iov_base: rest.as_mut_ptr() as *mut libc::c_void, iov_len: rest.len() });
+                    skip = 0;
+                    if self.iovecs.len() == MAX_IOVECS {
+                        break;
+                    }
+                }
+                let count = self.iovecs.len() as u32;
+                let iovecs = self.iovecs.as_ptr();
+                if write {
+                    on_target!(target, |fd| opcode::Writev::new(fd, iovecs, count).offset(offset).ioprio(ioprio).build())
+                } else {
+                    on_target!(target, |fd| opcode::Readv::new(fd, iovecs, count).offset(offset).ioprio(ioprio).build())
+                }
+            }
+        }
+    }
+
+    // Count a completion of the transfer's latest submission. A read that
+    // reaches the end of the file ends there, leaving the rest of its
+    // buffers as zeros.
+    fn progress(&mut self, result: i32, write: bool) -> io::Result<()> {
+        match result {
+            0 if write => Err(io::ErrorKind::WriteZero.into()),
+            0 => {
+                self.ended = true;
+                Ok(())
+            }
+            moved if moved > 0 => {
+                self.moved += moved as usize;
+                Ok(())
+            }
+            e if e == -libc::EINTR || e == -libc::EAGAIN => Ok(()),
+            e => Err(io::Error::from_raw_os_error(-e)),
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.ended || self.moved >= self.len
+    }
+
+    // The transferred bytes, buffer by buffer
+    fn slices<'a>(&'a self, fixed: &'a [AlignedBuf]) -> Vec<&'a [u8]> {
+        match &self.buffers {
+            Buffers::Fixed(index) => vec![&fixed[*index as usize][..self.len]],
+            Buffers::Owned(buffers) => buffers.iter().map(|buffer| &buffer[..]).collect(),
+        }
+    }
+}
+
+// Where a finished read's bytes go
+enum ReadDone {
+    Whole { size: usize, done: Sender<io::Result<Bytes>> },
+    Vectored { sizes: Vec<u64>, done: Sender<io::Result<Vec<Vec<u8>>>> },
+}
+
+enum Work {
+    // A write, then its read-back if writes are verified
+    Write { written: Transfer, read_back: Option<Transfer>, done: Sender<io::Result<()>> },
+    Read { transfer: Transfer, done: ReadDone },
+    Sync { synced: bool, done: Sender<io::Result<()>> },
+}
+
+// Operation in flight on a ring
+struct Pending {
+    file: u64,
+    work: Work,
+    // Submissions not yet completed, and the first error one completed with
+    outstanding: u8,
+    error: Option<io::Error>,
+    // ioprio field of its reads and writes
+    ioprio: u16,
+    // Released once the operation has finished
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+// A file operations run on
+struct RingFile {
+    file: std::fs::File,
+    // Its slot among the ring's registered files, if it has one
+    slot: Option<u32>,
+    // Operations in flight on it
+    ops: usize,
+    // Whether it is closed once they finish
+    forgotten: bool,
+}
+
+pub(crate) struct Ring {
+    ring: IoUring,
+    name: String,
+    // Registered buffers, and the indexes of those not in use; none if they
+    // could not be registered
+    fixed: Vec<AlignedBuf>,
+    free: Vec<u16>,
+    files: HashMap<u64, RingFile>,
+    // Registered file slots not in use; none if the ring has no file table
+    free_slots: Vec<u32>,
+    // Operations in flight, by the user data of their submissions, and the
+    // slots free for new ones
+    ops: Vec<Option<Pending>>,
+    vacant: Vec<usize>,
+    // Completions taken off the ring while making room for submissions, not
+    // yet handled
+    reaped: VecDeque<(u64, i32)>,
+    waker: Option<Arc<Waker>>,
+}
+
+impl Ring {
+    // Build a ring of `entries` submissions, `name` in warnings, and register
+    // its buffers. Registration pins the buffers' memory, which
+    // RLIMIT_MEMLOCK may not allow; the ring then does all of its I/O
+    // through unregistered buffers. A sparse file table is registered too,
+    // failing which files are used by descriptor. A ring with a waker polls
+    // it, so `turn` returns once it is woken.
+    pub(crate) fn new(builder: &io_uring::Builder, entries: u32, name: String, waker: Option<Arc<Waker>>) -> io::Result<Self> {
+        let ring = builder.build(entries)?;
+        let mut fixed: Vec<AlignedBuf> = (0..FIXED_BUFFERS).map(|_| AlignedBuf::zeroed(FIXED_BUFFER_SIZE)).collect();
+        let iovecs: Vec<libc::iovec> = fixed
+            .iter_mut()
+            .map(|buffer| libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() })
+            .collect();
+        // SAFETY: the buffers stay allocated, and in place, until after the
+        // ring is dropped, which unregisters them
+        if let Err(e) = unsafe { ring.submitter().register_buffers(&iovecs) } {
+            warn!("Could not register io_uring buffers for {}, using unregistered ones: {}", name, e);
+            fixed.clear();
+        }
+        let free = (0..fixed.len() as u16).rev().collect();
+        // Kernels before 5.19 cannot register an empty table, but take one
+        // of unused (-1) entries
+        let table = ring.submitter().register_files_sparse(FILE_SLOTS).or_else(|_| ring.submitter().register_files(&[-1; FILE_SLOTS as usize]));
+        let free_slots = match table {
+            Ok(()) => (0..FILE_SLOTS).rev().collect(),
+            Err(e) => {
+                warn!("Could not register a file table for {}, using descriptors: {}", name, e);
+                Vec::new()
+            }
+        };
+        let mut ring = Self {
+            ring,
+            name,
+            fixed,
+            free,
+            files: HashMap::new(),
+            free_slots,
+            ops: Vec::new(),
+            vacant: Vec::new(),
+            reaped: VecDeque::new(),
+            waker,
+        };
+        ring.arm_waker()?;
+        Ok(ring)
+    }
+
+    // Poll the waker's eventfd until it is next written
+    fn arm_waker(&mut self) -> io::Result<()> {
+        let Some(waker) = &self.waker else {
+            return Ok(());
+        };
+        let entry = opcode::PollAdd::new(types::Fd(waker.eventfd.as_raw_fd()), libc::POLLIN as u32).build().user_data(WAKE);
+        self.push(&[entry])
+    }
+
+    // Whether operations can be started on file `id`
+    pub(crate) fn has_file(&self, id: u64) -> bool {
+        self.files.contains_key(&id)
+    }
+
+    // Run operations started on file `id` on `file`, registering it if a
+    // slot is free
+    pub(crate) fn add_file(&mut self, id: u64, file: std::fs::File) {
+        let slot = self.free_slots.pop().and_then(|slot| match self.ring.submitter().register_files_update(slot, &[file.as_raw_fd()]) {
+            Ok(_) => Some(slot),
+            Err(e) => {
+                warn!("Could not register a data file with {}, using its descriptor: {}", self.name, e);
+                self.free_slots.push(slot);
+                None
+            }
+        });
+        self.files.insert(id, RingFile { file, slot, ops: 0, forgotten: false });
+    }
+
+    // Close file `id` once the operations in flight on it finish
+    pub(crate) fn forget_file(&mut self, id: u64) {
+        if let Some(file) = self.files.get_mut(&id) {
+            file.forgotten = true;
+            if file.ops == 0 {
+                self.close_file(id);
+            }
+        }
+    }
+
+    // Unregister and close file `id`, on which nothing is in flight
+    fn close_file(&mut self, id: u64) {
+        let Some(file) = self.files.remove(&id) else {
+            return;
+        };
+        if let Some(slot) = file.slot {
+            match self.ring.submitter().register_files_update(slot, &[-1]) {
+                Ok(_) => self.free_slots.push(slot),
+                // The slot stays taken, as the kernel may still hold the file
+                Err(e) => warn!("Could not unregister a data file from {}: {}", self.name, e),
+            }
+        }
+    }
+
+    // Operations in flight
+    pub(crate) fn in_flight(&self) -> usize {
+        self.ops.len() - self.vacant.len()
+    }
+
+    // Start `op` on file `id`, which has been added, its reads and writes at
+    // I/O priority `ioprio`. `permit` is released once it finishes.
+    pub(crate) fn start(&mut self, id: u64, op: UringOp, ioprio: u16, permit: Option<OwnedSemaphorePermit>) -> io::Result<()> {
+        let work = match op {
+            UringOp::Write { data, offset, done } => {
+                let verify = verify_writes();
+                let len = align_up(data.len() as u64) as usize;
+                let buffers = match self.take_fixed(len) {
+                    Some(index) => {
+                        let buffer = &mut self.fixed[index as usize];
+                        buffer[..data.len()].copy_from_slice(&data);
+                        buffer[data.len()..len].fill(0);
+                        Buffers::Fixed(index)
+                    }
+                    None => Buffers::Owned(vec![AlignedBuf::copy_from(&data, len)]),
+                };
+                let read_back = verify.then(|| Transfer::owned(vec![AlignedBuf::zeroed(len)], offset));
+                Work::Write { written: Transfer::new(buffers, offset, len), read_back, done }
+            }
+            UringOp::Read { size, offset, done } => {
+                let len = align_up(size) as usize;
+                let buffers = match self.take_fixed(len) {
+                    // Registered buffers are reused, so clear what the last
+                    // operation left in this one
+                    Some(index) => {
+                        self.fixed[index as usize][..len].fill(0);
+                        Buffers::Fixed(index)
+                    }
+                    None => Buffers::Owned(vec![AlignedBuf::zeroed(len)]),
+                };
+                Work::Read { transfer: Transfer::new(buffers, offset, len), done: ReadDone::Whole { size: size as usize, done } }
+            }
+            UringOp::WriteVectored { buffers, offset, done } => {
+                let padded: Vec<AlignedBuf> = buffers.iter().map(|data| AlignedBuf::copy_from(data, align_up(data.len() as u64) as usize)).collect();
+                let written = Transfer::owned(padded, offset);
+                let read_back = verify_writes().then(|| Transfer::owned(vec![AlignedBuf::zeroed(written.len)], offset));
+                Work::Write { written, read_back, done }
+            }
+            UringOp::ReadVectored { sizes, offset, done } => {
+                let buffers = sizes.iter().map(|&size| AlignedBuf::zeroed(align_up(size) as usize)).collect();
+                Work::Read { transfer: Transfer::owned(buffers, offset), done: ReadDone::Vectored { sizes, done } }
+            }
+            UringOp::Sync { done } => Work::Sync { synced: false, done },
+        };
+        let slot = match self.vacant.pop() {
+            Some(slot) => slot,
+            None => {
+                self.ops.push(None);
+                self.ops.len() - 1
+            }
+        };
+        self.files.get_mut(&id).expect("operation started on a file the ring does not have").ops += 1;
+        self.ops[slot] = Some(Pending { file: id, work, outstanding: 0, error: None, ioprio, permit });
+        self.submit(slot)
+    }
+
+    // A free registered buffer for a transfer of `len` bytes, if it fits
+    fn take_fixed(&mut self, len: usize) -> Option<u16> {
+        if len > FIXED_BUFFER_SIZE {
+            return None;
+        }
+        self.free.pop()
+    }
+
+    // Queue the next submissions of the operation in `slot`. The user data of
+    // each is the slot, shifted to leave the low bit for the part of the
+    // operation it is: a write's read-back, or anything else. A verified
+    // write goes in a chain with its read-back, linked with IOSQE_IO_LINK so
+    // the kernel starts the read once the write completes in full, without a
+    // trip back to this thread; a write that fails or comes up short cancels
+    // the read, and both are submitted again from where the write stopped.
+    fn submit(&mut self, slot: usize) -> io::Result<()> {
+        let Self { fixed, files, ops, .. } = self;
+        let pending = ops[slot].as_mut().expect("submission for a finished operation");
+        let file = &files[&pending.file];
+        let target = match file.slot {
+            Some(slot) => Target::Fixed(types::Fixed(slot)),
+            None => Target::Fd(types::Fd(file.file.as_raw_fd())),
+        };
+        let user_data = (slot as u64) << 1;
+        let ioprio = pending.ioprio;
+        let entries = match &mut pending.work {
+            Work::Write { written, read_back: Some(read_back), .. } if !written.finished() => {
+                read_back.moved = 0;
+                let write = written.entry(target, fixed, true, ioprio).flags(squeue::Flags::IO_LINK).user_data(user_data);
+                vec![write, read_back.entry(target, fixed, false, ioprio).user_data(user_data | 1)]
+            }
+            Work::Write { written, .. } if !written.finished() => vec![written.entry(target, fixed, true, ioprio).user_data(user_data)],
+            Work::Write { read_back: Some(read_back), .. } => vec![read_back.entry(target, fixed, false, ioprio).user_data(user_data | 1)],
+            Work::Write { .. } => unreachable!("write submitted once finished"),
+            Work::Read { transfer, .. } => vec![transfer.entry(target, fixed, false, ioprio).user_data(user_data)],
+            Work::Sync { .. } => vec![on_target!(target, |fd| opcode::Fsync::new(fd).flags(types::FsyncFlags::DATASYNC).build()).user_data(user_data)],
+        };
+        pending.outstanding = entries.len() as u8;
+        self.push(&entries)
+    }
+
+    // Queue submissions together, handing those queued to the kernel first if
+    // the submission queue lacks room, so a chain is never split between two
+    // io_uring_enter calls
+    fn push(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+        loop {
+            // SAFETY: every buffer and iovec the entries point to belongs to
+            // an operation in `ops`, or to the ring, and stays in place until
+            // the entries complete
+            if unsafe { self.ring.submission().push_multiple(entries) }.is_ok() {
+                return Ok(());
+            }
+            self.enter(0)?;
+            // Completions may have to be reaped before the kernel takes more
+            self.reap();
+            let sqpoll = self.ring.params().is_setup_sqpoll();
+            let queue = self.ring.submission();
+            if sqpoll && queue.capacity() - queue.len() < entries.len() {
+                // The kernel's polling thread has yet to take them
+                drop(queue);
+                let _ = self.ring.submitter().squeue_wait();
+            }
+        }
+    }
+
+    // Hand what is queued to the kernel, waiting for `want` completions
+    fn enter(&mut self, want: usize) -> io::Result<()> {
+        match self.ring.submit_and_wait(want) {
+            Ok(_) => Ok(()),
+            // Interrupted, or the completion queue must be reaped first
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY | libc::EAGAIN)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reap(&mut self) {
+        self.reaped.extend(self.ring.completion().map(|completion| (completion.user_data(), completion.result())));
+    }
+
+    // Hand new submissions to the kernel and handle the completions that have
+    // arrived. With `wait`, first wait for one, or for the waker.
+    pub(crate) fn turn(&mut self, wait: bool) -> io::Result<()> {
+        let want = usize::from(wait && self.reaped.is_empty());
+        self.enter(want)?;
+        self.reap();
+        while let Some((user_data, result)) = self.reaped.pop_front() {
+            if user_data == WAKE {
+                if let Some(waker) = &self.waker {
+                    waker.drain();
+                }
+                self.arm_waker()?;
+                continue;
+            }
+            self.complete(user_data, result)?;
+        }
+        Ok(())
+    }
+
+    // Handle a completion of part of an operation. Once none of its
+    // submissions is outstanding, submit what is left of it or finish it.
+    fn complete(&mut self, user_data: u64, result: i32) -> io::Result<()> {
+        let slot = (user_data >> 1) as usize;
+        let read_back_part = user_data & 1 == 1;
+        let pending = self.ops[slot].as_mut().expect("completion for a finished operation");
+        pending.outstanding -= 1;
+        let progress = match &mut pending.work {
+            // Cancelled as the write it is linked to failed or came up short
+            Work::Write { .. } if read_back_part && result == -libc::ECANCELED => Ok(()),
+            Work::Write { read_back: Some(read_back), .. } if read_back_part => read_back.progress(result, false),
+            Work::Write { written, .. } => written.progress(result, true),
+            Work::Read { transfer, .. } => transfer.progress(result, false),
+            Work::Sync { .. } if result == -libc::EINTR => Ok(()),
+            Work::Sync { .. } if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+            Work::Sync { synced, .. } => {
+                *synced = true;
+                Ok(())
+            }
+        };
+        if let Err(e) = progress {
+            pending.error.get_or_insert(e);
+        }
+        if pending.outstanding > 0 {
+            return Ok(());
+        }
+        if let Some(e) = pending.error.take() {
+            self.finish(slot, Err(e));
+            return Ok(());
+        }
+        let finished = match &pending.work {
+            Work::Write { written, read_back, .. } => written.finished() && read_back.as_ref().is_none_or(Transfer::finished),
+            Work::Read { transfer, .. } => transfer.finished(),
+            Work::Sync { synced, .. } => *synced,
+        };
+        if finished {
+            self.finish(slot, Ok(()));
+            return Ok(());
+        }
+        self.submit(slot)
+    }
+
+    // Send the result of the operation in `slot` and free what it holds
+    fn finish(&mut self, slot: usize, result: io::Result<()>) {
+        let Pending { file, work, permit, .. } = self.ops[slot].take().expect("operation finished twice");
+        self.vacant.push(slot);
+        let mut transfers = Vec::with_capacity(2);
+        match work {
+            Work::Write { written, read_back, done } => {
+                let result = result.and_then(|()| match &read_back {
+                    Some(read_back) => compare(&written, read_back, &self.fixed),
+                    None => Ok(()),
+                });
+                let _ = done.send(result);
+                transfers.push(written);
+                transfers.extend(read_back);
+            }
+            Work::Read { mut transfer, done } => {
+                match done {
+                    ReadDone::Whole { size, done } => {
+                        let read = result.map(|()| match &mut transfer.buffers {
+                            // Copied out, as the buffer goes back to be reused
+                            Buffers::Fixed(index) => Bytes::copy_from_slice(&self.fixed[*index as usize][..size]),
+                            Buffers::Owned(buffers) => Bytes::from_owner(buffers.pop().expect("read without a buffer")).slice(..size),
+                        });
+                        let _ = done.send(read);
+                    }
+                    ReadDone::Vectored { sizes, done } => {
+                        let read = result.map(|()| {
+                            transfer.slices(&self.fixed).into_iter().zip(&sizes).map(|(buffer, &size)| buffer[..size as usize].to_vec()).collect()
+                        });
+                        let _ = done.send(read);
+                    }
+                }
+                transfers.push(transfer);
+            }
+            Work::Sync { done, .. } => {
+                let _ = done.send(result);
+            }
+        }
+        for transfer in transfers {
+            if let Buffers::Fixed(index) = transfer.buffers {
+                self.free.push(index);
+            }
+        }
+        if let Some(ring_file) = self.files.get_mut(&file) {
+            ring_file.ops -= 1;
+            if ring_file.forgotten && ring_file.ops == 0 {
+                self.close_file(file);
+            }
+        }
+        drop(permit);
+    }
+
+    // Fail every operation in flight once the ring can no longer be driven.
+    // Their buffers, and the ring's, are leaked rather than freed, as the
+    // kernel may still be using them.
+    pub(crate) fn abandon(mut self, e: &io::Error) {
+        let name = std::mem::take(&mut self.name);
+        let failure = || io::Error::new(e.kind(), format!("io_uring ring of {} failed: {}", name, e));
+        for pending in self.ops.drain(..).flatten() {
+            match pending.work {
+                Work::Write { written, read_back, done } => {
+                    std::mem::forget((written, read_back));
+                    let _ = done.send(Err(failure()));
+                }
+                Work::Read { transfer, done } => {
+                    std::mem::forget(transfer);
+                    match done {
+                        ReadDone::Whole { done, .. } => {
+                            let _ = done.send(Err(failure()));
+                        }
+                        ReadDone::Vectored { done, .. } => {
+                            let _ = done.send(Err(failure()));
+                        }
+                    }
+                }
+                Work::Sync { done, .. } => {
+                    let _ = done.send(Err(failure()));
+                }
+            }
+        }
+        std::mem::forget(self);
+    }
+}
+
+// Compare what `read_back` read with what `written` wrote there
+fn compare(written: &Transfer, read_back: &Transfer, fixed: &[AlignedBuf]) -> io::Result<()> {
+    let read = read_back.slices(fixed).concat();
+    let mut position = 0;
+    for data in written.slices(fixed) {
+        check_written(data, &read[position..position + data.len()], written.offset + position as u64)?;
+        position += data.len();
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use std::time::{Duration, SystemTime};
+
+// How many prior versions of an overwritten record are kept, and for how
+// long. Versioning is off unless at least one limit is set; overwrites then
+// release the replaced version's blocks right away.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct VersionPolicy {
+    // Prior versions kept per record; the oldest are dropped first
+    pub(crate) max_versions: Option<usize>,
+    // Prior versions superseded longer ago than this are dropped
+    pub(crate) max_age: Option<Duration>,
+}
+
+impl VersionPolicy {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_versions.is_some() || self.max_age.is_some()
+    }
+
+    // How many of a record's prior versions, oldest first, fall outside the
+    // policy. `superseded` holds when each version was replaced, oldest first.
+    pub(crate) fn excess(&self, superseded: &[SystemTime], now: SystemTime) -> usize {
+        let mut excess = self.max_versions.map_or(0, |max| superseded.len().saturating_sub(max));
+        if let Some(max_age) = self.max_age {
+            let aged = superseded
+                .iter()
+                .take_while(|at| now.duration_since(**at).is_ok_and(|age| age > max_age))
+                .count();
+            excess = excess.max(aged);
+        }
+        excess
+    }
+}
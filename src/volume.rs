@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::info;
+
+use crate::file_io::{read_in_pieces, write_in_pieces, Advice, FileIO, BLOCK_SIZE};
+
+// I/O parameters of the data files under one path, such as the mount point of
+// a SATA SSD or an NVMe namespace. Parameters left out are detected or keep
+// their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VolumeConfig {
+    pub(crate) path: PathBuf,
+    // O_DIRECT alignment, instead of what the device reports
+    #[serde(default)]
+    pub(crate) alignment: Option<u64>,
+    // Largest single read or write; larger ones are split into pieces of
+    // this size
+    #[serde(default)]
+    pub(crate) max_io_size: Option<u64>,
+    // Reads and writes in flight at once on each data file; on Linux also
+    // the size of the submission queue of a polled ring
+    #[serde(default)]
+    pub(crate) queue_depth: Option<usize>,
+    // NUMA node whose I/O workers run the I/O of its data files, or whose
+    // CPUs and memory a polled ring's thread uses
+    #[serde(default)]
+    pub(crate) numa_node: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IoConfig {
+    volumes: Vec<VolumeConfig>,
+}
+
+// Volumes from `--io-config`; none until it is loaded
+static VOLUMES: OnceLock<Vec<VolumeConfig>> = OnceLock::new();
+
+// Load and check the per-volume I/O configuration at `path`. Volume paths
+// must exist, and are resolved so data files are matched however they are
+// reached.
+pub(crate) fn load(path: &Path) -> Result<()> {
+    let config: IoConfig = serde_json::from_slice(&std::fs::read(path)?).map_err(|e| anyhow::anyhow!("Invalid I/O configuration in {}: {}", path.display(), e))?;
+    let mut volumes = Vec::with_capacity(config.volumes.len());
+    for mut volume in config.volumes {
+        volume.path = std::fs::canonicalize(&volume.path).map_err(|e| anyhow::anyhow!("Volume {} in {}: {}", volume.path.display(), path.display(), e))?;
+        if let Some(alignment) = volume.alignment.filter(|&alignment| alignment < BLOCK_SIZE || !alignment.is_power_of_two()) {
+            anyhow::bail!("Alignment {} of volume {} is not a power of two of at least {} bytes", alignment, volume.path.display(), BLOCK_SIZE);
+        }
+        if let Some(max_io_size) = volume.max_io_size.filter(|&size| size == 0 || size % volume.alignment.unwrap_or(BLOCK_SIZE) != 0) {
+            anyhow::bail!("Maximum I/O size {} of volume {} is not a positive multiple of its alignment", max_io_size, volume.path.display());
+        }
+        if volume.queue_depth == Some(0) {
+            anyhow::bail!("Queue depth of volume {} must be positive", volume.path.display());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(node) = volume.numa_node {
+            crate::numa::node_cpus(node).map_err(|e| anyhow::anyhow!("Volume {}: {}", volume.path.display(), e))?;
+        }
+        info!(
+            "Volume {}: alignment {:?}, maximum I/O size {:?}, queue depth {:?}, NUMA node {:?}",
+            volume.path.display(),
+            volume.alignment,
+            volume.max_io_size,
+            volume.queue_depth,
+            volume.numa_node
+        );
+        volumes.push(volume);
+    }
+    VOLUMES.set(volumes).map_err(|_| anyhow::anyhow!("I/O configuration is already loaded"))
+}
+
+// The configured volume holding `path`, the one with the longest path if they
+// nest. A file that does not exist yet is resolved through its directory.
+pub(crate) fn for_path(path: &Path) -> Option<&'static VolumeConfig> {
+    let volumes = VOLUMES.get().filter(|volumes| !volumes.is_empty())?;
+    let resolved = std::fs::canonicalize(path).ok().or_else(|| {
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        Some(std::fs::canonicalize(parent).ok()?.join(path.file_name()?))
+    })?;
+    volumes.iter().filter(|volume| resolved.starts_with(&volume.path)).max_by_key(|volume| volume.path.components().count())
+}
+
+// Coarsest alignment configured for any volume. Records in every data file
+// share one layout, so they are aligned to suit all of them.
+pub(crate) fn coarsest_alignment() -> Option<u64> {
+    VOLUMES.get()?.iter().filter_map(|volume| volume.alignment).max()
+}
+
+// Decorator applying a volume's maximum I/O size and queue depth to a data
+// file on it
+pub(crate) struct VolumeFileIO {
+    inner: Arc<dyn FileIO + Send + Sync>,
+    max_io_size: u64,
+    queue: Option<Semaphore>,
+}
+
+impl VolumeFileIO {
+    // Wrap `file` if its volume limits I/O size or queue depth
+    pub(crate) fn wrap(file: Arc<dyn FileIO + Send + Sync>, volume: &VolumeConfig) -> Arc<dyn FileIO + Send + Sync> {
+        if volume.max_io_size.is_none() && volume.queue_depth.is_none() {
+            return file;
+        }
+        Arc::new(VolumeFileIO {
+            inner: file,
+            max_io_size: volume.max_io_size.unwrap_or(u64::MAX),
+            queue: volume.queue_depth.map(Semaphore::new),
+        })
+    }
+
+    // Wait for a slot in the queue, if its depth is limited
+    async fn slot(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        match &self.queue {
+            Some(queue) => Ok(Some(queue.acquire().await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl FileIO for VolumeFileIO {
+    async fn write_at(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        if data.len() as u64 <= self.max_io_size {
+            let _slot = self.slot().await?;
+            return self.inner.write_at(data, offset).await;
+        }
+        write_in_pieces(data, offset, self.max_io_size, |piece, offset| async move {
+            let _slot = self.slot().await?;
+            self.inner.write_at(piece.into(), offset).await
+        })
+        .await
+    }
+
+    async fn read_at(&self, size: u64, offset: u64) -> Result<Bytes> {
+        read_in_pieces(size, offset, self.max_io_size, |size, offset| async move {
+            let _slot = self.slot().await?;
+            self.inner.read_at(size, offset).await
+        })
+        .await
+    }
+
+    // A batch over the maximum size goes out buffer by buffer, each split as
+    // needed
+    async fn write_vectored_at(&self, buffers: Vec<Vec<u8>>, offset: u64) -> Result<()> {
+        let total: u64 = buffers.iter().map(|buffer| buffer.len() as u64).sum();
+        if total <= self.max_io_size {
+            let _slot = self.slot().await?;
+            return self.inner.write_vectored_at(buffers, offset).await;
+        }
+        let mut position = offset;
+        for buffer in buffers {
+            let length = buffer.len() as u64;
+            self.write_at(buffer, position).await?;
+            position += length;
+        }
+        Ok(())
+    }
+
+    async fn read_vectored_at(&self, sizes: Vec<u64>, offset: u64) -> Result<Vec<Vec<u8>>> {
+        if sizes.iter().sum::<u64>() <= self.max_io_size {
+            let _slot = self.slot().await?;
+            return self.inner.read_vectored_at(sizes, offset).await;
+        }
+        let mut position = offset;
+        let mut buffers = Vec::with_capacity(sizes.len());
+        for size in sizes {
+            buffers.push(Vec::from(self.read_at(size, position).await?));
+            position += size;
+        }
+        Ok(buffers)
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.inner.len().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.inner.set_len(size)
+    }
+
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn segment_size(&self) -> Option<u64> {
+        self.inner.segment_size()
+    }
+
+    fn storage_kind(&self) -> Option<&'static str> {
+        self.inner.storage_kind()
+    }
+
+    fn preallocate(&self, length: u64) -> Result<()> {
+        self.inner.preallocate(length)
+    }
+
+    fn remove_segment(&self, index: u64) -> Result<bool> {
+        self.inner.remove_segment(index)
+    }
+
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> Result<()> {
+        self.inner.advise(offset, length, advice)
+    }
+
+    fn try_read_at(&self, size: u64, offset: u64) -> Result<Option<Bytes>> {
+        if size > self.max_io_size {
+            return Ok(None);
+        }
+        self.inner.try_read_at(size, offset)
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
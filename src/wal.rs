@@ -0,0 +1,526 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::file_manager::{RecordKey, RequestMap, Trash, Versions};
+use crate::index_store::{IndexContents, PersistedRecord, PersistedTrash};
+use crate::snapshot::Crc32;
+
+// Bytes before each entry's payload: payload length and CRC-32, little endian
+pub(crate) const FRAME_HEADER: usize = 8;
+
+// Prefix a payload with its length and CRC-32, as log entries and index
+// checkpoints are stored
+pub(crate) fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    let mut frame = Vec::with_capacity(FRAME_HEADER + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc.finish().to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Payload of the frame at `cursor`, unless it is torn or fails its CRC-32
+pub(crate) fn decode_frame(contents: &[u8], cursor: usize) -> Option<&[u8]> {
+    let header = contents.get(cursor..cursor + FRAME_HEADER)?;
+    let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = contents.get(cursor + FRAME_HEADER..cursor + FRAME_HEADER + length)?;
+    let mut actual = Crc32::new();
+    actual.update(payload);
+    (actual.finish() == crc).then_some(payload)
+}
+
+// A change to the request map, as recorded in the write-ahead log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum WalOp {
+    Put { record: PersistedRecord },
+    // Inserted as one unit, e.g. an atomic batch
+    PutMany { records: Vec<PersistedRecord> },
+    Delete { namespace: String, request_id: String },
+    Rename { namespace: String, request_id: String, new_request_id: String },
+    // Adds or, after a move by compaction, updates a prior version of a record
+    Version { record: PersistedRecord },
+    DropVersions { namespace: String, request_id: String, generations: Vec<u64> },
+    // Moves a deleted record to the trash or, after a move by compaction,
+    // updates its trashed copy
+    Trash { trashed: PersistedTrash },
+    Purge { namespace: String, request_id: String },
+    Undelete { namespace: String, request_id: String },
+}
+
+impl WalOp {
+    // Apply the change to a request map and the prior versions and trash kept
+    // alongside it during replay
+    pub(crate) fn apply(self, request_map: &RequestMap, versions: &mut Versions, trash: &mut Trash) {
+        match self {
+            WalOp::Put { record } => insert(request_map, record),
+            WalOp::PutMany { records } => {
+                for record in records {
+                    insert(request_map, record);
+                }
+            }
+            WalOp::Delete { namespace, request_id } => {
+                request_map.remove(&RecordKey { namespace, request_id });
+            }
+            WalOp::Rename { namespace, request_id, new_request_id } => {
+                let key = RecordKey { namespace, request_id };
+                if let Some(metadata) = request_map.remove(&key) {
+                    request_map.insert(RecordKey { namespace: key.namespace.clone(), request_id: new_request_id.clone() }, metadata);
+                }
+                if let Some(history) = versions.remove(&key) {
+                    versions.insert(RecordKey { namespace: key.namespace, request_id: new_request_id }, history);
+                }
+            }
+            WalOp::Version { record } => {
+                let (key, metadata) = record.into_entry();
+                let history = versions.entry(key).or_default();
+                history.retain(|version| version.generation != metadata.generation);
+                let position = history.partition_point(|version| version.generation < metadata.generation);
+                history.insert(position, metadata);
+            }
+            WalOp::DropVersions { namespace, request_id, generations } => {
+                let key = RecordKey { namespace, request_id };
+                if let Some(history) = versions.get_mut(&key) {
+                    history.retain(|version| !generations.contains(&version.generation));
+                    if history.is_empty() {
+                        versions.remove(&key);
+                    }
+                }
+            }
+            WalOp::Trash { trashed } => {
+                let (key, trashed) = trashed.into_entry();
+                // A newer record written under the same ID stays
+                let same = request_map.get(&key).is_some_and(|metadata| {
+                    metadata.offset == trashed.metadata.offset && metadata.generation == trashed.metadata.generation
+                });
+                if same {
+                    request_map.remove(&key);
+                }
+                trash.insert(key, trashed);
+            }
+            WalOp::Purge { namespace, request_id } => {
+                trash.remove(&RecordKey { namespace, request_id });
+            }
+            WalOp::Undelete { namespace, request_id } => {
+                let key = RecordKey { namespace, request_id };
+                if let Some(trashed) = trash.remove(&key) {
+                    request_map.insert(key, trashed.metadata);
+                }
+            }
+        }
+    }
+}
+
+fn insert(request_map: &RequestMap, record: PersistedRecord) {
+    let (key, metadata) = record.into_entry();
+    request_map.insert(key, metadata);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry {
+    sequence: u64,
+    op: WalOp,
+}
+
+// Write-ahead log kept next to a data file
+pub(crate) fn wal_path(data_path: &str) -> String {
+    format!("{}.wal", data_path)
+}
+
+// An append to the log failed to reach the disk. The changes it held, and
+// every change logged after them, are lost and have to be rolled back.
+#[derive(Debug)]
+pub(crate) struct LogFailed {
+    pub(crate) path: String,
+    pub(crate) reason: String,
+}
+
+impl std::fmt::Display for LogFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to append to {}: {}", self.path, self.reason)
+    }
+}
+
+impl std::error::Error for LogFailed {}
+
+// Append-only log of request map changes since the last checkpoint of the
+// sidecar index. Every entry carries the next sequence number and a CRC-32.
+// Entries are staged in memory under the file manager's lock and written and
+// synced by group commit outside it: whoever waits on a commit flushes every
+// entry staged so far with a single sync, so concurrent writers share it.
+pub(crate) struct Wal {
+    shared: Arc<SharedLog>,
+    // Sequence of the last entry staged or replayed
+    pub(crate) sequence: u64,
+    // Entries in the log, i.e. since the last checkpoint, staged or not
+    pub(crate) entries: usize,
+}
+
+// The part of the log flushed outside the file manager's lock
+struct SharedLog {
+    path: String,
+    // Held while the file is written, synced or cut back, so flushes run one
+    // at a time and in sequence order
+    file: Mutex<LogFile>,
+    staged: Mutex<Staged>,
+}
+
+struct LogFile {
+    file: File,
+    // Length of the valid prefix of the log
+    len: u64,
+    // Entries in the file
+    entries: usize,
+}
+
+// Entries not yet on disk
+#[derive(Default)]
+struct Staged {
+    frames: Vec<u8>,
+    count: usize,
+    // Sequence of the last entry staged, and of the last one synced
+    last: u64,
+    durable: u64,
+    // Why a flush failed, until the log is rolled back or checkpointed
+    failed: Option<String>,
+    // Bumped on every roll back, so a commit of a rolled back entry cannot
+    // be mistaken for one of the entry that reused its sequence
+    rollbacks: u64,
+}
+
+// A point in the log to wait on before acknowledging the changes up to it
+pub(crate) struct Commit {
+    shared: Arc<SharedLog>,
+    sequence: u64,
+    rollbacks: u64,
+}
+
+impl Commit {
+    // Wait until every entry up to the commit point is synced, flushing them
+    // if nobody else is
+    pub(crate) async fn wait(self) -> Result<()> {
+        if self.shared.durable(self.sequence, self.rollbacks)? {
+            return Ok(());
+        }
+        tokio::task::spawn_blocking(move || self.shared.flush(self.sequence, self.rollbacks)).await?
+    }
+}
+
+impl SharedLog {
+    fn failure(&self, reason: &str) -> anyhow::Error {
+        LogFailed { path: self.path.clone(), reason: reason.to_string() }.into()
+    }
+
+    // Whether entries up to `sequence` are synced; fails if they were lost
+    fn durable(&self, sequence: u64, rollbacks: u64) -> Result<bool> {
+        let staged = self.staged.lock().unwrap();
+        if staged.rollbacks != rollbacks {
+            return Err(self.failure("the change was rolled back after an earlier append failed"));
+        }
+        if staged.durable >= sequence {
+            return Ok(true);
+        }
+        match &staged.failed {
+            Some(reason) => Err(self.failure(reason)),
+            None => Ok(false),
+        }
+    }
+
+    // Write and sync every staged entry, unless entries up to `sequence` are
+    // already synced. A failure cuts the file back to its valid prefix, drops
+    // everything staged and fails the log until it is rolled back.
+    fn flush(&self, sequence: u64, rollbacks: u64) -> Result<()> {
+        let mut log = self.file.lock().unwrap();
+        if self.durable(sequence, rollbacks)? {
+            return Ok(());
+        }
+        let (frames, count, last) = {
+            let mut staged = self.staged.lock().unwrap();
+            (std::mem::take(&mut staged.frames), std::mem::take(&mut staged.count), staged.last)
+        };
+        let written = log.file.write_all(&frames).and_then(|()| log.file.sync_data());
+        let mut staged = self.staged.lock().unwrap();
+        if let Err(e) = written {
+            // Cut off a partial or unsynced frame so later entries stay
+            // readable
+            let _ = log.file.set_len(log.len);
+            staged.frames.clear();
+            staged.count = 0;
+            staged.failed = Some(e.to_string());
+            return Err(self.failure(&e.to_string()));
+        }
+        log.len += frames.len() as u64;
+        log.entries += count;
+        staged.durable = last;
+        Ok(())
+    }
+}
+
+// The entries of a log, read front to back
+pub(crate) struct Replay {
+    // Changes made after the checkpoint, in order
+    pub(crate) ops: Vec<WalOp>,
+    // Sequence of the last entry, or the checkpoint's if there is none after it
+    pub(crate) sequence: u64,
+    // Entries read, including those the checkpoint already covers
+    pub(crate) entries: usize,
+    // Length of the valid prefix; anything after it is a torn or corrupt tail
+    pub(crate) valid_len: u64,
+}
+
+// Read entries up to the first torn or corrupt frame. Entries the checkpoint
+// at sequence `checkpoint` already covers are skipped, so each change is
+// applied exactly once.
+fn parse(path: &str, contents: &[u8], checkpoint: u64) -> Result<Replay> {
+    let mut ops = Vec::new();
+    let mut sequence = checkpoint;
+    let mut entries = 0;
+    let mut cursor = 0;
+    while let Some(payload) = decode_frame(contents, cursor) {
+        let Ok(entry) = serde_json::from_slice::<WalEntry>(payload) else {
+            break;
+        };
+
+        if entry.sequence > sequence {
+            if entry.sequence != sequence + 1 {
+                anyhow::bail!("{} jumps from sequence {} to {}", path, sequence, entry.sequence);
+            }
+            sequence = entry.sequence;
+            ops.push(entry.op);
+        }
+        entries += 1;
+        cursor += FRAME_HEADER + payload.len();
+    }
+    Ok(Replay { ops, sequence, entries, valid_len: cursor as u64 })
+}
+
+// Apply logged changes to a checkpoint of the index as recovery does, for
+// tools that read the index without opening the data file
+pub(crate) fn replay_onto(contents: IndexContents, ops: Vec<WalOp>) -> IndexContents {
+    let request_map = RequestMap::in_memory();
+    let mut versions = Versions::new();
+    let mut trash: Trash = contents.trash.into_iter().collect();
+    for (key, metadata) in contents.entries {
+        request_map.insert(key, metadata);
+    }
+    for (key, metadata) in contents.versions {
+        versions.entry(key).or_default().push(metadata);
+    }
+    for op in ops {
+        op.apply(&request_map, &mut versions, &mut trash);
+    }
+
+    let entries = request_map.read().iter().collect();
+    let versions = versions
+        .into_iter()
+        .flat_map(|(key, history)| history.into_iter().map(move |metadata| (key.clone(), metadata)))
+        .collect();
+    IndexContents { entries, versions, trash: trash.into_iter().collect() }
+}
+
+// Read the log without changing it, returning its entries and its length. A
+// missing log is an empty one.
+pub(crate) fn read(path: &str, checkpoint: u64) -> Result<(Replay, u64)> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok((parse(path, &contents, checkpoint)?, contents.len() as u64))
+}
+
+impl Wal {
+    // Open the log, returning it with the changes made after the checkpoint
+    // at sequence `checkpoint`, in order. A torn or corrupt tail, left by a
+    // crash mid-append, is cut off.
+    pub(crate) fn open(path: &str, checkpoint: u64) -> Result<(Self, Vec<WalOp>)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let replay = parse(path, &contents, checkpoint)?;
+
+        if replay.valid_len < contents.len() as u64 {
+            warn!("Discarding {} bytes of torn or corrupt entries at the end of {}", contents.len() as u64 - replay.valid_len, path);
+            file.set_len(replay.valid_len)?;
+            file.sync_all()?;
+        }
+
+        let shared = SharedLog {
+            path: path.to_string(),
+            file: Mutex::new(LogFile { file, len: replay.valid_len, entries: replay.entries }),
+            staged: Mutex::new(Staged { last: replay.sequence, durable: replay.sequence, ..Staged::default() }),
+        };
+        let wal = Self {
+            shared: Arc::new(shared),
+            sequence: replay.sequence,
+            entries: replay.entries,
+        };
+        Ok((wal, replay.ops))
+    }
+
+    // Stage a change, returning its sequence. It is not on disk until a
+    // commit covering it has been waited on. Fails without staging anything
+    // once a flush has failed, until the log is rolled back.
+    pub(crate) fn append(&mut self, op: WalOp) -> Result<u64> {
+        let entry = WalEntry {
+            sequence: self.sequence + 1,
+            op,
+        };
+        let mut staged = self.shared.staged.lock().unwrap();
+        if let Some(reason) = &staged.failed {
+            return Err(self.shared.failure(reason));
+        }
+        let frame = encode_frame(&serde_json::to_vec(&entry)?);
+        staged.frames.extend_from_slice(&frame);
+        staged.count += 1;
+        staged.last = entry.sequence;
+        self.sequence = entry.sequence;
+        self.entries += 1;
+        Ok(self.sequence)
+    }
+
+    // Commit point covering every change staged so far
+    pub(crate) fn commit(&self) -> Commit {
+        Commit {
+            shared: self.shared.clone(),
+            sequence: self.sequence,
+            rollbacks: self.shared.staged.lock().unwrap().rollbacks,
+        }
+    }
+
+    // Write and sync every change staged so far, e.g. before the log is read
+    // back to checkpoint it
+    pub(crate) fn flush(&self) -> Result<()> {
+        let commit = self.commit();
+        commit.shared.flush(commit.sequence, commit.rollbacks)
+    }
+
+    // Whether a flush has failed and the log has yet to be rolled back
+    pub(crate) fn failed(&self) -> bool {
+        self.shared.staged.lock().unwrap().failed.is_some()
+    }
+
+    // Empty the log once a checkpoint covering all its entries is on disk,
+    // staged ones included, which the checkpoint has made durable. That also
+    // clears a failed flush. Sequence numbers carry on from where they were.
+    pub(crate) fn reset(&mut self) -> Result<()> {
+        let mut log = self.shared.file.lock().unwrap();
+        log.file.set_len(0)?;
+        log.file.sync_all()?;
+        log.len = 0;
+        log.entries = 0;
+        let mut staged = self.shared.staged.lock().unwrap();
+        staged.frames.clear();
+        staged.count = 0;
+        staged.durable = staged.last;
+        staged.failed = None;
+        self.entries = 0;
+        Ok(())
+    }
+
+    // Cut the file back to the entries that reached the disk after a flush
+    // failed, so they can be read back to roll the request map back to
+    pub(crate) fn discard_unsynced(&self) -> Result<()> {
+        let log = self.shared.file.lock().unwrap();
+        log.file.set_len(log.len)?;
+        log.file.sync_all()?;
+        Ok(())
+    }
+
+    // Drop every change staged after the last entry on disk and take appends
+    // again, once the caller has rolled the request map back to match.
+    // Returns the sequence of that entry.
+    pub(crate) fn roll_back(&mut self) -> u64 {
+        let log = self.shared.file.lock().unwrap();
+        let mut staged = self.shared.staged.lock().unwrap();
+        staged.frames.clear();
+        staged.count = 0;
+        staged.last = staged.durable;
+        staged.failed = None;
+        staged.rollbacks += 1;
+        self.sequence = staged.durable;
+        self.entries = log.entries;
+        self.sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sequence: u64, request_id: &str) -> Vec<u8> {
+        let op = WalOp::Delete { namespace: String::new(), request_id: request_id.to_string() };
+        encode_frame(&serde_json::to_vec(&WalEntry { sequence, op }).unwrap())
+    }
+
+    fn deleted(replay: &Replay) -> Vec<&str> {
+        replay
+            .ops
+            .iter()
+            .map(|op| match op {
+                WalOp::Delete { request_id, .. } => request_id.as_str(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_skips_entries_the_checkpoint_covers() {
+        let log = [entry(1, "a"), entry(2, "b"), entry(3, "c")].concat();
+        let replay = parse("test.wal", &log, 1).unwrap();
+        assert_eq!(deleted(&replay), ["b", "c"]);
+        assert_eq!(replay.sequence, 3);
+        assert_eq!(replay.entries, 3);
+        assert_eq!(replay.valid_len, log.len() as u64);
+    }
+
+    #[test]
+    fn parse_stops_at_a_torn_tail() {
+        let valid = [entry(1, "a"), entry(2, "b")].concat();
+        let torn = entry(3, "c");
+        for cut in [1, FRAME_HEADER, torn.len() - 1] {
+            let log = [valid.as_slice(), &torn[..cut]].concat();
+            let replay = parse("test.wal", &log, 0).unwrap();
+            assert_eq!(deleted(&replay), ["a", "b"]);
+            assert_eq!(replay.sequence, 2);
+            assert_eq!(replay.valid_len, valid.len() as u64);
+        }
+    }
+
+    #[test]
+    fn parse_stops_at_a_crc_mismatch() {
+        let first = entry(1, "a");
+        let mut corrupt = entry(2, "b");
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        let log = [first.clone(), corrupt, entry(3, "c")].concat();
+        let replay = parse("test.wal", &log, 0).unwrap();
+        assert_eq!(deleted(&replay), ["a"]);
+        assert_eq!(replay.sequence, 1);
+        assert_eq!(replay.entries, 1);
+        assert_eq!(replay.valid_len, first.len() as u64);
+    }
+
+    #[test]
+    fn parse_rejects_a_sequence_gap() {
+        let log = [entry(1, "a"), entry(3, "c")].concat();
+        let Err(error) = parse("test.wal", &log, 0) else { panic!("a gap was accepted") };
+        assert_eq!(error.to_string(), "test.wal jumps from sequence 1 to 3");
+    }
+
+    #[test]
+    fn parse_rejects_a_gap_after_the_checkpoint() {
+        let log = [entry(1, "a"), entry(2, "b")].concat();
+        assert!(parse("test.wal", &log, 5).is_ok());
+        let log = [entry(1, "a"), entry(7, "g")].concat();
+        let Err(error) = parse("test.wal", &log, 5) else { panic!("a gap was accepted") };
+        assert_eq!(error.to_string(), "test.wal jumps from sequence 5 to 7");
+    }
+}